@@ -0,0 +1,305 @@
+//! anf.rs: Conversion to A-normal form, in which every intermediate result is
+//! named by a `let`, so operations only have variables and literals as
+//! operands, and the order of evaluation is explicit.
+
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::exprs::{map_subexpressions, subexpressions};
+use crate::intern::intern;
+use crate::opt::temporary;
+use crate::parser::{Definition, Expression, MatchArm, Variable};
+use crate::subst::{names, rename_apart};
+
+/// Convert `e` to A-normal form, so `f (g x)` becomes `let t = g x in f t`.
+/// Subexpressions which are only evaluated conditionally, like the branches
+/// of an `if`, and function bodies are converted where they are. A `let` in
+/// an operand is moved out to enclose the rest of the operation, so its
+/// variables are renamed apart first, lest they capture the rest's.
+pub fn to_anf(e: Expression) -> Expression {
+    let e = rename_apart(&e);
+    let mut anf = Anf { used: names(&e) };
+    anf.term(e)
+}
+
+/// What to do with the result of the expression being converted: build the
+/// rest of the program around it.
+type Continuation<'a, T = Expression> = Box<dyn FnOnce(&mut Anf, T) -> Expression + 'a>;
+
+struct Anf {
+    /// The names which temporaries mustn't take.
+    used: HashSet<String>,
+}
+
+impl Anf {
+    /// Convert `e`, leaving its result where it is.
+    fn term(&mut self, e: Expression) -> Expression {
+        self.normalize(e, Box::new(|_, e| e))
+    }
+
+    /// Convert `e`, passing its result to `k`, which may be any expression
+    /// whose operands are atomic.
+    fn normalize(&mut self, e: Expression, k: Continuation) -> Expression {
+        use Expression::*;
+        match e {
+            e if is_atomic(&e) => k(self, e),
+            Fn(param, body) => {
+                let body = self.term(Rc::unwrap_or_clone(body));
+                k(self, Fn(param, Rc::new(body)))
+            }
+            Let(definitions, body) => {
+                let recursive = definitions.iter().any(|d| d.recursive);
+                if recursive {
+                    let definitions = definitions
+                        .into_iter()
+                        .map(|d| Definition {
+                            expr: Rc::new(self.term(Rc::unwrap_or_clone(d.expr))),
+                            ..d
+                        })
+                        .collect();
+                    let body = self.normalize(Rc::unwrap_or_clone(body), k);
+                    Let(definitions, Rc::new(body))
+                } else {
+                    // The definitions' temporaries are bound before the
+                    // group, so they can't see its variables either.
+                    self.normalize_definitions(
+                        definitions.into_iter(),
+                        vec![],
+                        Box::new(move |anf, definitions| {
+                            let body = anf.normalize(Rc::unwrap_or_clone(body), k);
+                            Let(definitions, Rc::new(body))
+                        }),
+                    )
+                }
+            }
+            If(cond, yes, no) => self.atom(
+                Rc::unwrap_or_clone(cond),
+                Box::new(move |anf, cond| {
+                    let yes = anf.term(Rc::unwrap_or_clone(yes));
+                    let no = anf.term(Rc::unwrap_or_clone(no));
+                    k(anf, If(Rc::new(cond), Rc::new(yes), Rc::new(no)))
+                }),
+            ),
+            And(left, right) => self.atom(
+                Rc::unwrap_or_clone(left),
+                Box::new(move |anf, left| {
+                    let right = anf.term(Rc::unwrap_or_clone(right));
+                    k(anf, And(Rc::new(left), Rc::new(right)))
+                }),
+            ),
+            Or(left, right) => self.atom(
+                Rc::unwrap_or_clone(left),
+                Box::new(move |anf, left| {
+                    let right = anf.term(Rc::unwrap_or_clone(right));
+                    k(anf, Or(Rc::new(left), Rc::new(right)))
+                }),
+            ),
+            Seq(first, second) => self.normalize(
+                Rc::unwrap_or_clone(first),
+                Box::new(move |anf, first| {
+                    let second = anf.normalize(Rc::unwrap_or_clone(second), k);
+                    Seq(Rc::new(first), Rc::new(second))
+                }),
+            ),
+            Ann(e, t) => self.normalize(
+                Rc::unwrap_or_clone(e),
+                Box::new(move |anf, e| k(anf, Ann(Rc::new(e), t))),
+            ),
+            Match(scrutinee, arms) => self.atom(
+                Rc::unwrap_or_clone(scrutinee),
+                Box::new(move |anf, scrutinee| {
+                    let arms = arms
+                        .into_iter()
+                        .map(|arm| MatchArm {
+                            pattern: arm.pattern,
+                            guard: arm.guard.map(|guard| anf.term(guard)),
+                            body: anf.term(arm.body),
+                        })
+                        .collect();
+                    k(anf, Match(Rc::new(scrutinee), arms))
+                }),
+            ),
+            // Everything else evaluates all its operands, from left to right.
+            e => {
+                let operands = subexpressions(&e).into_iter().cloned().collect();
+                self.atoms(
+                    operands,
+                    vec![],
+                    Box::new(move |anf, atoms| {
+                        let mut atoms = atoms.into_iter();
+                        let e = map_subexpressions(e, |_| {
+                            atoms.next().expect("an atom for each operand")
+                        });
+                        k(anf, e)
+                    }),
+                )
+            }
+        }
+    }
+
+    /// Convert `e`, binding its result to a temporary unless it's already
+    /// atomic, and pass the atom to `k`.
+    fn atom(&mut self, e: Expression, k: Continuation) -> Expression {
+        self.normalize(
+            e,
+            Box::new(|anf, e| {
+                if is_atomic(&e) {
+                    return k(anf, e);
+                }
+                let t = anf.temporary();
+                let body = k(anf, Expression::Var(t.clone()));
+                Expression::Let(
+                    vec![Definition {
+                        var: t,
+                        expr: Rc::new(e),
+                        recursive: false,
+                    }],
+                    Rc::new(body),
+                )
+            }),
+        )
+    }
+
+    /// Convert each of `es` to an atom with [`Anf::atom`], in order, and pass
+    /// them, after the `done` ones, to `k`.
+    fn atoms(
+        &mut self,
+        mut es: Vec<Expression>,
+        mut done: Vec<Expression>,
+        k: Continuation<'_, Vec<Expression>>,
+    ) -> Expression {
+        if es.is_empty() {
+            return k(self, done);
+        }
+        let first = es.remove(0);
+        self.atom(
+            first,
+            Box::new(move |anf, atom| {
+                done.push(atom);
+                anf.atoms(es, done, k)
+            }),
+        )
+    }
+
+    /// Convert the expressions of `definitions`, in order, and pass them,
+    /// after the `done` ones, to `k`.
+    fn normalize_definitions(
+        &mut self,
+        mut definitions: std::vec::IntoIter<Definition>,
+        mut done: Vec<Definition>,
+        k: Continuation<'_, Vec<Definition>>,
+    ) -> Expression {
+        let Some(d) = definitions.next() else {
+            return k(self, done);
+        };
+        let Definition {
+            var,
+            expr,
+            recursive,
+        } = d;
+        self.normalize(
+            Rc::unwrap_or_clone(expr),
+            Box::new(move |anf, expr| {
+                done.push(Definition {
+                    var,
+                    expr: Rc::new(expr),
+                    recursive,
+                });
+                anf.normalize_definitions(definitions, done, k)
+            }),
+        )
+    }
+
+    fn temporary(&mut self) -> Variable {
+        let name = temporary(&self.used);
+        self.used.insert(name.clone());
+        Variable {
+            ident: intern(&name),
+        }
+    }
+}
+
+/// Variables and literals, which needn't be named.
+fn is_atomic(e: &Expression) -> bool {
+    use Expression::*;
+    matches!(
+        e,
+        Var(_) | Num(_) | Float(_) | True | False | Str(_) | Char(_) | Unit | Nil
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::eval;
+    use crate::parser::parse;
+
+    fn anf(e: &str) -> String {
+        to_anf(parse(e).unwrap()).to_string()
+    }
+
+    /// Whether the operands of every operation in `e` are atomic.
+    fn is_anf(e: &Expression) -> bool {
+        use Expression::*;
+        let operands_atomic = match e {
+            Fn(_, _) | Let(_, _) | Seq(_, _) | Ann(_, _) => true,
+            If(cond, _, _) | And(cond, _) | Or(cond, _) | Match(cond, _) => is_atomic(cond),
+            e => subexpressions(e).into_iter().all(is_atomic),
+        };
+        operands_atomic && subexpressions(e).into_iter().all(is_anf)
+    }
+
+    #[test]
+    fn test_to_anf() {
+        assert_eq!(anf("f (g x)"), "let t = g x in f t");
+        assert_eq!(anf("f (g (h x))"), "let t = h x in let t1 = g t in f t1");
+        assert_eq!(anf("1 + 2 * 3"), "let t = 2 * 3 in 1 + t");
+        assert_eq!(
+            anf("(1 + 2) * (3 + 4)"),
+            "let t = 1 + 2 in let t1 = 3 + 4 in t * t1"
+        );
+        assert_eq!(anf("f x"), "f x");
+        // Functions are named too, and their bodies converted.
+        assert_eq!(
+            anf("g (fn x. (x * 2 + 1))"),
+            "let t1 = fn x. let t = x * 2 in t + 1 in g t1"
+        );
+        // Including in the function position of an application.
+        assert_eq!(anf("map f l"), "let t = map f in t l");
+        // Branches are only evaluated if taken, so are converted in place.
+        assert_eq!(
+            anf("if f x then g (h y) else 0"),
+            "let t = f x in if t then let t1 = h y in g t1 else 0"
+        );
+        // A let's definition needn't be atomic.
+        assert_eq!(
+            anf("let x = f (g 1) in x"),
+            "let t = g 1 in let x = f t in x"
+        );
+        // Temporaries don't clash with the program's variables.
+        assert_eq!(anf("t (t 1)"), "let t1 = t 1 in t t1");
+    }
+
+    #[test]
+    fn test_to_anf_preserves_evaluation() {
+        for source in [
+            "1 + 2 * 3 - 4 / 2",
+            "let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact (2 + 3)",
+            "let rec even = fn n. if n < 1 then true else odd (n - 1) \
+             and odd = fn n. if n < 1 then false else even (n - 1) in even (2 * 2)",
+            "let f = fn x. <x, x + 1> in snd(f (fst(<3, 4>)))",
+            "hd(tl([1 + 1, 2 * 2, 3])) :: [fst(<4, 5>)]",
+            "let x = 1 and y = 2 in let x = y and y = x in x - y",
+            "false and 1 / 0 == 1 or not(false)",
+            "(fn f. f (f 1)) (fn x. (x * 10))",
+            "let x = 5 in (let x = 1 in x) + x",
+            "let x = 5 in <let x = 1 in x, x>",
+            "let f = fn x. x in f (let f = fn y. 2 in f 1) + f 3",
+        ] {
+            let e = parse(source).unwrap();
+            let converted = to_anf(e.clone());
+            assert!(is_anf(&converted), "{} isn't in A-normal form", converted);
+            assert_eq!(eval(&converted), eval(&e), "{}", source);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! arbitrary.rs: proptest strategies for well-formed expressions, i.e. those
+//! the parser could have produced, for property tests.
+
+use proptest::collection::{btree_map, vec};
+use proptest::prelude::*;
+use proptest::strategy::Union;
+use std::rc::Rc;
+
+use crate::parser::{Definition, Expression, MatchArm, Pattern, Type, Variable};
+
+const KEYWORDS: &[&str] = &[
+    "let", "in", "if", "then", "else", "fn", "true", "false", "not", "and", "or", "succ", "pred",
+    "fst", "snd", "nil", "hd", "tl", "rec", "match", "with", "when",
+];
+
+/// Identifiers, which may name variables or record fields. Keywords can't.
+fn ident() -> impl Strategy<Value = String> {
+    "[a-z][a-zA-Z0-9]{0,4}".prop_filter("keywords aren't identifiers", |s| {
+        !KEYWORDS.contains(&s.as_str())
+    })
+}
+
+fn variable() -> impl Strategy<Value = Variable> {
+    ident().prop_map(|s| Variable { ident: s.into() })
+}
+
+/// The literals which may also appear in patterns.
+fn literal() -> impl Strategy<Value = Expression> {
+    prop_oneof![
+        Just(Expression::True),
+        Just(Expression::False),
+        Just(Expression::Unit),
+        any::<i64>().prop_map(Expression::Num),
+        any::<f64>()
+            .prop_filter("MiniML has no literal for infinities or NaN", |f| f
+                .is_finite())
+            .prop_map(Expression::Float),
+        any::<String>().prop_map(Expression::Str),
+        any::<char>().prop_map(Expression::Char),
+    ]
+}
+
+fn pattern() -> impl Strategy<Value = Pattern> {
+    let leaf = prop_oneof![
+        Just(Pattern::Wildcard),
+        Just(Pattern::Nil),
+        variable().prop_map(Pattern::Var),
+        literal().prop_map(Pattern::Literal),
+    ];
+    leaf.prop_recursive(3, 8, 2, |p| {
+        prop_oneof![
+            (p.clone(), p.clone())
+                .prop_map(|(head, tail)| Pattern::Cons(Box::new(head), Box::new(tail))),
+            (p.clone(), p).prop_map(|(p1, p2)| Pattern::Pair(Box::new(p1), Box::new(p2))),
+        ]
+    })
+}
+
+fn ty() -> impl Strategy<Value = Type> {
+    let leaf = prop_oneof![
+        Just(Type::Int),
+        Just(Type::Bool),
+        Just(Type::Float),
+        Just(Type::String),
+        Just(Type::Char),
+        Just(Type::Unit),
+    ];
+    leaf.prop_recursive(3, 8, 2, |t| {
+        prop_oneof![
+            (t.clone(), t.clone())
+                .prop_map(|(arg, result)| Type::Fn(Box::new(arg), Box::new(result))),
+            (t.clone(), t.clone()).prop_map(|(t1, t2)| Type::Pair(Box::new(t1), Box::new(t2))),
+            t.prop_map(|t| Type::List(Box::new(t))),
+        ]
+    })
+}
+
+impl Arbitrary for Expression {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Expression>;
+
+    /// Trees up to 4 deep. Every variant is generated, but only in shapes the
+    /// grammar allows, e.g. tuples have at least two elements and a record's
+    /// fields are distinct.
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        use Expression::*;
+        let leaf = prop_oneof![literal(), Just(Nil), variable().prop_map(Var)];
+        leaf.prop_recursive(4, 32, 4, |e| {
+            let unary = |f: fn(Rc<Expression>) -> Expression| {
+                e.clone().prop_map(move |e| f(Rc::new(e))).boxed()
+            };
+            let binary = |f: fn(Rc<Expression>, Rc<Expression>) -> Expression| {
+                (e.clone(), e.clone())
+                    .prop_map(move |(e1, e2)| f(Rc::new(e1), Rc::new(e2)))
+                    .boxed()
+            };
+            Union::new(vec![
+                unary(Not),
+                unary(Succ),
+                unary(Pred),
+                unary(Neg),
+                unary(Fst),
+                unary(Snd),
+                unary(Hd),
+                unary(Tl),
+                binary(Pair),
+                binary(Eq),
+                binary(Neq),
+                binary(Lt),
+                binary(Gt),
+                binary(Le),
+                binary(Ge),
+                binary(Cons),
+                binary(And),
+                binary(Or),
+                binary(Add),
+                binary(Sub),
+                binary(Mul),
+                binary(Div),
+                binary(Mod),
+                binary(Apply),
+                binary(Seq),
+                // `let rec` makes every definition recursive.
+                (vec((variable(), e.clone()), 1..3), any::<bool>(), e.clone())
+                    .prop_map(|(definitions, recursive, body)| {
+                        let definitions = definitions
+                            .into_iter()
+                            .map(|(var, expr)| Definition {
+                                var,
+                                expr: Rc::new(expr),
+                                recursive,
+                            })
+                            .collect();
+                        Let(definitions, Rc::new(body))
+                    })
+                    .boxed(),
+                (e.clone(), e.clone(), e.clone())
+                    .prop_map(|(cond, yes, no)| If(Rc::new(cond), Rc::new(yes), Rc::new(no)))
+                    .boxed(),
+                vec(e.clone(), 2..4).prop_map(Tuple).boxed(),
+                btree_map(ident(), e.clone(), 1..4)
+                    .prop_map(|fields| Record(fields.into_iter().collect()))
+                    .boxed(),
+                (e.clone(), ident())
+                    .prop_map(|(e, field)| Field(Rc::new(e), field))
+                    .boxed(),
+                (variable(), e.clone())
+                    .prop_map(|(var, body)| Fn(var, Rc::new(body)))
+                    .boxed(),
+                (
+                    e.clone(),
+                    vec(
+                        (pattern(), proptest::option::of(e.clone()), e.clone()),
+                        1..3,
+                    ),
+                )
+                    .prop_map(|(scrutinee, arms)| {
+                        let arms = arms
+                            .into_iter()
+                            .map(|(pattern, guard, body)| MatchArm {
+                                pattern,
+                                guard,
+                                body,
+                            })
+                            .collect();
+                        Match(Rc::new(scrutinee), arms)
+                    })
+                    .boxed(),
+                (e.clone(), ty())
+                    .prop_map(|(e, t)| Ann(Rc::new(e), t))
+                    .boxed(),
+            ])
+        })
+        .boxed()
+    }
+}
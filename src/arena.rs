@@ -0,0 +1,158 @@
+//! arena.rs: An arena representation of expressions, for large programs. Nodes
+//! are stored contiguously and refer to their children by index, rather than
+//! each owning a chain of boxes.
+
+use crate::exprs::{map_subexpressions, subexpressions};
+use crate::parser::{parse, Expression, ParseError};
+
+/// The index of a node in an [`Arena`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct NodeId(u32);
+
+/// One expression in an [`Arena`].
+#[derive(Clone, PartialEq, Debug)]
+pub struct Node {
+    /// The node's variant and its data, with each subexpression replaced by `()`.
+    pub shell: Expression,
+    /// The node's subexpressions, in the order of [`subexpressions`].
+    pub children: Vec<NodeId>,
+}
+
+/// A store of expressions, which may share the arena but not nodes.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Arena {
+    nodes: Vec<Node>,
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena::default()
+    }
+
+    /// Parse a complete MiniML expression into the arena.
+    pub fn parse(&mut self, input: &str) -> Result<NodeId, ParseError> {
+        Ok(self.alloc(parse(input)?))
+    }
+
+    /// Move an expression into the arena, returning the id of its root.
+    /// Parents are stored before their children, and siblings consecutively.
+    pub fn alloc(&mut self, e: Expression) -> NodeId {
+        let root = self.reserve();
+        let mut pending = vec![(root, e)];
+        while let Some((id, e)) = pending.pop() {
+            let mut children = Vec::new();
+            let shell = map_subexpressions(e, |child| {
+                let child_id = self.reserve();
+                children.push(child_id);
+                pending.push((child_id, child));
+                Expression::Unit
+            });
+            self.nodes[id.0 as usize] = Node { shell, children };
+        }
+        root
+    }
+
+    fn reserve(&mut self) -> NodeId {
+        let id = NodeId(self.nodes.len() as u32);
+        self.nodes.push(Node {
+            shell: Expression::Unit,
+            children: Vec::new(),
+        });
+        id
+    }
+
+    pub fn get(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0 as usize]
+    }
+
+    /// Rebuild the owned expression rooted at `id`.
+    pub fn to_expression(&self, id: NodeId) -> Expression {
+        // Visit nodes in post-order, so each node's children are the most
+        // recently built expressions when it is reached.
+        let mut pending = vec![(id, false)];
+        let mut built = Vec::new();
+        while let Some((id, ready)) = pending.pop() {
+            let node = self.get(id);
+            if ready {
+                let mut children = built
+                    .split_off(built.len() - node.children.len())
+                    .into_iter();
+                built.push(map_subexpressions(node.shell.clone(), |_| {
+                    children.next().unwrap()
+                }));
+            } else {
+                pending.push((id, true));
+                pending.extend(node.children.iter().rev().map(|&child| (child, false)));
+            }
+        }
+        built.pop().unwrap()
+    }
+
+    /// The number of nodes in the arena.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+impl Node {
+    /// Whether this node is a leaf, e.g. a literal or variable.
+    pub fn is_leaf(&self) -> bool {
+        debug_assert_eq!(subexpressions(&self.shell).len(), self.children.len());
+        self.children.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::visit::node_count;
+
+    #[test]
+    fn test_arena_round_trip() {
+        let mut arena = Arena::new();
+        for source in [
+            "1 + 2 * 3",
+            "let rec f = fn n. if n < 1 then 0 else f (n - 1) and g = f in g 10",
+            "match [1, 2] with x :: _ when x > 0 -> <x, ()> | _ -> <0, ()>",
+            "({ a = (1, 2.5, \"s\") }.a : Int * Float * String)",
+        ] {
+            let e = parse(source).unwrap();
+            let before = arena.len();
+            let id = arena.parse(source).unwrap();
+            assert_eq!(arena.len() - before, node_count(&e));
+            assert_eq!(arena.to_expression(id), e);
+        }
+    }
+
+    #[test]
+    fn test_arena_nodes() {
+        let mut arena = Arena::new();
+        let id = arena.parse("1 + x").unwrap();
+        let add = arena.get(id);
+        assert_eq!(add.shell, parse("() + ()").unwrap());
+        assert_eq!(add.children.len(), 2);
+        assert_eq!(arena.get(add.children[0]).shell, Expression::Num(1));
+        assert!(arena.get(add.children[1]).is_leaf());
+    }
+
+    #[test]
+    fn test_arena_large() {
+        // A long list, i.e. a deeply nested chain of conses.
+        let source = format!(
+            "[{}]",
+            (0..1000)
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let mut arena = Arena::new();
+        let id = arena.parse(&source).unwrap();
+        assert_eq!(arena.len(), 2001);
+        assert_eq!(arena.get(id).children, [NodeId(id.0 + 1), NodeId(id.0 + 2)]);
+        assert_eq!(arena.to_expression(id), parse(&source).unwrap());
+    }
+}
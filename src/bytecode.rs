@@ -0,0 +1,583 @@
+//! bytecode.rs: Compilation of expressions to instructions for a stack
+//! machine, which pushes each operand before the operation using it.
+
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::interp::Primitive;
+use crate::parser::{Definition, Expression, Variable};
+
+/// An instruction for the stack machine. Jumps and closures refer to other
+/// instructions by their index in the code.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Instr {
+    PushInt(i64),
+    PushFloat(f64),
+    PushBool(bool),
+    PushStr(String),
+    PushChar(char),
+    PushUnit,
+    PushNil,
+    /// Discard the value on top of the stack.
+    Pop,
+    /// Push the value of the variable bound `n` bindings in, counting from
+    /// the innermost, 0.
+    Load(usize),
+    /// Bind the value on top of the stack, which it's popped from.
+    Bind,
+    /// Bind functions defined together by `let rec`, whose bodies start at
+    /// the instructions given, to each other, the last innermost.
+    BindRec(Vec<usize>),
+    /// Drop the innermost `n` bindings.
+    Unbind(usize),
+    /// Push a function, whose body starts at the instruction given, closing
+    /// over the current bindings.
+    MakeClosure(usize),
+    /// Pop an argument, then a function, and apply the function to it.
+    Call,
+    /// Return from a function, leaving its result on the stack.
+    Return,
+    Jump(usize),
+    /// Pop a boolean, and jump if it's false.
+    JumpIfFalse(usize),
+    Not,
+    Succ,
+    Pred,
+    Neg,
+    Fst,
+    Snd,
+    Hd,
+    Tl,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Neq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    MakePair,
+    Cons,
+    /// Fail, as a variable which isn't bound would be looked up.
+    Unbound(Variable),
+    /// Fail, as an expression the machine can't evaluate would be.
+    Unsupported(Expression),
+}
+
+/// Compile a closed expression to code which leaves its value on the stack.
+/// Variables which aren't bound, and expressions the machine can't evaluate,
+/// only fail if they're reached.
+pub fn compile(e: &Expression) -> Vec<Instr> {
+    let mut compiler = Compiler::default();
+    compiler.compile(e);
+    compiler.code
+}
+
+/// Simplify code without changing what it does: operations on integer
+/// constants which can't fail are done in advance, e.g. `PushInt(1),
+/// PushInt(2), Add` becomes `PushInt(3)`, and jumps to the next instruction
+/// are removed. Simplifying the result again leaves it as it is.
+pub fn peephole(mut code: Vec<Instr>) -> Vec<Instr> {
+    // Removing a jump can make the one before it go to the next instruction.
+    while let Some(simplified) = simplify(&code) {
+        code = simplified;
+    }
+    code
+}
+
+/// One pass of [`peephole`], or `None` if there's nothing to simplify.
+fn simplify(code: &[Instr]) -> Option<Vec<Instr>> {
+    let mut jumped_to: HashSet<usize> = code.iter().flat_map(targets).copied().collect();
+    let mut simplified = vec![];
+    // Whether each instruction simplified is jumped to, so can't be folded
+    // into the one before it.
+    let mut fixed = vec![];
+    // Where each instruction, and the end of the code, has moved to.
+    let mut moved = vec![0; code.len() + 1];
+    let mut changed = false;
+    for (i, instr) in code.iter().enumerate() {
+        moved[i] = simplified.len();
+        if *instr == Instr::Jump(i + 1) {
+            if jumped_to.contains(&i) {
+                jumped_to.insert(i + 1);
+            }
+            changed = true;
+            continue;
+        }
+        simplified.push(instr.clone());
+        fixed.push(jumped_to.contains(&i));
+        while let Some((folded, n)) = fold(&simplified, &fixed) {
+            let start = simplified.len() - n;
+            simplified.truncate(start);
+            simplified.push(folded);
+            fixed.truncate(start + 1);
+            changed = true;
+        }
+    }
+    moved[code.len()] = simplified.len();
+    if !changed {
+        return None;
+    }
+    for instr in &mut simplified {
+        for target in targets_mut(instr) {
+            *target = moved[*target];
+        }
+    }
+    Some(simplified)
+}
+
+/// The constant the instructions ending `code` compute, and how many of
+/// them there are, if they can be folded into one.
+fn fold(code: &[Instr], fixed: &[bool]) -> Option<(Instr, usize)> {
+    use Instr::*;
+    let folded = match code {
+        [.., PushInt(l), PushInt(r), op] => match op {
+            Add => PushInt(l.checked_add(*r)?),
+            Sub => PushInt(l.checked_sub(*r)?),
+            Mul => PushInt(l.checked_mul(*r)?),
+            Div => PushInt(l.checked_div(*r)?),
+            Mod => PushInt(l.checked_rem(*r)?),
+            Eq => PushBool(l == r),
+            Neq => PushBool(l != r),
+            Lt => PushBool(l < r),
+            Gt => PushBool(l > r),
+            Le => PushBool(l <= r),
+            Ge => PushBool(l >= r),
+            _ => return fold_unary(code, fixed),
+        },
+        _ => return fold_unary(code, fixed),
+    };
+    match fixed[fixed.len() - 2..] {
+        [false, false] => Some((folded, 3)),
+        _ => None,
+    }
+}
+
+fn fold_unary(code: &[Instr], fixed: &[bool]) -> Option<(Instr, usize)> {
+    use Instr::*;
+    let folded = match code {
+        [.., PushInt(n), Succ] => PushInt(n.checked_add(1)?),
+        [.., PushInt(n), Pred] => PushInt(n.checked_sub(1)?),
+        [.., PushInt(n), Neg] => PushInt(n.checked_neg()?),
+        [.., PushBool(b), Not] => PushBool(!b),
+        _ => return None,
+    };
+    match fixed.last() {
+        Some(false) => Some((folded, 2)),
+        _ => None,
+    }
+}
+
+/// The instructions an instruction refers to.
+fn targets(instr: &Instr) -> &[usize] {
+    match instr {
+        Instr::Jump(to) | Instr::JumpIfFalse(to) | Instr::MakeClosure(to) => {
+            std::slice::from_ref(to)
+        }
+        Instr::BindRec(starts) => starts,
+        _ => &[],
+    }
+}
+
+fn targets_mut(instr: &mut Instr) -> &mut [usize] {
+    match instr {
+        Instr::Jump(to) | Instr::JumpIfFalse(to) | Instr::MakeClosure(to) => {
+            std::slice::from_mut(to)
+        }
+        Instr::BindRec(starts) => starts,
+        _ => &mut [],
+    }
+}
+
+#[derive(Default)]
+struct Compiler {
+    code: Vec<Instr>,
+    /// The variables bound where the code being compiled runs, innermost
+    /// last. `None` stands for a definition of a `let` which isn't in scope
+    /// in those following it.
+    scope: Vec<Option<Rc<str>>>,
+}
+
+impl Compiler {
+    fn emit(&mut self, instr: Instr) {
+        self.code.push(instr);
+    }
+
+    /// Emit a jump whose target is filled in later, by [`Compiler::patch`].
+    fn emit_jump(&mut self, jump: fn(usize) -> Instr) -> usize {
+        self.emit(jump(0));
+        self.code.len() - 1
+    }
+
+    /// Make the jump at `at` go to the next instruction emitted.
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len();
+        match &mut self.code[at] {
+            Instr::Jump(to) | Instr::JumpIfFalse(to) => *to = target,
+            instr => unreachable!("{:?} isn't a jump", instr),
+        }
+    }
+
+    fn compile(&mut self, e: &Expression) {
+        use Expression::*;
+        if let Some((op, operands)) = Primitive::of(e) {
+            for operand in operands {
+                self.compile(operand);
+            }
+            return self.emit(instr(op));
+        }
+        match e {
+            Num(n) => self.emit(Instr::PushInt(*n)),
+            Float(f) => self.emit(Instr::PushFloat(*f)),
+            True => self.emit(Instr::PushBool(true)),
+            False => self.emit(Instr::PushBool(false)),
+            Str(s) => self.emit(Instr::PushStr(s.clone())),
+            Char(c) => self.emit(Instr::PushChar(*c)),
+            Unit => self.emit(Instr::PushUnit),
+            Nil => self.emit(Instr::PushNil),
+            Var(var) => {
+                let bound = self
+                    .scope
+                    .iter()
+                    .rev()
+                    .position(|name| name.as_ref().is_some_and(|name| *name == var.ident));
+                match bound {
+                    Some(n) => self.emit(Instr::Load(n)),
+                    None => self.emit(Instr::Unbound(var.clone())),
+                }
+            }
+            Fn(param, body) => {
+                let start = self.function(&[], param, body);
+                self.emit(Instr::MakeClosure(start));
+            }
+            Apply(f, arg) => {
+                self.compile(f);
+                self.compile(arg);
+                self.emit(Instr::Call);
+            }
+            Let(definitions, body) if !definitions.iter().any(|d| d.recursive) => {
+                self.bind(definitions);
+                self.compile(body);
+                self.unbind(definitions.len());
+            }
+            Let(definitions, body) => {
+                let bound = self.bind_rec(definitions);
+                self.compile(body);
+                self.unbind(bound);
+            }
+            // `a and b` is `if a then b else false`, and `a or b` is
+            // `if a then true else b`.
+            And(left, right) => self.branch(left, right, &False),
+            Or(left, right) => self.branch(left, &True, right),
+            If(cond, yes, no) => self.branch(cond, yes, no),
+            Seq(first, second) => {
+                self.compile(first);
+                self.emit(Instr::Pop);
+                self.compile(second);
+            }
+            Ann(e, _) => self.compile(e),
+            _ => self.emit(Instr::Unsupported(e.clone())),
+        }
+    }
+
+    fn branch(&mut self, cond: &Expression, yes: &Expression, no: &Expression) {
+        self.compile(cond);
+        let to_no = self.emit_jump(Instr::JumpIfFalse);
+        self.compile(yes);
+        let to_end = self.emit_jump(Instr::Jump);
+        self.patch(to_no);
+        self.compile(no);
+        self.patch(to_end);
+    }
+
+    /// Compile a function's body, which is jumped over, to run with `group`
+    /// bound and then `param`. Returns where the body starts.
+    fn function(&mut self, group: &[Rc<str>], param: &Variable, body: &Expression) -> usize {
+        let over = self.emit_jump(Instr::Jump);
+        let start = self.code.len();
+        let depth = self.scope.len();
+        self.scope.extend(group.iter().cloned().map(Some));
+        self.scope.push(Some(param.ident.clone()));
+        self.compile(body);
+        self.emit(Instr::Return);
+        self.scope.truncate(depth);
+        self.patch(over);
+        start
+    }
+
+    /// Bind the definitions of a plain `let`, each evaluated where none of
+    /// them are in scope.
+    fn bind(&mut self, definitions: &[Definition]) {
+        let depth = self.scope.len();
+        for d in definitions {
+            self.compile(&d.expr);
+            self.emit(Instr::Bind);
+            self.scope.push(None);
+        }
+        for (name, d) in self.scope[depth..].iter_mut().zip(definitions) {
+            *name = Some(d.var.ident.clone());
+        }
+    }
+
+    /// Bind the definitions of a `let rec`: the functions first, to each
+    /// other, then the rest with the functions in scope. Returns the number
+    /// of bindings made.
+    fn bind_rec(&mut self, definitions: &[Definition]) -> usize {
+        let (functions, others): (Vec<_>, Vec<_>) = definitions
+            .iter()
+            .partition(|d| matches!(*d.expr, Expression::Fn(_, _)));
+        let group: Vec<_> = functions.iter().map(|d| d.var.ident.clone()).collect();
+        let starts = functions
+            .iter()
+            .map(|d| match &*d.expr {
+                Expression::Fn(param, body) => self.function(&group, param, body),
+                _ => unreachable!("partitioned by being a function"),
+            })
+            .collect();
+        self.emit(Instr::BindRec(starts));
+        self.scope.extend(group.into_iter().map(Some));
+        let others: Vec<_> = others.into_iter().cloned().collect();
+        self.bind(&others);
+        definitions.len()
+    }
+
+    fn unbind(&mut self, n: usize) {
+        self.emit(Instr::Unbind(n));
+        self.scope.truncate(self.scope.len() - n);
+    }
+}
+
+/// The instruction performing a primitive operation.
+fn instr(op: Primitive) -> Instr {
+    match op {
+        Primitive::Not => Instr::Not,
+        Primitive::Succ => Instr::Succ,
+        Primitive::Pred => Instr::Pred,
+        Primitive::Neg => Instr::Neg,
+        Primitive::Fst => Instr::Fst,
+        Primitive::Snd => Instr::Snd,
+        Primitive::Hd => Instr::Hd,
+        Primitive::Tl => Instr::Tl,
+        Primitive::Add => Instr::Add,
+        Primitive::Sub => Instr::Sub,
+        Primitive::Mul => Instr::Mul,
+        Primitive::Div => Instr::Div,
+        Primitive::Mod => Instr::Mod,
+        Primitive::Eq => Instr::Eq,
+        Primitive::Neq => Instr::Neq,
+        Primitive::Lt => Instr::Lt,
+        Primitive::Gt => Instr::Gt,
+        Primitive::Le => Instr::Le,
+        Primitive::Ge => Instr::Ge,
+        Primitive::Pair => Instr::MakePair,
+        Primitive::Cons => Instr::Cons,
+    }
+}
+
+/// The primitive operation an instruction performs, and how many operands
+/// it takes, if it performs one.
+pub(crate) fn primitive(instr: &Instr) -> Option<(Primitive, usize)> {
+    Some(match instr {
+        Instr::Not => (Primitive::Not, 1),
+        Instr::Succ => (Primitive::Succ, 1),
+        Instr::Pred => (Primitive::Pred, 1),
+        Instr::Neg => (Primitive::Neg, 1),
+        Instr::Fst => (Primitive::Fst, 1),
+        Instr::Snd => (Primitive::Snd, 1),
+        Instr::Hd => (Primitive::Hd, 1),
+        Instr::Tl => (Primitive::Tl, 1),
+        Instr::Add => (Primitive::Add, 2),
+        Instr::Sub => (Primitive::Sub, 2),
+        Instr::Mul => (Primitive::Mul, 2),
+        Instr::Div => (Primitive::Div, 2),
+        Instr::Mod => (Primitive::Mod, 2),
+        Instr::Eq => (Primitive::Eq, 2),
+        Instr::Neq => (Primitive::Neq, 2),
+        Instr::Lt => (Primitive::Lt, 2),
+        Instr::Gt => (Primitive::Gt, 2),
+        Instr::Le => (Primitive::Le, 2),
+        Instr::Ge => (Primitive::Ge, 2),
+        Instr::MakePair => (Primitive::Pair, 2),
+        Instr::Cons => (Primitive::Cons, 2),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use Instr::*;
+
+    fn compiled(source: &str) -> Vec<Instr> {
+        compile(&parse(source).unwrap())
+    }
+
+    #[test]
+    fn test_compile() {
+        assert_eq!(compiled("1 + 2"), [PushInt(1), PushInt(2), Add]);
+        // Operands are pushed left to right.
+        assert_eq!(
+            compiled("<1 * 2, 'c'>"),
+            [PushInt(1), PushInt(2), Mul, PushChar('c'), MakePair]
+        );
+        assert_eq!(
+            compiled("if true then 1 else 2"),
+            [
+                PushBool(true),
+                JumpIfFalse(4),
+                PushInt(1),
+                Jump(5),
+                PushInt(2)
+            ]
+        );
+        assert_eq!(
+            compiled("a and b"),
+            [
+                Unbound(Variable { ident: "a".into() }),
+                JumpIfFalse(4),
+                Unbound(Variable { ident: "b".into() }),
+                Jump(5),
+                PushBool(false)
+            ]
+        );
+        assert_eq!(
+            compiled("(fn x. (x + 1)) 2"),
+            [
+                Jump(5),
+                Load(0),
+                PushInt(1),
+                Add,
+                Return,
+                MakeClosure(1),
+                PushInt(2),
+                Call
+            ]
+        );
+        assert_eq!(compiled("(); 1"), [PushUnit, Pop, PushInt(1)]);
+        assert_eq!(
+            compiled("match x with _ -> 1"),
+            [Unsupported(parse("match x with _ -> 1").unwrap())]
+        );
+    }
+
+    #[test]
+    fn test_compile_variables() {
+        // Variables are numbered from the innermost binding.
+        assert_eq!(
+            compiled("fn x. fn y. <x, y>"),
+            [
+                Jump(8),
+                Jump(6),
+                Load(1),
+                Load(0),
+                MakePair,
+                Return,
+                MakeClosure(2),
+                Return,
+                MakeClosure(1)
+            ]
+        );
+        // Definitions of a plain let can't see each other.
+        assert_eq!(
+            compiled("let x = 1 in let x = 2 and y = x in <x, y>"),
+            [
+                PushInt(1),
+                Bind,
+                PushInt(2),
+                Bind,
+                Load(1),
+                Bind,
+                Load(1),
+                Load(0),
+                MakePair,
+                Unbind(2),
+                Unbind(1)
+            ]
+        );
+        assert_eq!(
+            compiled("let rec f = fn n. f n and x = f 1 in x"),
+            [
+                Jump(5),
+                Load(1),
+                Load(0),
+                Call,
+                Return,
+                BindRec(vec![1]),
+                Load(0),
+                PushInt(1),
+                Call,
+                Bind,
+                Load(0),
+                Unbind(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_peephole() {
+        let optimized = |source| peephole(compiled(source));
+        assert_eq!(optimized("1 + 2"), [PushInt(3)]);
+        assert_eq!(optimized("-(1 + 2 * 3) < pred(0)"), [PushBool(true)]);
+        assert_eq!(optimized("not(not(true))"), [PushBool(true)]);
+        // Operations which would fail are left to fail when they're run.
+        assert_eq!(optimized("1 / 0"), [PushInt(1), PushInt(0), Div]);
+        assert_eq!(
+            optimized("succ(9223372036854775807)"),
+            [PushInt(i64::MAX), Succ]
+        );
+        // Jumps are moved with the instructions they go to.
+        assert_eq!(
+            optimized("if true then 1 + 1 else 2 * 3"),
+            [
+                PushBool(true),
+                JumpIfFalse(4),
+                PushInt(2),
+                Jump(5),
+                PushInt(6)
+            ]
+        );
+        assert_eq!(
+            optimized("(fn x. (x + (1 + 1))) 2"),
+            [
+                Jump(5),
+                Load(0),
+                PushInt(2),
+                Add,
+                Return,
+                MakeClosure(1),
+                PushInt(2),
+                Call
+            ]
+        );
+        assert_eq!(
+            peephole(vec![Jump(2), Jump(2), PushInt(1), Jump(4)]),
+            [PushInt(1)]
+        );
+        // An instruction jumped to isn't folded into the one before it.
+        assert_eq!(
+            peephole(vec![PushInt(1), Jump(3), PushInt(2), PushInt(3), Add]),
+            [PushInt(1), Jump(3), PushInt(2), PushInt(3), Add]
+        );
+        assert_eq!(
+            peephole(vec![PushInt(1), Jump(2), PushInt(2), Add]),
+            [PushInt(3)]
+        );
+    }
+
+    #[test]
+    fn test_peephole_idempotent() {
+        for source in [
+            "1 + 2 * 3",
+            "if 1 < 2 then (fn x. (x * (2 + 2))) 1 else 0",
+            "let rec f = fn n. if n < 1 + 1 then 0 else f (n - 1) in f (5 * 5)",
+            "a and b or not(false)",
+        ] {
+            let once = peephole(compiled(source));
+            assert_eq!(peephole(once.clone()), once, "{}", source);
+        }
+    }
+}
@@ -0,0 +1,585 @@
+//! c.rs: A backend generating C for programs on integers and booleans whose
+//! functions are all declared at the top level, such as those lambda lifting
+//! leaves. Each function is a C function. Every value is an `int64_t`:
+//! booleans are 1 or 0, and functions not applied to all their parameters
+//! at once are pointers to closures.
+
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::Rc;
+
+use crate::codegen::{unique, CodegenError};
+use crate::infer::infer;
+use crate::parser::{Definition, Expression, Program, Type};
+
+type Result<T> = std::result::Result<T, CodegenError>;
+
+/// The names the generated C can't give to variables or functions.
+const RESERVED: &[&str] = &[
+    "apply",
+    "auto",
+    "break",
+    "case",
+    "char",
+    "closure",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "intptr_t",
+    "long",
+    "main",
+    "make_closure",
+    "malloc",
+    "memcpy",
+    "printf",
+    "puts",
+    "register",
+    "restrict",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "typedef",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+];
+
+/// Closures, and their application, for programs which need them. A
+/// closure holds the arguments a function has been applied to so far, and
+/// calls it through a wrapper taking them as an array once it has them all.
+const CLOSURES: &str = "#include <stdlib.h>
+#include <string.h>
+
+struct closure {
+    int64_t (*code)(const int64_t *args);
+    int64_t arity, count;
+    int64_t args[];
+};
+
+static int64_t make_closure(int64_t (*code)(const int64_t *), int64_t arity) {
+    struct closure *c = malloc(sizeof *c + arity * sizeof(int64_t));
+    c->code = code;
+    c->arity = arity;
+    c->count = 0;
+    return (int64_t)(intptr_t)c;
+}
+
+static int64_t apply(int64_t f, int64_t arg) {
+    struct closure *c = (struct closure *)(intptr_t)f;
+    struct closure *applied = malloc(sizeof *c + c->arity * sizeof(int64_t));
+    memcpy(applied, c, sizeof *c + c->count * sizeof(int64_t));
+    applied->args[applied->count++] = arg;
+    if (applied->count == applied->arity) {
+        return applied->code(applied->args);
+    }
+    return (int64_t)(intptr_t)applied;
+}
+";
+
+/// A C program which evaluates `program`'s declarations in order, then
+/// prints the value of its main expression, if it has one, which must be an
+/// integer or boolean. The program must be well-typed, and only use
+/// integers, booleans and functions declared at the top level.
+pub fn emit_c(program: &Program) -> Result<String> {
+    let main = program.main.clone().unwrap_or(Expression::Unit);
+    let whole = program.declarations.iter().rev().fold(main, |main, group| {
+        Expression::Let(group.clone(), Rc::new(main))
+    });
+    let result = infer(&whole)?;
+
+    let mut c = C {
+        used: RESERVED.iter().map(|name| name.to_string()).collect(),
+        ..C::default()
+    };
+    let mut init = Body::new(&c.used);
+    for group in &program.declarations {
+        c.declare(group, &mut init)?;
+    }
+    let mut main = String::new();
+    if let Some(e) = &program.main {
+        let value = init.expression(&c, e)?;
+        match result {
+            Type::Int => init.line(format!("printf(\"%\" PRId64 \"\\n\", (int64_t){});", value)),
+            Type::Bool => init.line(format!("puts({} ? \"true\" : \"false\");", value)),
+            _ => return Err(CodegenError::Unsupported(e.clone())),
+        }
+    }
+    main += "\nint main(void) {\n";
+
+    let mut out = "#include <inttypes.h>\n#include <stdio.h>\n".to_string();
+    if c.closures.get() {
+        out += CLOSURES;
+    }
+    out += "\n";
+    for f in &c.functions {
+        out += &format!("{};\n", signature(&f.name, &f.params));
+    }
+    for global in &c.globals {
+        out += &format!("static int64_t {};\n", global);
+    }
+    if c.closures.get() {
+        for f in &c.functions {
+            let args: Vec<_> = (0..f.params.len())
+                .map(|i| format!("args[{}]", i))
+                .collect();
+            out += &format!(
+                "\nstatic int64_t {}(const int64_t *args) {{\n    return {}({});\n}}\n",
+                f.wrapper,
+                f.name,
+                args.join(", ")
+            );
+        }
+    }
+    for f in &c.functions {
+        out += &format!("\n{} {{\n{}}}\n", signature(&f.name, &f.params), f.body);
+    }
+    out += &main;
+    init.line("return 0;".to_string());
+    out += &init.lines.concat();
+    out += "}\n";
+    Ok(out)
+}
+
+fn signature(name: &str, params: &[String]) -> String {
+    let params: Vec<_> = params.iter().map(|p| format!("int64_t {}", p)).collect();
+    format!("int64_t {}({})", name, params.join(", "))
+}
+
+#[derive(Default)]
+struct C {
+    /// The names taken by functions and globals.
+    used: HashSet<String>,
+    /// What each top-level variable in scope is in C.
+    scope: HashMap<Rc<str>, Global>,
+    functions: Vec<Function>,
+    globals: Vec<String>,
+    /// Whether the program makes closures, so needs [`CLOSURES`].
+    closures: Cell<bool>,
+}
+
+#[derive(Clone)]
+enum Global {
+    Value(String),
+    Function {
+        name: String,
+        arity: usize,
+        /// The wrapper a closure calls the function through.
+        wrapper: String,
+    },
+}
+
+struct Function {
+    name: String,
+    params: Vec<String>,
+    body: String,
+    wrapper: String,
+}
+
+impl C {
+    /// Declare the definitions of a top-level `let`. Values are computed by
+    /// `init`, at the start of `main`.
+    fn declare(&mut self, group: &[Definition], init: &mut Body) -> Result<()> {
+        let recursive = group.iter().any(|d| d.recursive);
+        let mut declared = vec![];
+        for d in group {
+            let name = unique(&d.var.ident, &mut self.used);
+            let global = match params(&d.expr).0.len() {
+                0 if recursive => return Err(CodegenError::Unsupported((*d.expr).clone())),
+                0 => Global::Value(name),
+                arity => Global::Function {
+                    wrapper: unique(&format!("{}_apply", name), &mut self.used),
+                    name,
+                    arity,
+                },
+            };
+            declared.push((d.var.ident.clone(), global));
+        }
+        // A recursive group's functions can refer to each other, but a plain
+        // `let`'s definitions don't see each other.
+        let outer = self.scope.clone();
+        if recursive {
+            self.scope.extend(declared.iter().cloned());
+        }
+        let mut values = vec![];
+        for (d, (_, global)) in group.iter().zip(&declared) {
+            let (params, body) = params(&d.expr);
+            match global {
+                Global::Function { name, wrapper, .. } => {
+                    let mut function = Body::new(&self.used);
+                    let params: Vec<_> = params
+                        .iter()
+                        .map(|p| function.bind(p.ident.clone()))
+                        .collect();
+                    let result = function.expression(self, body)?;
+                    function.line(format!("return {};", result));
+                    self.functions.push(Function {
+                        name: name.clone(),
+                        params,
+                        body: function.lines.concat(),
+                        wrapper: wrapper.clone(),
+                    });
+                }
+                Global::Value(name) => values.push((name.clone(), init.expression(self, body)?)),
+            }
+        }
+        for (name, value) in values {
+            init.line(format!("{} = {};", name, value));
+            self.globals.push(name);
+        }
+        self.scope = outer;
+        self.scope.extend(declared);
+        Ok(())
+    }
+}
+
+/// The parameters of a curried function, and its body.
+fn params(e: &Expression) -> (Vec<&crate::parser::Variable>, &Expression) {
+    let mut params = vec![];
+    let mut body = e;
+    while let Expression::Fn(param, inner) = body {
+        params.push(param);
+        body = inner;
+    }
+    (params, body)
+}
+
+/// The statements of a function being generated.
+struct Body {
+    lines: Vec<String>,
+    indent: usize,
+    /// The names taken in the function, including by functions and globals.
+    used: HashSet<String>,
+    /// The local each variable in scope is held in, innermost last.
+    scope: Vec<(Rc<str>, String)>,
+}
+
+impl Body {
+    fn new(used: &HashSet<String>) -> Body {
+        Body {
+            lines: vec![],
+            indent: 1,
+            used: used.clone(),
+            scope: vec![],
+        }
+    }
+
+    fn line(&mut self, line: String) {
+        self.lines
+            .push(format!("{}{}\n", "    ".repeat(self.indent), line));
+    }
+
+    /// Give a local for `var`, which is then in scope.
+    fn bind(&mut self, var: Rc<str>) -> String {
+        let local = unique(&var, &mut self.used);
+        self.scope.push((var, local.clone()));
+        local
+    }
+
+    /// A C expression giving the value of `e`, after the statements it needs
+    /// have been added.
+    fn expression(&mut self, c: &C, e: &Expression) -> Result<String> {
+        use Expression::*;
+        let unsupported = || CodegenError::Unsupported(e.clone());
+        let binary = |body: &mut Body, op: &str, e1, e2| -> Result<String> {
+            let left = body.expression(c, e1)?;
+            Ok(format!("({} {} {})", left, op, body.expression(c, e2)?))
+        };
+        match e {
+            // Literals are `int`s unless told otherwise, which would overflow
+            // in e.g. `100000 * 100000`. `9223372036854775808` is too big to
+            // negate, and `- -1` mustn't become the decrement `--1`.
+            Num(i64::MIN) => Ok("(-INT64_C(9223372036854775807) - 1)".to_string()),
+            Num(n) if *n < 0 => Ok(format!("(-INT64_C({}))", n.unsigned_abs())),
+            Num(n) => Ok(format!("INT64_C({})", n)),
+            True => Ok("1".to_string()),
+            False => Ok("0".to_string()),
+            Var(var) => {
+                if let Some((_, local)) = self.scope.iter().rev().find(|(v, _)| *v == var.ident) {
+                    return Ok(local.clone());
+                }
+                match c.scope.get(&var.ident) {
+                    Some(Global::Value(global)) => Ok(global.clone()),
+                    Some(Global::Function { arity, wrapper, .. }) => {
+                        c.closures.set(true);
+                        Ok(format!("make_closure({}, {})", wrapper, arity))
+                    }
+                    None => Err(unsupported()),
+                }
+            }
+            Add(e1, e2) => binary(self, "+", e1, e2),
+            Sub(e1, e2) => binary(self, "-", e1, e2),
+            Mul(e1, e2) => binary(self, "*", e1, e2),
+            Div(e1, e2) => binary(self, "/", e1, e2),
+            Mod(e1, e2) => binary(self, "%", e1, e2),
+            Eq(e1, e2) => binary(self, "==", e1, e2),
+            Neq(e1, e2) => binary(self, "!=", e1, e2),
+            Lt(e1, e2) => binary(self, "<", e1, e2),
+            Gt(e1, e2) => binary(self, ">", e1, e2),
+            Le(e1, e2) => binary(self, "<=", e1, e2),
+            Ge(e1, e2) => binary(self, ">=", e1, e2),
+            Not(e) => Ok(format!("!{}", self.expression(c, e)?)),
+            Neg(e) => Ok(format!("(-{})", self.expression(c, e)?)),
+            Succ(e) => Ok(format!("({} + 1)", self.expression(c, e)?)),
+            Pred(e) => Ok(format!("({} - 1)", self.expression(c, e)?)),
+            If(cond, yes, no) => self.branch(c, cond, yes, no),
+            And(left, right) => self.branch(c, left, right, &False),
+            Or(left, right) => self.branch(c, left, &True, right),
+            Let(definitions, body) if !definitions.iter().any(|d| d.recursive) => {
+                let values = definitions
+                    .iter()
+                    .map(|d| self.expression(c, &d.expr))
+                    .collect::<Result<Vec<_>>>()?;
+                let depth = self.scope.len();
+                for (d, value) in definitions.iter().zip(values) {
+                    let local = self.bind(d.var.ident.clone());
+                    self.line(format!("int64_t {} = {};", local, value));
+                }
+                let result = self.expression(c, body);
+                self.scope.truncate(depth);
+                result
+            }
+            // A top-level function applied to all its parameters is called
+            // directly. Anything else is applied through a closure.
+            Apply(_, _) => {
+                let mut args = vec![];
+                let mut f = e;
+                while let Apply(inner, arg) = f {
+                    args.push(&**arg);
+                    f = inner;
+                }
+                args.reverse();
+                let mut value = match f {
+                    Var(var) if !self.scope.iter().any(|(v, _)| *v == var.ident) => {
+                        match c.scope.get(&var.ident) {
+                            Some(Global::Function { name, arity, .. }) if *arity <= args.len() => {
+                                let called = args.drain(..*arity).collect::<Vec<_>>();
+                                let called = called
+                                    .into_iter()
+                                    .map(|arg| self.expression(c, arg))
+                                    .collect::<Result<Vec<_>>>()?;
+                                format!("{}({})", name, called.join(", "))
+                            }
+                            _ => self.expression(c, f)?,
+                        }
+                    }
+                    f => self.expression(c, f)?,
+                };
+                for arg in args {
+                    c.closures.set(true);
+                    value = format!("apply({}, {})", value, self.expression(c, arg)?);
+                }
+                Ok(value)
+            }
+            Ann(e, _) => self.expression(c, e),
+            _ => Err(unsupported()),
+        }
+    }
+
+    /// The value of `e`, with the statements it needs kept apart, indented
+    /// for a block.
+    fn block(&mut self, c: &C, e: &Expression) -> Result<(Vec<String>, String)> {
+        let outer = mem::take(&mut self.lines);
+        self.indent += 1;
+        let value = self.expression(c, e);
+        self.indent -= 1;
+        let lines = mem::replace(&mut self.lines, outer);
+        Ok((lines, value?))
+    }
+
+    /// A conditional expression, or if either branch needs statements, an
+    /// `if` statement assigning its value. Only the branch taken is evaluated.
+    fn branch(
+        &mut self,
+        c: &C,
+        cond: &Expression,
+        yes: &Expression,
+        no: &Expression,
+    ) -> Result<String> {
+        let cond = self.expression(c, cond)?;
+        let (yes_lines, yes) = self.block(c, yes)?;
+        let (no_lines, no) = self.block(c, no)?;
+        if yes_lines.is_empty() && no_lines.is_empty() {
+            return Ok(format!("({} ? {} : {})", cond, yes, no));
+        }
+        let result = unique("t", &mut self.used);
+        self.line(format!("int64_t {};", result));
+        self.line(format!("if ({}) {{", cond));
+        self.lines.extend(yes_lines);
+        self.indent += 1;
+        self.line(format!("{} = {};", result, yes));
+        self.indent -= 1;
+        self.line("} else {".to_string());
+        self.lines.extend(no_lines);
+        self.indent += 1;
+        self.line(format!("{} = {};", result, no));
+        self.indent -= 1;
+        self.line("}".to_string());
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lift::lambda_lift;
+    use crate::parser::{parse, parse_program};
+    use std::process::Command;
+
+    fn c(source: &str) -> Result<String> {
+        emit_c(&parse_program(source).unwrap())
+    }
+
+    /// Compile and run the C, if there's a C compiler to, giving what it printed.
+    fn run(c: &str) -> Option<String> {
+        let dir = std::env::temp_dir().join(format!("miniml_c_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, binary) = (dir.join("main.c"), dir.join("main"));
+        std::fs::write(&source, c).unwrap();
+        let compiled = Command::new("cc")
+            .arg(&source)
+            .arg("-o")
+            .arg(&binary)
+            .status()
+            .ok()?;
+        assert!(compiled.success(), "{}", c);
+        let output = Command::new(&binary).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        Some(String::from_utf8(output.stdout).unwrap())
+    }
+
+    #[test]
+    fn test_emit_c() {
+        let program = c("1 + 2 * 3").unwrap();
+        assert!(program.contains("(INT64_C(1) + (INT64_C(2) * INT64_C(3)))"));
+        assert!(c("let f = fn x. -(-x) ;; f 5")
+            .unwrap()
+            .contains("return (-(-x));"));
+        assert!(c("-9223372036854775808 - -1")
+            .unwrap()
+            .contains("((-INT64_C(9223372036854775807) - 1) - (-INT64_C(1)))"));
+        assert!(program.contains("int main(void) {"));
+        assert_eq!(
+            c("let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) ;; fact 5").unwrap(),
+            "#include <inttypes.h>
+#include <stdio.h>
+
+int64_t fact(int64_t n);
+
+int64_t fact(int64_t n) {
+    return ((n < INT64_C(1)) ? INT64_C(1) : (n * fact((n - INT64_C(1)))));
+}
+
+int main(void) {
+    printf(\"%\" PRId64 \"\\n\", (int64_t)fact(INT64_C(5)));
+    return 0;
+}
+"
+        );
+        // Lets become locals, renamed where they'd clash.
+        assert!(c("let x = 1 ;; let f = fn int. let x = int + x in if x > 1 then let y = x in y else 0 ;; f 2")
+            .unwrap()
+            .contains(
+                "int64_t f(int64_t int1) {
+    int64_t x1 = (int1 + x);
+    int64_t t;
+    if ((x1 > INT64_C(1))) {
+        int64_t y = x1;
+        t = y;
+    } else {
+        t = INT64_C(0);
+    }
+    return t;
+}"
+            ));
+    }
+
+    #[test]
+    fn test_emit_c_runs() {
+        for (source, expected) in [
+            ("1 + 2 * 3", "7\n"),
+            (
+                "let x = 6 ;; let x = x * 7 ;; x == 42 and not(false)",
+                "true\n",
+            ),
+            (
+                "let rec even = fn n. if n == 0 then true else odd (n - 1) \
+                 and odd = fn n. if n == 0 then false else even (n - 1) ;; even 10",
+                "true\n",
+            ),
+            (
+                "let rec gcd = fn a b. if b == 0 then a else gcd b (a % b) ;; gcd 1071 462",
+                "21\n",
+            ),
+            ("let x = 1", ""),
+            ("let f = fn x. -(-x) ;; f 5", "5\n"),
+            ("- -5 + -(-1)", "6\n"),
+            ("-9223372036854775808", "-9223372036854775808\n"),
+            (
+                "(-9223372036854775807 - 1) == -9223372036854775808",
+                "true\n",
+            ),
+            ("100000 * 100000", "10000000000\n"),
+            ("-7 / 2", "-3\n"),
+            ("1 - 2 - 3", "-4\n"),
+        ] {
+            let Some(output) = run(&c(source).unwrap()) else {
+                return;
+            };
+            assert_eq!(output, expected, "{}", source);
+        }
+        // Lifted functions are partially applied to what they capture.
+        for (source, expected) in [
+            ("let y = 10 in let add = fn x. (x + y) in add 5", "15\n"),
+            (
+                "let twice = fn f. fn x. f (f x) in let n = 3 in twice (fn x. (x * n)) 2",
+                "18\n",
+            ),
+            (
+                "let rec sum = fn f. fn n. if n == 0 then 0 else f n + sum f (n - 1) in \
+                 let k = 2 in sum (fn x. (x * k)) 4 < 30",
+                "true\n",
+            ),
+        ] {
+            let lifted = lambda_lift(parse(source).unwrap());
+            let Some(output) = run(&emit_c(&lifted).unwrap()) else {
+                return;
+            };
+            assert_eq!(output, expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_emit_c_errors() {
+        assert_eq!(
+            c("let f = fn x. x ;; f"),
+            Err(CodegenError::Unsupported(parse("f").unwrap()))
+        );
+        assert_eq!(
+            c("let add = fn x y. (x + y) ;; add 1"),
+            Err(CodegenError::Unsupported(parse("add 1").unwrap()))
+        );
+        assert_eq!(
+            c("\"s\""),
+            Err(CodegenError::Unsupported(Expression::Str("s".to_string())))
+        );
+        assert!(matches!(c("1 + true"), Err(CodegenError::Type(_))));
+    }
+}
@@ -0,0 +1,45 @@
+//! codegen.rs: What the backends which generate code in other languages have
+//! in common.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::infer::TypeError;
+use crate::parser::Expression;
+
+/// Why code couldn't be generated for an expression.
+#[derive(Clone, PartialEq, Debug)]
+pub enum CodegenError {
+    /// The expression has no type, which the code generated relies on.
+    Type(TypeError),
+    /// The expression uses a construct, or a value, the backend can't express.
+    Unsupported(Expression),
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodegenError::Type(error) => write!(f, "type error: {}", error),
+            CodegenError::Unsupported(e) => write!(f, "cannot generate code for {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
+impl From<TypeError> for CodegenError {
+    fn from(error: TypeError) -> CodegenError {
+        CodegenError::Type(error)
+    }
+}
+
+/// `name`, or if that's `used`, the first of `name1`, `name2`, ... which
+/// isn't. It's then added to `used`.
+pub(crate) fn unique(name: &str, used: &mut HashSet<String>) -> String {
+    let unique = std::iter::once(name.to_string())
+        .chain((1..).map(|i| format!("{}{}", name, i)))
+        .find(|candidate| !used.contains(candidate))
+        .expect("there are only finitely many names in use");
+    used.insert(unique.clone());
+    unique
+}
@@ -0,0 +1,333 @@
+//! cps.rs: Conversion to continuation-passing style, in which no call
+//! returns: each is passed a continuation, a function to call with its
+//! result, so every call is a tail call.
+
+use std::rc::Rc;
+
+use crate::exprs::{map_subexpressions, subexpressions};
+use crate::intern::intern;
+use crate::parser::{Definition, Expression, MatchArm, Variable};
+use crate::subst::rename_apart;
+
+/// Convert `e` to continuation-passing style. The result is a function of the
+/// continuation to call with the value of `e`, so applying it to `fn x. x`
+/// gives that value. Functions take their argument and then a continuation.
+/// Type annotations are dropped, as the types they describe change, and the
+/// guards of `match` arms, which must produce a value to test, are the one
+/// place calls aren't tail calls.
+pub fn to_cps(e: Expression) -> Expression {
+    // The rest of an operation goes in a continuation within the scope of
+    // any `let` in its operands, so mustn't use a variable one binds.
+    let e = rename_apart(&e);
+    let mut cps = Cps::default();
+    let k = cps.fresh("k");
+    let body = cps.cps(e, Expression::Var(k.clone()));
+    Expression::Fn(k, Rc::new(body))
+}
+
+#[derive(Default)]
+struct Cps {
+    /// How many variables have been introduced so far.
+    count: usize,
+}
+
+impl Cps {
+    /// `e` converted, passing its value to the continuation `k`. The variables
+    /// introduced here can't be written in source, so can't be captured by
+    /// the program's, but continuations may use the program's variables too.
+    fn cps(&mut self, e: Expression, k: Expression) -> Expression {
+        use Expression::*;
+        match e {
+            e if is_atomic(&e) => Apply(Rc::new(k), Rc::new(e)),
+            Fn(param, body) => {
+                let f = self.function(param, Rc::unwrap_or_clone(body));
+                Apply(Rc::new(k), Rc::new(f))
+            }
+            Apply(f, arg) => self.operands(
+                vec![Rc::unwrap_or_clone(f), Rc::unwrap_or_clone(arg)],
+                |mut values| {
+                    let arg = values.pop().expect("an argument");
+                    let f = values.pop().expect("a function");
+                    Apply(Rc::new(Apply(Rc::new(f), Rc::new(arg))), Rc::new(k))
+                },
+            ),
+            Let(definitions, body) if definitions.iter().any(|d| d.recursive) => {
+                // As in evaluation, the functions are bound first, then any
+                // other definitions with them in scope.
+                let (functions, others): (Vec<_>, Vec<_>) = definitions
+                    .into_iter()
+                    .partition(|d| matches!(*d.expr, Fn(_, _)));
+                let functions = functions
+                    .into_iter()
+                    .map(|d| match Rc::unwrap_or_clone(d.expr) {
+                        Fn(param, body) => Definition {
+                            expr: Rc::new(self.function(param, Rc::unwrap_or_clone(body))),
+                            ..d
+                        },
+                        _ => unreachable!("partitioned by being a function"),
+                    })
+                    .collect();
+                let body = if others.is_empty() {
+                    Rc::unwrap_or_clone(body)
+                } else {
+                    let others = others
+                        .into_iter()
+                        .map(|d| Definition {
+                            recursive: false,
+                            ..d
+                        })
+                        .collect();
+                    Let(others, body)
+                };
+                Let(functions, Rc::new(self.cps(body, k)))
+            }
+            Let(definitions, body) => {
+                let (vars, exprs): (Vec<_>, Vec<_>) = definitions
+                    .into_iter()
+                    .map(|d| (d.var, Rc::unwrap_or_clone(d.expr)))
+                    .unzip();
+                let body = self.cps(Rc::unwrap_or_clone(body), k);
+                self.operands(exprs, |values| {
+                    let definitions = vars
+                        .into_iter()
+                        .zip(values)
+                        .map(|(var, value)| Definition {
+                            var,
+                            expr: Rc::new(value),
+                            recursive: false,
+                        })
+                        .collect();
+                    Let(definitions, Rc::new(body))
+                })
+            }
+            // The continuation is shared between the branches.
+            If(cond, yes, no) => self.share(k, |cps, k| {
+                let yes = cps.cps(Rc::unwrap_or_clone(yes), k.clone());
+                let no = cps.cps(Rc::unwrap_or_clone(no), k);
+                cps.operands(vec![Rc::unwrap_or_clone(cond)], |mut values| {
+                    let cond = values.pop().expect("a condition");
+                    If(Rc::new(cond), Rc::new(yes), Rc::new(no))
+                })
+            }),
+            // The right operand is only evaluated if the left doesn't decide
+            // the result, but is still checked to be a boolean.
+            And(left, right) => self.share(k, |cps, k| {
+                let v = cps.fresh("v");
+                let w = cps.fresh("v");
+                let and = And(Rc::new(Var(v.clone())), Rc::new(Var(w.clone())));
+                let right = cps.cps(
+                    Rc::unwrap_or_clone(right),
+                    Fn(w, Rc::new(Apply(Rc::new(k.clone()), Rc::new(and)))),
+                );
+                let body = If(
+                    Rc::new(Var(v.clone())),
+                    Rc::new(right),
+                    Rc::new(Apply(Rc::new(k), Rc::new(False))),
+                );
+                cps.cps(Rc::unwrap_or_clone(left), Fn(v, Rc::new(body)))
+            }),
+            Or(left, right) => self.share(k, |cps, k| {
+                let v = cps.fresh("v");
+                let w = cps.fresh("v");
+                let or = Or(Rc::new(Var(v.clone())), Rc::new(Var(w.clone())));
+                let right = cps.cps(
+                    Rc::unwrap_or_clone(right),
+                    Fn(w, Rc::new(Apply(Rc::new(k.clone()), Rc::new(or)))),
+                );
+                let body = If(
+                    Rc::new(Var(v.clone())),
+                    Rc::new(Apply(Rc::new(k), Rc::new(True))),
+                    Rc::new(right),
+                );
+                cps.cps(Rc::unwrap_or_clone(left), Fn(v, Rc::new(body)))
+            }),
+            Seq(first, second) => {
+                let v = self.fresh("v");
+                let second = self.cps(Rc::unwrap_or_clone(second), k);
+                self.cps(Rc::unwrap_or_clone(first), Fn(v, Rc::new(second)))
+            }
+            Ann(e, _) => self.cps(Rc::unwrap_or_clone(e), k),
+            Match(scrutinee, arms) => self.share(k, |cps, k| {
+                let arms: Vec<_> = arms
+                    .into_iter()
+                    .map(|arm| MatchArm {
+                        pattern: arm.pattern,
+                        guard: arm.guard.map(|guard| {
+                            let x = cps.fresh("v");
+                            cps.cps(guard, Fn(x.clone(), Rc::new(Var(x))))
+                        }),
+                        body: cps.cps(arm.body, k.clone()),
+                    })
+                    .collect();
+                cps.operands(vec![Rc::unwrap_or_clone(scrutinee)], |mut values| {
+                    let scrutinee = values.pop().expect("a scrutinee");
+                    Match(Rc::new(scrutinee), arms)
+                })
+            }),
+            // Everything else evaluates all its operands, from left to right,
+            // then does something with their values which can't fail to return.
+            e => {
+                let operands = subexpressions(&e).into_iter().cloned().collect();
+                self.operands(operands, |values| {
+                    let mut values = values.into_iter();
+                    let e = map_subexpressions(e, |_| values.next().expect("a value per operand"));
+                    Apply(Rc::new(k), Rc::new(e))
+                })
+            }
+        }
+    }
+
+    /// A function of `param` converted to take a continuation after it.
+    fn function(&mut self, param: Variable, body: Expression) -> Expression {
+        let k = self.fresh("k");
+        let body = self.cps(body, Expression::Var(k.clone()));
+        Expression::Fn(param, Rc::new(Expression::Fn(k, Rc::new(body))))
+    }
+
+    /// Evaluate `operands`, from left to right, then continue with `body` of
+    /// their values. Atomic operands are used as they are.
+    fn operands(
+        &mut self,
+        operands: Vec<Expression>,
+        body: impl FnOnce(Vec<Expression>) -> Expression,
+    ) -> Expression {
+        let vars: Vec<_> = operands
+            .iter()
+            .map(|e| (!is_atomic(e)).then(|| self.fresh("v")))
+            .collect();
+        let values = operands
+            .iter()
+            .zip(&vars)
+            .map(|(e, var)| var.clone().map_or_else(|| e.clone(), Expression::Var))
+            .collect();
+        let mut e = body(values);
+        for (operand, var) in operands.into_iter().zip(vars).rev() {
+            if let Some(var) = var {
+                e = self.cps(operand, Expression::Fn(var, Rc::new(e)));
+            }
+        }
+        e
+    }
+
+    /// Pass `k` to `f` as a variable, binding it to one if it isn't already,
+    /// so that `f` can use it more than once without copying it.
+    fn share(
+        &mut self,
+        k: Expression,
+        f: impl FnOnce(&mut Cps, Expression) -> Expression,
+    ) -> Expression {
+        if let Expression::Var(_) = k {
+            return f(self, k);
+        }
+        let var = self.fresh("k");
+        let body = f(self, Expression::Var(var.clone()));
+        Expression::Let(
+            vec![Definition {
+                var,
+                expr: Rc::new(k),
+                recursive: false,
+            }],
+            Rc::new(body),
+        )
+    }
+
+    /// A new variable, which can't be written in source, so can't clash with
+    /// the program's.
+    fn fresh(&mut self, prefix: &str) -> Variable {
+        self.count += 1;
+        Variable {
+            ident: intern(&format!("${}{}", prefix, self.count)),
+        }
+    }
+}
+
+fn is_atomic(e: &Expression) -> bool {
+    use Expression::*;
+    matches!(
+        e,
+        Var(_) | Num(_) | Float(_) | True | False | Str(_) | Char(_) | Unit | Nil
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::{eval, EvalError, Value};
+    use crate::parser::parse;
+
+    /// The value of `source` converted to CPS, passed the identity continuation.
+    fn run_cps(source: &str) -> Result<Value, EvalError> {
+        let cps = to_cps(parse(source).unwrap());
+        eval(&Expression::Apply(
+            Rc::new(cps),
+            Rc::new(parse("fn x. x").unwrap()),
+        ))
+    }
+
+    /// Whether every application in `e` is in tail position, not counting
+    /// those within `match` guards.
+    fn tail_calls_only(e: &Expression, tail: bool) -> bool {
+        use Expression::*;
+        match e {
+            Apply(f, arg) => tail && tail_calls_only(f, true) && tail_calls_only(arg, false),
+            Fn(_, body) => tail_calls_only(body, true),
+            Let(definitions, body) => {
+                definitions.iter().all(|d| tail_calls_only(&d.expr, false))
+                    && tail_calls_only(body, tail)
+            }
+            If(cond, yes, no) => {
+                tail_calls_only(cond, false)
+                    && tail_calls_only(yes, tail)
+                    && tail_calls_only(no, tail)
+            }
+            Match(scrutinee, arms) => {
+                tail_calls_only(scrutinee, false)
+                    && arms.iter().all(|arm| tail_calls_only(&arm.body, tail))
+            }
+            e => subexpressions(e)
+                .into_iter()
+                .all(|e| tail_calls_only(e, false)),
+        }
+    }
+
+    #[test]
+    fn test_to_cps() {
+        for source in [
+            "1 + 2 * 3",
+            "(1 + 2) * (3 - 4) / 2 % 3",
+            "succ(pred(-5)) :: [<1, 2.5>]",
+            "let x = 1 + 2 and y = 3 in let x = y and y = x in x - y",
+            "let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact 5",
+            "let rec even = fn n. if n < 1 then true else odd (n - 1) \
+             and odd = fn n. if n < 1 then false else even (n - 1) in even 7",
+            "let rec f = fn n. (n + 1) and x = f 1 in f x",
+            "(fn f. f (f 1)) (fn x. (x * 10))",
+            "false and 1 / 0 == 1 or not(false)",
+            "1 < 2 and 3",
+            "hd(tl([1, 2, 3])) + fst(<4, 5>)",
+            "1 / 0",
+            "let x = 5 in (let x = 1 in x) + x",
+            "let x = 5 in <let x = 1 in x, x>",
+            "let rec f = fn n. n in (let rec f = fn n. (n + 1) in f 1) + f 1",
+        ] {
+            let e = parse(source).unwrap();
+            assert!(
+                tail_calls_only(&to_cps(e.clone()), true),
+                "{} has calls which aren't tail calls",
+                source
+            );
+            assert_eq!(run_cps(source), eval(&e), "{}", source);
+        }
+        // The interpreter can't evaluate these yet.
+        for source in [
+            "match f [1, 2] with x :: rest when g x -> rest | _ -> h nil",
+            "(f 1 : Int) + 1; g 2",
+        ] {
+            assert!(
+                tail_calls_only(&to_cps(parse(source).unwrap()), true),
+                "{}",
+                source
+            );
+        }
+    }
+}
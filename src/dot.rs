@@ -0,0 +1,102 @@
+//! dot.rs: Export expressions as Graphviz graphs, to see how they parsed.
+
+use crate::exprs::subexpressions;
+use crate::parser::Expression;
+use crate::parser::Expression::*;
+
+/// Render an expression as a Graphviz digraph, with one node per subexpression
+/// labelled by its variant, and an edge from each node to each of its children.
+pub fn to_dot(e: &Expression) -> String {
+    let mut dot = String::from("digraph {\n");
+    add_node(e, &mut dot, &mut 0);
+    dot.push_str("}\n");
+    dot
+}
+
+/// Add the nodes and edges for `e` to `dot`, numbering nodes from `next_id`.
+/// Returns the id of `e`'s node.
+fn add_node(e: &Expression, dot: &mut String, next_id: &mut usize) -> usize {
+    let id = *next_id;
+    *next_id += 1;
+    dot.push_str(&format!("    n{} [label=\"{}\"];\n", id, escape(&label(e))));
+    for child in subexpressions(e) {
+        let child_id = add_node(child, dot, next_id);
+        dot.push_str(&format!("    n{} -> n{};\n", id, child_id));
+    }
+    id
+}
+
+/// The variant's name, with any data that isn't a subexpression.
+fn label(e: &Expression) -> String {
+    let name = match e {
+        Num(n) => return format!("Num {}", n),
+        Float(f) => return format!("Float {:?}", f),
+        Str(s) => return format!("Str {:?}", s),
+        Char(c) => return format!("Char {:?}", c),
+        Var(v) => return format!("Var {}", v.ident),
+        Fn(v, _) => return format!("Fn {}", v.ident),
+        Field(_, f) => return format!("Field {}", f),
+        Let(definitions, _) => {
+            let vars = definitions.iter().map(|d| &*d.var.ident);
+            return format!("Let {}", vars.collect::<Vec<_>>().join(" "));
+        }
+        Record(fields) => {
+            let fields = fields.iter().map(|(f, _)| f.as_str());
+            return format!("Record {}", fields.collect::<Vec<_>>().join(" "));
+        }
+        True => "True",
+        False => "False",
+        Nil => "Nil",
+        Unit => "Unit",
+        Not(_) => "Not",
+        If(_, _, _) => "If",
+        Succ(_) => "Succ",
+        Pred(_) => "Pred",
+        Neg(_) => "Neg",
+        Fst(_) => "Fst",
+        Snd(_) => "Snd",
+        Hd(_) => "Hd",
+        Tl(_) => "Tl",
+        Pair(_, _) => "Pair",
+        Tuple(_) => "Tuple",
+        Apply(_, _) => "Apply",
+        Match(_, _) => "Match",
+        Ann(_, _) => "Ann",
+        _ => return format!("{:?}", e.as_binary().unwrap().0),
+    };
+    name.to_string()
+}
+
+/// Escape a label for use inside a double-quoted DOT string.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_to_dot() {
+        let dot = to_dot(&parse("1 + 2 * 3").unwrap());
+        assert!(dot.starts_with("digraph {"));
+        assert!(dot.contains("n0 [label=\"Add\"];"));
+        assert_eq!(dot.matches("->").count(), 4);
+        assert_eq!(dot.matches("[label=").count(), 5);
+        assert!(dot.contains("n0 -> n1;"));
+        assert!(dot.contains("n0 -> n2;"));
+        assert!(dot.contains("n2 [label=\"Mul\"];"));
+    }
+
+    #[test]
+    fn test_to_dot_labels() {
+        let dot = to_dot(&parse("let f = fn x. \"a\\\"b\" in f { y = 1 }.y").unwrap());
+        assert!(dot.contains("[label=\"Let f\"]"));
+        assert!(dot.contains("[label=\"Fn x\"]"));
+        assert!(dot.contains("[label=\"Str \\\"a\\\\\\\"b\\\"\"]"));
+        assert!(dot.contains("[label=\"Field y\"]"));
+        assert!(dot.contains("[label=\"Record y\"]"));
+        assert_eq!(dot.matches("->").count(), 7);
+    }
+}
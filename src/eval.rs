@@ -1,17 +1,19 @@
 //! eval.rs: A basic interpreter for MiniML expressions.
-//! Largely useful for testing.
+//! Only built when testing; interp.rs is the evaluator the crate exposes.
 
+use crate::exprs::pattern_variables;
 use crate::{parser, pprint};
-use parser::Expression;
 use parser::Expression::*;
-use parser::Variable;
+use parser::{Definition, Expression, MatchArm, Variable};
 use pprint::pprint;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 /// Contexts represent value environments.
 /// e.g. those introduced by `let x = e1 in e2`, or by applications.
 type Context = HashMap<Variable, Expression>;
 
+#[allow(dead_code)]
 fn pprint_ctx(ctx: &Context) -> String {
     let mut s = String::new();
     for (k, v) in ctx.iter() {
@@ -20,17 +22,9 @@ fn pprint_ctx(ctx: &Context) -> String {
     s
 }
 
-#[derive(Clone, PartialEq, Debug)]
-pub enum EvaluationOrder {
-    Normal,
-    Applicative,
-}
-
-const EVALUATION_ORDER: EvaluationOrder = EvaluationOrder::Normal;
-
 /// Evaluate under an empty context.
 pub fn eval(e: &Expression) -> Result<Expression, &'static str> {
-    return eval_under(e, &Context::new());
+    eval_under(e, &Context::new())
 }
 
 /// Evaluate with a given context.
@@ -38,12 +32,16 @@ pub fn eval_under(e: &Expression, ctx: &Context) -> Result<Expression, &'static
     match e {
         True => Ok(True),
         False => Ok(False),
-        Num(n) => Ok(e.clone()),
+        Num(_n) => Ok(e.clone()),
+        Float(_f) => Ok(e.clone()),
+        Str(_s) => Ok(e.clone()),
+        Char(_c) => Ok(e.clone()),
         Var(v) => match ctx.get(v) {
             Some(bound_e) => eval_under(bound_e, ctx),
             None => Ok(e.clone()),
         },
         Nil => Ok(Nil),
+        Unit => Ok(Unit),
         Not(e) => match eval_under(e, ctx)? {
             True => Ok(False),
             False => Ok(True),
@@ -55,17 +53,22 @@ pub fn eval_under(e: &Expression, ctx: &Context) -> Result<Expression, &'static
             // If the condition doesn't (yet) evaluate to a normal form, DO NOT
             // simplify the yes and no branches - they may diverge, even if the
             // program is well-formed.
-            cond_n => Ok(If(Box::new(cond_n), yes.clone(), no.clone())), //_ => Err("If applied to non-boolean condition.")
+            cond_n => Ok(If(Rc::new(cond_n), yes.clone(), no.clone())), //_ => Err("If applied to non-boolean condition.")
         },
         And(left, right) => match eval_under(left, ctx)? {
             True => eval_under(right, ctx),
             False => Ok(False),
             _ => Err("And applied to non-boolean condition."),
         },
+        Or(left, right) => match eval_under(left, ctx)? {
+            True => Ok(True),
+            False => eval_under(right, ctx),
+            _ => Err("Or applied to non-boolean condition."),
+        },
 
         Succ(e) => match eval_under(e, ctx)? {
             Num(n) => {
-                if n == u32::MAX {
+                if n == i64::MAX {
                     Ok(Num(n))
                 } else {
                     Ok(Num(n + 1))
@@ -78,14 +81,37 @@ pub fn eval_under(e: &Expression, ctx: &Context) -> Result<Expression, &'static
                 if n == 0 {
                     Ok(Num(0))
                 } else {
-                    Ok(Num(n - 1))
+                    Ok(Num(n.saturating_sub(1)))
                 }
             }
             _ => Err("Pred applied to non-integer parameter."),
         },
+        Neg(e) => match eval_under(e, ctx)? {
+            Num(n) => Ok(Num(n.saturating_neg())),
+            Float(f) => Ok(Float(-f)),
+            _ => Err("Neg applied to non-numeric parameter."),
+        },
         Add(left, right) => match (eval_under(left, ctx)?, eval_under(right, ctx)?) {
             (Num(l), Num(r)) => Ok(Num(l + r)),
-            (l, r) => Ok(Add(Box::new(l), Box::new(r))),
+            (l, r) => Ok(Add(Rc::new(l), Rc::new(r))),
+        },
+        Sub(left, right) => match (eval_under(left, ctx)?, eval_under(right, ctx)?) {
+            (Num(l), Num(r)) => Ok(Num(l - r)),
+            (l, r) => Ok(Sub(Rc::new(l), Rc::new(r))),
+        },
+        Mul(left, right) => match (eval_under(left, ctx)?, eval_under(right, ctx)?) {
+            (Num(l), Num(r)) => Ok(Num(l * r)),
+            (l, r) => Ok(Mul(Rc::new(l), Rc::new(r))),
+        },
+        Div(left, right) => match (eval_under(left, ctx)?, eval_under(right, ctx)?) {
+            (Num(_l), Num(0)) => Err("Division by zero."),
+            (Num(l), Num(r)) => Ok(Num(l / r)),
+            (l, r) => Ok(Div(Rc::new(l), Rc::new(r))),
+        },
+        Mod(left, right) => match (eval_under(left, ctx)?, eval_under(right, ctx)?) {
+            (Num(_l), Num(0)) => Err("Division by zero."),
+            (Num(l), Num(r)) => Ok(Num(l % r)),
+            (l, r) => Ok(Mod(Rc::new(l), Rc::new(r))),
         },
         Eq(left, right) => match (eval_under(left, ctx)?, eval_under(right, ctx)?) {
             (Num(l), Num(r)) => Ok(true_or_false(l == r)),
@@ -93,7 +119,31 @@ pub fn eval_under(e: &Expression, ctx: &Context) -> Result<Expression, &'static
             (True, False) => Ok(False),
             (False, True) => Ok(False),
             (False, False) => Ok(True),
-            (l, r) => Ok(Eq(Box::new(l), Box::new(r))),
+            (l, r) => Ok(Eq(Rc::new(l), Rc::new(r))),
+        },
+        Neq(left, right) => match (eval_under(left, ctx)?, eval_under(right, ctx)?) {
+            (Num(l), Num(r)) => Ok(true_or_false(l != r)),
+            (True, True) => Ok(False),
+            (True, False) => Ok(True),
+            (False, True) => Ok(True),
+            (False, False) => Ok(False),
+            (l, r) => Ok(Neq(Rc::new(l), Rc::new(r))),
+        },
+        Lt(left, right) => match (eval_under(left, ctx)?, eval_under(right, ctx)?) {
+            (Num(l), Num(r)) => Ok(true_or_false(l < r)),
+            (l, r) => Ok(Lt(Rc::new(l), Rc::new(r))),
+        },
+        Gt(left, right) => match (eval_under(left, ctx)?, eval_under(right, ctx)?) {
+            (Num(l), Num(r)) => Ok(true_or_false(l > r)),
+            (l, r) => Ok(Gt(Rc::new(l), Rc::new(r))),
+        },
+        Le(left, right) => match (eval_under(left, ctx)?, eval_under(right, ctx)?) {
+            (Num(l), Num(r)) => Ok(true_or_false(l <= r)),
+            (l, r) => Ok(Le(Rc::new(l), Rc::new(r))),
+        },
+        Ge(left, right) => match (eval_under(left, ctx)?, eval_under(right, ctx)?) {
+            (Num(l), Num(r)) => Ok(true_or_false(l >= r)),
+            (l, r) => Ok(Ge(Rc::new(l), Rc::new(r))),
         },
 
         // - Normal form
@@ -102,55 +152,108 @@ pub fn eval_under(e: &Expression, ctx: &Context) -> Result<Expression, &'static
             let mut inner_ctx = ctx.clone();
             inner_ctx.remove(var);
             let body_normal = eval_under(body, &inner_ctx)?;
-            Ok(Fn(var.clone(), Box::new(body_normal)))
+            Ok(Fn(var.clone(), Rc::new(body_normal)))
         }
 
         // Applications
         Apply(l, r) => eval_apply(l, r, ctx),
 
-        Let(var, bound_expression, body) => {
-            // Evaluate e1 in the current context,
-            let bound_value = eval_under(&*bound_expression, ctx)?;
-            // Add x = e1 into a new inner context,
+        // Annotations have no effect at runtime.
+        Ann(e, _t) => eval_under(e, ctx),
+
+        Seq(first, second) => {
+            eval_under(first, ctx)?;
+            eval_under(second, ctx)
+        }
+
+        Let(definitions, body) => {
             let mut inner_ctx = ctx.clone();
-            inner_ctx.insert(var.clone(), bound_value);
+            for definition in definitions {
+                // Evaluate each e1 in the current context,
+                let bound_value = eval_definition(definition, definitions, ctx)?;
+                // Add x = e1 into a new inner context,
+                inner_ctx.insert(definition.var.clone(), bound_value);
+            }
             // And evaluate e2!
             eval_under(body, &inner_ctx)
         }
 
-        _ => todo!(),
+        _ => Err("Unsupported expression."),
     }
 }
 
+/// Evaluate one definition of a `let ... and ...` group.
+fn eval_definition(
+    definition: &Definition,
+    group: &[Definition],
+    ctx: &Context,
+) -> Result<Expression, &'static str> {
+    if !definition.recursive {
+        return eval_under(&definition.expr, ctx);
+    }
+    // The rest of a mutually recursive group is seen through an inner `let rec`,
+    let others: Vec<Definition> = group
+        .iter()
+        .filter(|d| d.var != definition.var)
+        .cloned()
+        .collect();
+    let expr = if others.is_empty() {
+        definition.expr.clone()
+    } else {
+        Rc::new(Let(others, definition.expr.clone()))
+    };
+    // and the knot is tied with Y (fn x. e1) so that x is in scope in e1.
+    let knot = Fn(definition.var.clone(), expr);
+    eval_under(&Apply(y_combinator(), Rc::new(knot)), ctx)
+}
+
+/// Curry's Y combinator, `fn f. (fn x. f (x x)) (fn x. f (x x))`.
+/// Its variables can't be written in source, so they never capture user variables.
+fn y_combinator() -> Rc<Expression> {
+    let var = |s: &str| Variable { ident: s.into() };
+    let f = || Rc::new(Var(var("Y'f")));
+    let x = || Rc::new(Var(var("Y'x")));
+    let half = || {
+        Rc::new(Fn(
+            var("Y'x"),
+            Rc::new(Apply(f(), Rc::new(Apply(x(), x())))),
+        ))
+    };
+    Rc::new(Fn(var("Y'f"), Rc::new(Apply(half(), half()))))
+}
+
 fn eval_apply(l: &Expression, r: &Expression, ctx: &Context) -> Result<Expression, &'static str> {
     let l_normal = eval_under(l, ctx)?;
     match l_normal {
         Fn(var, body) => {
             // Beta reduction.
             // Replace every occurrence of var in body with r.
-            let body_replaced = replace_var_in_expr_with_r(&var, &*body, r);
-            eval_under(&*body_replaced, ctx)
+            let body_replaced = replace_var_in_expr_with_r(&var, &body, r);
+            eval_under(&body_replaced, ctx)
         }
         _ => {
             // - Not too eager...
-            Ok(Apply(Box::new(l_normal), Box::new(r.clone())))
+            Ok(Apply(Rc::new(l_normal), Rc::new(r.clone())))
             // - Too eager?
-            //Ok(Apply(Box::new(l_normal),
-            //         Box::new(eval_under_dbg(r, ctx, idbg)?)))
+            //Ok(Apply(Rc::new(l_normal),
+            //         Rc::new(eval_under_dbg(r, ctx, idbg)?)))
         }
     }
 }
 
-fn replace_var_in_expr_with_r(
+pub(crate) fn replace_var_in_expr_with_r(
     var: &Variable,
     body: &Expression,
     r: &Expression,
-) -> Box<Expression> {
+) -> Rc<Expression> {
     let b = body.clone();
-    Box::new(match body {
+    Rc::new(match body {
         True => b,
         False => b,
         Num(_n) => b,
+        Float(_f) => b,
+        Str(_s) => b,
+        Char(_c) => b,
         Var(_v) => {
             if *_v == *var {
                 r.clone()
@@ -159,58 +262,152 @@ fn replace_var_in_expr_with_r(
             }
         }
         Nil => b,
-        Let(_var, _bound_expr, _body) => {
-            let new_body = if *_var == *var {
+        Unit => b,
+        Let(_definitions, _body) => {
+            let shadowed = _definitions.iter().any(|d| d.var == *var);
+            let new_body = if shadowed {
                 _body.clone()
             } else {
-                replace_var_in_expr_with_r(var, &*_body, r)
+                replace_var_in_expr_with_r(var, _body, r)
             };
-            let new_binding = replace_var_in_expr_with_r(var, &_bound_expr, r);
-            Let(_var.clone(), new_binding, new_body)
+            let new_definitions = _definitions
+                .iter()
+                .map(|d| Definition {
+                    expr: if shadowed && d.recursive {
+                        d.expr.clone()
+                    } else {
+                        replace_var_in_expr_with_r(var, &d.expr, r)
+                    },
+                    ..d.clone()
+                })
+                .collect();
+            Let(new_definitions, new_body)
         }
-        Not(_e) => Not(replace_var_in_expr_with_r(var, &*_e, r)),
+        Not(_e) => Not(replace_var_in_expr_with_r(var, _e, r)),
         If(_cond, _yes, _no) => If(
-            replace_var_in_expr_with_r(var, &*_cond, r),
-            replace_var_in_expr_with_r(var, &*_yes, r),
-            replace_var_in_expr_with_r(var, &*_no, r),
+            replace_var_in_expr_with_r(var, _cond, r),
+            replace_var_in_expr_with_r(var, _yes, r),
+            replace_var_in_expr_with_r(var, _no, r),
+        ),
+        Succ(_e) => Succ(replace_var_in_expr_with_r(var, _e, r)),
+        Pred(_e) => Pred(replace_var_in_expr_with_r(var, _e, r)),
+        Neg(_e) => Neg(replace_var_in_expr_with_r(var, _e, r)),
+        Fst(_e) => Fst(replace_var_in_expr_with_r(var, _e, r)),
+        Snd(_e) => Snd(replace_var_in_expr_with_r(var, _e, r)),
+        Hd(_e) => Hd(replace_var_in_expr_with_r(var, _e, r)),
+        Tl(_e) => Tl(replace_var_in_expr_with_r(var, _e, r)),
+        Tuple(_es) => Tuple(
+            _es.iter()
+                .map(|e| Rc::unwrap_or_clone(replace_var_in_expr_with_r(var, e, r)))
+                .collect(),
         ),
-        Succ(_e) => Succ(replace_var_in_expr_with_r(var, &*_e, r)),
-        Pred(_e) => Pred(replace_var_in_expr_with_r(var, &*_e, r)),
-        Fst(_e) => Fst(replace_var_in_expr_with_r(var, &*_e, r)),
-        Snd(_e) => Snd(replace_var_in_expr_with_r(var, &*_e, r)),
-        Hd(_e) => Hd(replace_var_in_expr_with_r(var, &*_e, r)),
-        Tl(_e) => Tl(replace_var_in_expr_with_r(var, &*_e, r)),
+        Record(_fields) => Record(
+            _fields
+                .iter()
+                .map(|(f, e)| {
+                    (
+                        f.clone(),
+                        Rc::unwrap_or_clone(replace_var_in_expr_with_r(var, e, r)),
+                    )
+                })
+                .collect(),
+        ),
+        Field(_e, _f) => Field(replace_var_in_expr_with_r(var, _e, r), _f.clone()),
         Pair(_e1, _e2) => Pair(
-            replace_var_in_expr_with_r(var, &*_e1, r),
-            replace_var_in_expr_with_r(var, &*_e2, r),
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
         ),
         Fn(_var, _body) => {
             let new_body = if *_var == *var {
                 _body.clone()
             } else {
-                replace_var_in_expr_with_r(var, &*_body, r)
+                replace_var_in_expr_with_r(var, _body, r)
             };
             Fn(_var.clone(), new_body)
         }
+        Seq(_e1, _e2) => Seq(
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
+        ),
+        Ann(_e, _t) => Ann(replace_var_in_expr_with_r(var, _e, r), _t.clone()),
+        Match(_e, _arms) => Match(
+            replace_var_in_expr_with_r(var, _e, r),
+            _arms
+                .iter()
+                .map(|arm| {
+                    // Arms which bind var shadow it.
+                    if pattern_variables(&arm.pattern).contains(&var) {
+                        return arm.clone();
+                    }
+                    MatchArm {
+                        pattern: arm.pattern.clone(),
+                        guard: arm.guard.as_ref().map(|guard| {
+                            Rc::unwrap_or_clone(replace_var_in_expr_with_r(var, guard, r))
+                        }),
+                        body: Rc::unwrap_or_clone(replace_var_in_expr_with_r(var, &arm.body, r)),
+                    }
+                })
+                .collect(),
+        ),
         Eq(_e1, _e2) => Eq(
-            replace_var_in_expr_with_r(var, &*_e1, r),
-            replace_var_in_expr_with_r(var, &*_e2, r),
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
+        ),
+        Neq(_e1, _e2) => Neq(
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
+        ),
+        Lt(_e1, _e2) => Lt(
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
+        ),
+        Gt(_e1, _e2) => Gt(
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
+        ),
+        Le(_e1, _e2) => Le(
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
+        ),
+        Ge(_e1, _e2) => Ge(
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
         ),
         Cons(_e1, _e2) => Cons(
-            replace_var_in_expr_with_r(var, &*_e1, r),
-            replace_var_in_expr_with_r(var, &*_e2, r),
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
         ),
         And(_e1, _e2) => And(
-            replace_var_in_expr_with_r(var, &*_e1, r),
-            replace_var_in_expr_with_r(var, &*_e2, r),
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
+        ),
+        Or(_e1, _e2) => Or(
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
         ),
         Add(_e1, _e2) => Add(
-            replace_var_in_expr_with_r(var, &*_e1, r),
-            replace_var_in_expr_with_r(var, &*_e2, r),
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
+        ),
+        Sub(_e1, _e2) => Sub(
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
+        ),
+        Mul(_e1, _e2) => Mul(
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
+        ),
+        Div(_e1, _e2) => Div(
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
+        ),
+        Mod(_e1, _e2) => Mod(
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
         ),
         Apply(_e1, _e2) => Apply(
-            replace_var_in_expr_with_r(var, &*_e1, r),
-            replace_var_in_expr_with_r(var, &*_e2, r),
+            replace_var_in_expr_with_r(var, _e1, r),
+            replace_var_in_expr_with_r(var, _e2, r),
         ),
     })
 }
@@ -227,6 +424,7 @@ fn true_or_false(b: bool) -> Expression {
 mod tests {
     use super::*;
     use crate::exprs;
+    use crate::parser::Type;
     use exprs::*;
 
     #[test]
@@ -250,6 +448,21 @@ mod tests {
         assert_eq!(eval(&expr), Ok(True));
     }
 
+    #[test]
+    fn test_eval_basic_or() {
+        let expr = Or(bFalse(), bFalse());
+        assert_eq!(eval(&expr), Ok(False));
+
+        let expr = Or(bFalse(), bTrue());
+        assert_eq!(eval(&expr), Ok(True));
+
+        let expr = Or(bTrue(), bFalse());
+        assert_eq!(eval(&expr), Ok(True));
+
+        let expr = Or(bTrue(), bTrue());
+        assert_eq!(eval(&expr), Ok(True));
+    }
+
     #[test]
     fn test_eval_basic_not() {
         let expr = Not(bFalse());
@@ -316,67 +529,217 @@ mod tests {
 
     #[test]
     fn test_eval_basic_let() {
-        let expr = Let(*bVariable("x"), bTrue(), bVar("x"));
+        let expr = bLet(bVariable("x"), bTrue(), bVar("x"));
         assert_eq!(eval(&expr), Ok(True));
 
-        let expr = Let(*bVariable("x"), bTrue(), bNot(bVar("x")));
+        let expr = bLet(bVariable("x"), bTrue(), bNot(bVar("x")));
         assert_eq!(eval(&expr), Ok(False));
     }
 
+    #[test]
+    fn test_eval_let_rec() {
+        // let rec fact = fn n. if n == 0 then 1 else n * fact (pred n) in fact 5
+        let fact = bFn(
+            "n",
+            bIf(
+                bEq(bVar("n"), bNum(0)),
+                bNum(1),
+                bMul(bVar("n"), bApply(bVar("fact"), bPred(bVar("n")))),
+            ),
+        );
+        let expr = bLetRec(bVariable("fact"), fact, bApply(bVar("fact"), bNum(5)));
+        assert_eq!(eval(&expr), Ok(Num(120)));
+    }
+
+    #[test]
+    fn test_eval_let_rec_and() {
+        // let rec even = fn n. if n == 0 then true else odd (pred n)
+        //     and odd = fn n. if n == 0 then false else even (pred n)
+        // in even k
+        let parity = |name: &str, base: Rc<Expression>, other: &str| Definition {
+            var: *bVariable(name),
+            expr: bFn(
+                "n",
+                bIf(
+                    bEq(bVar("n"), bNum(0)),
+                    base,
+                    bApply(bVar(other), bPred(bVar("n"))),
+                ),
+            ),
+            recursive: true,
+        };
+        let group = vec![
+            parity("even", bTrue(), "odd"),
+            parity("odd", bFalse(), "even"),
+        ];
+        for k in 0..6 {
+            let expr = Let(group.clone(), bApply(bVar("even"), bNum(k)));
+            assert_eq!(eval(&expr), Ok(if k % 2 == 0 { True } else { False }));
+        }
+    }
+
+    #[test]
+    fn test_eval_basic_seq() {
+        let expr = bSeq(bNum(1), bNum(2));
+        assert_eq!(eval(&expr), Ok(Num(2)));
+
+        let expr = bSeq(bUnit(), bSeq(bTrue(), bNot(bTrue())));
+        assert_eq!(eval(&expr), Ok(False));
+
+        // Errors in the first expression aren't discarded.
+        let expr = bSeq(bNot(bNum(1)), bNum(2));
+        assert_eq!(eval(&expr), Err("Not applied to non-boolean."));
+    }
+
+    #[test]
+    fn test_eval_annotation() {
+        let expr = Ann(bAdd(bNum(1), bNum(2)), Type::Int);
+        assert_eq!(eval(&expr), Ok(Num(3)));
+    }
+
     #[test]
     fn test_eval_basic_add() {
         let expr = bAdd(bNum(0), bNum(0));
-        assert_eq!(eval(&*expr), Ok(Num(0)));
+        assert_eq!(eval(&expr), Ok(Num(0)));
 
         let expr = bAdd(bNum(1), bNum(0));
-        assert_eq!(eval(&*expr), Ok(Num(1)));
+        assert_eq!(eval(&expr), Ok(Num(1)));
 
         let expr = bAdd(bNum(0), bNum(1));
-        assert_eq!(eval(&*expr), Ok(Num(1)));
+        assert_eq!(eval(&expr), Ok(Num(1)));
 
         let expr = bAdd(bNum(2), bNum(2));
-        assert_eq!(eval(&*expr), Ok(Num(4)));
+        assert_eq!(eval(&expr), Ok(Num(4)));
 
         let expr = bAdd(bNum(3), bNum(1));
-        assert_eq!(eval(&*expr), Ok(Num(4)));
+        assert_eq!(eval(&expr), Ok(Num(4)));
+    }
+
+    #[test]
+    fn test_eval_basic_sub() {
+        let expr = bSub(bNum(0), bNum(0));
+        assert_eq!(eval(&expr), Ok(Num(0)));
+
+        let expr = bSub(bNum(3), bNum(1));
+        assert_eq!(eval(&expr), Ok(Num(2)));
+
+        let expr = bSub(bNum(1), bNum(3));
+        assert_eq!(eval(&expr), Ok(Num(-2)));
+    }
+
+    #[test]
+    fn test_eval_basic_mul() {
+        let expr = bMul(bNum(0), bNum(5));
+        assert_eq!(eval(&expr), Ok(Num(0)));
+
+        let expr = bMul(bNum(3), bNum(4));
+        assert_eq!(eval(&expr), Ok(Num(12)));
+
+        let expr = bMul(bNum(-2), bNum(3));
+        assert_eq!(eval(&expr), Ok(Num(-6)));
+    }
+
+    #[test]
+    fn test_eval_basic_neg() {
+        let expr = bNeg(bNum(3));
+        assert_eq!(eval(&expr), Ok(Num(-3)));
+
+        let expr = bNeg(bNeg(bNum(3)));
+        assert_eq!(eval(&expr), Ok(Num(3)));
+
+        let expr = bNeg(bAdd(bNum(1), bNum(2)));
+        assert_eq!(eval(&expr), Ok(Num(-3)));
+
+        let expr = bNeg(bNum(i64::MIN));
+        assert_eq!(eval(&expr), Ok(Num(i64::MAX)));
+
+        let expr = bNeg(bTrue());
+        assert_eq!(eval(&expr), Err("Neg applied to non-numeric parameter."));
+    }
+
+    #[test]
+    fn test_eval_basic_div() {
+        let expr = bDiv(bNum(8), bNum(2));
+        assert_eq!(eval(&expr), Ok(Num(4)));
+
+        let expr = bDiv(bNum(7), bNum(2));
+        assert_eq!(eval(&expr), Ok(Num(3)));
+
+        let expr = bDiv(bNum(1), bNum(0));
+        assert_eq!(eval(&expr), Err("Division by zero."));
+    }
+
+    #[test]
+    fn test_eval_basic_mod() {
+        let expr = bMod(bNum(10), bNum(3));
+        assert_eq!(eval(&expr), Ok(Num(1)));
+
+        let expr = bMod(bNum(9), bNum(3));
+        assert_eq!(eval(&expr), Ok(Num(0)));
+
+        let expr = bMod(bNum(1), bNum(0));
+        assert_eq!(eval(&expr), Err("Division by zero."));
+    }
+
+    #[test]
+    fn test_eval_basic_neq() {
+        assert_eq!(eval(&bNeq(bNum(1), bNum(2))), Ok(True));
+        assert_eq!(eval(&bNeq(bNum(2), bNum(2))), Ok(False));
+        assert_eq!(eval(&bNeq(bTrue(), bFalse())), Ok(True));
+        assert_eq!(eval(&bNeq(bFalse(), bFalse())), Ok(False));
+    }
+
+    #[test]
+    fn test_eval_basic_comparisons() {
+        assert_eq!(eval(&bLt(bNum(1), bNum(2))), Ok(True));
+        assert_eq!(eval(&bLt(bNum(2), bNum(2))), Ok(False));
+        assert_eq!(eval(&bGt(bNum(3), bNum(2))), Ok(True));
+        assert_eq!(eval(&bGt(bNum(2), bNum(2))), Ok(False));
+        assert_eq!(eval(&bLe(bNum(2), bNum(2))), Ok(True));
+        assert_eq!(eval(&bLe(bNum(3), bNum(2))), Ok(False));
+        assert_eq!(eval(&bGe(bNum(2), bNum(2))), Ok(True));
+        assert_eq!(eval(&bGe(bNum(1), bNum(2))), Ok(False));
     }
 
     #[test]
     fn test_eval_basic_succ() {
         let expr = bSucc(bNum(0));
-        assert_eq!(eval(&*expr), Ok(Num(1)));
+        assert_eq!(eval(&expr), Ok(Num(1)));
 
         let expr = bSucc(bNum(1));
-        assert_eq!(eval(&*expr), Ok(Num(2)));
+        assert_eq!(eval(&expr), Ok(Num(2)));
 
         let expr = bSucc(bNum(2));
-        assert_eq!(eval(&*expr), Ok(Num(3)));
+        assert_eq!(eval(&expr), Ok(Num(3)));
 
         let expr = bSucc(bNum(3));
-        assert_eq!(eval(&*expr), Ok(Num(4)));
+        assert_eq!(eval(&expr), Ok(Num(4)));
 
         // Addition is saturating.
-        let expr = bSucc(bNum(u32::MAX));
-        assert_eq!(eval(&*expr), Ok(Num(u32::MAX)));
+        let expr = bSucc(bNum(i64::MAX));
+        assert_eq!(eval(&expr), Ok(Num(i64::MAX)));
     }
 
     #[test]
     fn test_eval_basic_pred() {
         // Subtraction is saturating.
         let expr = bPred(bNum(0));
-        assert_eq!(eval(&*expr), Ok(Num(0)));
+        assert_eq!(eval(&expr), Ok(Num(0)));
 
         let expr = bPred(bNum(1));
-        assert_eq!(eval(&*expr), Ok(Num(0)));
+        assert_eq!(eval(&expr), Ok(Num(0)));
 
         let expr = bPred(bNum(2));
-        assert_eq!(eval(&*expr), Ok(Num(1)));
+        assert_eq!(eval(&expr), Ok(Num(1)));
 
         let expr = bPred(bNum(3));
-        assert_eq!(eval(&*expr), Ok(Num(2)));
+        assert_eq!(eval(&expr), Ok(Num(2)));
+
+        let expr = bPred(bNum(i64::MAX));
+        assert_eq!(eval(&expr), Ok(Num(i64::MAX - 1)));
 
-        let expr = bPred(bNum(u32::MAX));
-        assert_eq!(eval(&*expr), Ok(Num(u32::MAX - 1)));
+        let expr = bPred(bNum(i64::MIN));
+        assert_eq!(eval(&expr), Ok(Num(i64::MIN)));
     }
 
     #[test]
@@ -386,7 +749,7 @@ mod tests {
         assert_eq!(eval(&expr), Ok(Num(6)));
     }
 
-    fn rust_fib(n: u32) -> u32 {
+    fn rust_fib(n: i64) -> i64 {
         match n {
             0 => 0,
             1 => 1,
@@ -395,13 +758,14 @@ mod tests {
     }
 
     #[test]
+    #[allow(non_snake_case)]
     fn test_eval_basic_y_comb() {
         // Curry's Y combinator
         let V = bFn("Vy", bApply(bVar("Yx"), bApply(bVar("Vy"), bVar("Vy"))));
         let Y = bFn("Yx", bApply(V.clone(), V));
 
         let mut COMBINATORS = Context::new();
-        COMBINATORS.insert(*bVariable("Y"), *Y);
+        COMBINATORS.insert(*bVariable("Y"), Rc::unwrap_or_clone(Y));
 
         let fib = bApply(
             bVar("Y"),
@@ -437,7 +801,7 @@ mod tests {
             if let Ok(Num(got)) = mml_fib(n) {
                 assert_eq!(got, expected);
             } else {
-                assert!(false);
+                panic!("fib({}) did not evaluate to a number", n);
             }
         }
     }
@@ -1,88 +1,159 @@
 //! exprs.rs: Handy functions for expressions.
 
+// The boxed constructors mirror the constructor names they wrap.
+#![allow(non_snake_case)]
+
+use std::rc::Rc;
+
 use crate::parser;
 use parser::Expression;
 use parser::Expression::*;
-use parser::Variable;
+use parser::{Definition, MatchArm, Pattern, Variable};
 
 // region Boxed constructors for convenient building of expressions.
-pub fn bTrue() -> Box<Expression> {
-    Box::new(True)
+pub fn bTrue() -> Rc<Expression> {
+    Rc::new(True)
 }
-pub fn bFalse() -> Box<Expression> {
-    Box::new(False)
+pub fn bFalse() -> Rc<Expression> {
+    Rc::new(False)
 }
-pub fn bNot(b: Box<Expression>) -> Box<Expression> {
-    Box::new(Not(b))
+pub fn bNot(b: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Not(b))
 }
-pub fn bIf(c: Box<Expression>, yes: Box<Expression>, no: Box<Expression>) -> Box<Expression> {
-    Box::new(If(c, yes, no))
+pub fn bIf(c: Rc<Expression>, yes: Rc<Expression>, no: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(If(c, yes, no))
 }
 
 pub fn bVariable(s: &str) -> Box<Variable> {
-    Box::new(Variable {
-        ident: s.to_string(),
-    })
+    Box::new(Variable { ident: s.into() })
 }
-pub fn bVar(s: &str) -> Box<Expression> {
-    Box::new(Var(Variable {
-        ident: s.to_string(),
-    }))
+pub fn bVar(s: &str) -> Rc<Expression> {
+    Rc::new(Var(Variable { ident: s.into() }))
 }
 
-pub fn bAdd(l: Box<Expression>, r: Box<Expression>) -> Box<Expression> {
-    Box::new(Add(l, r))
+pub fn bAdd(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Add(l, r))
+}
+pub fn bSub(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Sub(l, r))
+}
+pub fn bMul(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Mul(l, r))
+}
+pub fn bDiv(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Div(l, r))
+}
+pub fn bMod(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Mod(l, r))
+}
+pub fn bNum(n: i64) -> Rc<Expression> {
+    Rc::new(Num(n))
+}
+pub fn bFloat(f: f64) -> Rc<Expression> {
+    Rc::new(Float(f))
+}
+pub fn bStr(s: &str) -> Rc<Expression> {
+    Rc::new(Str(s.to_string()))
 }
-pub fn bNum(n: u32) -> Box<Expression> {
-    Box::new(Num(n))
+pub fn bChar(c: char) -> Rc<Expression> {
+    Rc::new(Char(c))
 }
-pub fn bSucc(e: Box<Expression>) -> Box<Expression> {
-    Box::new(Succ(e))
+pub fn bSucc(e: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Succ(e))
 }
-pub fn bPred(e: Box<Expression>) -> Box<Expression> {
-    Box::new(Pred(e))
+pub fn bPred(e: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Pred(e))
 }
 
-pub fn bEq(l: Box<Expression>, r: Box<Expression>) -> Box<Expression> {
-    Box::new(Eq(l, r))
+pub fn bNeg(e: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Neg(e))
 }
 
-pub fn bLet(v: Box<Variable>, def_expr: Box<Expression>, body: Box<Expression>) -> Box<Expression> {
-    Box::new(Let(*v, def_expr, body))
+pub fn bEq(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Eq(l, r))
 }
-pub fn bFn(s: &str, body: Box<Expression>) -> Box<Expression> {
-    Box::new(Fn(
-        Variable {
-            ident: s.to_string(),
-        },
-        body,
-    ))
+pub fn bNeq(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Neq(l, r))
+}
+pub fn bLt(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Lt(l, r))
+}
+pub fn bGt(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Gt(l, r))
 }
-pub fn bApply(l: Box<Expression>, r: Box<Expression>) -> Box<Expression> {
-    Box::new(Apply(l, r))
+pub fn bLe(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Le(l, r))
+}
+pub fn bGe(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Ge(l, r))
 }
 
-pub fn bPair(l: Box<Expression>, r: Box<Expression>) -> Box<Expression> {
-    Box::new(Pair(l, r))
+#[allow(clippy::boxed_local)]
+pub fn bLet(v: Box<Variable>, def_expr: Rc<Expression>, body: Rc<Expression>) -> Rc<Expression> {
+    let definition = Definition {
+        var: *v,
+        expr: def_expr,
+        recursive: false,
+    };
+    Rc::new(Let(vec![definition], body))
+}
+#[allow(clippy::boxed_local)]
+pub fn bLetRec(v: Box<Variable>, def_expr: Rc<Expression>, body: Rc<Expression>) -> Rc<Expression> {
+    let definition = Definition {
+        var: *v,
+        expr: def_expr,
+        recursive: true,
+    };
+    Rc::new(Let(vec![definition], body))
 }
-pub fn bFst(p: Box<Expression>) -> Box<Expression> {
-    Box::new(Fst(p))
+pub fn bFn(s: &str, body: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Fn(Variable { ident: s.into() }, body))
 }
-pub fn bSnd(p: Box<Expression>) -> Box<Expression> {
-    Box::new(Snd(p))
+pub fn bApply(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Apply(l, r))
+}
+pub fn bSeq(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Seq(l, r))
 }
 
-pub fn bHd(e: Box<Expression>) -> Box<Expression> {
-    Box::new(Hd(e))
+pub fn bPair(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Pair(l, r))
+}
+pub fn bTuple(es: Vec<Expression>) -> Rc<Expression> {
+    Rc::new(Tuple(es))
+}
+pub fn bRecord(fields: Vec<(&str, Expression)>) -> Rc<Expression> {
+    Rc::new(Record(
+        fields
+            .into_iter()
+            .map(|(f, e)| (f.to_string(), e))
+            .collect(),
+    ))
 }
-pub fn bTl(e: Box<Expression>) -> Box<Expression> {
-    Box::new(Tl(e))
+pub fn bField(e: Rc<Expression>, f: &str) -> Rc<Expression> {
+    Rc::new(Field(e, f.to_string()))
 }
-pub fn bCons(l: Box<Expression>, r: Box<Expression>) -> Box<Expression> {
-    Box::new(Cons(l, r))
+pub fn bFst(p: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Fst(p))
 }
-pub fn bNil() -> Box<Expression> {
-    Box::new(Nil)
+pub fn bSnd(p: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Snd(p))
+}
+
+pub fn bHd(e: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Hd(e))
+}
+pub fn bTl(e: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Tl(e))
+}
+pub fn bCons(l: Rc<Expression>, r: Rc<Expression>) -> Rc<Expression> {
+    Rc::new(Cons(l, r))
+}
+pub fn bNil() -> Rc<Expression> {
+    Rc::new(Nil)
+}
+pub fn bUnit() -> Rc<Expression> {
+    Rc::new(Unit)
 }
 // endregion
 
@@ -92,24 +163,50 @@ pub fn expression_arity(e: &Expression) -> u32 {
         True => 0,
         False => 0,
         Num(_n) => 0,
+        Float(_f) => 0,
+        Str(_s) => 0,
+        Char(_c) => 0,
         Var(_v) => 0,
         Nil => 0,
-        Let(_var, _bound_expr, _body) => 3,
+        Unit => 0,
+        Let(definitions, _body) => 2 * definitions.len() as u32 + 1,
         Not(_e) => 1,
         If(_cond, _yes, _no) => 3,
         Succ(_e) => 1,
         Pred(_e) => 1,
+        Neg(_e) => 1,
         Fst(_e) => 1,
         Snd(_e) => 1,
         Hd(_e) => 1,
         Tl(_e) => 1,
         Pair(_e1, _e2) => 2,
-        Fn(v, _e) => 2,
+        Tuple(es) => es.len() as u32,
+        Record(fields) => fields.len() as u32,
+        Field(_e, _f) => 1,
+        Fn(_v, _e) => 2,
         Eq(_e1, _e2) => 2,
+        Neq(_e1, _e2) => 2,
+        Lt(_e1, _e2) => 2,
+        Gt(_e1, _e2) => 2,
+        Le(_e1, _e2) => 2,
+        Ge(_e1, _e2) => 2,
         Cons(_e1, _e2) => 2,
         And(_e1, _e2) => 2,
+        Or(_e1, _e2) => 2,
         Add(_e1, _e2) => 2,
+        Sub(_e1, _e2) => 2,
+        Mul(_e1, _e2) => 2,
+        Div(_e1, _e2) => 2,
+        Mod(_e1, _e2) => 2,
         Apply(_e1, _e2) => 2,
+        Seq(_e1, _e2) => 2,
+        Ann(_e, _t) => 1,
+        Match(_e, arms) => {
+            arms.iter()
+                .map(|arm| 2 + arm.guard.is_some() as u32)
+                .sum::<u32>()
+                + 1
+        }
     }
 }
 
@@ -118,24 +215,178 @@ pub fn expr_needs_paren(e: &Expression) -> bool {
         True => false,
         False => false,
         Num(_n) => false,
+        Float(_f) => false,
+        Str(_s) => false,
+        Char(_c) => false,
         Var(_v) => false,
         Nil => false,
-        Let(_var, _bound_expr, _body) => true,
+        Unit => false,
+        Let(_definitions, _body) => true,
         Not(_e) => true,
         If(_cond, _yes, _no) => true,
         Succ(_e) => true,
         Pred(_e) => true,
+        Neg(_e) => true,
         Fst(_e) => true,
         Snd(_e) => true,
         Hd(_e) => true,
         Tl(_e) => true,
         Pair(_e1, _e2) => false,
-        Fn(v, _e) => true,
+        Tuple(_es) => false,
+        Record(_fields) => false,
+        Field(_e, _f) => false,
+        Fn(_v, _e) => true,
         Eq(_e1, _e2) => true,
+        Neq(_e1, _e2) => true,
+        Lt(_e1, _e2) => true,
+        Gt(_e1, _e2) => true,
+        Le(_e1, _e2) => true,
+        Ge(_e1, _e2) => true,
         Cons(_e1, _e2) => true,
         And(_e1, _e2) => true,
+        Or(_e1, _e2) => true,
         Add(_e1, _e2) => true,
+        Sub(_e1, _e2) => true,
+        Mul(_e1, _e2) => true,
+        Div(_e1, _e2) => true,
+        Mod(_e1, _e2) => true,
         Apply(_e1, _e2) => true,
+        Seq(_e1, _e2) => true,
+        Match(_e, _arms) => true,
+        Ann(_e, _t) => false,
+    }
+}
+
+/// The subexpressions of e, from left to right. Unlike [`expression_arity`],
+/// this leaves out bound variables.
+pub fn subexpressions(e: &Expression) -> Vec<&Expression> {
+    match e {
+        True | False | Num(_) | Float(_) | Str(_) | Char(_) | Var(_) | Nil | Unit => vec![],
+        Let(definitions, body) => definitions
+            .iter()
+            .map(|d| &*d.expr)
+            .chain(std::iter::once(&**body))
+            .collect(),
+        Not(e) | Succ(e) | Pred(e) | Neg(e) | Fst(e) | Snd(e) | Hd(e) | Tl(e) => vec![e],
+        Field(e, _) | Fn(_, e) | Ann(e, _) => vec![e],
+        If(cond, yes, no) => vec![cond, yes, no],
+        Pair(e1, e2)
+        | Eq(e1, e2)
+        | Neq(e1, e2)
+        | Lt(e1, e2)
+        | Gt(e1, e2)
+        | Le(e1, e2)
+        | Ge(e1, e2)
+        | Cons(e1, e2)
+        | And(e1, e2)
+        | Or(e1, e2)
+        | Add(e1, e2)
+        | Sub(e1, e2)
+        | Mul(e1, e2)
+        | Div(e1, e2)
+        | Mod(e1, e2)
+        | Apply(e1, e2)
+        | Seq(e1, e2) => vec![e1, e2],
+        Tuple(es) => es.iter().collect(),
+        Record(fields) => fields.iter().map(|(_, e)| e).collect(),
+        Match(e, arms) => {
+            let mut es = vec![&**e];
+            for arm in arms {
+                es.extend(&arm.guard);
+                es.push(&arm.body);
+            }
+            es
+        }
+    }
+}
+
+/// Rebuild e with `f` applied to each of its subexpressions, in the same order
+/// as [`subexpressions`].
+pub fn map_subexpressions(
+    e: Expression,
+    mut f: impl FnMut(Expression) -> Expression,
+) -> Expression {
+    let mut f = |e: Rc<Expression>| Rc::new(f(Rc::unwrap_or_clone(e)));
+    match e {
+        True | False | Num(_) | Float(_) | Str(_) | Char(_) | Var(_) | Nil | Unit => e,
+        Let(definitions, body) => {
+            let definitions = definitions
+                .into_iter()
+                .map(|d| Definition {
+                    expr: f(d.expr),
+                    ..d
+                })
+                .collect();
+            Let(definitions, f(body))
+        }
+        Not(e) => Not(f(e)),
+        If(cond, yes, no) => If(f(cond), f(yes), f(no)),
+        Succ(e) => Succ(f(e)),
+        Pred(e) => Pred(f(e)),
+        Neg(e) => Neg(f(e)),
+        Fst(e) => Fst(f(e)),
+        Snd(e) => Snd(f(e)),
+        Hd(e) => Hd(f(e)),
+        Tl(e) => Tl(f(e)),
+        Tuple(es) => Tuple(
+            es.into_iter()
+                .map(|e| Rc::unwrap_or_clone(f(Rc::new(e))))
+                .collect(),
+        ),
+        Record(fields) => Record(
+            fields
+                .into_iter()
+                .map(|(name, e)| (name, Rc::unwrap_or_clone(f(Rc::new(e)))))
+                .collect(),
+        ),
+        Field(e, name) => Field(f(e), name),
+        Fn(v, body) => Fn(v, f(body)),
+        Ann(e, t) => Ann(f(e), t),
+        Match(e, arms) => {
+            let e = f(e);
+            let arms = arms
+                .into_iter()
+                .map(|arm| {
+                    let guard = arm.guard.map(|g| Rc::unwrap_or_clone(f(Rc::new(g))));
+                    MatchArm {
+                        pattern: arm.pattern,
+                        guard,
+                        body: Rc::unwrap_or_clone(f(Rc::new(arm.body))),
+                    }
+                })
+                .collect();
+            Match(e, arms)
+        }
+        Pair(e1, e2) => Pair(f(e1), f(e2)),
+        Eq(e1, e2) => Eq(f(e1), f(e2)),
+        Neq(e1, e2) => Neq(f(e1), f(e2)),
+        Lt(e1, e2) => Lt(f(e1), f(e2)),
+        Gt(e1, e2) => Gt(f(e1), f(e2)),
+        Le(e1, e2) => Le(f(e1), f(e2)),
+        Ge(e1, e2) => Ge(f(e1), f(e2)),
+        Cons(e1, e2) => Cons(f(e1), f(e2)),
+        And(e1, e2) => And(f(e1), f(e2)),
+        Or(e1, e2) => Or(f(e1), f(e2)),
+        Add(e1, e2) => Add(f(e1), f(e2)),
+        Sub(e1, e2) => Sub(f(e1), f(e2)),
+        Mul(e1, e2) => Mul(f(e1), f(e2)),
+        Div(e1, e2) => Div(f(e1), f(e2)),
+        Mod(e1, e2) => Mod(f(e1), f(e2)),
+        Apply(e1, e2) => Apply(f(e1), f(e2)),
+        Seq(e1, e2) => Seq(f(e1), f(e2)),
+    }
+}
+
+/// The variables a pattern binds, from left to right.
+pub fn pattern_variables(p: &Pattern) -> Vec<&Variable> {
+    match p {
+        Pattern::Var(v) => vec![v],
+        Pattern::Wildcard | Pattern::Literal(_) | Pattern::Nil => vec![],
+        Pattern::Cons(p1, p2) | Pattern::Pair(p1, p2) => {
+            let mut vars = pattern_variables(p1);
+            vars.extend(pattern_variables(p2));
+            vars
+        }
     }
 }
 
@@ -0,0 +1,783 @@
+//! infer.rs: Hindley-Milner type inference, by Algorithm W, which finds the
+//! most general type of an expression without any annotations.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::ptr;
+use std::rc::Rc;
+
+use crate::exprs::subexpressions;
+use crate::parser::{Definition, Expression, Spans, Type, Variable};
+
+/// Why an expression has no type.
+#[derive(Clone, PartialEq, Debug)]
+pub enum TypeError {
+    /// Two types which had to be the same weren't, e.g. in `1 + true`.
+    Mismatch { expected: Type, found: Type },
+    /// A type variable would have to contain itself, as in `fn x. x x`, where
+    /// `x`'s type would have to be a function taking `x`'s type.
+    OccursCheck(u32, Type),
+    /// A variable isn't bound.
+    Unbound(Variable),
+    /// The expression uses a construct inference can't handle yet.
+    Unsupported(Expression),
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeError::Mismatch { expected, found } => {
+                write!(f, "expected {} but found {}", expected, found)
+            }
+            TypeError::OccursCheck(var, t) => write!(
+                f,
+                "cannot construct the infinite type {} = {}",
+                Type::Var(*var),
+                t
+            ),
+            TypeError::Unbound(var) => write!(f, "unbound variable {}", var.ident),
+            TypeError::Unsupported(e) => write!(f, "cannot infer the type of {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+type Result<T> = std::result::Result<T, TypeError>;
+
+/// The most general type of a closed expression. Its type variables are
+/// numbered from 0 in the order they first appear, so `fn x. x` has the type
+/// `'a -> 'a`.
+pub fn infer(e: &Expression) -> Result<Type> {
+    infer_at(e).map_err(|(error, _)| error)
+}
+
+/// [`infer`], failing with the span of the subexpression at fault, given the
+/// spans [`crate::parser::parse_spanned`] found, e.g. that of `true` in
+/// `1 + true`.
+pub fn infer_spanned(
+    e: &Expression,
+    spans: &Spans,
+) -> std::result::Result<Type, (TypeError, Range<usize>)> {
+    fn locate(e: &Expression, spans: &Spans, target: &Expression) -> Option<Range<usize>> {
+        if ptr::eq(e, target) {
+            return Some(spans.span.clone());
+        }
+        subexpressions(e)
+            .into_iter()
+            .zip(&spans.children)
+            .find_map(|(e, spans)| locate(e, spans, target))
+    }
+    infer_at(e).map_err(|(error, at)| {
+        let span = locate(e, spans, at).unwrap_or_else(|| spans.span.clone());
+        (error, span)
+    })
+}
+
+/// The type of `e`, or why it has none and the subexpression at fault. Types
+/// in either are renumbered together.
+fn infer_at(e: &Expression) -> std::result::Result<Type, (TypeError, &Expression)> {
+    let mut inferencer = Inferencer::default();
+    let mut numbers = HashMap::new();
+    match inferencer.infer(&Env::new(), e) {
+        Ok((s, t)) => Ok(renumber(&apply(&s, &t), &mut numbers)),
+        Err(error) => {
+            let error = match error {
+                TypeError::Mismatch { expected, found } => TypeError::Mismatch {
+                    expected: renumber(&expected, &mut numbers),
+                    found: renumber(&found, &mut numbers),
+                },
+                TypeError::OccursCheck(a, t) => {
+                    let Type::Var(a) = renumber(&Type::Var(a), &mut numbers) else {
+                        unreachable!("variables are renumbered to variables")
+                    };
+                    TypeError::OccursCheck(a, renumber(&t, &mut numbers))
+                }
+                error => error,
+            };
+            Err((error, inferencer.culprit.unwrap_or(e)))
+        }
+    }
+}
+
+/// What each type variable has been found to be.
+pub type Substitution = HashMap<u32, Type>;
+
+/// The types of the variables in scope.
+type Env = HashMap<Rc<str>, Scheme>;
+
+/// The type of a variable, which is polymorphic in `vars`: each use of the
+/// variable can have them be different types. Only `let` makes variables
+/// polymorphic; function parameters have a single type.
+#[derive(Clone, Debug)]
+struct Scheme {
+    vars: Vec<u32>,
+    t: Type,
+}
+
+impl From<Type> for Scheme {
+    fn from(t: Type) -> Scheme {
+        Scheme { vars: vec![], t }
+    }
+}
+
+/// `t` with the variables `s` replaces replaced, throughout.
+pub fn apply(s: &Substitution, t: &Type) -> Type {
+    match t {
+        Type::Var(a) => match s.get(a) {
+            // The substitution may map to types which are themselves
+            // substituted, until it's composed.
+            Some(t) => apply(s, t),
+            None => t.clone(),
+        },
+        Type::Fn(arg, result) => Type::Fn(Box::new(apply(s, arg)), Box::new(apply(s, result))),
+        Type::Pair(first, second) => {
+            Type::Pair(Box::new(apply(s, first)), Box::new(apply(s, second)))
+        }
+        Type::List(element) => Type::List(Box::new(apply(s, element))),
+        t => t.clone(),
+    }
+}
+
+/// `env` with `s` applied to each scheme, whose own variables are left alone.
+fn apply_env(s: &Substitution, env: &Env) -> Env {
+    env.iter()
+        .map(|(var, scheme)| {
+            let mut s = s.clone();
+            s.retain(|a, _| !scheme.vars.contains(a));
+            let t = apply(&s, &scheme.t);
+            (
+                var.clone(),
+                Scheme {
+                    vars: scheme.vars.clone(),
+                    t,
+                },
+            )
+        })
+        .collect()
+}
+
+/// Add the type variables in `t` to `vars`.
+fn type_vars(t: &Type, vars: &mut HashSet<u32>) {
+    match t {
+        Type::Var(a) => {
+            vars.insert(*a);
+        }
+        Type::Fn(t1, t2) | Type::Pair(t1, t2) => {
+            type_vars(t1, vars);
+            type_vars(t2, vars);
+        }
+        Type::List(element) => type_vars(element, vars),
+        _ => {}
+    }
+}
+
+/// The scheme polymorphic in the variables of `t` which aren't free in `env`,
+/// so which nothing else yet constrains.
+fn generalize(env: &Env, t: Type) -> Scheme {
+    let mut fixed = HashSet::new();
+    for scheme in env.values() {
+        let mut vars = HashSet::new();
+        type_vars(&scheme.t, &mut vars);
+        fixed.extend(vars.into_iter().filter(|a| !scheme.vars.contains(a)));
+    }
+    let mut vars = HashSet::new();
+    type_vars(&t, &mut vars);
+    let mut vars: Vec<_> = vars.difference(&fixed).copied().collect();
+    vars.sort_unstable();
+    Scheme { vars, t }
+}
+
+/// The substitution which applies `s2`, then `s1`.
+fn compose(s1: &Substitution, s2: &Substitution) -> Substitution {
+    let mut composed: Substitution = s2.iter().map(|(a, t)| (*a, apply(s1, t))).collect();
+    for (a, t) in s1 {
+        composed.entry(*a).or_insert_with(|| t.clone());
+    }
+    composed
+}
+
+/// The most general substitution which makes `expected` and `found` the same.
+/// A variable can't be made a type containing it, which would be infinite.
+pub fn unify(expected: &Type, found: &Type) -> Result<Substitution> {
+    match (expected, found) {
+        (Type::Var(a), Type::Var(b)) if a == b => Ok(Substitution::new()),
+        (Type::Var(a), t) | (t, Type::Var(a)) if occurs(*a, t) => {
+            Err(TypeError::OccursCheck(*a, t.clone()))
+        }
+        (Type::Var(a), t) | (t, Type::Var(a)) => Ok(Substitution::from([(*a, t.clone())])),
+        (Type::Fn(a1, r1), Type::Fn(a2, r2)) | (Type::Pair(a1, r1), Type::Pair(a2, r2)) => {
+            let s1 = unify(a1, a2)?;
+            let s2 = unify(&apply(&s1, r1), &apply(&s1, r2))?;
+            Ok(compose(&s2, &s1))
+        }
+        (Type::List(t1), Type::List(t2)) => unify(t1, t2),
+        (t1, t2) if t1 == t2 => Ok(Substitution::new()),
+        _ => Err(TypeError::Mismatch {
+            expected: expected.clone(),
+            found: found.clone(),
+        }),
+    }
+}
+
+/// Whether the type variable `a` appears in `t`.
+fn occurs(a: u32, t: &Type) -> bool {
+    match t {
+        Type::Var(b) => a == *b,
+        Type::Fn(t1, t2) | Type::Pair(t1, t2) => occurs(a, t1) || occurs(a, t2),
+        Type::List(element) => occurs(a, element),
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct Inferencer<'a> {
+    /// The number of type variables made so far.
+    count: u32,
+    /// The subexpression inference failed at.
+    culprit: Option<&'a Expression>,
+}
+
+impl<'a> Inferencer<'a> {
+    fn fresh(&mut self) -> Type {
+        self.count += 1;
+        Type::Var(self.count - 1)
+    }
+
+    /// The type of a use of a variable: its scheme's type with fresh
+    /// variables for those it's polymorphic in.
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let s = scheme.vars.iter().map(|a| (*a, self.fresh())).collect();
+        apply(&s, &scheme.t)
+    }
+
+    /// [`unify`], blaming `at` if the types can't be.
+    fn unify(&mut self, expected: &Type, found: &Type, at: &'a Expression) -> Result<Substitution> {
+        let s = unify(expected, found);
+        if s.is_err() {
+            self.culprit = Some(at);
+        }
+        s
+    }
+
+    /// The substitution inferring the type of `e` in `env` required, and the
+    /// type, which it's yet to be applied to. Errors not blamed on anything
+    /// more specific are blamed on `e`.
+    fn infer(&mut self, env: &Env, e: &'a Expression) -> Result<(Substitution, Type)> {
+        let result = self.infer_node(env, e);
+        if result.is_err() && self.culprit.is_none() {
+            self.culprit = Some(e);
+        }
+        result
+    }
+
+    fn infer_node(&mut self, env: &Env, e: &'a Expression) -> Result<(Substitution, Type)> {
+        use Expression::*;
+        let none = Substitution::new();
+        match e {
+            Num(_) => Ok((none, Type::Int)),
+            Float(_) => Ok((none, Type::Float)),
+            True | False => Ok((none, Type::Bool)),
+            Str(_) => Ok((none, Type::String)),
+            Char(_) => Ok((none, Type::Char)),
+            Unit => Ok((none, Type::Unit)),
+            Var(var) => match env.get(&var.ident) {
+                Some(scheme) => Ok((none, self.instantiate(scheme))),
+                None => Err(TypeError::Unbound(var.clone())),
+            },
+            Fn(param, body) => {
+                let arg = self.fresh();
+                let mut inner = env.clone();
+                inner.insert(param.ident.clone(), arg.clone().into());
+                let (s, result) = self.infer(&inner, body)?;
+                Ok((
+                    s.clone(),
+                    Type::Fn(Box::new(apply(&s, &arg)), Box::new(result)),
+                ))
+            }
+            // The function must be one, then take the argument's type.
+            Apply(e1, e2) => {
+                let (s1, f) = self.infer(env, e1)?;
+                let (s2, arg) = self.infer(&apply_env(&s1, env), e2)?;
+                let (param, result) = (self.fresh(), self.fresh());
+                let function = Type::Fn(Box::new(param.clone()), Box::new(result.clone()));
+                let s3 = self.unify(&function, &apply(&s2, &f), e1)?;
+                let s4 = self.unify(&apply(&s3, &param), &apply(&s3, &arg), e2)?;
+                let s = [s4, s3, s2, s1]
+                    .iter()
+                    .fold(none, |s, next| compose(&s, next));
+                Ok((s.clone(), apply(&s, &result)))
+            }
+            Let(definitions, body) => self.infer_let(env, definitions, body),
+            If(cond, yes, no) => {
+                let (s, _) = self.operands(env, &[(cond, Type::Bool)], Type::Unit)?;
+                let (s, yes) = self.then_infer(s, env, yes)?;
+                let (s, no_type) = self.then_infer(s, env, no)?;
+                let s = compose(&self.unify(&yes, &no_type, no)?, &s);
+                Ok((s.clone(), apply(&s, &yes)))
+            }
+            Not(e) => self.operands(env, &[(e, Type::Bool)], Type::Bool),
+            And(e1, e2) | Or(e1, e2) => {
+                self.operands(env, &[(e1, Type::Bool), (e2, Type::Bool)], Type::Bool)
+            }
+            Succ(e) | Pred(e) => self.operands(env, &[(e, Type::Int)], Type::Int),
+            Neg(e) => {
+                let (s, t) = self.infer(env, e)?;
+                self.numeric(s, t, e)
+            }
+            // Arithmetic is on two integers or two floats.
+            Add(e1, e2) | Sub(e1, e2) | Mul(e1, e2) | Div(e1, e2) | Mod(e1, e2) => {
+                let (s, left) = self.infer(env, e1)?;
+                let (s, right) = self.then_infer(s, env, e2)?;
+                let s = compose(&self.unify(&left, &right, e2)?, &s);
+                self.numeric(s, left, e1)
+            }
+            // Any two values of the same type can be compared.
+            Eq(e1, e2) | Neq(e1, e2) | Lt(e1, e2) | Gt(e1, e2) | Le(e1, e2) | Ge(e1, e2) => {
+                let (s, left) = self.infer(env, e1)?;
+                let (s, right) = self.then_infer(s, env, e2)?;
+                let s = compose(&self.unify(&left, &right, e2)?, &s);
+                Ok((s, Type::Bool))
+            }
+            Pair(e1, e2) => {
+                let (s, first) = self.infer(env, e1)?;
+                let (s, second) = self.then_infer(s, env, e2)?;
+                Ok((
+                    s.clone(),
+                    Type::Pair(Box::new(apply(&s, &first)), Box::new(second)),
+                ))
+            }
+            Fst(pair) | Snd(pair) => {
+                let (first, second) = (self.fresh(), self.fresh());
+                let t = Type::Pair(Box::new(first.clone()), Box::new(second.clone()));
+                let (s, _) = self.operands(env, &[(pair, t)], Type::Unit)?;
+                let component = if let Fst(_) = e { first } else { second };
+                Ok((s.clone(), apply(&s, &component)))
+            }
+            Nil => Ok((none, Type::List(Box::new(self.fresh())))),
+            // The tail must be a list of the head's type.
+            Cons(head, tail) => {
+                let (s, t) = self.infer(env, head)?;
+                let list = Type::List(Box::new(t));
+                let (s, found) = self.then_infer(s, env, tail)?;
+                let s = compose(&self.unify(&apply(&s, &list), &found, tail)?, &s);
+                Ok((s.clone(), apply(&s, &list)))
+            }
+            Hd(list) | Tl(list) => {
+                let element = self.fresh();
+                let t = Type::List(Box::new(element.clone()));
+                let result = if let Hd(_) = e { element } else { t.clone() };
+                self.operands(env, &[(list, t)], result)
+            }
+            Seq(first, second) => {
+                let (s, _) = self.infer(env, first)?;
+                self.then_infer(s, env, second)
+            }
+            // The annotation must be an instance of the type inferred, which
+            // it then replaces.
+            Ann(e, t) => self.operands(env, &[(e, t.clone())], t.clone()),
+            _ => Err(TypeError::Unsupported(e.clone())),
+        }
+    }
+
+    /// Infer the type of `e` after the substitution `s`, returning both
+    /// substitutions composed, and the type with them applied.
+    fn then_infer(
+        &mut self,
+        s: Substitution,
+        env: &Env,
+        e: &'a Expression,
+    ) -> Result<(Substitution, Type)> {
+        let (s2, t) = self.infer(&apply_env(&s, env), e)?;
+        let s = compose(&s2, &s);
+        let t = apply(&s, &t);
+        Ok((s, t))
+    }
+
+    /// Infer the types of `operands`, which must be those given, in order, and
+    /// give the `result` type.
+    fn operands(
+        &mut self,
+        env: &Env,
+        operands: &[(&'a Expression, Type)],
+        result: Type,
+    ) -> Result<(Substitution, Type)> {
+        let mut s = Substitution::new();
+        for (e, expected) in operands {
+            let (next, found) = self.then_infer(s, env, e)?;
+            s = compose(&self.unify(&apply(&next, expected), &found, e)?, &next);
+        }
+        Ok((s.clone(), apply(&s, &result)))
+    }
+
+    /// Require `t` to be a number. One not yet known is taken to be an integer.
+    fn numeric(
+        &mut self,
+        s: Substitution,
+        t: Type,
+        at: &'a Expression,
+    ) -> Result<(Substitution, Type)> {
+        match apply(&s, &t) {
+            t @ (Type::Int | Type::Float) => Ok((s, t)),
+            t => {
+                let s = compose(&self.unify(&Type::Int, &t, at)?, &s);
+                Ok((s, Type::Int))
+            }
+        }
+    }
+
+    /// A plain `let`'s definitions are typed in the outer environment, but
+    /// those of a `let rec` with each other in scope, at the types they're
+    /// found to have. Only in the body are they generalized, so a recursive
+    /// function isn't polymorphic within its own definition.
+    fn infer_let(
+        &mut self,
+        env: &Env,
+        definitions: &'a [Definition],
+        body: &'a Expression,
+    ) -> Result<(Substitution, Type)> {
+        let recursive = definitions.iter().any(|d| d.recursive);
+        let vars: Vec<_> = definitions.iter().map(|_| self.fresh()).collect();
+        let mut group = env.clone();
+        for (d, var) in definitions.iter().zip(&vars) {
+            group.insert(d.var.ident.clone(), var.clone().into());
+        }
+        let scope = if recursive { &group } else { env };
+        let mut s = Substitution::new();
+        for (d, var) in definitions.iter().zip(&vars) {
+            let (next, t) = self.then_infer(s, scope, &d.expr)?;
+            s = compose(&self.unify(&apply(&next, var), &t, &d.expr)?, &next);
+        }
+        let outer = apply_env(&s, env);
+        let mut inner = outer.clone();
+        for (d, var) in definitions.iter().zip(&vars) {
+            inner.insert(d.var.ident.clone(), generalize(&outer, apply(&s, var)));
+        }
+        let (s2, t) = self.infer(&inner, body)?;
+        Ok((compose(&s2, &s), t))
+    }
+}
+
+/// `t` with its type variables renumbered in the order they appear, after
+/// those already in `numbers`.
+fn renumber(t: &Type, numbers: &mut HashMap<u32, u32>) -> Type {
+    match t {
+        Type::Var(a) => {
+            let next = numbers.len() as u32;
+            Type::Var(*numbers.entry(*a).or_insert(next))
+        }
+        Type::Fn(arg, result) => {
+            let arg = renumber(arg, numbers);
+            Type::Fn(Box::new(arg), Box::new(renumber(result, numbers)))
+        }
+        Type::Pair(first, second) => {
+            let first = renumber(first, numbers);
+            Type::Pair(Box::new(first), Box::new(renumber(second, numbers)))
+        }
+        Type::List(element) => Type::List(Box::new(renumber(element, numbers))),
+        t => t.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, parse_spanned};
+
+    fn type_of(source: &str) -> Result<String> {
+        infer(&parse(source).unwrap()).map(|t| t.to_string())
+    }
+
+    #[test]
+    fn test_infer() {
+        assert_eq!(type_of("fn x. x"), Ok("'a -> 'a".to_string()));
+        assert_eq!(type_of("fn x. (x + 1)"), Ok("Int -> Int".to_string()));
+        assert_eq!(type_of("1 + 2 * 3"), Ok("Int".to_string()));
+        assert_eq!(type_of("1.5 * 2.0"), Ok("Float".to_string()));
+        assert_eq!(type_of("fn x. (x * 2.0)"), Ok("Float -> Float".to_string()));
+        assert_eq!(type_of("fn x. -x"), Ok("Int -> Int".to_string()));
+        assert_eq!(
+            type_of("\"a\" < \"b\" or not(true)"),
+            Ok("Bool".to_string())
+        );
+        assert_eq!(
+            type_of("fn f. fn x. f (f x)"),
+            Ok("('a -> 'a) -> 'a -> 'a".to_string())
+        );
+        assert_eq!(
+            type_of("fn f. fn g. fn x. f (g x)"),
+            Ok("('a -> 'b) -> ('c -> 'a) -> 'c -> 'b".to_string())
+        );
+        assert_eq!(type_of("fn x. fn y. x"), Ok("'a -> 'b -> 'a".to_string()));
+        assert_eq!(
+            type_of("fn p. <snd(p), fst(p)>"),
+            Ok("'a * 'b -> 'b * 'a".to_string())
+        );
+        assert_eq!(
+            type_of("fn x. if x then 'a' else 'b'"),
+            Ok("Bool -> Char".to_string())
+        );
+        assert_eq!(type_of("let x = 1 in x + x"), Ok("Int".to_string()));
+        assert_eq!(
+            type_of("let id = fn x. x in <id 1, id true>"),
+            Ok("Int * Bool".to_string())
+        );
+        assert_eq!(
+            type_of("let id = fn x. x in id id"),
+            Ok("'a -> 'a".to_string())
+        );
+        assert_eq!(
+            type_of("let pair = fn x. <x, x> in <pair 'a', pair ()>"),
+            Ok("(Char * Char) * Unit * Unit".to_string())
+        );
+        // Variables the environment constrains aren't generalized.
+        assert_eq!(
+            type_of("fn y. let f = fn x. y in <f 1, f true>"),
+            Ok("'a -> 'a * 'a".to_string())
+        );
+        assert_eq!(
+            type_of("fn y. let f = fn x. (x + y) in f"),
+            Ok("Int -> Int -> Int".to_string())
+        );
+        assert_eq!(
+            type_of("let rec map = fn f. fn p. <f (fst(p)), f (snd(p))> in map (fn x. (x < 1))"),
+            Ok("Int * Int -> Bool * Bool".to_string())
+        );
+        assert_eq!(type_of("nil"), Ok("'a list".to_string()));
+        assert_eq!(type_of("[1, 2, 3]"), Ok("Int list".to_string()));
+        assert_eq!(type_of("[[true], nil]"), Ok("Bool list list".to_string()));
+        assert_eq!(
+            type_of("fn x. fn l. (x :: l)"),
+            Ok("'a -> 'a list -> 'a list".to_string())
+        );
+        assert_eq!(type_of("fn l. hd(l)"), Ok("'a list -> 'a".to_string()));
+        assert_eq!(
+            type_of("fn l. (hd(tl(l)) + 1)"),
+            Ok("Int list -> Int".to_string())
+        );
+        assert_eq!(
+            type_of("let rec length = fn l. if l == nil then 0 else 1 + length (tl(l)) in length"),
+            Ok("'a list -> Int".to_string())
+        );
+        assert_eq!(
+            type_of("let l = nil in <1 :: l, 'c' :: l>"),
+            Ok("Int list * Char list".to_string())
+        );
+        assert_eq!(
+            type_of("(fn x. x : Int -> Int)"),
+            Ok("Int -> Int".to_string())
+        );
+        assert_eq!(type_of("(fn x. x : Int -> Int) 2"), Ok("Int".to_string()));
+        // Annotating a use makes just that use less polymorphic.
+        assert_eq!(
+            type_of("let id = fn x. x in <(id : Bool -> Bool), id>"),
+            Ok("(Bool -> Bool) * ('a -> 'a)".to_string())
+        );
+        assert_eq!(
+            type_of("fn f. fn x. (f x : Char)"),
+            Ok("('a -> Char) -> 'a -> Char".to_string())
+        );
+        assert_eq!(
+            type_of("fn p. (p : Int * Bool)"),
+            Ok("Int * Bool -> Int * Bool".to_string())
+        );
+        assert_eq!(
+            type_of("let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact"),
+            Ok("Int -> Int".to_string())
+        );
+        assert_eq!(
+            type_of(
+                "let rec even = fn n. if n < 1 then true else odd (n - 1) \
+                 and odd = fn n. if n < 1 then false else even (n - 1) in even"
+            ),
+            Ok("Int -> Bool".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unify() {
+        let var = |a| Type::Var(a);
+        let function = |arg, result| Type::Fn(Box::new(arg), Box::new(result));
+        let s = unify(&function(var(0), Type::Int), &function(Type::Bool, var(1))).unwrap();
+        assert_eq!(s, Substitution::from([(0, Type::Bool), (1, Type::Int)]));
+        let s = unify(&function(var(0), var(0)), &function(var(1), Type::Char)).unwrap();
+        assert_eq!(apply(&s, &var(0)), Type::Char);
+        assert_eq!(apply(&s, &var(1)), Type::Char);
+        assert_eq!(unify(&var(2), &var(2)), Ok(Substitution::new()));
+        assert_eq!(
+            unify(&var(0), &function(var(0), var(1))),
+            Err(TypeError::OccursCheck(0, function(var(0), var(1))))
+        );
+        assert_eq!(
+            unify(&Type::List(Box::new(var(3))), &var(3)),
+            Err(TypeError::OccursCheck(3, Type::List(Box::new(var(3)))))
+        );
+    }
+
+    /// The message inference fails with, and the source it blames.
+    fn error(source: &str) -> (String, &str) {
+        let (e, spans) = parse_spanned(source).unwrap();
+        let (error, span) = infer_spanned(&e, &spans).unwrap_err();
+        (error.to_string(), &source[span])
+    }
+
+    #[test]
+    fn test_type_error_messages() {
+        assert_eq!(
+            error("1 + true"),
+            ("expected Int but found Bool".to_string(), "true")
+        );
+        assert_eq!(
+            error("if 1 then 2 else 3"),
+            ("expected Bool but found Int".to_string(), "1")
+        );
+        assert_eq!(
+            error("fn f. <f 1, f 'c'>"),
+            ("expected Int but found Char".to_string(), "'c'")
+        );
+        assert_eq!(
+            error("(fn x. (x + 1)) 2 3"),
+            (
+                "expected 'a -> 'b but found Int".to_string(),
+                "(fn x. (x + 1)) 2"
+            )
+        );
+        assert_eq!(
+            error("let f = fn x. x in f 1 + f ()"),
+            ("expected Int but found Unit".to_string(), "f ()")
+        );
+        assert_eq!(
+            error("fn x. x x"),
+            (
+                "cannot construct the infinite type 'a = 'a -> 'b".to_string(),
+                "x"
+            )
+        );
+        assert_eq!(
+            error("let y = 1 in y + z"),
+            ("unbound variable z".to_string(), "z")
+        );
+        assert_eq!(
+            error("let p = <1, 2> in fst(p) + snd(p) == true"),
+            ("expected Int but found Bool".to_string(), "true")
+        );
+    }
+
+    #[test]
+    fn test_annotation_errors() {
+        assert_eq!(
+            error("(1 : Bool)"),
+            ("expected Bool but found Int".to_string(), "1")
+        );
+        assert_eq!(
+            error("((fn x. (x + 1)) : Float -> Float)"),
+            (
+                "expected Float but found Int".to_string(),
+                "(fn x. (x + 1))"
+            )
+        );
+        // The annotation can't be more general than the type inferred.
+        assert_eq!(
+            error("let f = (fn x. x : Int -> Int) in f true"),
+            ("expected Int but found Bool".to_string(), "true")
+        );
+    }
+
+    #[test]
+    fn test_list_errors() {
+        assert_eq!(
+            error("1 :: true :: nil"),
+            ("expected Int but found Bool".to_string(), "true :: nil")
+        );
+        assert_eq!(
+            error("[1, 2.0]"),
+            ("expected Int but found Float".to_string(), "2.0]")
+        );
+        assert_eq!(
+            error("1 :: 2"),
+            ("expected Int list but found Int".to_string(), "2")
+        );
+        assert_eq!(
+            error("hd(<1, 2>)"),
+            ("expected 'a list but found Int * Int".to_string(), "<1, 2>")
+        );
+        assert_eq!(
+            error("fn l. (l :: l)"),
+            (
+                "cannot construct the infinite type 'a = 'a list".to_string(),
+                "l"
+            )
+        );
+        // A parameter is a list of one type throughout.
+        assert_eq!(
+            error("fn l. <1 :: l, true :: l>"),
+            ("expected Bool but found Int".to_string(), "l")
+        );
+    }
+
+    #[test]
+    fn test_infer_errors() {
+        assert!(matches!(
+            infer(&parse("fn x. x x").unwrap()),
+            Err(TypeError::OccursCheck(_, _))
+        ));
+        assert!(matches!(
+            infer(&parse("fn f. f f 1").unwrap()),
+            Err(TypeError::OccursCheck(_, _))
+        ));
+        // Function parameters have one type, unlike let-bound variables.
+        assert_eq!(
+            infer(&parse("(fn id. <id 1, id true>) (fn x. x)").unwrap()),
+            Err(TypeError::Mismatch {
+                expected: Type::Int,
+                found: Type::Bool
+            })
+        );
+        // Nor are recursive functions polymorphic in their own definitions.
+        assert_eq!(
+            infer(&parse("let rec f = fn x. if f true then x else x + 1 in f").unwrap()),
+            Err(TypeError::Mismatch {
+                expected: Type::Bool,
+                found: Type::Int
+            })
+        );
+        assert_eq!(
+            infer(&parse("if 1 then 2 else 3").unwrap()),
+            Err(TypeError::Mismatch {
+                expected: Type::Bool,
+                found: Type::Int
+            })
+        );
+        assert_eq!(
+            infer(&parse("if true then 2 else ()").unwrap()),
+            Err(TypeError::Mismatch {
+                expected: Type::Int,
+                found: Type::Unit
+            })
+        );
+        assert_eq!(
+            infer(&parse("(fn x. (x + 1)) 'a'").unwrap()),
+            Err(TypeError::Mismatch {
+                expected: Type::Int,
+                found: Type::Char
+            })
+        );
+        assert!(matches!(
+            infer(&parse("1 2").unwrap()),
+            Err(TypeError::Mismatch {
+                expected: Type::Fn(_, _),
+                found: Type::Int
+            })
+        ));
+        assert_eq!(
+            infer(&parse("x").unwrap()),
+            Err(TypeError::Unbound(Variable { ident: "x".into() }))
+        );
+        // Plain lets' definitions can't refer to themselves.
+        assert_eq!(
+            infer(&parse("let f = fn n. f n in f").unwrap()),
+            Err(TypeError::Unbound(Variable { ident: "f".into() }))
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! intern.rs: Share the storage of repeated identifiers, so that each name is
+//! allocated once and copies of it are cheap.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+thread_local! {
+    /// Every name interned so far on this thread. Names are never freed.
+    static NAMES: RefCell<HashSet<Rc<str>>> = RefCell::new(HashSet::new());
+}
+
+/// The shared copy of `name`, allocating it if this is its first use.
+pub fn intern(name: &str) -> Rc<str> {
+    NAMES.with(|names| {
+        let mut names = names.borrow_mut();
+        match names.get(name) {
+            Some(interned) => interned.clone(),
+            None => {
+                let interned: Rc<str> = Rc::from(name);
+                names.insert(interned.clone());
+                interned
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, Expression};
+
+    #[test]
+    fn test_intern() {
+        let x = intern("x");
+        assert!(Rc::ptr_eq(&x, &intern("x")));
+        assert!(!Rc::ptr_eq(&x, &intern("y")));
+        assert_eq!(&*x, "x");
+    }
+
+    #[test]
+    fn test_parses_share_names() {
+        let ident = |source: &str| match parse(source).unwrap() {
+            Expression::Var(v) => v.ident,
+            e => panic!("not a variable: {:?}", e),
+        };
+        assert!(Rc::ptr_eq(&ident("x"), &ident("x")));
+        assert!(Rc::ptr_eq(&ident("x"), &ident(" x ")));
+    }
+}
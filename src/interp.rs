@@ -0,0 +1,1220 @@
+//! interp.rs: An interpreter which evaluates MiniML expressions to values,
+//! rather than rewriting them to other expressions as eval.rs does.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+use crate::parser::{Definition, Expression, MatchArm, Pattern, Variable};
+use crate::value::{self, Data, View};
+
+/// The result of evaluating an expression.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Char(char),
+    Unit,
+    Nil,
+    Cons(Rc<Value>, Rc<Value>),
+    Pair(Rc<Value>, Rc<Value>),
+    Tuple(Vec<Value>),
+    /// Named fields in the order they were written.
+    Record(Vec<(String, Value)>),
+    /// A function, along with the environment it was defined in.
+    Closure {
+        param: Variable,
+        body: Rc<Expression>,
+        env: Env,
+    },
+    /// An expression whose evaluation has been put off until its value is
+    /// needed, by [`eval_lazy`].
+    Thunk(Rc<Thunk>),
+}
+
+/// A suspended evaluation, which is performed at most once. Forcing it again
+/// gives the value it was forced to before.
+#[derive(Debug)]
+pub struct Thunk(RefCell<ThunkState>);
+
+#[derive(Clone, Debug)]
+enum ThunkState {
+    Delayed(Rc<Expression>, Env),
+    Forced(Value),
+}
+
+/// Values print as the MiniML literals they'd be written as, lists in
+/// brackets, and functions as `<fun>`.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        value::fmt(&self.clone().forced(), f)
+    }
+}
+
+impl Data for Value {
+    fn view(&self) -> View<'_, Value> {
+        match self {
+            Value::Int(n) => View::Int(*n),
+            Value::Float(x) => View::Float(*x),
+            Value::Bool(b) => View::Bool(*b),
+            Value::Str(s) => View::Str(s),
+            Value::Char(c) => View::Char(*c),
+            Value::Unit => View::Unit,
+            Value::Nil => View::Nil,
+            Value::Cons(head, tail) => View::Cons(head, tail),
+            Value::Pair(first, second) => View::Pair(first, second),
+            Value::Tuple(components) => View::Tuple(components),
+            Value::Record(fields) => View::Record(fields),
+            Value::Closure { .. } => View::Function,
+            Value::Thunk(_) => View::Delayed,
+        }
+    }
+
+    fn from_int(n: i64) -> Value {
+        Value::Int(n)
+    }
+
+    fn from_float(x: f64) -> Value {
+        Value::Float(x)
+    }
+
+    fn from_bool(b: bool) -> Value {
+        Value::Bool(b)
+    }
+
+    fn from_pair(first: Rc<Value>, second: Rc<Value>) -> Value {
+        Value::Pair(first, second)
+    }
+
+    fn from_cons(head: Rc<Value>, tail: Rc<Value>) -> Value {
+        Value::Cons(head, tail)
+    }
+
+    /// The value a thunk has been forced to, or the value itself if it isn't
+    /// a thunk. Thunks which haven't been forced are left as they are.
+    fn forced(self) -> Value {
+        match &self {
+            Value::Thunk(thunk) => match &*thunk.0.borrow() {
+                ThunkState::Forced(v) => v.clone().forced(),
+                ThunkState::Delayed(_, _) => self.clone(),
+            },
+            _ => self,
+        }
+    }
+}
+
+/// Thunks are only equal if they're the same one, as their values may not
+/// be known yet.
+impl PartialEq for Thunk {
+    fn eq(&self, other: &Thunk) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+/// The variables in scope and their values. Extending an environment leaves
+/// the original as it was, so closures can share what they capture.
+#[derive(Clone, Default, Debug)]
+pub struct Env(Option<Rc<Binding>>);
+
+#[derive(Debug)]
+struct Binding {
+    frame: Frame,
+    next: Env,
+}
+
+#[derive(Debug)]
+enum Frame {
+    Value(Variable, Value),
+    /// Functions defined together by `let rec`. Each is made a closure over
+    /// the environment starting at this frame as it's looked up, so they can
+    /// refer to themselves and each other without a reference cycle.
+    Rec(Vec<RecFunction>),
+}
+
+#[derive(Debug)]
+struct RecFunction {
+    name: Variable,
+    param: Variable,
+    body: Rc<Expression>,
+}
+
+/// Environments are only equal if they're the same one, e.g. captured by
+/// the same closure.
+impl PartialEq for Env {
+    fn eq(&self, other: &Env) -> bool {
+        match (&self.0, &other.0) {
+            (Some(b1), Some(b2)) => Rc::ptr_eq(b1, b2),
+            (b1, b2) => b1.is_none() && b2.is_none(),
+        }
+    }
+}
+
+impl Env {
+    pub fn empty() -> Env {
+        Env(None)
+    }
+
+    /// The environment with `var` bound to `value`, hiding any outer binding of `var`.
+    pub fn extend(&self, var: Variable, value: Value) -> Env {
+        self.push(Frame::Value(var, value))
+    }
+
+    /// The environment with the functions among `definitions`, a `let rec`
+    /// group, bound so they can refer to themselves and each other. Its other
+    /// definitions are left to be bound in turn.
+    pub(crate) fn extend_rec(&self, definitions: &[Definition]) -> Env {
+        let functions = definitions
+            .iter()
+            .filter_map(|d| match &*d.expr {
+                Expression::Fn(param, body) => Some(RecFunction {
+                    name: d.var.clone(),
+                    param: param.clone(),
+                    body: body.clone(),
+                }),
+                _ => None,
+            })
+            .collect();
+        self.push(Frame::Rec(functions))
+    }
+
+    fn push(&self, frame: Frame) -> Env {
+        Env(Some(Rc::new(Binding {
+            frame,
+            next: self.clone(),
+        })))
+    }
+
+    /// The value of the innermost binding of `var`.
+    pub fn lookup(&self, var: &Variable) -> Option<Value> {
+        let mut env = self;
+        while let Some(binding) = &env.0 {
+            match &binding.frame {
+                Frame::Value(bound, value) if bound == var => return Some(value.clone()),
+                Frame::Rec(functions) => {
+                    if let Some(f) = functions.iter().find(|f| f.name == *var) {
+                        return Some(Value::Closure {
+                            param: f.param.clone(),
+                            body: f.body.clone(),
+                            env: env.clone(),
+                        });
+                    }
+                }
+                Frame::Value(_, _) => {}
+            }
+            env = &binding.next;
+        }
+        None
+    }
+}
+
+/// An error raised while evaluating an expression.
+#[derive(Clone, PartialEq, Debug)]
+pub enum EvalError {
+    /// An operation was applied to a value of the wrong kind, e.g. `not(1)`.
+    TypeMismatch {
+        expected: &'static str,
+        found: Value,
+    },
+    /// A variable isn't bound in the environment.
+    Unbound(Variable),
+    /// Integer arithmetic overflowed.
+    Overflow,
+    /// An integer was divided by zero.
+    DivisionByZero,
+    /// Evaluation took more steps than it was allowed.
+    OutOfFuel,
+    /// No arm of a `match` matched the value.
+    NoMatch(Value),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::TypeMismatch { expected, found } => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            EvalError::Unbound(var) => write!(f, "unbound variable {}", var.ident),
+            EvalError::Overflow => f.write_str("integer overflow"),
+            EvalError::DivisionByZero => f.write_str("division by zero"),
+            EvalError::OutOfFuel => f.write_str("ran out of fuel"),
+            EvalError::NoMatch(v) => write!(f, "no arm matched {}", v),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+impl From<value::Error<Value>> for EvalError {
+    fn from(error: value::Error<Value>) -> EvalError {
+        match error {
+            value::Error::TypeMismatch { expected, found } => {
+                EvalError::TypeMismatch { expected, found }
+            }
+            value::Error::Overflow => EvalError::Overflow,
+            value::Error::DivisionByZero => EvalError::DivisionByZero,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, EvalError>;
+
+/// Evaluate a closed expression to a value.
+pub fn eval(e: &Expression) -> Result<Value> {
+    eval_in(e, &Env::empty())
+}
+
+/// Evaluate an expression whose free variables are bound in `env`.
+pub fn eval_in(e: &Expression, env: &Env) -> Result<Value> {
+    Interpreter::default().eval(e, env)
+}
+
+/// Evaluate a closed expression, giving up with [`EvalError::OutOfFuel`]
+/// after evaluating `fuel` subexpressions, e.g. if it doesn't terminate.
+pub fn eval_with_fuel(e: &Expression, fuel: u64) -> Result<Value> {
+    let interpreter = Interpreter {
+        fuel: Cell::new(Some(fuel)),
+        ..Interpreter::default()
+    };
+    interpreter.eval(e, &Env::empty())
+}
+
+/// Evaluate a closed expression, recording each primitive operation
+/// performed along the way, in order.
+pub fn eval_traced(e: &Expression) -> (Result<Value>, Vec<TraceEvent>) {
+    let interpreter = Interpreter {
+        trace: Some(RefCell::default()),
+        ..Interpreter::default()
+    };
+    let result = interpreter.eval(e, &Env::empty());
+    (result, interpreter.trace.unwrap().into_inner())
+}
+
+/// Evaluate a closed expression by need: function arguments, `let`
+/// definitions and the components of pairs and lists are only evaluated if
+/// their values are used, and then only once. The result is evaluated as far
+/// as its outermost constructor, so its components may still be thunks.
+pub fn eval_lazy(e: &Expression) -> Result<Value> {
+    let interpreter = Interpreter {
+        lazy: true,
+        ..Interpreter::default()
+    };
+    interpreter.eval(e, &Env::empty())
+}
+
+/// The operations built into MiniML, whose operands are all evaluated.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Primitive {
+    Not,
+    Succ,
+    Pred,
+    Neg,
+    Fst,
+    Snd,
+    Hd,
+    Tl,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Neq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Pair,
+    Cons,
+}
+
+impl Primitive {
+    /// The operation an expression performs, and its operands, if it's a primitive.
+    pub(crate) fn of(e: &Expression) -> Option<(Primitive, Vec<&Rc<Expression>>)> {
+        use Expression as E;
+        Some(match e {
+            E::Not(e) => (Primitive::Not, vec![e]),
+            E::Succ(e) => (Primitive::Succ, vec![e]),
+            E::Pred(e) => (Primitive::Pred, vec![e]),
+            E::Neg(e) => (Primitive::Neg, vec![e]),
+            E::Fst(e) => (Primitive::Fst, vec![e]),
+            E::Snd(e) => (Primitive::Snd, vec![e]),
+            E::Hd(e) => (Primitive::Hd, vec![e]),
+            E::Tl(e) => (Primitive::Tl, vec![e]),
+            E::Add(e1, e2) => (Primitive::Add, vec![e1, e2]),
+            E::Sub(e1, e2) => (Primitive::Sub, vec![e1, e2]),
+            E::Mul(e1, e2) => (Primitive::Mul, vec![e1, e2]),
+            E::Div(e1, e2) => (Primitive::Div, vec![e1, e2]),
+            E::Mod(e1, e2) => (Primitive::Mod, vec![e1, e2]),
+            E::Eq(e1, e2) => (Primitive::Eq, vec![e1, e2]),
+            E::Neq(e1, e2) => (Primitive::Neq, vec![e1, e2]),
+            E::Lt(e1, e2) => (Primitive::Lt, vec![e1, e2]),
+            E::Gt(e1, e2) => (Primitive::Gt, vec![e1, e2]),
+            E::Le(e1, e2) => (Primitive::Le, vec![e1, e2]),
+            E::Ge(e1, e2) => (Primitive::Ge, vec![e1, e2]),
+            E::Pair(e1, e2) => (Primitive::Pair, vec![e1, e2]),
+            E::Cons(e1, e2) => (Primitive::Cons, vec![e1, e2]),
+            _ => return None,
+        })
+    }
+}
+
+/// A primitive operation performed during evaluation.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TraceEvent {
+    pub op: Primitive,
+    pub operands: Vec<Value>,
+    pub result: Value,
+}
+
+/// The state of an evaluation in progress.
+#[derive(Default)]
+struct Interpreter {
+    /// How many more subexpressions may be evaluated, if that's limited.
+    fuel: Cell<Option<u64>>,
+    /// The primitive operations performed so far, if they're being recorded.
+    trace: Option<RefCell<Vec<TraceEvent>>>,
+    /// Whether evaluation is delayed until values are needed.
+    lazy: bool,
+}
+
+impl Interpreter {
+    fn eval(&self, e: &Expression, env: &Env) -> Result<Value> {
+        if let Some(fuel) = self.fuel.get() {
+            if fuel == 0 {
+                return Err(EvalError::OutOfFuel);
+            }
+            self.fuel.set(Some(fuel - 1));
+        }
+        // Evaluation recurses as deeply as the program does, so grow the
+        // stack on demand.
+        stacker::maybe_grow(256 * 1024, 4 * 1024 * 1024, || self.eval_node(e, env))
+    }
+
+    /// The value of `e` in `env`, or a thunk to compute it later if
+    /// evaluation is lazy.
+    fn delay(&self, e: &Rc<Expression>, env: &Env) -> Result<Value> {
+        if !self.lazy {
+            return self.eval(e, env);
+        }
+        Ok(Value::Thunk(Rc::new(Thunk(RefCell::new(
+            ThunkState::Delayed(e.clone(), env.clone()),
+        )))))
+    }
+
+    /// Evaluate a thunk, if `v` is one and it hasn't been already.
+    fn force(&self, v: Value) -> Result<Value> {
+        let Value::Thunk(thunk) = v else {
+            return Ok(v);
+        };
+        let state = thunk.0.borrow().clone();
+        match state {
+            ThunkState::Forced(v) => Ok(v),
+            ThunkState::Delayed(e, env) => {
+                let v = self.eval(&e, &env)?;
+                *thunk.0.borrow_mut() = ThunkState::Forced(v.clone());
+                Ok(v)
+            }
+        }
+    }
+
+    fn eval_node(&self, e: &Expression, env: &Env) -> Result<Value> {
+        use Expression::*;
+        let eval = |e| self.eval(e, env);
+        let delay = |e| self.delay(e, env);
+        match e {
+            Pair(e1, e2) if self.lazy => {
+                return Ok(Value::Pair(Rc::new(delay(e1)?), Rc::new(delay(e2)?)))
+            }
+            Cons(e1, e2) if self.lazy => {
+                return Ok(Value::Cons(Rc::new(delay(e1)?), Rc::new(delay(e2)?)))
+            }
+            _ => {}
+        }
+        if let Some((op, operands)) = Primitive::of(e) {
+            let operands = operands
+                .into_iter()
+                .map(|e| eval(e))
+                .collect::<Result<Vec<_>>>()?;
+            // A component taken from a pair or list may not be evaluated yet.
+            let result = self.force(self.apply(op, &operands)?)?;
+            if let Some(trace) = &self.trace {
+                trace.borrow_mut().push(TraceEvent {
+                    op,
+                    operands,
+                    result: result.clone(),
+                });
+            }
+            return Ok(result);
+        }
+        match e {
+            Num(n) => Ok(Value::Int(*n)),
+            Float(f) => Ok(Value::Float(*f)),
+            True => Ok(Value::Bool(true)),
+            False => Ok(Value::Bool(false)),
+            Str(s) => Ok(Value::Str(s.clone())),
+            Char(c) => Ok(Value::Char(*c)),
+            Unit => Ok(Value::Unit),
+            Nil => Ok(Value::Nil),
+            Var(var) => self.force(
+                env.lookup(var)
+                    .ok_or_else(|| EvalError::Unbound(var.clone()))?,
+            ),
+            // The definitions are evaluated in the outer environment, so they
+            // can't refer to each other.
+            Let(definitions, body) if !definitions.iter().any(|d| d.recursive) => {
+                let mut inner = env.clone();
+                for definition in definitions {
+                    inner = inner.extend(definition.var.clone(), delay(&definition.expr)?);
+                }
+                self.eval(body, &inner)
+            }
+            // The functions are bound first, then any other definitions are
+            // evaluated with them in scope.
+            Let(definitions, body) => {
+                let functions_env = env.extend_rec(definitions);
+                let mut inner = functions_env.clone();
+                for definition in definitions.iter().filter(|d| !is_function(d)) {
+                    inner = inner.extend(
+                        definition.var.clone(),
+                        self.delay(&definition.expr, &functions_env)?,
+                    );
+                }
+                self.eval(body, &inner)
+            }
+            Fn(param, body) => Ok(Value::Closure {
+                param: param.clone(),
+                body: body.clone(),
+                env: env.clone(),
+            }),
+            // The body is evaluated where the function was defined, not where
+            // it's applied.
+            Apply(f, arg) => match eval(f)? {
+                Value::Closure { param, body, env } => {
+                    let arg = delay(arg)?;
+                    self.eval(&body, &env.extend(param, arg))
+                }
+                found => Err(EvalError::TypeMismatch {
+                    expected: "a function",
+                    found,
+                }),
+            },
+            // The right operand is only evaluated if the left doesn't decide the
+            // result.
+            And(left, right) => match bool(eval(left)?)? {
+                true => Ok(Value::Bool(bool(eval(right)?)?)),
+                false => Ok(Value::Bool(false)),
+            },
+            Or(left, right) => match bool(eval(left)?)? {
+                true => Ok(Value::Bool(true)),
+                false => Ok(Value::Bool(bool(eval(right)?)?)),
+            },
+            // Only the branch taken is evaluated.
+            If(cond, yes, no) => {
+                if bool(eval(cond)?)? {
+                    eval(yes)
+                } else {
+                    eval(no)
+                }
+            }
+            // Nothing has an effect, but the first expression may still fail.
+            Seq(first, second) => {
+                eval(first)?;
+                eval(second)
+            }
+            // Annotations have no effect at runtime.
+            Ann(e, _) => eval(e),
+            Tuple(components) => Ok(Value::Tuple(
+                components
+                    .iter()
+                    .map(|e| self.delay_component(e, env))
+                    .collect::<Result<_>>()?,
+            )),
+            Record(fields) => Ok(Value::Record(
+                fields
+                    .iter()
+                    .map(|(field, e)| Ok((field.clone(), self.delay_component(e, env)?)))
+                    .collect::<Result<_>>()?,
+            )),
+            Field(record, field) => {
+                let record = eval(record)?;
+                let v = match &record {
+                    Value::Record(fields) => fields.iter().find(|(f, _)| f == field),
+                    _ => None,
+                };
+                match v {
+                    Some((_, v)) => self.force(v.clone()),
+                    None => Err(EvalError::TypeMismatch {
+                        expected: "a record with that field",
+                        found: record,
+                    }),
+                }
+            }
+            Match(scrutinee, arms) => self.matching(eval(scrutinee)?, arms, env),
+            _ => unreachable!("primitive operations are applied above"),
+        }
+    }
+
+    /// The value of a component of a tuple or record, which isn't shared
+    /// like the operands of other expressions, so must be copied to delay.
+    fn delay_component(&self, e: &Expression, env: &Env) -> Result<Value> {
+        match self.lazy {
+            true => self.delay(&Rc::new(e.clone()), env),
+            false => self.eval(e, env),
+        }
+    }
+
+    /// The value of the first arm whose pattern matches `v` and whose guard
+    /// holds, with the pattern's variables bound.
+    fn matching(&self, v: Value, arms: &[MatchArm], env: &Env) -> Result<Value> {
+        for arm in arms {
+            let mut inner = env.clone();
+            if !self.matches(&arm.pattern, v.clone(), &mut inner)? {
+                continue;
+            }
+            if let Some(guard) = &arm.guard {
+                if !bool(self.eval(guard, &inner)?)? {
+                    continue;
+                }
+            }
+            return self.eval(&arm.body, &inner);
+        }
+        Err(EvalError::NoMatch(v))
+    }
+
+    /// Whether `v` matches `pattern`, binding the pattern's variables in `env`
+    /// if it does. Only as much of `v` as the pattern looks at is forced.
+    fn matches(&self, pattern: &Pattern, v: Value, env: &mut Env) -> Result<bool> {
+        match pattern {
+            Pattern::Wildcard => Ok(true),
+            Pattern::Var(var) => {
+                *env = env.extend(var.clone(), v);
+                Ok(true)
+            }
+            Pattern::Literal(literal) => {
+                let literal = self.eval(literal, &Env::empty())?;
+                bool(self.apply(Primitive::Eq, &[self.force(v)?, literal])?)
+            }
+            Pattern::Nil | Pattern::Cons(_, _) => match (pattern, self.force(v)?) {
+                (Pattern::Nil, Value::Nil) => Ok(true),
+                (Pattern::Cons(head, tail), Value::Cons(h, t)) => {
+                    Ok(self.matches(head, (*h).clone(), env)?
+                        && self.matches(tail, (*t).clone(), env)?)
+                }
+                (_, Value::Nil | Value::Cons(_, _)) => Ok(false),
+                (_, found) => Err(EvalError::TypeMismatch {
+                    expected: "a list",
+                    found,
+                }),
+            },
+            Pattern::Pair(first, second) => match self.force(v)? {
+                Value::Pair(f, s) => Ok(self.matches(first, (*f).clone(), env)?
+                    && self.matches(second, (*s).clone(), env)?),
+                found => Err(EvalError::TypeMismatch {
+                    expected: "a pair",
+                    found,
+                }),
+            },
+        }
+    }
+
+    /// Perform a primitive operation on its evaluated operands. Lazy
+    /// components of values being compared are forced as they're reached.
+    fn apply(&self, op: Primitive, operands: &[Value]) -> Result<Value> {
+        value::apply(op, operands, &|v| self.force(v))
+    }
+}
+
+/// Perform a primitive operation on its evaluated operands, none of which
+/// are thunks.
+pub(crate) fn apply_primitive(op: Primitive, operands: &[Value]) -> Result<Value> {
+    Interpreter::default().apply(op, operands)
+}
+
+/// Whether a value, none of whose components are thunks, matches `pattern`,
+/// binding the pattern's variables in `env` if it does.
+pub(crate) fn match_pattern(pattern: &Pattern, v: Value, env: &mut Env) -> Result<bool> {
+    Interpreter::default().matches(pattern, v, env)
+}
+
+/// Whether a definition is of a function, so can be bound by `let rec`
+/// before it's evaluated.
+pub(crate) fn is_function(d: &Definition) -> bool {
+    matches!(*d.expr, Expression::Fn(_, _))
+}
+
+fn bool(v: Value) -> Result<bool> {
+    Ok(value::bool(v)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn run(source: &str) -> Result<Value> {
+        eval(&parse(source).unwrap())
+    }
+
+    #[test]
+    fn test_eval_primitives() {
+        assert_eq!(run("1 + 2"), Ok(Value::Int(3)));
+        assert_eq!(run("not(true)"), Ok(Value::Bool(false)));
+        assert_eq!(run("not(not(true))"), Ok(Value::Bool(true)));
+        assert_eq!(run("if false then 1 else 2 + 3"), Ok(Value::Int(5)));
+        assert_eq!(run("if true then ()"), Ok(Value::Unit));
+        assert_eq!(run("2.5"), Ok(Value::Float(2.5)));
+        assert_eq!(run("nil"), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn test_eval_variables() {
+        assert_eq!(run("let x = 1 in x + x"), Ok(Value::Int(2)));
+        assert_eq!(run("let x = 1 and y = 2 in x + y"), Ok(Value::Int(3)));
+        assert_eq!(
+            run("x"),
+            Err(EvalError::Unbound(Variable { ident: "x".into() }))
+        );
+        // Definitions can't see each other without `rec`.
+        assert_eq!(
+            run("let x = 1 and y = x in y"),
+            Err(EvalError::Unbound(Variable { ident: "x".into() }))
+        );
+    }
+
+    #[test]
+    fn test_eval_shadowing() {
+        assert_eq!(run("let x = 1 in let x = 2 in x"), Ok(Value::Int(2)));
+        assert_eq!(
+            run("let x = 1 in let x = x + 1 in x + 10"),
+            Ok(Value::Int(12))
+        );
+        assert_eq!(
+            run("let x = true in (let x = 2 in x) + (if x then 1 else 0)"),
+            Ok(Value::Int(3))
+        );
+        assert_eq!(
+            run("let x = 1 and y = 2 in let x = y in x"),
+            Ok(Value::Int(2))
+        );
+    }
+
+    #[test]
+    fn test_eval_closures() {
+        assert_eq!(run("(fn x. (x + 1)) 2"), Ok(Value::Int(3)));
+        assert_eq!(run("(fn x y. x) 1 2"), Ok(Value::Int(1)));
+        assert_eq!(run("let y = 1 in (fn x. (x + y))(10)"), Ok(Value::Int(11)));
+        // `y` is the one in scope where the function was defined, not where
+        // it's called.
+        assert_eq!(
+            run("let y = 1 in let f = fn x. (x + y) in let y = 100 in f 10"),
+            Ok(Value::Int(11))
+        );
+        assert_eq!(
+            run("let add = fn x y. (x + y) in let inc = add 1 in let x = 5 in inc x"),
+            Ok(Value::Int(6))
+        );
+        assert!(matches!(
+            run("let y = 1 in fn x. y"),
+            Ok(Value::Closure { param, .. }) if &*param.ident == "x"
+        ));
+        // A closure shares its body with the function it was made from.
+        let e = parse("fn x. (x + 1)").unwrap();
+        let (
+            Expression::Fn(_, body),
+            Ok(Value::Closure {
+                body: closure_body, ..
+            }),
+        ) = (&e, eval(&e))
+        else {
+            panic!("expected a closure");
+        };
+        assert!(Rc::ptr_eq(body, &closure_body));
+        assert_eq!(
+            run("1 2"),
+            Err(EvalError::TypeMismatch {
+                expected: "a function",
+                found: Value::Int(1)
+            })
+        );
+    }
+
+    #[test]
+    fn test_eval_arithmetic() {
+        assert_eq!(run("7 - 2 * 3"), Ok(Value::Int(1)));
+        assert_eq!(run("7 / 2"), Ok(Value::Int(3)));
+        assert_eq!(run("-7 % 2"), Ok(Value::Int(-1)));
+        assert_eq!(
+            run("-x"),
+            Err(EvalError::Unbound(Variable { ident: "x".into() }))
+        );
+        assert_eq!(run("- 1.5 * 2.0"), Ok(Value::Float(-3.0)));
+        assert_eq!(run("1.0 / 0.0"), Ok(Value::Float(f64::INFINITY)));
+        assert_eq!(run("1 / 0"), Err(EvalError::DivisionByZero));
+        assert_eq!(run("1 % 0"), Err(EvalError::DivisionByZero));
+        assert_eq!(run("-9223372036854775808 / -1"), Err(EvalError::Overflow));
+        assert_eq!(
+            run("1 + 1.0"),
+            Err(EvalError::TypeMismatch {
+                expected: "an integer",
+                found: Value::Float(1.0)
+            })
+        );
+        assert_eq!(run("1 < 2"), Ok(Value::Bool(true)));
+        assert_eq!(run("2.0 <= 1.0"), Ok(Value::Bool(false)));
+        assert_eq!(run("'b' > 'a'"), Ok(Value::Bool(true)));
+        assert_eq!(run("\"ab\" >= \"b\""), Ok(Value::Bool(false)));
+        assert_eq!(run("0.0 / 0.0 < 1.0"), Ok(Value::Bool(false)));
+        assert!(matches!(
+            run("1 < 'a'"),
+            Err(EvalError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_eval_succ_pred() {
+        assert_eq!(run("succ(1)"), Ok(Value::Int(2)));
+        assert_eq!(run("pred(succ(pred(0)))"), Ok(Value::Int(-1)));
+        assert_eq!(run("succ(9223372036854775807)"), Err(EvalError::Overflow));
+        assert!(matches!(
+            run("succ(true)"),
+            Err(EvalError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_eval_pairs() {
+        let pair = |v1, v2| Value::Pair(Rc::new(v1), Rc::new(v2));
+        assert_eq!(run("<1, true>"), Ok(pair(Value::Int(1), Value::Bool(true))));
+        assert_eq!(run("fst(<1, true>)"), Ok(Value::Int(1)));
+        assert_eq!(run("snd(<1, true>)"), Ok(Value::Bool(true)));
+        assert_eq!(run("fst(snd(<1, <2, 3>>))"), Ok(Value::Int(2)));
+        assert_eq!(
+            run("fst(1)"),
+            Err(EvalError::TypeMismatch {
+                expected: "a pair",
+                found: Value::Int(1)
+            })
+        );
+        assert!(matches!(
+            run("snd([1])"),
+            Err(EvalError::TypeMismatch {
+                expected: "a pair",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_eval_lists() {
+        let cons = |v1, v2| Value::Cons(Rc::new(v1), Rc::new(v2));
+        assert_eq!(
+            run("[1, 2]"),
+            Ok(cons(Value::Int(1), cons(Value::Int(2), Value::Nil)))
+        );
+        assert_eq!(run("1 :: nil"), Ok(cons(Value::Int(1), Value::Nil)));
+        assert_eq!(run("hd([1, 2])"), Ok(Value::Int(1)));
+        assert_eq!(run("tl([1, 2])"), Ok(cons(Value::Int(2), Value::Nil)));
+        assert_eq!(run("hd(tl(tl([1, 2, 3])))"), Ok(Value::Int(3)));
+        assert_eq!(run("tl([1])"), Ok(Value::Nil));
+        assert_eq!(
+            run("hd(nil)"),
+            Err(EvalError::TypeMismatch {
+                expected: "a non-empty list",
+                found: Value::Nil
+            })
+        );
+        assert_eq!(
+            run("tl(nil)"),
+            Err(EvalError::TypeMismatch {
+                expected: "a non-empty list",
+                found: Value::Nil
+            })
+        );
+        assert!(matches!(
+            run("hd(<1, 2>)"),
+            Err(EvalError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_eval_let_rec() {
+        assert_eq!(
+            run("let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact 5"),
+            Ok(Value::Int(120))
+        );
+        assert_eq!(
+            run("let rec even = fn n. if n < 1 then true else odd (n - 1) \
+                 and odd = fn n. if n < 1 then false else even (n - 1) in odd 7"),
+            Ok(Value::Bool(true))
+        );
+        // A recursive function sees itself, not whatever shadows it later.
+        assert_eq!(
+            run("let rec f = fn n. if n < 1 then 0 else 1 + f (n - 1) in \
+                 let g = f in let f = fn n. 100 in g 3"),
+            Ok(Value::Int(3))
+        );
+        // Other definitions may use the functions, but not vice versa.
+        assert_eq!(
+            run("let rec f = fn x. (x + 1) and y = f 1 in y"),
+            Ok(Value::Int(2))
+        );
+        assert_eq!(
+            run("let rec f = fn x. y and y = 1 in f 1"),
+            Err(EvalError::Unbound(Variable { ident: "y".into() }))
+        );
+    }
+
+    #[test]
+    fn test_eval_equality() {
+        assert_eq!(run("<1, 2> == <1, 2>"), Ok(Value::Bool(true)));
+        assert_eq!(run("<1, 2> == <1, 3>"), Ok(Value::Bool(false)));
+        assert_eq!(run("<1, 2> != <2, 1>"), Ok(Value::Bool(true)));
+        assert_eq!(run("[1, 2] == 1 :: 2 :: nil"), Ok(Value::Bool(true)));
+        assert_eq!(run("[1, 2] == [1]"), Ok(Value::Bool(false)));
+        assert_eq!(run("nil == []"), Ok(Value::Bool(true)));
+        assert_eq!(
+            run("<[<true, 'a'>], \"s\"> == <[<true, 'a'>], \"s\">"),
+            Ok(Value::Bool(true))
+        );
+        assert_eq!(run("() == ()"), Ok(Value::Bool(true)));
+        assert_eq!(run("0.0 / 0.0 == 0.0 / 0.0"), Ok(Value::Bool(false)));
+        assert_eq!(
+            run("1 == true"),
+            Err(EvalError::TypeMismatch {
+                expected: "a value of the same type",
+                found: Value::Bool(true)
+            })
+        );
+        assert!(matches!(
+            run("<1, 2> == <1, true>"),
+            Err(EvalError::TypeMismatch { .. })
+        ));
+        // Functions can't be compared, even with themselves.
+        assert!(matches!(
+            run("let f = fn x. x in f == f"),
+            Err(EvalError::TypeMismatch {
+                expected: "a value other than a function",
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("<1, fn x. x> != <1, fn x. x>"),
+            Err(EvalError::TypeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_eval_tuples_and_records() {
+        assert_eq!(
+            run("(1, true, 'c')"),
+            Ok(Value::Tuple(vec![
+                Value::Int(1),
+                Value::Bool(true),
+                Value::Char('c')
+            ]))
+        );
+        assert_eq!(run("{ a = 1, b = 2 + 3 }.b"), Ok(Value::Int(5)));
+        assert_eq!(run("let r = { a = 1 } in r.a + r.a"), Ok(Value::Int(2)));
+        assert_eq!(run("(1, 2, 3) == (1, 1 + 1, 3)"), Ok(Value::Bool(true)));
+        assert_eq!(run("{ a = 1 } != { a = 2 }"), Ok(Value::Bool(true)));
+        assert_eq!(
+            run("{ a = 1 }.b"),
+            Err(EvalError::TypeMismatch {
+                expected: "a record with that field",
+                found: Value::Record(vec![("a".to_string(), Value::Int(1))])
+            })
+        );
+        assert!(run("(1, 2) == (1, 2, 3)").is_err());
+        assert!(run("{ a = 1 } == { b = 1 }").is_err());
+    }
+
+    #[test]
+    fn test_eval_match() {
+        assert_eq!(
+            run("match [1, 2] with nil -> 0 | x :: _ -> x"),
+            Ok(Value::Int(1))
+        );
+        assert_eq!(
+            run("match 5 with n when n < 0 -> 0 | n -> n * 2"),
+            Ok(Value::Int(10))
+        );
+        assert_eq!(
+            run("match <1, <2, 3>> with <a, <b, c>> -> a + b + c"),
+            Ok(Value::Int(6))
+        );
+        assert_eq!(
+            run("match \"b\" with \"a\" -> 1 | \"b\" -> 2"),
+            Ok(Value::Int(2))
+        );
+        assert_eq!(
+            run("let rec len = fn l. match l with nil -> 0 | _ :: t -> 1 + len t in len [1, 2, 3]"),
+            Ok(Value::Int(3))
+        );
+        // The pattern's variables are only bound in its own arm.
+        assert_eq!(
+            run("let x = 1 in match 2 with x when x > 5 -> x | _ -> x"),
+            Ok(Value::Int(1))
+        );
+        assert_eq!(
+            run("match 3 with 1 -> 1"),
+            Err(EvalError::NoMatch(Value::Int(3)))
+        );
+        assert_eq!(
+            run("match 1 with nil -> 0"),
+            Err(EvalError::TypeMismatch {
+                expected: "a list",
+                found: Value::Int(1)
+            })
+        );
+    }
+
+    #[test]
+    fn test_eval_seq_and_ann() {
+        assert_eq!(run("1; 2"), Ok(Value::Int(2)));
+        assert_eq!(run("1 / 0; 2"), Err(EvalError::DivisionByZero));
+        assert_eq!(run("(1 : Int) + 1"), Ok(Value::Int(2)));
+    }
+
+    #[test]
+    fn test_eval_short_circuit() {
+        assert_eq!(run("true and false"), Ok(Value::Bool(false)));
+        assert_eq!(run("false or true"), Ok(Value::Bool(true)));
+        // `unbound` would fail if it were evaluated.
+        assert_eq!(run("false and unbound"), Ok(Value::Bool(false)));
+        assert_eq!(run("true or unbound"), Ok(Value::Bool(true)));
+        assert_eq!(
+            run("true and unbound"),
+            Err(EvalError::Unbound(Variable {
+                ident: "unbound".into()
+            }))
+        );
+        assert!(matches!(
+            run("true and 1"),
+            Err(EvalError::TypeMismatch {
+                expected: "a boolean",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_eval_with_fuel() {
+        let e = parse("1 + 2").unwrap();
+        assert_eq!(eval_with_fuel(&e, 3), Ok(Value::Int(3)));
+        assert_eq!(eval_with_fuel(&e, 2), Err(EvalError::OutOfFuel));
+
+        let e = parse("let rec loop = fn x. loop x in loop 0").unwrap();
+        assert_eq!(eval_with_fuel(&e, 100_000), Err(EvalError::OutOfFuel));
+        let e =
+            parse("let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact 5").unwrap();
+        assert_eq!(eval_with_fuel(&e, 1_000), Ok(Value::Int(120)));
+    }
+
+    #[test]
+    fn test_eval_traced() {
+        let (result, trace) = eval_traced(&parse("succ(succ(0))").unwrap());
+        assert_eq!(result, Ok(Value::Int(2)));
+        assert_eq!(
+            trace,
+            [
+                TraceEvent {
+                    op: Primitive::Succ,
+                    operands: vec![Value::Int(0)],
+                    result: Value::Int(1)
+                },
+                TraceEvent {
+                    op: Primitive::Succ,
+                    operands: vec![Value::Int(1)],
+                    result: Value::Int(2)
+                },
+            ]
+        );
+
+        // Operands are evaluated first, and only the operations performed are
+        // recorded.
+        let (_, trace) =
+            eval_traced(&parse("let f = fn x. (x * 2) in if f 1 < 3 then 1 else pred(1)").unwrap());
+        let ops: Vec<_> = trace.iter().map(|event| event.op).collect();
+        assert_eq!(ops, [Primitive::Mul, Primitive::Lt]);
+
+        // The trace is kept up to the failure.
+        let (result, trace) = eval_traced(&parse("hd(tl([1]))").unwrap());
+        assert!(result.is_err());
+        let ops: Vec<_> = trace.iter().map(|event| event.op).collect();
+        assert_eq!(ops, [Primitive::Cons, Primitive::Tl]);
+    }
+
+    #[test]
+    fn test_eval_lazy() {
+        let lazy = |source| eval_lazy(&parse(source).unwrap());
+        let diverging = "let rec loop = fn x. loop x in (fn x. 1) (loop 0)";
+        assert_eq!(lazy(diverging), Ok(Value::Int(1)));
+        assert_eq!(
+            eval_with_fuel(&parse(diverging).unwrap(), 10_000),
+            Err(EvalError::OutOfFuel)
+        );
+        assert_eq!(
+            lazy("let rec loop = fn x. loop x in fst(<1, loop 0>)"),
+            Ok(Value::Int(1))
+        );
+        assert_eq!(lazy("let x = 1 / 0 in 2"), Ok(Value::Int(2)));
+        assert_eq!(
+            lazy("hd(tl([1, 2 / 0, 3])) + 1"),
+            Err(EvalError::DivisionByZero)
+        );
+        assert_eq!(lazy("hd(tl(tl([1, 2 / 0, 3])))"), Ok(Value::Int(3)));
+        assert_eq!(lazy("[1, 2] == [1, 1 + 1]"), Ok(Value::Bool(true)));
+        let factorial = "let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact 5";
+        assert_eq!(lazy(factorial), Ok(Value::Int(120)));
+
+        // A thunk is only evaluated once, however often it's used.
+        let fuel_used = |source| {
+            let interpreter = Interpreter {
+                fuel: Cell::new(Some(1000)),
+                lazy: true,
+                ..Interpreter::default()
+            };
+            interpreter
+                .eval(&parse(source).unwrap(), &Env::empty())
+                .unwrap();
+            1000 - interpreter.fuel.get().unwrap()
+        };
+        let once = fuel_used("let x = 1 + 2 + 3 + 4 in x");
+        let twice = fuel_used("let x = 1 + 2 + 3 + 4 in x + x");
+        // Just the addition and the second reference to x.
+        assert_eq!(twice - once, 2);
+    }
+
+    #[test]
+    fn test_eval_lazy_agrees_with_eval() {
+        use std::mem;
+
+        let interpreter = Interpreter {
+            lazy: true,
+            ..Interpreter::default()
+        };
+        // A lazy value with all its components forced.
+        fn forced(interpreter: &Interpreter, v: Value) -> Result<Value> {
+            let component = |v: &Rc<Value>| forced(interpreter, (**v).clone()).map(Rc::new);
+            Ok(match interpreter.force(v)? {
+                Value::Cons(head, tail) => Value::Cons(component(&head)?, component(&tail)?),
+                Value::Pair(first, second) => Value::Pair(component(&first)?, component(&second)?),
+                Value::Tuple(components) => Value::Tuple(
+                    components
+                        .into_iter()
+                        .map(|v| forced(interpreter, v))
+                        .collect::<Result<_>>()?,
+                ),
+                Value::Record(fields) => Value::Record(
+                    fields
+                        .into_iter()
+                        .map(|(field, v)| Ok((field, forced(interpreter, v)?)))
+                        .collect::<Result<_>>()?,
+                ),
+                v => v,
+            })
+        }
+        for source in [
+            "[1 + 1, 2 * 3]",
+            "<1, <'a', 2.5>>",
+            "(1, 2 + 3, [true])",
+            "{ a = 1, b = { c = 2 * 2 } }",
+            "{ a = 1, b = { c = 2 * 2 } }.b.c",
+            "let r = { x = 1, y = 2 } in r.x + r.y",
+            "(1, 2) == (1, 2)",
+            "1; 2; 3",
+            "(1 : Int) + (2 : Int)",
+            "match [1, 2, 3] with nil -> 0 | x :: y :: _ -> x + y | _ -> 1",
+            "match <1, [2]> with <a, b :: nil> when a < b -> b | _ -> 0",
+            "let rec sum = fn l. match l with nil -> 0 | x :: xs -> x + sum xs in sum [1, 2, 3]",
+            "match 2 with 1 -> 1",
+            "{ a = 1 }.b",
+            "1 / 0; 2",
+        ] {
+            let e = parse(source).unwrap();
+            let lazy = interpreter
+                .eval(&e, &Env::empty())
+                .and_then(|v| forced(&interpreter, v));
+            // Values in errors may be left unevaluated, so only which error
+            // was raised is compared.
+            assert_eq!(
+                lazy.map(|v| v.to_string())
+                    .map_err(|e| mem::discriminant(&e)),
+                eval(&e)
+                    .map(|v| v.to_string())
+                    .map_err(|e| mem::discriminant(&e)),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_value_display() {
+        let show = |source| run(source).unwrap().to_string();
+        assert_eq!(show("3"), "3");
+        assert_eq!(show("-3"), "-3");
+        assert_eq!(show("2.5"), "2.5");
+        assert_eq!(show("2.0"), "2.0");
+        assert_eq!(show("1 < 2"), "true");
+        assert_eq!(show("\"a\\\"b\""), "\"a\\\"b\"");
+        assert_eq!(show("'x'"), "'x'");
+        assert_eq!(show("()"), "()");
+        assert_eq!(show("<1, <true, ()>>"), "<1, <true, ()>>");
+        assert_eq!(show("fn x. x"), "<fun>");
+        // Lists print in brackets, not as nested conses.
+        assert_eq!(show("nil"), "[]");
+        assert_eq!(show("1 :: 2 :: 3 :: nil"), "[1, 2, 3]");
+        assert_eq!(show("[[1], [], [2, 3]]"), "[[1], [], [2, 3]]");
+        assert_eq!(show("[<1, 'a'>]"), "[<1, 'a'>]");
+        assert_eq!(show("(1, \"a\", [2])"), "(1, \"a\", [2])");
+        assert_eq!(show("{ a = 1, b = (2, 3) }"), "{ a = 1, b = (2, 3) }");
+
+        // Only the parts of a lazy value which have been evaluated are shown.
+        let lazy = eval_lazy(&parse("[1 + 1, 2]").unwrap()).unwrap();
+        assert_eq!(lazy.to_string(), "<lazy> :: <lazy>");
+        let interpreter = Interpreter {
+            lazy: true,
+            ..Interpreter::default()
+        };
+        let Value::Cons(head, _) = &lazy else {
+            panic!("expected a list, found {}", lazy);
+        };
+        interpreter.force((**head).clone()).unwrap();
+        assert_eq!(lazy.to_string(), "2 :: <lazy>");
+    }
+
+    #[test]
+    fn test_env() {
+        let var = |s: &str| Variable { ident: s.into() };
+        let outer = Env::empty().extend(var("x"), Value::Int(1));
+        let inner = outer.extend(var("x"), Value::Int(2));
+        assert_eq!(inner.lookup(&var("x")), Some(Value::Int(2)));
+        assert_eq!(outer.lookup(&var("x")), Some(Value::Int(1)));
+        assert_eq!(inner.lookup(&var("y")), None);
+        assert_eq!(Env::empty().lookup(&var("x")), None);
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        assert_eq!(
+            run("not(1)"),
+            Err(EvalError::TypeMismatch {
+                expected: "a boolean",
+                found: Value::Int(1)
+            })
+        );
+        assert_eq!(
+            run("1 + true"),
+            Err(EvalError::TypeMismatch {
+                expected: "an integer",
+                found: Value::Bool(true)
+            })
+        );
+        assert_eq!(run("9223372036854775807 + 1"), Err(EvalError::Overflow));
+        // The branch not taken isn't evaluated, so can't fail.
+        assert_eq!(run("if true then 1 else not(1)"), Ok(Value::Int(1)));
+    }
+}
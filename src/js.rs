@@ -0,0 +1,362 @@
+//! js.rs: A backend generating JavaScript, which has closures of its own, so
+//! the whole language translates directly. Integers are `BigInt`s, so they
+//! divide as MiniML's do, though they never overflow. Pairs, tuples and conses
+//! are arrays, and `nil` and `()` are `null`.
+
+use crate::parser::{Definition, Expression, MatchArm, Pattern, Variable};
+
+/// The names JavaScript doesn't let variables have, or gives a meaning to.
+const RESERVED: &[&str] = &[
+    "arguments",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "eval",
+    "export",
+    "extends",
+    "finally",
+    "for",
+    "function",
+    "implements",
+    "import",
+    "instanceof",
+    "interface",
+    "new",
+    "null",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "try",
+    "typeof",
+    "undefined",
+    "var",
+    "void",
+    "while",
+    "with",
+    "yield",
+    "Infinity",
+    "NaN",
+];
+
+/// Structural equality, as MiniML's `==` is.
+const EQ: &str = "const $eq = (a, b) => Array.isArray(a) \
+    ? Array.isArray(b) && a.length === b.length && a.every((x, i) => $eq(x, b[i])) \
+    : a === b;";
+
+/// A JavaScript expression evaluating to the value of `e`, so `fn x. (x + 1)`
+/// becomes `(x) => (x + 1n)`. A `match` no arm of which matches throws.
+pub fn emit_js(e: &Expression) -> String {
+    let mut js = Js { eq: false };
+    let body = js.expression(e);
+    if js.eq {
+        format!("(() => {{ {} return {}; }})()", EQ, body)
+    } else {
+        body
+    }
+}
+
+struct Js {
+    /// Whether `$eq` is used, so needs defining.
+    eq: bool,
+}
+
+impl Js {
+    fn expression(&mut self, e: &Expression) -> String {
+        use Expression::*;
+        match e {
+            Num(n) if *n < 0 => format!("({}n)", n),
+            Num(n) => format!("{}n", n),
+            Float(f) if *f < 0.0 => format!("({})", f),
+            Float(f) => f.to_string(),
+            True => "true".to_string(),
+            False => "false".to_string(),
+            Str(s) => string(s),
+            Char(c) => string(&c.to_string()),
+            Unit | Nil => "null".to_string(),
+            Var(var) => name(var),
+            Fn(param, body) => format!("(({}) => {})", name(param), self.expression(body)),
+            Apply(f, arg) => format!("{}({})", self.expression(f), self.expression(arg)),
+            Let(definitions, body) if !definitions.iter().any(|d| d.recursive) => {
+                // Only the last of several definitions of the same variable
+                // is in scope in the body, but they're all evaluated.
+                let params: Vec<_> = definitions
+                    .iter()
+                    .enumerate()
+                    .map(|(i, d)| {
+                        match definitions[i + 1..].iter().any(|later| later.var == d.var) {
+                            true => format!("${}", i),
+                            false => name(&d.var),
+                        }
+                    })
+                    .collect();
+                let args: Vec<_> = definitions
+                    .iter()
+                    .map(|d| self.expression(&d.expr))
+                    .collect();
+                format!(
+                    "(({}) => {})({})",
+                    params.join(", "),
+                    self.expression(body),
+                    args.join(", ")
+                )
+            }
+            Let(definitions, body) => {
+                let mut block = "(() => {".to_string();
+                for d in rec_order(definitions) {
+                    block += &format!(" const {} = {};", name(&d.var), self.expression(&d.expr));
+                }
+                format!("{} return {}; }})()", block, self.expression(body))
+            }
+            If(cond, yes, no) => format!(
+                "({} ? {} : {})",
+                self.expression(cond),
+                self.expression(yes),
+                self.expression(no)
+            ),
+            Not(e) => format!("!{}", self.expression(e)),
+            Neg(e) => format!("(-{})", self.expression(e)),
+            Succ(e) => format!("({} + 1n)", self.expression(e)),
+            Pred(e) => format!("({} - 1n)", self.expression(e)),
+            And(e1, e2) => self.binary("&&", e1, e2),
+            Or(e1, e2) => self.binary("||", e1, e2),
+            Add(e1, e2) => self.binary("+", e1, e2),
+            Sub(e1, e2) => self.binary("-", e1, e2),
+            Mul(e1, e2) => self.binary("*", e1, e2),
+            Div(e1, e2) => self.binary("/", e1, e2),
+            Mod(e1, e2) => self.binary("%", e1, e2),
+            Lt(e1, e2) => self.binary("<", e1, e2),
+            Gt(e1, e2) => self.binary(">", e1, e2),
+            Le(e1, e2) => self.binary("<=", e1, e2),
+            Ge(e1, e2) => self.binary(">=", e1, e2),
+            Eq(e1, e2) => self.equal(e1, e2),
+            Neq(e1, e2) => format!("!{}", self.equal(e1, e2)),
+            Pair(e1, e2) | Cons(e1, e2) => {
+                format!("[{}, {}]", self.expression(e1), self.expression(e2))
+            }
+            Fst(e) | Hd(e) => format!("{}[0]", self.expression(e)),
+            Snd(e) | Tl(e) => format!("{}[1]", self.expression(e)),
+            Tuple(es) => {
+                let es: Vec<_> = es.iter().map(|e| self.expression(e)).collect();
+                format!("[{}]", es.join(", "))
+            }
+            Record(fields) => {
+                let fields: Vec<_> = fields
+                    .iter()
+                    .map(|(field, e)| format!("{}: {}", field, self.expression(e)))
+                    .collect();
+                format!("({{{}}})", fields.join(", "))
+            }
+            Field(e, field) => format!("{}.{}", self.expression(e), field),
+            Seq(first, second) => {
+                format!("({}, {})", self.expression(first), self.expression(second))
+            }
+            Ann(e, _) => self.expression(e),
+            Match(scrutinee, arms) => self.matching(scrutinee, arms),
+        }
+    }
+
+    fn binary(&mut self, op: &str, e1: &Expression, e2: &Expression) -> String {
+        format!("({} {} {})", self.expression(e1), op, self.expression(e2))
+    }
+
+    fn equal(&mut self, e1: &Expression, e2: &Expression) -> String {
+        self.eq = true;
+        format!("$eq({}, {})", self.expression(e1), self.expression(e2))
+    }
+
+    /// A function trying each arm in turn, applied to the scrutinee.
+    fn matching(&mut self, scrutinee: &Expression, arms: &[MatchArm]) -> String {
+        let mut function = "(($m) => {".to_string();
+        for arm in arms {
+            let (mut tests, mut bindings) = (vec![], vec![]);
+            self.pattern(&arm.pattern, "$m".to_string(), &mut tests, &mut bindings);
+            let test = match tests.is_empty() {
+                true => "true".to_string(),
+                false => tests.join(" && "),
+            };
+            let mut taken = String::new();
+            for (var, value) in bindings {
+                taken += &format!(" const {} = {};", name(var), value);
+            }
+            let body = self.expression(&arm.body);
+            taken += &match &arm.guard {
+                Some(guard) => format!(" if ({}) return {};", self.expression(guard), body),
+                None => format!(" return {};", body),
+            };
+            function += &format!(" if ({}) {{{} }}", test, taken);
+        }
+        format!(
+            "{} throw new Error(\"no arm matched\"); }})({})",
+            function,
+            self.expression(scrutinee)
+        )
+    }
+
+    /// Add the tests `value` must pass to match `pattern` to `tests`, in the
+    /// order they can be made, and the variables it binds to `bindings`.
+    fn pattern<'p>(
+        &mut self,
+        pattern: &'p Pattern,
+        value: String,
+        tests: &mut Vec<String>,
+        bindings: &mut Vec<(&'p Variable, String)>,
+    ) {
+        match pattern {
+            Pattern::Wildcard => {}
+            Pattern::Var(var) => bindings.push((var, value)),
+            Pattern::Literal(literal) => {
+                self.eq = true;
+                tests.push(format!("$eq({}, {})", value, self.expression(literal)));
+            }
+            Pattern::Nil => tests.push(format!("{} === null", value)),
+            Pattern::Cons(head, tail) => {
+                tests.push(format!("{} !== null", value));
+                self.pattern(head, format!("{}[0]", value), tests, bindings);
+                self.pattern(tail, format!("{}[1]", value), tests, bindings);
+            }
+            Pattern::Pair(first, second) => {
+                self.pattern(first, format!("{}[0]", value), tests, bindings);
+                self.pattern(second, format!("{}[1]", value), tests, bindings);
+            }
+        }
+    }
+}
+
+/// A `let rec` group's functions, then its other definitions, which are
+/// evaluated with the functions in scope, as the interpreter does.
+fn rec_order(definitions: &[Definition]) -> impl Iterator<Item = &Definition> {
+    let is_function = |d: &&Definition| matches!(*d.expr, Expression::Fn(_, _));
+    let functions = definitions.iter().filter(is_function);
+    functions.chain(definitions.iter().filter(move |d| !is_function(d)))
+}
+
+/// The name a variable has in JavaScript: its own, unless that's reserved,
+/// in which case a `$`, which MiniML names can't contain, is added.
+fn name(var: &Variable) -> String {
+    match RESERVED.contains(&&*var.ident) {
+        true => format!("{}$", var.ident),
+        false => var.ident.to_string(),
+    }
+}
+
+/// A JavaScript string literal.
+fn string(s: &str) -> String {
+    let mut literal = "\"".to_string();
+    for c in s.chars() {
+        match c {
+            '\\' => literal.push_str("\\\\"),
+            '"' => literal.push_str("\\\""),
+            '\n' => literal.push_str("\\n"),
+            '\t' => literal.push_str("\\t"),
+            c if c.is_control() => literal += &format!("\\u{{{:x}}}", c as u32),
+            c => literal.push(c),
+        }
+    }
+    literal + "\""
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::eval;
+    use crate::parser::parse;
+    use std::process::Command;
+
+    fn js(source: &str) -> String {
+        emit_js(&parse(source).unwrap())
+    }
+
+    /// What node prints the value of some JavaScript as, if node is installed.
+    fn node(js: &str) -> Option<String> {
+        let output = Command::new("node")
+            .arg("-e")
+            .arg(format!("console.log(String({}))", js))
+            .output()
+            .ok()?;
+        assert!(output.status.success(), "{}: {:?}", js, output);
+        Some(
+            String::from_utf8(output.stdout)
+                .unwrap()
+                .trim_end()
+                .to_string(),
+        )
+    }
+
+    #[test]
+    fn test_emit_js() {
+        assert_eq!(js("(fn x. (x + 1)) 2"), "((x) => (x + 1n))(2n)");
+        assert_eq!(js("fn x. fn y. x"), "((x) => ((y) => x))");
+        assert_eq!(
+            js("let x = 1 and y = 2 in x * y"),
+            "((x, y) => (x * y))(1n, 2n)"
+        );
+        assert_eq!(
+            js("let rec f = fn n. f n in f"),
+            "(() => { const f = ((n) => f(n)); return f; })()"
+        );
+        assert_eq!(js("[1, -2]"), "[1n, [(-2n), null]]");
+        assert_eq!(js("hd(tl(<\"a\\n\", 'b'>))"), "[\"a\\n\", \"b\"][1][0]");
+        assert_eq!(js("fn new. new"), "((new$) => new$)");
+        assert!(js("1 == 1").contains("const $eq = "));
+        assert!(!js("1 < 2").contains("$eq"));
+    }
+
+    #[test]
+    fn test_emit_js_runs() {
+        for source in [
+            "(fn x. (x + 1)) 2",
+            "7 / -2 + 7 % -2",
+            "1.5 * 2.0 < 3.5 and not(false)",
+            "let x = 1 in let x = 2 and y = x in x * 10 + y",
+            "let x = 1 and x = 2 in x",
+            "let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact 20",
+            "let rec even = fn n. if n < 1 then true else odd (n - 1) \
+             and odd = fn n. if n < 1 then false else even (n - 1) in even 7",
+            "let rec map = fn f. fn l. if l == nil then nil else f (hd(l)) :: map f (tl(l)) \
+             in hd(tl(map (fn x. (x * x)) [1, 2, 3]))",
+            "[1, 2] == [1, 2] and <1, 'a'> != <1, 'b'>",
+            "let rec f = fn n. n and x = f 5 in x",
+        ] {
+            let Some(output) = node(&js(source)) else {
+                return;
+            };
+            let expected = eval(&parse(source).unwrap()).unwrap().to_string();
+            assert_eq!(output, expected, "{}", source);
+        }
+        // The interpreter can't evaluate these.
+        for (source, expected) in [
+            (
+                "match [1, 2, 3] with nil -> 0 | x :: y :: rest -> x + y | _ -> 100",
+                "3",
+            ),
+            (
+                "match <1, 2> with <1, x> when x > 5 -> x | <a, b> -> a - b",
+                "-1",
+            ),
+            ("match 'c' with 'a' -> 1 | 'c' -> 2", "2"),
+            ("{a = 1, b = (2, 3)}.a; (); 4", "4"),
+        ] {
+            let Some(output) = node(&js(source)) else {
+                return;
+            };
+            assert_eq!(output, expected, "{}", source);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! json.rs: Build expressions from the tagged JSON that `--emit ast-json`
+//! produces, so that other tools can generate MiniML programs.
+
+use crate::parser::Expression;
+use serde::Deserialize;
+use std::fmt;
+
+/// An error produced while importing an expression from JSON.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ImportError {
+    /// A human-readable description of what was wrong with the JSON, e.g. an
+    /// unknown variant tag or a missing field.
+    pub message: String,
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "malformed expression: {}", self.message)
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// Reconstruct an expression from its JSON representation, in which each node
+/// is an object tagged by its variant, e.g. `{"Add": [{"Num": 1}, {"Num": 2}]}`.
+pub fn from_json(value: &serde_json::Value) -> Result<Expression, ImportError> {
+    Expression::deserialize(value).map_err(|e| ImportError {
+        message: e.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_json() {
+        assert_eq!(
+            from_json(&json!({"Add": [{"Num": 1}, {"Var": {"ident": "x"}}]})),
+            Ok(parse("1 + x").unwrap())
+        );
+        assert_eq!(from_json(&json!("Unit")), Ok(Expression::Unit));
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        for source in [
+            "let rec f = fn n. if n < 1 then 1.5 else f (n - 1) in f 3",
+            "match <1, [2]> with <x, y :: _> when x > y -> \"a\" | _ -> \"b\"",
+            "({ a = 'c', b = (1, (), nil) }.a : Char)",
+        ] {
+            let e = parse(source).unwrap();
+            assert_eq!(from_json(&serde_json::to_value(&e).unwrap()), Ok(e));
+        }
+    }
+
+    #[test]
+    fn test_malformed_json() {
+        let err = from_json(&json!({"Plus": [{"Num": 1}, {"Num": 2}]})).unwrap_err();
+        assert!(err.message.contains("unknown variant `Plus`"), "{}", err);
+
+        let err = from_json(&json!({"Var": {}})).unwrap_err();
+        assert!(err.message.contains("missing field `ident`"), "{}", err);
+
+        let err = from_json(&json!({"Num": "one"})).unwrap_err();
+        assert!(err.message.contains("invalid type"), "{}", err);
+
+        assert!(from_json(&json!({"Add": [{"Num": 1}]})).is_err());
+        assert!(from_json(&json!(null)).is_err());
+    }
+}
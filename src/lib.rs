@@ -0,0 +1,48 @@
+//! A compiler for MiniML, a tiny subset of ML.
+//!
+//! Source text is turned into an [`Expression`] with [`parse`]:
+//!
+//! ```
+//! use std::rc::Rc;
+//!
+//! use miniml_compiler::{parse, Expression};
+//!
+//! assert_eq!(parse("succ(1)").unwrap(), Expression::Succ(Rc::new(Expression::Num(1))));
+//! assert!(parse("succ(").is_err());
+//! ```
+
+pub mod anf;
+#[cfg(test)]
+mod arbitrary;
+pub mod arena;
+pub mod bytecode;
+pub mod c;
+pub mod codegen;
+pub mod cps;
+pub mod dot;
+#[cfg(test)]
+mod eval;
+pub mod exprs;
+pub mod infer;
+pub mod intern;
+pub mod interp;
+pub mod js;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod lift;
+pub mod opt;
+pub mod parser;
+pub mod pprint;
+pub mod reduce;
+pub mod secd;
+pub mod step;
+pub mod subst;
+mod value;
+pub mod visit;
+pub mod vm;
+pub mod wasm;
+
+pub use parser::{
+    parse, parse_program, parse_spanned, parse_with_depth_limit, BinOp, Definition, Expression,
+    MatchArm, ParseError, ParseErrorKind, Pattern, Program, Spans, Type, Variable,
+};
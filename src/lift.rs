@@ -0,0 +1,290 @@
+//! lift.rs: Lambda lifting, which moves every function to the top level of
+//! a program, so that none captures variables from where it was defined.
+
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::exprs::map_subexpressions;
+use crate::intern::intern;
+use crate::parser::{Definition, Expression, Program, Variable};
+use crate::subst::{free_vars, names, subst};
+
+/// Lift every function in `e` to a top-level declaration of the program
+/// returned, whose main expression is what's left of `e`. The variables a
+/// function captures become extra parameters, before its own, which are
+/// passed where it was defined. So `let y = 1 in fn x. (x + y)` becomes
+/// `let lambda = fn y. fn x. (x + y) ;; let y = 1 in lambda y`. Functions
+/// defined together by `let rec` are lifted together, with the variables
+/// any of them capture.
+pub fn lambda_lift(e: Expression) -> Program {
+    let mut lifter = Lifter {
+        used: names(&e),
+        lifted: HashSet::new(),
+        declarations: vec![],
+    };
+    let main = lifter.lift(e);
+    Program {
+        declarations: lifter.declarations,
+        main: Some(main),
+    }
+}
+
+struct Lifter {
+    /// The names which lifted functions mustn't take.
+    used: HashSet<String>,
+    /// The names of the lifted functions, which are in scope everywhere so
+    /// needn't be captured.
+    lifted: HashSet<String>,
+    /// The groups of functions lifted so far. Each only refers to those
+    /// before it, or in its own group.
+    declarations: Vec<Vec<Definition>>,
+}
+
+impl Lifter {
+    fn lift(&mut self, e: Expression) -> Expression {
+        use Expression::*;
+        match e {
+            Fn(param, body) => {
+                let body = self.lift(Rc::unwrap_or_clone(body));
+                let f = Fn(param, Rc::new(body));
+                let captured = self.captured(&f, &[]);
+                let name = self.fresh("lambda");
+                let call = call(&name, &captured);
+                self.declare(vec![(name, curried(&captured, f))], false);
+                call
+            }
+            Let(definitions, body) if definitions.iter().any(|d| d.recursive) => {
+                let (functions, others): (Vec<_>, Vec<_>) = definitions
+                    .into_iter()
+                    .partition(|d| matches!(*d.expr, Fn(_, _)));
+                let functions: Vec<_> = functions
+                    .into_iter()
+                    .map(|d| match Rc::unwrap_or_clone(d.expr) {
+                        Fn(param, body) => (
+                            d.var,
+                            Fn(param, Rc::new(self.lift(Rc::unwrap_or_clone(body)))),
+                        ),
+                        _ => unreachable!("partitioned by being a function"),
+                    })
+                    .collect();
+                let group: Vec<_> = functions.iter().map(|(var, _)| var.clone()).collect();
+                let captured = functions.iter().fold(vec![], |mut captured, (_, f)| {
+                    for var in self.captured(f, &group) {
+                        if !captured.contains(&var) {
+                            captured.push(var);
+                        }
+                    }
+                    captured
+                });
+                // References within the group are to the lifted functions,
+                // passed what they capture.
+                let calls: Vec<_> = functions
+                    .iter()
+                    .map(|(var, _)| {
+                        let name = self.fresh(&var.ident);
+                        (var.clone(), name.clone(), call(&name, &captured))
+                    })
+                    .collect();
+                let lifted = functions
+                    .into_iter()
+                    .map(|(_, f)| {
+                        let f = calls
+                            .iter()
+                            .fold(f, |f, (var, _, call)| subst(&f, &var.ident, call));
+                        curried(&captured, f)
+                    })
+                    .zip(&calls)
+                    .map(|(f, (_, name, _))| (name.clone(), f))
+                    .collect();
+                self.declare(lifted, true);
+                // The other definitions are evaluated with the functions in
+                // scope, as in evaluation.
+                let bindings = calls
+                    .into_iter()
+                    .map(|(var, _, call)| Definition {
+                        var,
+                        expr: Rc::new(call),
+                        recursive: false,
+                    })
+                    .collect();
+                let body = if others.is_empty() {
+                    self.lift(Rc::unwrap_or_clone(body))
+                } else {
+                    let others = others
+                        .into_iter()
+                        .map(|d| Definition {
+                            expr: Rc::new(self.lift(Rc::unwrap_or_clone(d.expr))),
+                            recursive: false,
+                            ..d
+                        })
+                        .collect();
+                    Let(others, Rc::new(self.lift(Rc::unwrap_or_clone(body))))
+                };
+                Let(bindings, Rc::new(body))
+            }
+            e => map_subexpressions(e, |child| self.lift(child)),
+        }
+    }
+
+    /// The variables free in `f`, which must be passed to it once it's lifted,
+    /// in order. Lifted functions and those of the `group` being lifted with it
+    /// aren't captured.
+    fn captured(&self, f: &Expression, group: &[Variable]) -> Vec<Variable> {
+        let mut captured: Vec<_> = free_vars(f)
+            .into_iter()
+            .filter(|var| !self.lifted.contains(var) && !group.iter().any(|g| *g.ident == **var))
+            .collect();
+        captured.sort();
+        captured
+            .into_iter()
+            .map(|var| Variable {
+                ident: intern(&var),
+            })
+            .collect()
+    }
+
+    fn declare(&mut self, functions: Vec<(Variable, Expression)>, recursive: bool) {
+        self.declarations.push(
+            functions
+                .into_iter()
+                .map(|(var, expr)| Definition {
+                    var,
+                    expr: Rc::new(expr),
+                    recursive,
+                })
+                .collect(),
+        );
+    }
+
+    /// A name for a lifted function, based on `name`, which isn't in use.
+    fn fresh(&mut self, name: &str) -> Variable {
+        let name = std::iter::once(name.to_string())
+            .chain((1..).map(|i| format!("{}{}", name, i)))
+            .find(|candidate| !self.used.contains(candidate))
+            .expect("there are only finitely many names in use");
+        self.used.insert(name.clone());
+        self.lifted.insert(name.clone());
+        Variable {
+            ident: intern(&name),
+        }
+    }
+}
+
+/// `f` applied to each of the `captured` variables in turn.
+fn call(f: &Variable, captured: &[Variable]) -> Expression {
+    captured
+        .iter()
+        .fold(Expression::Var(f.clone()), |call, var| {
+            Expression::Apply(Rc::new(call), Rc::new(Expression::Var(var.clone())))
+        })
+}
+
+/// `f` with the `captured` variables as extra parameters, before its own.
+fn curried(captured: &[Variable], f: Expression) -> Expression {
+    captured
+        .iter()
+        .rev()
+        .fold(f, |f, var| Expression::Fn(var.clone(), Rc::new(f)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::eval;
+    use crate::parser::parse;
+
+    /// The declarations and main expression of `p`, as source.
+    fn show(p: &Program) -> Vec<String> {
+        let mut lines: Vec<_> = p
+            .declarations
+            .iter()
+            .map(|group| {
+                let definitions: Vec<_> = group
+                    .iter()
+                    .map(|d| format!("{} = {}", d.var.ident, d.expr))
+                    .collect();
+                let rec = if group[0].recursive { "rec " } else { "" };
+                format!("let {}{}", rec, definitions.join(" and "))
+            })
+            .collect();
+        lines.extend(p.main.iter().map(|e| e.to_string()));
+        lines
+    }
+
+    /// The expression binding the declarations of `p` around its main expression.
+    fn expression(p: Program) -> Expression {
+        p.declarations
+            .into_iter()
+            .rev()
+            .fold(p.main.unwrap(), |main, group| {
+                Expression::Let(group, Rc::new(main))
+            })
+    }
+
+    fn has_function(e: &Expression) -> bool {
+        crate::visit::fold(e, false, |has, e| has || matches!(e, Expression::Fn(_, _)))
+    }
+
+    /// Whether `p`'s functions are all declared at the top level, and refer
+    /// only to their parameters and each other.
+    fn is_lifted(p: &Program) -> bool {
+        let globals: HashSet<_> = p
+            .declarations
+            .iter()
+            .flatten()
+            .map(|d| d.var.ident.to_string())
+            .collect();
+        p.declarations.iter().flatten().all(|d| {
+            let mut body = &*d.expr;
+            while let Expression::Fn(_, inner) = body {
+                body = inner;
+            }
+            !has_function(body) && free_vars(&d.expr).is_subset(&globals)
+        }) && !p.main.iter().any(has_function)
+    }
+
+    #[test]
+    fn test_lambda_lift() {
+        assert_eq!(
+            show(&lambda_lift(parse("let y = 1 in fn x. (x + y)").unwrap())),
+            ["let lambda = fn y x. (x + y)", "let y = 1 in lambda y"]
+        );
+        assert_eq!(
+            show(&lambda_lift(parse("fn x. fn y. (x + y)").unwrap())),
+            [
+                "let lambda = fn x y. (x + y)",
+                "let lambda1 = fn x. lambda x",
+                "lambda1"
+            ]
+        );
+        assert_eq!(
+            show(&lambda_lift(
+                parse("let k = 2 in let rec f = fn n. if n < 1 then k else f (n - 1) in f 3")
+                    .unwrap()
+            )),
+            [
+                "let rec f1 = fn k n. if n < 1 then k else f1 k (n - 1)",
+                "let k = 2 in let f = f1 k in f 3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lambda_lift_preserves_evaluation() {
+        for source in [
+            "let y = 1 in (fn x. (x + y)) 41",
+            "let a = 1 in let b = 2 in (fn x. fn y. (x + y + a * b)) 3 4",
+            "let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact 5",
+            "let base = 100 in let rec even = fn n. if n < 1 then base else odd (n - 1) \
+             and odd = fn n. if n < 1 then 0 else even (n - 1) in even 4",
+            "let rec f = fn n. (n + 1) and x = f 1 in f x",
+            "let twice = fn f. fn x. f (f x) in twice (fn x. (x * 3)) 2",
+            "let lambda = 5 in (fn x. (x + lambda)) 1",
+        ] {
+            let e = parse(source).unwrap();
+            let program = lambda_lift(e.clone());
+            assert!(is_lifted(&program), "{:?}", show(&program));
+            assert_eq!(eval(&expression(program)), eval(&e), "{}", source);
+        }
+    }
+}
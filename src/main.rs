@@ -1,8 +1,72 @@
-mod eval;
-mod exprs;
-mod parser;
-mod pprint;
+//! The `miniml_compiler` command line: parse a MiniML source file and emit
+//! it in the requested form.
+//!
+//! Usage: `miniml_compiler [--emit ast|ast-json] <file>`, where `ast-json`
+//! needs the `serde` feature.
 
-fn main() {
-    println!("Hello, world!");
+use miniml_compiler::parse_program;
+use std::process::ExitCode;
+
+#[cfg(feature = "serde")]
+const USAGE: &str = "usage: miniml_compiler [--emit ast|ast-json] <file>";
+#[cfg(not(feature = "serde"))]
+const USAGE: &str = "usage: miniml_compiler [--emit ast] <file>";
+
+/// What to print for a successfully parsed program.
+enum Emit {
+    /// The Rust debug representation of the AST.
+    Ast,
+    /// The AST serialized as JSON, with variants tagged by name.
+    #[cfg(feature = "serde")]
+    AstJson,
+}
+
+fn main() -> ExitCode {
+    let mut emit = Emit::Ast;
+    let mut path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--emit" => {
+                emit = match args.next().as_deref() {
+                    Some("ast") => Emit::Ast,
+                    #[cfg(feature = "serde")]
+                    Some("ast-json") => Emit::AstJson,
+                    _ => return usage_error(),
+                }
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return usage_error(),
+        }
+    }
+    let Some(path) = path else {
+        return usage_error();
+    };
+
+    let source = match std::fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let program = match parse_program(&source) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match emit {
+        Emit::Ast => println!("{:#?}", program),
+        #[cfg(feature = "serde")]
+        Emit::AstJson => println!("{}", serde_json::to_string(&program).unwrap()),
+    }
+    ExitCode::SUCCESS
+}
+
+fn usage_error() -> ExitCode {
+    eprintln!("{}", USAGE);
+    ExitCode::from(2)
 }
@@ -0,0 +1,491 @@
+//! opt.rs: Optimization passes, which rewrite expressions into simpler ones
+//! that evaluate to the same thing.
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::exprs::{is_simple, map_subexpressions, subexpressions};
+use crate::intern::intern;
+use crate::interp::{self, Primitive, Value};
+use crate::parser::{Definition, Expression, Variable};
+use crate::subst::{free_var_counts, free_vars, names, subst};
+use crate::visit::{fold, map_expr, node_count};
+
+/// Evaluate the operations whose operands are all literals, so `1 + 2 * 3`
+/// becomes `7`, and `if`s whose condition is. Operations which would fail,
+/// e.g. dividing by zero or overflowing, are left for evaluation to report.
+pub fn const_fold(e: Expression) -> Expression {
+    map_expr(e, &|e| match e {
+        Expression::If(cond, yes, no) => match Rc::unwrap_or_clone(cond) {
+            Expression::True => Rc::unwrap_or_clone(yes),
+            Expression::False => Rc::unwrap_or_clone(no),
+            cond => Expression::If(Rc::new(cond), yes, no),
+        },
+        e if is_foldable(&e) => interp::eval(&e).ok().and_then(literal).unwrap_or(e),
+        e => e,
+    })
+}
+
+/// Remove the definitions of `let`s which aren't used, and are pure, replacing
+/// a `let` with its body if none are left. A `let rec` definition only used by
+/// definitions which aren't used themselves is unused.
+pub fn elim_dead_let(e: Expression) -> Expression {
+    map_expr(e, &|e| match e {
+        Expression::Let(definitions, body) => {
+            let recursive = definitions.iter().any(|d| d.recursive);
+            let mut used = free_vars(&body);
+            let mut live = vec![false; definitions.len()];
+            // Each pass brings in the definitions those found live so far
+            // use, until there are no more.
+            loop {
+                let mut changed = false;
+                for (d, live) in definitions.iter().zip(&mut live) {
+                    if !*live && (used.contains(&*d.var.ident) || !is_pure(&d.expr)) {
+                        *live = true;
+                        changed = true;
+                        if recursive {
+                            used.extend(free_vars(&d.expr));
+                        }
+                    }
+                }
+                if !changed || !recursive {
+                    break;
+                }
+            }
+            let definitions: Vec<_> = definitions
+                .into_iter()
+                .zip(live)
+                .filter_map(|(d, live)| live.then_some(d))
+                .collect();
+            if definitions.is_empty() {
+                Rc::unwrap_or_clone(body)
+            } else {
+                Expression::Let(definitions, body)
+            }
+        }
+        e => e,
+    })
+}
+
+/// Substitute the pure definitions of plain `let`s which are used exactly once
+/// for their use, and remove them. Impure ones might fail, and their use
+/// mightn't be evaluated, or not first. Uses within functions aren't replaced, as
+/// the function may be applied many times, repeating the work. Nor are
+/// definitions which refer to others of their group, which would be captured.
+pub fn inline_lets(e: Expression) -> Expression {
+    map_expr(e, &|e| match e {
+        Expression::Let(definitions, body) if !definitions.iter().any(|d| d.recursive) => {
+            let counts = free_var_counts(&body);
+            let group: HashSet<_> = definitions.iter().map(|d| d.var.ident.clone()).collect();
+            let mut body = Rc::unwrap_or_clone(body);
+            let mut kept = vec![];
+            for d in definitions {
+                let inlinable = counts.get(&*d.var.ident) == Some(&1)
+                    && is_pure(&d.expr)
+                    && !free_vars(&d.expr)
+                        .iter()
+                        .any(|v| group.contains(v.as_str()))
+                    && !used_in_function(&body, &d.var.ident);
+                if inlinable {
+                    body = subst(&body, &d.var.ident, &d.expr);
+                } else {
+                    kept.push(d);
+                }
+            }
+            if kept.is_empty() {
+                body
+            } else {
+                Expression::Let(kept, Rc::new(body))
+            }
+        }
+        e => e,
+    })
+}
+
+/// Common subexpression elimination: bind pure subexpressions which occur
+/// more than once in a scope to a variable, evaluated once, and use that
+/// instead. So `(a + b) * (a + b)` becomes `let t = a + b in t * t`. Only the
+/// parts of a scope which are always evaluated are searched, so nothing's
+/// evaluated which wouldn't have been; the branches of an `if`, for example,
+/// are searched separately.
+pub fn cse(e: Expression) -> Expression {
+    let mut used = names(&e);
+    let mut temporaries: Vec<Definition> = vec![];
+    let mut body = e;
+    loop {
+        // The largest repeated subexpression, preferring the first found.
+        let mut counts: HashMap<&Expression, usize> = HashMap::new();
+        let mut common: Option<&Expression> = None;
+        let mut found = |e| {
+            if !is_shareable(e, &temporaries) {
+                return;
+            }
+            let count = counts.entry(e).or_default();
+            *count += 1;
+            if *count == 2 && common.is_none_or(|c| node_count(e) > node_count(c)) {
+                common = Some(e);
+            }
+        };
+        for t in &temporaries {
+            for_each_evaluated(&t.expr, &mut found);
+        }
+        for_each_evaluated(&body, &mut found);
+        let Some(common) = common.cloned() else {
+            break;
+        };
+        let t = Variable {
+            ident: intern(&temporary(&used)),
+        };
+        used.insert(t.ident.to_string());
+        let var = Expression::Var(t.clone());
+        for d in &mut temporaries {
+            d.expr = Rc::new(replace_evaluated((*d.expr).clone(), &common, &var));
+        }
+        body = replace_evaluated(body, &common, &var);
+        temporaries.push(Definition {
+            var: t,
+            expr: Rc::new(common),
+            recursive: false,
+        });
+    }
+    // Temporaries found later don't refer to earlier ones, so can enclose them.
+    temporaries
+        .into_iter()
+        .fold(cse_within(body), |body, mut t| {
+            t.expr = Rc::new(cse_within(Rc::unwrap_or_clone(t.expr)));
+            Expression::Let(vec![t], Rc::new(body))
+        })
+}
+
+/// Apply [`cse`] to each of the scopes nested within `e`.
+fn cse_within(e: Expression) -> Expression {
+    let evaluated = evaluated_subexpressions(&e);
+    let mut i = 0;
+    map_subexpressions(e, |child| {
+        i += 1;
+        if evaluated[i - 1] {
+            cse_within(child)
+        } else {
+            cse(child)
+        }
+    })
+}
+
+/// Whether `e` is worth binding to a variable, and can be. Temporaries are
+/// bound outside each other, so can't be used.
+fn is_shareable(e: &Expression, temporaries: &[Definition]) -> bool {
+    !is_simple(e)
+        && !matches!(e, Expression::Fn(_, _))
+        && terminates(e)
+        && !free_vars(e)
+            .iter()
+            .any(|v| temporaries.iter().any(|t| *t.var.ident == **v))
+}
+
+/// Call `f` on each subexpression of `e`, and `e` itself, which is evaluated
+/// whenever `e` is, and in the same scope.
+fn for_each_evaluated<'a>(e: &'a Expression, f: &mut impl FnMut(&'a Expression)) {
+    f(e);
+    for (child, evaluated) in subexpressions(e)
+        .into_iter()
+        .zip(evaluated_subexpressions(e))
+    {
+        if evaluated {
+            for_each_evaluated(child, f);
+        }
+    }
+}
+
+/// Replace the occurrences of `target` found by [`for_each_evaluated`] with
+/// `var`.
+fn replace_evaluated(e: Expression, target: &Expression, var: &Expression) -> Expression {
+    if e == *target {
+        return var.clone();
+    }
+    let evaluated = evaluated_subexpressions(&e);
+    let mut i = 0;
+    map_subexpressions(e, |child| {
+        i += 1;
+        if evaluated[i - 1] {
+            replace_evaluated(child, target, var)
+        } else {
+            child
+        }
+    })
+}
+
+/// For each of the subexpressions of `e`, in the order of [`subexpressions`],
+/// whether it's evaluated whenever `e` is, in the same scope. Those which bind
+/// variables or are evaluated conditionally aren't.
+fn evaluated_subexpressions(e: &Expression) -> Vec<bool> {
+    let n = subexpressions(e).len();
+    let mut evaluated = vec![false; n];
+    match e {
+        Expression::Fn(_, _) => {}
+        Expression::Let(definitions, _) => {
+            if !definitions.iter().any(|d| d.recursive) {
+                evaluated[..definitions.len()].fill(true);
+            }
+        }
+        Expression::Match(_, _)
+        | Expression::If(_, _, _)
+        | Expression::And(_, _)
+        | Expression::Or(_, _) => evaluated[0] = true,
+        _ => evaluated.fill(true),
+    }
+    evaluated
+}
+
+/// `t`, or if that's `used`, the first of `t1`, `t2`, ... which isn't.
+pub(crate) fn temporary(used: &HashSet<String>) -> String {
+    std::iter::once("t".to_string())
+        .chain((1..).map(|i| format!("t{}", i)))
+        .find(|name| !used.contains(name))
+        .expect("there are only finitely many names in use")
+}
+
+/// Whether `var` is free in any function within `e`. This may be the case
+/// even if that occurrence refers to a binding within `e`, so it's only a
+/// conservative test of whether the variable of an enclosing binding is.
+fn used_in_function(e: &Expression, var: &str) -> bool {
+    fold(e, false, |used, e| {
+        used || matches!(e, Expression::Fn(_, _)) && free_vars(e).contains(var)
+    })
+}
+
+/// Whether evaluating `e` certainly terminates, though it may fail, e.g. by
+/// dividing by zero. Applying a function might not, but everything else does.
+/// Function bodies aren't evaluated until they're applied, so may contain
+/// anything.
+fn terminates(e: &Expression) -> bool {
+    match e {
+        Expression::Apply(_, _) => false,
+        Expression::Fn(_, _) => true,
+        e => subexpressions(e).into_iter().all(terminates),
+    }
+}
+
+/// Whether evaluating `e` certainly terminates and succeeds, so it can be
+/// removed if its value isn't needed. Building a function, pair or list can't
+/// fail, but anything else can, e.g. by dividing by zero, overflowing or being
+/// given the wrong type, so is only pure if it's closed and evaluates.
+pub(crate) fn is_pure(e: &Expression) -> bool {
+    use Expression::*;
+    match e {
+        Fn(_, _) => true,
+        e if is_literal(e) || matches!(e, Var(_)) => true,
+        Pair(_, _) | Cons(_, _) | Let(_, _) => subexpressions(e).into_iter().all(is_pure),
+        e => terminates(e) && free_vars(e).is_empty() && interp::eval(e).is_ok(),
+    }
+}
+
+/// Whether `e` is an operation, besides building a pair or list, with only
+/// literal operands.
+fn is_foldable(e: &Expression) -> bool {
+    let operation = match e {
+        Expression::And(_, _) | Expression::Or(_, _) => true,
+        e => {
+            matches!(Primitive::of(e), Some((op, _)) if op != Primitive::Pair && op != Primitive::Cons)
+        }
+    };
+    operation && subexpressions(e).into_iter().all(is_literal)
+}
+
+fn is_literal(e: &Expression) -> bool {
+    use Expression::*;
+    matches!(
+        e,
+        Num(_) | Float(_) | True | False | Str(_) | Char(_) | Unit | Nil
+    )
+}
+
+/// The literal which evaluates to `v`, if there is one. Infinities and NaNs
+/// can't be written as literals.
+fn literal(v: Value) -> Option<Expression> {
+    Some(match v {
+        Value::Int(n) => Expression::Num(n),
+        Value::Float(f) if f.is_finite() => Expression::Float(f),
+        Value::Bool(true) => Expression::True,
+        Value::Bool(false) => Expression::False,
+        Value::Str(s) => Expression::Str(s),
+        Value::Char(c) => Expression::Char(c),
+        Value::Unit => Expression::Unit,
+        Value::Nil => Expression::Nil,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn folded(e: &str) -> String {
+        const_fold(parse(e).unwrap()).to_string()
+    }
+
+    #[test]
+    fn test_const_fold() {
+        assert_eq!(folded("1 + 2 * 3"), "7");
+        assert_eq!(folded("not(true)"), "false");
+        assert_eq!(folded("1.5 * 2.0"), "3.0");
+        assert_eq!(folded("succ(pred(-5))"), "-5");
+        assert_eq!(folded("1 < 2 and 'a' == 'b'"), "false");
+        assert_eq!(folded("\"a\" < \"b\""), "true");
+        assert_eq!(folded("if 1 < 2 then x else y"), "x");
+        assert_eq!(folded("hd(nil)"), "hd(nil)");
+        // The constant parts of an expression are folded, and the rest kept.
+        assert_eq!(folded("x + (1 + 2)"), "x + 3");
+        assert_eq!(folded("fn x. (x * (2 * 3))"), "fn x. (x * 6)");
+        assert_eq!(folded("f (10 - 4) y"), "f 6 y");
+        assert_eq!(folded("if b then 1 + 1 else 2"), "if b then 2 else 2");
+        assert_eq!(folded("<1 + 1, [2 * 2]>"), "<2, 4 :: nil>");
+        // Failing operations are left for evaluation.
+        assert_eq!(folded("1 / 0"), "1 / 0");
+        assert_eq!(folded("(2 + 3) % (1 - 1)"), "5 % 0");
+        assert_eq!(folded("9223372036854775807 + 1"), "9223372036854775807 + 1");
+        assert_eq!(folded("1 + true"), "1 + true");
+        assert_eq!(folded("0.0 / 0.0"), "0.0 / 0.0");
+    }
+
+    #[test]
+    fn test_elim_dead_let() {
+        let eliminated = |e| elim_dead_let(parse(e).unwrap()).to_string();
+        assert_eq!(eliminated("let x = 2 * 3 * 4 + 5 in 1"), "1");
+        assert_eq!(eliminated("let x = f () in x"), "let x = f () in x");
+        // Applications mightn't terminate, so aren't removed.
+        assert_eq!(eliminated("let x = f () in 1"), "let x = f () in 1");
+        assert_eq!(eliminated("let x = fn y. f y in 1"), "1");
+        assert_eq!(
+            eliminated("let x = 1 and y = 2 and z = f 3 in y"),
+            "let y = 2 and z = f 3 in y"
+        );
+        // The inner let is removed first, leaving x unused.
+        assert_eq!(eliminated("let x = 1 in let y = x in 2"), "2");
+        assert_eq!(eliminated("fn z. let x = z in z"), "fn z. z");
+        // A recursive function which is only used by itself is unused.
+        assert_eq!(
+            eliminated("let rec f = fn n. f n and g = fn n. n in g 1"),
+            "let rec g = fn n. n in g 1"
+        );
+        assert_eq!(
+            eliminated("let rec f = fn n. g n and g = fn n. n in f 1"),
+            "let rec f = fn n. g n and g = fn n. n in f 1"
+        );
+        assert_eq!(eliminated("let rec f = fn n. f n in 1"), "1");
+        // Definitions which would fail are kept, so the failure still happens.
+        assert_eq!(eliminated("let x = 1 / 0 in 2"), "let x = 1 / 0 in 2");
+        assert_eq!(eliminated("let x = hd(nil) in 2"), "let x = hd(nil) in 2");
+        assert_eq!(
+            eliminated("let x = 9223372036854775807 + 1 in 2"),
+            "let x = 9223372036854775807 + 1 in 2"
+        );
+        assert_eq!(eliminated("let x = y + 1 in 2"), "let x = y + 1 in 2");
+        assert_eq!(
+            eliminated("let x = <1 / 0, 2> in 2"),
+            "let x = <1 / 0, 2> in 2"
+        );
+        assert_eq!(
+            eliminated("let x = { a = 1 }.b in 2"),
+            "let x = { a = 1 }.b in 2"
+        );
+        // ...unless they're known to succeed.
+        assert_eq!(eliminated("let x = 1 / 2 in 2"), "2");
+        assert_eq!(eliminated("let x = <y, (1 + 2) :: nil> in 2"), "2");
+        let e = parse("let x = 1 / 0 in 2").unwrap();
+        assert_eq!(interp::eval(&elim_dead_let(e.clone())), interp::eval(&e));
+    }
+
+    #[test]
+    fn test_inline_lets() {
+        let inlined = |e| inline_lets(parse(e).unwrap()).to_string();
+        assert_eq!(inlined("let x = 1 + 2 in x * 3"), "(1 + 2) * 3");
+        assert_eq!(inlined("let x = y in <x, 1>"), "<y, 1>");
+        // Multiple uses would repeat the work.
+        assert_eq!(inlined("let x = 1 + 2 in x * x"), "let x = 1 + 2 in x * x");
+        // So might a use within a function, and an impure value may not terminate.
+        assert_eq!(
+            inlined("let x = 1 + 2 in fn y. (x + y)"),
+            "let x = 1 + 2 in fn y. (x + y)"
+        );
+        assert_eq!(inlined("let x = f 1 in x + 2"), "let x = f 1 in x + 2");
+        // Unused definitions are left for elim_dead_let.
+        assert_eq!(inlined("let x = 1 in 2"), "let x = 1 in 2");
+        assert_eq!(
+            inlined("let x = 1 and y = 2 in x + y * y"),
+            "let y = 2 in 1 + y * y"
+        );
+        // y would be captured by the group's y.
+        assert_eq!(
+            inlined("let x = y and y = 2 in x + y"),
+            "let x = y in x + 2"
+        );
+        assert_eq!(
+            inlined("let rec f = fn n. n in f 1"),
+            "let rec f = fn n. n in f 1"
+        );
+        // Inner lets are inlined first.
+        assert_eq!(inlined("let x = 1 in let y = x in y * 2"), "1 * 2");
+        // While x is unknown, x + 1 might fail, so isn't moved.
+        assert_eq!(
+            inlined("let x = 1 in let y = x + 1 in y * 2"),
+            "let y = 1 + 1 in y * 2"
+        );
+        // Substitution renames bound variables which would capture the value's.
+        assert_eq!(
+            inlined("let x = <y, 1> in let y = 2 in <x, <y, y>>"),
+            "let y1 = 2 in <<y, 1>, <y1, y1>>"
+        );
+        // A definition which would fail isn't moved to a use which mightn't
+        // be evaluated.
+        for source in [
+            "let x = 1 / 0 in if false then x else 0",
+            "let x = hd(nil) in false and x",
+            "let x = 9223372036854775807 + 1 in true or x == 1",
+        ] {
+            let e = parse(source).unwrap();
+            assert_eq!(inline_lets(e.clone()), e, "{}", source);
+            assert!(interp::eval(&e).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_cse() {
+        let eliminated = |e| cse(parse(e).unwrap()).to_string();
+        assert_eq!(eliminated("(a + b) * (a + b)"), "let t = a + b in t * t");
+        assert_eq!(eliminated("a + b * c"), "a + b * c");
+        // Calls mightn't terminate, or might be expensive, so aren't shared.
+        assert_eq!(eliminated("f 1 + f 1"), "f 1 + f 1");
+        assert_eq!(eliminated("(f 1 + 2) * (f 1 + 2)"), "(f 1 + 2) * (f 1 + 2)");
+        // The largest common subexpression is shared, then what's left.
+        assert_eq!(
+            eliminated("(a + b) * c + (a + b) * c + (a + b)"),
+            "let t1 = a + b in let t = t1 * c in t + t + t1"
+        );
+        // Temporaries don't clash with the program's variables.
+        assert_eq!(eliminated("(t + 1) * (t + 1)"), "let t1 = t + 1 in t1 * t1");
+        // Functions are their own scope.
+        assert_eq!(
+            eliminated("fn x. ((x + 1) * (x + 1))"),
+            "fn x. let t = x + 1 in t * t"
+        );
+        assert_eq!(
+            eliminated("<fn x. (x + 1), fn x. (x + 1)>"),
+            "<fn x. (x + 1), fn x. (x + 1)>"
+        );
+        // Branches are only evaluated if taken, so nothing's hoisted out of
+        // them, but within them is fine.
+        assert_eq!(
+            eliminated("if y == 0 then 0 else x / y + x / y"),
+            "if y == 0 then 0 else let t = x / y in t + t"
+        );
+        assert_eq!(
+            eliminated("x / y > 1 and x / y < 2"),
+            "x / y > 1 and x / y < 2"
+        );
+        assert_eq!(
+            eliminated("let x = a * a in x + a * a"),
+            "let x = a * a in x + a * a"
+        );
+    }
+}
@@ -1,274 +1,2847 @@
-use anyhow::Result;
+use crate::intern::intern;
+use lazy_static::lazy_static;
+use pest::error::{ErrorVariant, InputLocation};
 use pest::iterators::Pair;
+use pest::pratt_parser::{Assoc, Op, PrattParser};
 use pest::Parser;
 use pest_derive::Parser;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::rc::Rc;
 
 #[derive(Parser)]
 #[grammar = "miniml.pest"]
 struct MiniMLParser;
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expression {
     True,
     False,
-    Num(u32),
+    Num(i64),
+    Float(f64),
+    Str(String),
+    Char(char),
+    Var(Variable),
+    Nil,
+    Unit,
+    Let(Vec<Definition>, Rc<Expression>),
+    Not(Rc<Expression>),
+    If(Rc<Expression>, Rc<Expression>, Rc<Expression>),
+    Succ(Rc<Expression>),
+    Pred(Rc<Expression>),
+    Neg(Rc<Expression>),
+    Fst(Rc<Expression>),
+    Snd(Rc<Expression>),
+    Hd(Rc<Expression>),
+    Tl(Rc<Expression>),
+    Pair(Rc<Expression>, Rc<Expression>),
+    Tuple(Vec<Expression>),
+    /// Named fields in the order they were written, e.g. `{ a = 1, b = 2 }`.
+    Record(Vec<(String, Expression)>),
+    Field(Rc<Expression>, String),
+    Fn(Variable, Rc<Expression>),
+    Eq(Rc<Expression>, Rc<Expression>),
+    Neq(Rc<Expression>, Rc<Expression>),
+    Lt(Rc<Expression>, Rc<Expression>),
+    Gt(Rc<Expression>, Rc<Expression>),
+    Le(Rc<Expression>, Rc<Expression>),
+    Ge(Rc<Expression>, Rc<Expression>),
+    Cons(Rc<Expression>, Rc<Expression>),
+
+    And(Rc<Expression>, Rc<Expression>),
+    Or(Rc<Expression>, Rc<Expression>),
+
+    Add(Rc<Expression>, Rc<Expression>),
+    Sub(Rc<Expression>, Rc<Expression>),
+    Mul(Rc<Expression>, Rc<Expression>),
+    Div(Rc<Expression>, Rc<Expression>),
+    Mod(Rc<Expression>, Rc<Expression>),
+    Apply(Rc<Expression>, Rc<Expression>),
+    Seq(Rc<Expression>, Rc<Expression>),
+    Match(Rc<Expression>, Vec<MatchArm>),
+    Ann(Rc<Expression>, Type),
+}
+
+/// Float literals compare by their bits rather than as `f64`s do, so that a
+/// `NaN` literal equals itself, as `Eq` requires, and `0.0` and `-0.0` are
+/// different literals.
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        use Expression::*;
+        if let (Some((op1, l1, r1)), Some((op2, l2, r2))) = (self.as_binary(), other.as_binary()) {
+            return op1 == op2 && l1 == l2 && r1 == r2;
+        }
+        match (self, other) {
+            (True, True) | (False, False) | (Nil, Nil) | (Unit, Unit) => true,
+            (Num(n1), Num(n2)) => n1 == n2,
+            (Float(f1), Float(f2)) => f1.to_bits() == f2.to_bits(),
+            (Str(s1), Str(s2)) => s1 == s2,
+            (Char(c1), Char(c2)) => c1 == c2,
+            (Var(v1), Var(v2)) => v1 == v2,
+            (Let(definitions1, body1), Let(definitions2, body2)) => {
+                definitions1 == definitions2 && body1 == body2
+            }
+            (Not(e1), Not(e2))
+            | (Succ(e1), Succ(e2))
+            | (Pred(e1), Pred(e2))
+            | (Neg(e1), Neg(e2))
+            | (Fst(e1), Fst(e2))
+            | (Snd(e1), Snd(e2))
+            | (Hd(e1), Hd(e2))
+            | (Tl(e1), Tl(e2)) => e1 == e2,
+            (If(cond1, yes1, no1), If(cond2, yes2, no2)) => {
+                cond1 == cond2 && yes1 == yes2 && no1 == no2
+            }
+            (Pair(l1, r1), Pair(l2, r2)) | (Apply(l1, r1), Apply(l2, r2)) => l1 == l2 && r1 == r2,
+            (Tuple(es1), Tuple(es2)) => es1 == es2,
+            (Record(fields1), Record(fields2)) => fields1 == fields2,
+            (Field(e1, f1), Field(e2, f2)) => e1 == e2 && f1 == f2,
+            (Fn(v1, body1), Fn(v2, body2)) => v1 == v2 && body1 == body2,
+            (Match(e1, arms1), Match(e2, arms2)) => e1 == e2 && arms1 == arms2,
+            (Ann(e1, t1), Ann(e2, t2)) => e1 == e2 && t1 == t2,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Expression {}
+
+/// `f64` isn't `Hash`, so float literals are hashed by their bits, as they're
+/// compared.
+impl Hash for Expression {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        use Expression::*;
+        std::mem::discriminant(self).hash(state);
+        match self {
+            True | False | Nil | Unit => {}
+            Num(n) => n.hash(state),
+            Float(f) => f.to_bits().hash(state),
+            Str(s) => s.hash(state),
+            Char(c) => c.hash(state),
+            Var(v) => v.hash(state),
+            Let(definitions, body) => {
+                definitions.hash(state);
+                body.hash(state);
+            }
+            Not(e) | Succ(e) | Pred(e) | Neg(e) | Fst(e) | Snd(e) | Hd(e) | Tl(e) => e.hash(state),
+            If(cond, yes, no) => {
+                cond.hash(state);
+                yes.hash(state);
+                no.hash(state);
+            }
+            Pair(e1, e2)
+            | Eq(e1, e2)
+            | Neq(e1, e2)
+            | Lt(e1, e2)
+            | Gt(e1, e2)
+            | Le(e1, e2)
+            | Ge(e1, e2)
+            | Cons(e1, e2)
+            | And(e1, e2)
+            | Or(e1, e2)
+            | Add(e1, e2)
+            | Sub(e1, e2)
+            | Mul(e1, e2)
+            | Div(e1, e2)
+            | Mod(e1, e2)
+            | Apply(e1, e2)
+            | Seq(e1, e2) => {
+                e1.hash(state);
+                e2.hash(state);
+            }
+            Tuple(es) => es.hash(state),
+            Record(fields) => fields.hash(state),
+            Field(e, f) => {
+                e.hash(state);
+                f.hash(state);
+            }
+            Fn(v, body) => {
+                v.hash(state);
+                body.hash(state);
+            }
+            Match(e, arms) => {
+                e.hash(state);
+                arms.hash(state);
+            }
+            Ann(e, t) => {
+                e.hash(state);
+                t.hash(state);
+            }
+        }
+    }
+}
+
+/// The type of an expression, as written in annotations, or inferred.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Type {
+    Int,
+    Bool,
+    Float,
+    String,
+    Char,
+    Unit,
+    Fn(Box<Type>, Box<Type>),
+    Pair(Box<Type>, Box<Type>),
+    List(Box<Type>),
+    /// A type variable, which stands for any type. Only inference makes these;
+    /// they can't be written in annotations.
+    Var(u32),
+}
+
+/// One `pattern when guard -> body` arm of a `match`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    /// Must evaluate to true, with the pattern's variables bound, for the arm to be taken.
+    pub guard: Option<Expression>,
+    pub body: Expression,
+}
+
+/// The left hand side of a `match` arm.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Pattern {
+    Wildcard,
     Var(Variable),
+    /// A unit, boolean, number, string or character literal.
+    Literal(Expression),
     Nil,
-    Let(Variable, Box<Expression>, Box<Expression>),
-    Not(Box<Expression>),
-    If(Box<Expression>, Box<Expression>, Box<Expression>),
-    Succ(Box<Expression>),
-    Pred(Box<Expression>),
-    Fst(Box<Expression>),
-    Snd(Box<Expression>),
-    Hd(Box<Expression>),
-    Tl(Box<Expression>),
-    Pair(Box<Expression>, Box<Expression>),
-    Fn(Variable, Box<Expression>),
-    Eq(Box<Expression>, Box<Expression>),
-    Cons(Box<Expression>, Box<Expression>),
-
-    And(Box<Expression>, Box<Expression>),
-
-    Add(Box<Expression>, Box<Expression>),
-    Apply(Box<Expression>, Box<Expression>),
+    Cons(Box<Pattern>, Box<Pattern>),
+    Pair(Box<Pattern>, Box<Pattern>),
 }
 
 #[derive(Clone, PartialEq, Debug, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Variable {
-    pub ident: String,
+    /// Interned by the parser, so every occurrence of a name shares its storage.
+    pub ident: Rc<str>,
 }
 
-#[derive(Clone)]
-enum PartialExpressionOperator {
-    Apply,
-    Add,
+/// A whole program: top-level `let` declarations, then optionally a main expression.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Program {
+    /// One entry per top-level `let`, so a `let rec ... and ...` group stays together.
+    pub declarations: Vec<Vec<Definition>>,
+    pub main: Option<Expression>,
+}
+
+/// A binding of `var` to `expr`, as in `let var = expr`. Every definition of a
+/// `let ... and ...` group has the same `recursive` flag.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Definition {
+    pub var: Variable,
+    pub expr: Rc<Expression>,
+    /// Whether `var` is in scope in `expr`, as in `let rec`.
+    pub recursive: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BinOp {
+    Seq,
+    Or,
     And,
+    Eq,
+    Neq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
     Cons,
-    Equals,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+/// Every binary operator with its grammar rule, precedence and associativity.
+/// Higher precedences bind tighter.
+const FIXITY: &[(BinOp, Rule, u8, Assoc)] = &[
+    (BinOp::Seq, Rule::seq_op, 1, Assoc::Right),
+    (BinOp::Or, Rule::or_op, 2, Assoc::Left),
+    (BinOp::And, Rule::and_op, 3, Assoc::Left),
+    (BinOp::Eq, Rule::eq_op, 4, Assoc::Left),
+    (BinOp::Neq, Rule::neq_op, 4, Assoc::Left),
+    (BinOp::Lt, Rule::lt_op, 4, Assoc::Left),
+    (BinOp::Gt, Rule::gt_op, 4, Assoc::Left),
+    (BinOp::Le, Rule::le_op, 4, Assoc::Left),
+    (BinOp::Ge, Rule::ge_op, 4, Assoc::Left),
+    (BinOp::Cons, Rule::cons_op, 5, Assoc::Right),
+    (BinOp::Add, Rule::add_op, 6, Assoc::Left),
+    (BinOp::Sub, Rule::sub_op, 6, Assoc::Left),
+    (BinOp::Mul, Rule::mul_op, 7, Assoc::Left),
+    (BinOp::Div, Rule::div_op, 7, Assoc::Left),
+    (BinOp::Mod, Rule::mod_op, 7, Assoc::Left),
+];
+
+impl BinOp {
+    fn from_rule(rule: Rule) -> BinOp {
+        FIXITY
+            .iter()
+            .find(|(_, r, _, _)| *r == rule)
+            .map(|(op, _, _, _)| *op)
+            .unwrap_or_else(|| unreachable!("not a binary operator: {:?}", rule))
+    }
+
+    /// Build the expression applying this operator to `left` and `right`.
+    pub fn apply(self, left: Expression, right: Expression) -> Expression {
+        let (l, r) = (Rc::new(left), Rc::new(right));
+        match self {
+            BinOp::Seq => Expression::Seq(l, r),
+            BinOp::Or => Expression::Or(l, r),
+            BinOp::And => Expression::And(l, r),
+            BinOp::Eq => Expression::Eq(l, r),
+            BinOp::Neq => Expression::Neq(l, r),
+            BinOp::Lt => Expression::Lt(l, r),
+            BinOp::Gt => Expression::Gt(l, r),
+            BinOp::Le => Expression::Le(l, r),
+            BinOp::Ge => Expression::Ge(l, r),
+            BinOp::Cons => Expression::Cons(l, r),
+            BinOp::Add => Expression::Add(l, r),
+            BinOp::Sub => Expression::Sub(l, r),
+            BinOp::Mul => Expression::Mul(l, r),
+            BinOp::Div => Expression::Div(l, r),
+            BinOp::Mod => Expression::Mod(l, r),
+        }
+    }
+
+    /// How tightly this operator binds; higher binds tighter.
+    pub fn precedence(self) -> u8 {
+        self.fixity().2
+    }
+
+    /// Whether `a op b op c` groups as `a op (b op c)`.
+    pub fn is_right_assoc(self) -> bool {
+        matches!(self.fixity().3, Assoc::Right)
+    }
+
+    /// The operator as written in source.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Seq => ";",
+            BinOp::Or => "or",
+            BinOp::And => "and",
+            BinOp::Eq => "==",
+            BinOp::Neq => "!=",
+            BinOp::Lt => "<",
+            BinOp::Gt => ">",
+            BinOp::Le => "<=",
+            BinOp::Ge => ">=",
+            BinOp::Cons => "::",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+        }
+    }
+
+    fn fixity(self) -> &'static (BinOp, Rule, u8, Assoc) {
+        FIXITY.iter().find(|(op, _, _, _)| *op == self).unwrap()
+    }
+}
+
+impl Expression {
+    /// Split a binary operation into its operator and operands.
+    pub fn as_binary(&self) -> Option<(BinOp, &Expression, &Expression)> {
+        use Expression::*;
+        let (op, l, r) = match self {
+            Seq(l, r) => (BinOp::Seq, l, r),
+            Or(l, r) => (BinOp::Or, l, r),
+            And(l, r) => (BinOp::And, l, r),
+            Eq(l, r) => (BinOp::Eq, l, r),
+            Neq(l, r) => (BinOp::Neq, l, r),
+            Lt(l, r) => (BinOp::Lt, l, r),
+            Gt(l, r) => (BinOp::Gt, l, r),
+            Le(l, r) => (BinOp::Le, l, r),
+            Ge(l, r) => (BinOp::Ge, l, r),
+            Cons(l, r) => (BinOp::Cons, l, r),
+            Add(l, r) => (BinOp::Add, l, r),
+            Sub(l, r) => (BinOp::Sub, l, r),
+            Mul(l, r) => (BinOp::Mul, l, r),
+            Div(l, r) => (BinOp::Div, l, r),
+            Mod(l, r) => (BinOp::Mod, l, r),
+            _ => return None,
+        };
+        Some((op, l, r))
+    }
+}
+
+lazy_static! {
+    /// Resolves `e_binary` chains according to FIXITY, one level per precedence.
+    static ref PRATT_PARSER: PrattParser<Rule> = {
+        let max_precedence = FIXITY.iter().map(|(_, _, p, _)| *p).max().unwrap();
+        (1..=max_precedence).fold(PrattParser::new(), |pratt, precedence| {
+            let level = FIXITY
+                .iter()
+                .filter(|(_, _, p, _)| *p == precedence)
+                .map(|(_, rule, _, assoc)| Op::infix(*rule, *assoc))
+                .reduce(|ops, op| ops | op)
+                .unwrap();
+            pratt.op(level)
+        })
+        .op(Op::prefix(Rule::neg_op))
+    };
+}
+
+/// The byte ranges of the source an expression was parsed from: `span` for
+/// the whole expression, and `children` for its subexpressions, in the order
+/// of [`crate::exprs::subexpressions`]. Desugared nodes span the source they
+/// came from, e.g. each cons of `[1, 2]` spans from its head to the `]`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Spans {
+    pub span: Range<usize>,
+    pub children: Vec<Spans>,
+}
+
+impl Spans {
+    fn leaf(span: Range<usize>) -> Spans {
+        Spans {
+            span,
+            children: vec![],
+        }
+    }
+
+    /// The spans of a binary node, from the start of `left` to the end of `right`.
+    fn spanning(left: Spans, right: Spans) -> Spans {
+        Spans {
+            span: left.span.start..right.span.end,
+            children: vec![left, right],
+        }
+    }
+}
+
+/// An error produced while parsing MiniML source.
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// A human-readable description of what went wrong.
+    pub message: String,
+    /// The byte offset into the input at which parsing failed.
+    pub offset: usize,
+    /// What the parser was expecting to find at `offset`, if anything in particular.
+    pub expected: Option<String>,
+}
+
+/// The ways in which parsing can fail.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ParseErrorKind {
+    /// The input isn't valid MiniML.
+    Syntax,
+    /// The input nests more deeply than the parser was willing to go.
+    DepthLimitExceeded { limit: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<pest::error::Error<Rule>> for ParseError {
+    fn from(e: pest::error::Error<Rule>) -> Self {
+        let offset = match e.location {
+            InputLocation::Pos(pos) => pos,
+            InputLocation::Span((start, _end)) => start,
+        };
+        match e.variant {
+            ErrorVariant::ParsingError { positives, .. } => {
+                let mut expected: Vec<&str> = Vec::new();
+                for rule in positives.iter().map(describe_rule) {
+                    if !expected.contains(&rule) {
+                        expected.push(rule);
+                    }
+                }
+                // Identifiers, booleans and numbers are all expressions too.
+                if expected.contains(&"expression") {
+                    expected.retain(|rule| !matches!(*rule, "identifier" | "boolean" | "number"));
+                }
+                let expected = if expected.is_empty() {
+                    None
+                } else {
+                    Some(expected.join(" or "))
+                };
+                ParseError {
+                    kind: ParseErrorKind::Syntax,
+                    message: match &expected {
+                        Some(expected) => format!("expected {}", expected),
+                        None => "unexpected input".to_string(),
+                    },
+                    offset,
+                    expected,
+                }
+            }
+            ErrorVariant::CustomError { message } => ParseError {
+                kind: ParseErrorKind::Syntax,
+                message,
+                offset,
+                expected: None,
+            },
+        }
+    }
+}
+
+/// Describe a grammar rule in terms a MiniML programmer would recognise.
+fn describe_rule(rule: &Rule) -> &'static str {
+    match rule {
+        Rule::EOI => "end of input",
+        // `rec` is optional after `let`, so it's really the name that's missing.
+        Rule::x | Rule::var_stmt | Rule::rec_kw => "identifier",
+        Rule::c_num => "number",
+        Rule::c_bool => "boolean",
+        Rule::string_end => "closing quote",
+        Rule::ty | Rule::ty_pair | Rule::ty_list | Rule::ty_name => "type",
+        Rule::pattern
+        | Rule::p_wildcard
+        | Rule::p_nil
+        | Rule::p_literal
+        | Rule::p_var
+        | Rule::p_pair => "pattern",
+        Rule::seq_op
+        | Rule::add_op
+        | Rule::sub_op
+        | Rule::mul_op
+        | Rule::div_op
+        | Rule::mod_op
+        | Rule::or_op
+        | Rule::and_op
+        | Rule::cons_op
+        | Rule::eq_op
+        | Rule::neq_op
+        | Rule::lt_op
+        | Rule::gt_op
+        | Rule::le_op
+        | Rule::ge_op => "operator",
+        _ => "expression",
+    }
+}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+/// How deeply `parse` and friends let expressions nest. See [`parse_with_depth_limit`].
+pub const DEFAULT_DEPTH_LIMIT: usize = 1000;
+
+/// Parse a complete MiniML expression.
+pub fn parse(input: &str) -> Result<Expression> {
+    parse_spanned(input).map(|(e, _)| e)
+}
+
+/// Parse a complete MiniML expression, along with where in `input` each of
+/// its subexpressions came from.
+pub fn parse_spanned(input: &str) -> Result<(Expression, Spans)> {
+    let file = parse_rule(Rule::file, input, DEFAULT_DEPTH_LIMIT)?;
+
+    transform(file)
+}
+
+/// Parse a complete MiniML expression, failing with
+/// [`ParseErrorKind::DepthLimitExceeded`] rather than running out of stack
+/// if it nests more than `limit` deep, e.g. `succ(succ(0))` is two deep, as
+/// is `1 :: 2 :: nil`.
+pub fn parse_with_depth_limit(input: &str, limit: usize) -> Result<Expression> {
+    transform(parse_rule(Rule::file, input, limit)?).map(|(e, _)| e)
 }
 
-pub fn parser(input: &str) -> Result<Expression> {
-    let file = MiniMLParser::parse(Rule::file, input)
-        .expect("Bad parse")
-        .next()
-        .unwrap();
+/// Parse a complete MiniML program, e.g.
+/// `let double = fn x. x + x ;; let four = double 2 ;; four`.
+pub fn parse_program(input: &str) -> Result<Program> {
+    let program = parse_rule(Rule::program, input, DEFAULT_DEPTH_LIMIT)?;
 
-    println!("{:?}", file);
-    println!("");
+    let mut declarations = Vec::new();
+    let mut main = None;
+    for pair in program.into_inner() {
+        match pair.as_rule() {
+            Rule::declaration => {
+                declarations.push(transform_definitions(pair.into_inner().collect())?.0)
+            }
+            Rule::EOI => {}
+            _ => main = Some(transform_parse_output(pair)?),
+        }
+    }
+    Ok(Program { declarations, main })
+}
+
+/// The stack pest needs for each level of nesting, with room to spare.
+const STACK_PER_LEVEL: usize = 32 * 1024;
+/// The most stack pest will be given, however deep the input might go.
+const MAX_STACK: usize = 1 << 30;
+
+/// Run pest over `input`, first making sure there's enough stack for it.
+fn parse_rule(rule: Rule, input: &str, limit: usize) -> Result<Pair<'_, Rule>> {
+    let depth = nesting(input, limit)?;
+    let stack = (depth + 1).saturating_mul(STACK_PER_LEVEL).min(MAX_STACK);
+    let mut pairs = stacker::maybe_grow(stack, stack, || {
+        MiniMLParser::parse(rule, input).map_err(|e| parse_error(e, limit))
+    })?;
+    Ok(pairs.next().unwrap())
+}
+
+/// Turn a pest error into ours. pest gives up on input which recurses more
+/// than it has stack or calls for, which is the input being too deep.
+fn parse_error(e: pest::error::Error<Rule>, limit: usize) -> ParseError {
+    match &e.variant {
+        ErrorVariant::CustomError { message }
+            if matches!(
+                message.as_str(),
+                "call limit reached" | "stack limit reached"
+            ) =>
+        {
+            let offset = match e.location {
+                InputLocation::Pos(pos) => pos,
+                InputLocation::Span((start, _end)) => start,
+            };
+            too_deep(limit, offset)
+        }
+        _ => ParseError::from(e),
+    }
+}
+
+fn too_deep(limit: usize, offset: usize) -> ParseError {
+    ParseError {
+        kind: ParseErrorKind::DepthLimitExceeded { limit },
+        message: format!("expression nested more than {} deep", limit),
+        offset,
+        expected: None,
+    }
+}
+
+/// Something the rest of the input nests within, until whatever ends it.
+#[derive(Clone, Copy, PartialEq)]
+enum Level {
+    /// An open bracket, or the `<` of a pair, with the `-`s before it. A pair
+    /// is only closed by a `>` once its `,` has been seen, since until then a
+    /// `>` is a comparison.
+    Bracket {
+        prefixes: usize,
+        pair: bool,
+        comma: bool,
+    },
+    /// A keyword waiting for the word which continues it, e.g. `let` waits for
+    /// `in`, and a `match`'s arms each wait for the `|` of the next.
+    Waiting(&'static [u8]),
+    /// Anything else which nests what follows it, e.g. `fn x.`, or the `::`
+    /// of `1 :: 2 :: nil`.
+    Open,
+}
 
-    transform_parse_output(file)
+/// The levels the input has reached so far, innermost last.
+#[derive(Default)]
+struct Levels {
+    levels: Vec<Level>,
+    depth: usize,
+}
+
+impl Levels {
+    fn size(level: &Level) -> usize {
+        match level {
+            Level::Bracket { prefixes, .. } => prefixes + 1,
+            _ => 1,
+        }
+    }
+
+    fn push(&mut self, level: Level) {
+        self.depth += Self::size(&level);
+        self.levels.push(level);
+    }
+
+    /// The innermost level `f` accepts, which for anything but a bracket must
+    /// be within the innermost bracket.
+    fn find(&self, f: impl Fn(&Level) -> bool) -> Option<usize> {
+        let i = self
+            .levels
+            .iter()
+            .rposition(|level| f(level) || matches!(level, Level::Bracket { .. }))?;
+        f(&self.levels[i]).then_some(i)
+    }
+
+    /// Close the levels after the `i`th, or all of them.
+    fn close_after(&mut self, i: Option<usize>) {
+        for level in self.levels.drain(i.map_or(0, |i| i + 1)..) {
+            self.depth -= Self::size(&level);
+        }
+    }
+}
+
+/// Measure how deeply `input` nests, or fail if it goes more than `limit`
+/// deep. Brackets are counted without regard to whether they match. A run of
+/// `-`s nests as deep as its length, e.g. `- - x`, as `-(-(x))`. So do
+/// keywords, e.g. `fn x. fn y. x`, and right-associative operators, e.g.
+/// `1 :: 2 :: nil`, until their enclosing bracket or keyword ends.
+fn nesting(input: &str, limit: usize) -> Result<usize> {
+    let mut levels = Levels::default();
+    let mut deepest = 0;
+    let mut comments = 0;
+    // The `-`s since the last operand.
+    let mut prefixes = 0;
+    // Whether what came last could end an operand, so that a `<` after it is
+    // a comparison rather than a pair.
+    let mut operand = false;
+    let bracket = |l: &Level| matches!(l, Level::Bracket { .. });
+    let bytes = input.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        let start = i;
+        i += 1;
+        if rest.starts_with(b"{-") {
+            comments += 1;
+            levels.depth += 1;
+            i += 1;
+        } else if comments > 0 {
+            if rest.starts_with(b"-}") {
+                comments -= 1;
+                levels.depth -= 1;
+                i += 1;
+            }
+            continue;
+        } else if rest.starts_with(b"--") {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+            continue;
+        } else if rest[0].is_ascii_whitespace() {
+            continue;
+        } else if rest.starts_with(b"->") || rest.starts_with(b"<=") || rest.starts_with(b">=") {
+            i += 1;
+            prefixes = 0;
+            operand = false;
+            continue;
+        } else if rest[0] == b'-' {
+            prefixes += 1;
+            operand = false;
+        } else if matches!(rest[0], b'"' | b'\'') {
+            while i < bytes.len() && bytes[i] != rest[0] {
+                i += if bytes[i] == b'\\' { 2 } else { 1 };
+            }
+            i += 1;
+            prefixes = 0;
+            operand = true;
+            continue;
+        } else if matches!(rest[0], b'(' | b'[' | b'{') || rest[0] == b'<' && !operand {
+            levels.push(Level::Bracket {
+                prefixes,
+                pair: rest[0] == b'<',
+                comma: false,
+            });
+            prefixes = 0;
+            operand = false;
+        } else if matches!(rest[0], b')' | b']' | b'}' | b'>') {
+            let closed = levels.levels.iter().rposition(|l| match l {
+                Level::Bracket { pair, comma, .. } => rest[0] != b'>' || *pair && *comma,
+                _ => false,
+            });
+            // Otherwise it's a comparison, or a stray bracket.
+            if let Some(closed) = closed {
+                levels.close_after(closed.checked_sub(1));
+            }
+            prefixes = 0;
+            operand = closed.is_some();
+            continue;
+        } else {
+            prefixes = 0;
+            operand = false;
+            if rest[0] == b',' {
+                let within = levels.levels.iter().rposition(bracket);
+                levels.close_after(within);
+                if let Some(Level::Bracket { comma, .. }) = within.map(|i| &mut levels.levels[i]) {
+                    *comma = true;
+                }
+            } else if rest.starts_with(b";;") {
+                // The declarations of a program don't nest.
+                i += 1;
+                levels.close_after(levels.levels.iter().rposition(bracket));
+            } else if rest.starts_with(b"::") || rest[0] == b';' {
+                i += usize::from(rest[0] == b':');
+                levels.push(Level::Open);
+            } else if rest[0] == b'|' {
+                if let Some(arm) = levels.find(|l| *l == Level::Waiting(b"|")) {
+                    levels.close_after(Some(arm));
+                }
+            } else if rest[0].is_ascii_alphanumeric() || rest[0] == b'_' {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                let word = &bytes[start..i];
+                match word {
+                    b"let" => levels.push(Level::Waiting(b"in")),
+                    b"if" => levels.push(Level::Waiting(b"then")),
+                    b"match" => levels.push(Level::Waiting(b"with")),
+                    b"fn" => levels.push(Level::Open),
+                    // What follows the rest of a keyword still nests within it.
+                    b"in" | b"then" | b"else" | b"with" => {
+                        let waiting = |l: &Level| matches!(l, Level::Waiting(w) if *w == word);
+                        if let Some(keyword) = levels.find(waiting) {
+                            levels.close_after(Some(keyword));
+                            levels.levels[keyword] = match word {
+                                b"then" => Level::Waiting(b"else"),
+                                b"with" => Level::Waiting(b"|"),
+                                _ => Level::Open,
+                            };
+                        }
+                    }
+                    b"rec" | b"and" | b"or" | b"not" | b"succ" | b"pred" | b"fst" | b"snd"
+                    | b"hd" | b"tl" | b"when" => {}
+                    _ => operand = true,
+                }
+            }
+        }
+        if levels.depth + prefixes > limit {
+            return Err(too_deep(limit, start));
+        }
+        deepest = deepest.max(levels.depth + prefixes);
+    }
+    Ok(deepest)
 }
 
 fn transform_parse_output(input: Pair<Rule>) -> Result<Expression> {
-    println!("{:?}", input);
-    println!("");
+    transform(input).map(|(e, _)| e)
+}
+
+/// The span of a node, less any whitespace pest skipped looking for more of it.
+fn span_of(input: &Pair<Rule>) -> Range<usize> {
+    let start = input.as_span().start();
+    start..start + input.as_str().trim_end().len()
+}
+
+/// Transform a node of the parse tree into an expression, along with the
+/// spans of the expression and its subexpressions.
+fn transform(input: Pair<Rule>) -> Result<(Expression, Spans)> {
+    // Deeply nested input recurses through here, so grow the stack on demand.
+    stacker::maybe_grow(256 * 1024, 4 * 1024 * 1024, || transform_node(input))
+}
+
+fn transform_node(input: Pair<Rule>) -> Result<(Expression, Spans)> {
+    let span = span_of(&input);
+    // Most nodes span their whole rule.
+    let node = |e: Expression, children: Vec<Spans>| {
+        Ok((
+            e,
+            Spans {
+                span: span.clone(),
+                children,
+            },
+        ))
+    };
+    let unary = |input: Pair<Rule>, f: fn(Rc<Expression>) -> Expression| {
+        let (e, spans) = transform(input.into_inner().next().unwrap())?;
+        node(f(Rc::new(e)), vec![spans])
+    };
     match input.as_rule() {
         Rule::var_stmt => {
             let mut data = input.into_inner();
-            Ok(Expression::Var(transform_variable(data.next().unwrap())?))
+            node(
+                Expression::Var(transform_variable(data.next().unwrap())?),
+                vec![],
+            )
         }
         Rule::c_bool => match input.as_span().as_str() {
-            "true" => Ok(Expression::True),
-            "false" => Ok(Expression::False),
+            "true" => node(Expression::True, vec![]),
+            "false" => node(Expression::False, vec![]),
             _ => {
                 panic!();
             }
         },
-        Rule::c_num => Ok(Expression::Num(input.as_span().as_str().parse().unwrap())),
-        Rule::e_zeroth => transform_e_rule(input, PartialExpressionOperator::Apply),
-        Rule::e_first => transform_e_rule(input, PartialExpressionOperator::Add),
-        Rule::e_second => transform_e_rule(input, PartialExpressionOperator::And),
-        Rule::e_third => transform_e_rule(input, PartialExpressionOperator::Cons),
-        Rule::e_fourth => transform_e_rule(input, PartialExpressionOperator::Equals),
-        Rule::let_stmt => {
+        Rule::c_num => node(transform_num(input, false)?, vec![]),
+        Rule::c_float => node(transform_float(input, false)?, vec![]),
+        Rule::p_number => {
             let mut data = input.into_inner();
-            let variable = transform_parse_output(data.next().unwrap())?;
-            match variable {
-                Expression::Var(v) => {
-                    let e1 = transform_parse_output(data.next().unwrap())?;
-                    let e2 = transform_parse_output(data.next().unwrap())?;
-                    Ok(Expression::Let(v, Box::new(e1), Box::new(e2)))
-                }
-                _ => panic!(),
-            }
+            let first = data.next().unwrap();
+            let e = match data.next() {
+                Some(literal) => transform_number(literal, true)?,
+                None => transform_number(first, false)?,
+            };
+            node(e, vec![])
         }
-        Rule::not_stmt => {
+        Rule::c_string => {
+            let content = input.into_inner().next().unwrap();
+            node(Expression::Str(unescape(content.as_str())), vec![])
+        }
+        Rule::c_char => {
+            let content = input.into_inner().next().unwrap();
+            node(
+                Expression::Char(unescape(content.as_str()).chars().next().unwrap()),
+                vec![],
+            )
+        }
+        Rule::e_apply => transform_apply(input),
+        Rule::e_field => {
             let mut data = input.into_inner();
-            let e = transform_parse_output(data.next().unwrap())?;
-            Ok(Expression::Not(Box::new(e)))
+            let e = transform(data.next().unwrap())?;
+            data.try_fold(e, |(e, spans), field| {
+                let span = spans.span.start..field.as_span().end();
+                Ok((
+                    Expression::Field(Rc::new(e), transform_variable(field)?.ident.to_string()),
+                    Spans {
+                        span,
+                        children: vec![spans],
+                    },
+                ))
+            })
         }
-        Rule::if_stmt => {
+        Rule::e_binary | Rule::fn_body => transform_binary_ops(input),
+        Rule::let_stmt => {
+            let mut data: Vec<Pair<Rule>> = input.into_inner().collect();
+            let body = data.pop().unwrap();
+            let (definitions, mut children) = transform_definitions(data)?;
+            let (body, body_spans) = transform(body)?;
+            children.push(body_spans);
+            node(Expression::Let(definitions, Rc::new(body)), children)
+        }
+        Rule::match_stmt => {
             let mut data = input.into_inner();
-            let e1 = transform_parse_output(data.next().unwrap())?;
-            let e2 = transform_parse_output(data.next().unwrap())?;
-            let e3 = transform_parse_output(data.next().unwrap())?;
-            Ok(Expression::If(Box::new(e1), Box::new(e2), Box::new(e3)))
+            let (scrutinee, scrutinee_spans) = transform(data.next().unwrap())?;
+            let mut children = vec![scrutinee_spans];
+            let mut arms = Vec::new();
+            for arm in data {
+                let mut data: Vec<Pair<Rule>> = arm.into_inner().collect();
+                let body = data.pop().unwrap();
+                let guard = match data.len() {
+                    2 => {
+                        let (guard, guard_spans) = transform(data.pop().unwrap())?;
+                        children.push(guard_spans);
+                        Some(guard)
+                    }
+                    _ => None,
+                };
+                let pattern = transform_pattern(data.pop().unwrap())?;
+                let (body, body_spans) = transform(body)?;
+                children.push(body_spans);
+                arms.push(MatchArm {
+                    pattern,
+                    guard,
+                    body,
+                });
+            }
+            node(Expression::Match(Rc::new(scrutinee), arms), children)
         }
-        Rule::succ => {
+        Rule::not_stmt => unary(input, Expression::Not),
+        Rule::if_stmt => {
             let mut data = input.into_inner();
-            let e = transform_parse_output(data.next().unwrap())?;
-            Ok(Expression::Succ(Box::new(e)))
+            let (e1, s1) = transform(data.next().unwrap())?;
+            let (e2, s2) = transform(data.next().unwrap())?;
+            let (e3, s3) = match data.next() {
+                Some(e3) => transform(e3)?,
+                // The missing `else` is taken to be at the end of the `if`.
+                None => (Expression::Unit, Spans::leaf(span.end..span.end)),
+            };
+            node(
+                Expression::If(Rc::new(e1), Rc::new(e2), Rc::new(e3)),
+                vec![s1, s2, s3],
+            )
         }
+        Rule::succ => unary(input, Expression::Succ),
         Rule::pair => {
             let mut data = input.into_inner();
-            let e1 = transform_parse_output(data.next().unwrap())?;
-            let e2 = transform_parse_output(data.next().unwrap())?;
-            Ok(Expression::Pair(Box::new(e1), Box::new(e2)))
+            let (e1, s1) = transform(data.next().unwrap())?;
+            let (e2, s2) = transform(data.next().unwrap())?;
+            node(Expression::Pair(Rc::new(e1), Rc::new(e2)), vec![s1, s2])
         }
-        Rule::fst => {
-            let mut data = input.into_inner();
-            let e = transform_parse_output(data.next().unwrap())?;
-            Ok(Expression::Fst(Box::new(e)))
+        Rule::record => {
+            let mut fields: Vec<(String, Expression)> = Vec::new();
+            let mut children = Vec::new();
+            for field_def in input.into_inner() {
+                let mut data = field_def.into_inner();
+                let name = data.next().unwrap();
+                if fields.iter().any(|(f, _)| f == name.as_str()) {
+                    return Err(custom_error(&name, "duplicate field in record"));
+                }
+                let (e, spans) = transform(data.next().unwrap())?;
+                fields.push((name.as_str().to_string(), e));
+                children.push(spans);
+            }
+            node(Expression::Record(fields), children)
         }
-        Rule::snd => {
-            let mut data = input.into_inner();
-            let e = transform_parse_output(data.next().unwrap())?;
-            Ok(Expression::Snd(Box::new(e)))
+        Rule::fst => unary(input, Expression::Fst),
+        Rule::snd => unary(input, Expression::Snd),
+        Rule::nil => node(Expression::Nil, vec![]),
+        Rule::unit => node(Expression::Unit, vec![]),
+        Rule::paren => {
+            let mut data = input.into_inner().peekable();
+            let (e, spans) = transform(data.next().unwrap())?;
+            match data.next_if(|p| p.as_rule() == Rule::ty) {
+                Some(ty) => node(Expression::Ann(Rc::new(e), transform_type(ty)), vec![spans]),
+                None if data.peek().is_some() => {
+                    let mut elements = vec![e];
+                    let mut children = vec![spans];
+                    for element in data {
+                        let (e, spans) = transform(element)?;
+                        elements.push(e);
+                        children.push(spans);
+                    }
+                    node(Expression::Tuple(elements), children)
+                }
+                // A grouped expression spans its parentheses too.
+                None => node(e, spans.children),
+            }
+        }
+        Rule::list => {
+            // [e1, ..., en] is sugar for e1 :: ... :: en :: nil. The list spans
+            // its brackets, and each inner cons from its head to the `]`.
+            let elements = input
+                .into_inner()
+                .map(transform)
+                .collect::<Result<Vec<_>>>()?;
+            let nil = (Expression::Nil, Spans::leaf(span.end - 1..span.end));
+            let (e, spans) =
+                elements
+                    .into_iter()
+                    .rev()
+                    .fold(nil, |(tail, tail_spans), (head, head_spans)| {
+                        (
+                            Expression::Cons(Rc::new(head), Rc::new(tail)),
+                            Spans {
+                                span: head_spans.span.start..span.end,
+                                children: vec![head_spans, tail_spans],
+                            },
+                        )
+                    });
+            node(e, spans.children)
+        }
+        Rule::hd => unary(input, Expression::Hd),
+        Rule::tl => unary(input, Expression::Tl),
+        Rule::pred => unary(input, Expression::Pred),
+        Rule::fn_stmt => {
+            // `fn x y. e` is `fn x. fn y. e`, where the inner function spans
+            // from `y` to the end.
+            let mut data = input.into_inner().rev();
+            let body = transform(data.next().unwrap())?;
+            let (e, spans) = data.try_fold(body, |(body, body_spans), x| -> Result<_> {
+                let span = x.as_span().start()..span.end;
+                Ok((
+                    Expression::Fn(transform_variable(x)?, Rc::new(body)),
+                    Spans {
+                        span,
+                        children: vec![body_spans],
+                    },
+                ))
+            })?;
+            node(e, spans.children)
+        }
+        _ => todo!(),
+    }
+}
+
+/// A numeric literal, negated if `negative`, as it is directly after a `-`.
+fn transform_number(input: Pair<Rule>, negative: bool) -> Result<Expression> {
+    match input.as_rule() {
+        Rule::c_float => transform_float(input, negative),
+        _ => transform_num(input, negative),
+    }
+}
+
+/// Numeric literals which don't fit in an `i64` are rejected rather than wrapped or panicked on.
+fn transform_num(input: Pair<Rule>, negative: bool) -> Result<Expression> {
+    let literal = input.as_span().as_str().replace('_', "");
+    let sign = if negative { "-" } else { "" };
+    let (radix, digits) = match literal.get(..2) {
+        Some("0x") => (16, &literal[2..]),
+        Some("0o") => (8, &literal[2..]),
+        Some("0b") => (2, &literal[2..]),
+        _ => (10, literal.as_str()),
+    };
+
+    match i64::from_str_radix(&format!("{}{}", sign, digits), radix) {
+        Ok(n) => Ok(Expression::Num(n)),
+        Err(_) => Err(custom_error(&input, "numeric literal is out of range")),
+    }
+}
+
+fn transform_float(input: Pair<Rule>, negative: bool) -> Result<Expression> {
+    match input.as_span().as_str().replace('_', "").parse::<f64>() {
+        Ok(f) if negative => Ok(Expression::Float(-f)),
+        Ok(f) => Ok(Expression::Float(f)),
+        Err(_) => Err(custom_error(&input, "malformed floating-point literal")),
+    }
+}
+
+/// Resolve the escape sequences in the body of a string or character literal.
+/// The grammar only admits the escapes handled here.
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            unescaped.push(match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some(c) => c,
+                None => unreachable!("the grammar forbids a trailing backslash"),
+            });
+        } else {
+            unescaped.push(c);
         }
-        Rule::nil => Ok(Expression::Nil),
-        Rule::hd => {
+    }
+    unescaped
+}
+
+/// Report an error at the span of the given pair, in the same format as pest's own errors.
+fn custom_error(input: &Pair<Rule>, message: &str) -> ParseError {
+    pest::error::Error::new_from_span(
+        ErrorVariant::<Rule>::CustomError {
+            message: message.to_string(),
+        },
+        input.as_span(),
+    )
+    .into()
+}
+
+fn transform_type(input: Pair<Rule>) -> Type {
+    match input.as_rule() {
+        Rule::ty => {
             let mut data = input.into_inner();
-            let e = transform_parse_output(data.next().unwrap())?;
-            Ok(Expression::Hd(Box::new(e)))
+            let arg = transform_type(data.next().unwrap());
+            match data.next() {
+                Some(result) => Type::Fn(Box::new(arg), Box::new(transform_type(result))),
+                None => arg,
+            }
         }
-        Rule::tl => {
+        Rule::ty_pair => {
             let mut data = input.into_inner();
-            let e = transform_parse_output(data.next().unwrap())?;
-            Ok(Expression::Tl(Box::new(e)))
+            let first = transform_type(data.next().unwrap());
+            match data.next() {
+                Some(second) => Type::Pair(Box::new(first), Box::new(transform_type(second))),
+                None => first,
+            }
         }
-        Rule::pred => {
+        Rule::ty_list => {
             let mut data = input.into_inner();
-            let e = transform_parse_output(data.next().unwrap())?;
-            Ok(Expression::Pred(Box::new(e)))
+            let element = transform_type(data.next().unwrap());
+            // Each trailing `list` wraps the type so far, e.g. `Int list list`.
+            data.fold(element, |t, _list| Type::List(Box::new(t)))
         }
-        Rule::fn_stmt => {
+        Rule::ty_name => match input.as_str() {
+            "Int" => Type::Int,
+            "Bool" => Type::Bool,
+            "Float" => Type::Float,
+            "String" => Type::String,
+            "Char" => Type::Char,
+            "Unit" => Type::Unit,
+            name => unreachable!("not a type name: {}", name),
+        },
+        _ => unreachable!("not a type: {:?}", input.as_rule()),
+    }
+}
+
+fn transform_pattern(input: Pair<Rule>) -> Result<Pattern> {
+    match input.as_rule() {
+        Rule::pattern => {
+            // Fold `p1 :: p2 :: p3` to the right.
+            let mut data = input.into_inner().rev();
+            let last = transform_pattern(data.next().unwrap())?;
+            data.try_fold(last, |tail, head| {
+                Ok(Pattern::Cons(
+                    Box::new(transform_pattern(head)?),
+                    Box::new(tail),
+                ))
+            })
+        }
+        Rule::p_wildcard => Ok(Pattern::Wildcard),
+        Rule::p_nil => Ok(Pattern::Nil),
+        Rule::p_literal => Ok(Pattern::Literal(transform_parse_output(
+            input.into_inner().next().unwrap(),
+        )?)),
+        Rule::p_pair => {
             let mut data = input.into_inner();
-            let var = transform_variable(data.next().unwrap())?;
-            let e = transform_parse_output(data.next().unwrap())?;
-            Ok(Expression::Fn(var, Box::new(e)))
+            let p1 = transform_pattern(data.next().unwrap())?;
+            let p2 = transform_pattern(data.next().unwrap())?;
+            Ok(Pattern::Pair(Box::new(p1), Box::new(p2)))
         }
-        _ => todo!(),
+        Rule::p_var => Ok(Pattern::Var(transform_variable(
+            input.into_inner().next().unwrap(),
+        )?)),
+        _ => unreachable!("not a pattern: {:?}", input.as_rule()),
     }
 }
 
+/// Transform the `rec`, definitions and `and`s of a `let`, up to any `in`,
+/// along with the spans of the defining expressions.
+fn transform_definitions(data: Vec<Pair<Rule>>) -> Result<(Vec<Definition>, Vec<Spans>)> {
+    let recursive = data.iter().any(|p| p.as_rule() == Rule::rec_kw);
+    data.into_iter()
+        .filter(|p| p.as_rule() == Rule::definition)
+        .map(|definition| {
+            let mut data = definition.into_inner();
+            let var = transform_variable(data.next().unwrap())?;
+            let (expr, spans) = transform(data.next().unwrap())?;
+            Ok((
+                Definition {
+                    var,
+                    expr: Rc::new(expr),
+                    recursive,
+                },
+                spans,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|definitions| definitions.into_iter().unzip())
+}
+
 fn transform_variable(input: Pair<Rule>) -> Result<Variable> {
     match input.as_rule() {
         Rule::x => Ok(Variable {
-            ident: input.as_span().as_str().to_string(),
+            ident: intern(input.as_str()),
         }),
         _ => panic!(),
     }
 }
 
-fn transform_e_rule(input: Pair<Rule>, op: PartialExpressionOperator) -> Result<Expression> {
+/// Fold a chain of applications to the left, e.g. `f a b` becomes `(f a) b`.
+fn transform_apply(input: Pair<Rule>) -> Result<(Expression, Spans)> {
     let mut data = input.into_inner();
-    let e_left = data.next().unwrap();
-    let e_left_transformed = transform_parse_output(e_left)?;
+    let e_left = transform(data.next().unwrap())?;
 
-    let e_right_prime = data.next().unwrap();
-    transform_parse_output_partial(e_left_transformed, e_right_prime, op)
+    data.try_fold(e_left, |(left, left_spans), e_right| {
+        let (right, right_spans) = transform(e_right)?;
+        Ok((
+            Expression::Apply(Rc::new(left), Rc::new(right)),
+            Spans::spanning(left_spans, right_spans),
+        ))
+    })
 }
 
-fn transform_parse_output_partial(
-    left: Expression,
-    input: Pair<Rule>,
-    op: PartialExpressionOperator,
-) -> Result<Expression> {
-    let mut data = input.into_inner();
-    if data.len() == 0 {
-        return Ok(left);
+/// Resolve a chain of binary operators by precedence, e.g. `1 + 2 * 3`
+/// becomes `1 + (2 * 3)`.
+fn transform_binary_ops(input: Pair<Rule>) -> Result<(Expression, Spans)> {
+    let pairs: Vec<_> = input.into_inner().collect();
+    // A `-` directly before a numeric literal is folded into it, so that e.g.
+    // `-9223372036854775808` is in range. These are the literals' offsets.
+    let signed: Vec<usize> = pairs
+        .windows(2)
+        .filter(|w| {
+            w[0].as_rule() == Rule::neg_op
+                && w[0].as_span().end() == w[1].as_span().start()
+                && bare_number(w[1].clone()).is_some()
+        })
+        .map(|w| w[1].as_span().start())
+        .collect();
+    let e = PRATT_PARSER
+        .map_primary(|primary| {
+            let span = primary.as_span().start()..primary.as_span().end();
+            match bare_number(primary.clone()) {
+                Some(literal) if signed.contains(&span.start) => Ok((
+                    transform_number(literal, true)?,
+                    Spans::leaf(span.start - 1..span.end),
+                )),
+                _ => transform(primary),
+            }
+        })
+        .map_prefix(|op, operand| {
+            let (operand, operand_spans) = operand?;
+            if signed.contains(&op.as_span().end()) {
+                return Ok((operand, operand_spans));
+            }
+            Ok((
+                Expression::Neg(Rc::new(operand)),
+                Spans {
+                    span: op.as_span().start()..operand_spans.span.end,
+                    children: vec![operand_spans],
+                },
+            ))
+        })
+        .map_infix(|left, op, right| {
+            let ((left, left_spans), (right, right_spans)) = (left?, right?);
+            Ok((
+                BinOp::from_rule(op.as_rule()).apply(left, right),
+                Spans::spanning(left_spans, right_spans),
+            ))
+        })
+        .parse(pairs.into_iter());
+    e
+}
+
+/// The numeric literal an operand consists of, if it's nothing more, i.e. not
+/// applied or accessed.
+fn bare_number(operand: Pair<Rule>) -> Option<Pair<Rule>> {
+    match operand.as_rule() {
+        Rule::c_num | Rule::c_float => Some(operand),
+        Rule::e_apply | Rule::e_field => {
+            let mut inner = operand.into_inner();
+            match (inner.next(), inner.next()) {
+                (Some(only), None) => bare_number(only),
+                _ => None,
+            }
+        }
+        _ => None,
     }
+}
 
-    let expression = transform_parse_output(data.next().unwrap())?;
+#[test]
+fn test_spans() {
+    let (_, spans) = parse_spanned("1 + 2").unwrap();
+    assert_eq!(spans.span, 0..5);
+    assert_eq!(spans.children[0].span, 0..1);
+    assert_eq!(spans.children[1].span, 4..5);
 
-    let left_boxed = Box::new(left);
-    let exp_boxed = Box::new(expression);
+    let source = "let f = fn x y. ((x + 1) * y) in f 2 [3]";
+    let (_, spans) = parse_spanned(source).unwrap();
+    let slice = |spans: &Spans| &source[spans.span.clone()];
+    assert_eq!(slice(&spans), source);
+    let f = &spans.children[0];
+    assert_eq!(slice(f), "fn x y. ((x + 1) * y)");
+    assert_eq!(slice(&f.children[0]), "y. ((x + 1) * y)");
+    assert_eq!(slice(&f.children[0].children[0]), "((x + 1) * y)");
+    assert_eq!(slice(&f.children[0].children[0].children[0]), "(x + 1)");
 
-    let complete_left = match op {
-        PartialExpressionOperator::Apply => Expression::Apply(left_boxed, exp_boxed),
-        PartialExpressionOperator::Add => Expression::Add(left_boxed, exp_boxed),
-        PartialExpressionOperator::And => Expression::And(left_boxed, exp_boxed),
-        PartialExpressionOperator::Cons => Expression::Cons(left_boxed, exp_boxed),
-        PartialExpressionOperator::Equals => Expression::Eq(left_boxed, exp_boxed),
-    };
+    let body = &spans.children[1];
+    assert_eq!(slice(body), "f 2 [3]");
+    assert_eq!(slice(&body.children[0]), "f 2");
+    assert_eq!(slice(&body.children[1]), "[3]");
+    assert_eq!(slice(&body.children[1].children[0]), "3");
+    assert_eq!(slice(&body.children[1].children[1]), "]");
+
+    let (_, spans) = parse_spanned("[1, 2]").unwrap();
+    assert_eq!(spans.span, 0..6);
+    assert_eq!(spans.children[1].span, 4..6);
+    assert_eq!(parse_spanned("[]").unwrap().1.span, 0..2);
+
+    // Trailing whitespace isn't part of a span.
+    let source = "fn x. x \n + 1";
+    let (_, spans) = parse_spanned(source).unwrap();
+    assert_eq!(&source[spans.children[0].span.clone()], "fn x. x");
+}
+
+#[test]
+fn test_spans_match_subexpressions() {
+    use crate::exprs::subexpressions;
+
+    /// Every node has a span for each subexpression, within its own span.
+    fn check(e: &Expression, spans: &Spans) {
+        let children = subexpressions(e);
+        assert_eq!(children.len(), spans.children.len(), "{:?}", e);
+        for (child, child_spans) in children.into_iter().zip(&spans.children) {
+            assert!(spans.span.start <= child_spans.span.start);
+            assert!(child_spans.span.end <= spans.span.end);
+            check(child, child_spans);
+        }
+    }
 
-    transform_parse_output_partial(complete_left, data.next().unwrap(), op.clone())
+    for source in [
+        "let rec f = fn n. if n == 0 then 1 else n * f (n - 1) and g = f in g 5",
+        "match [1, 2] with nil -> 0 | x :: _ when x > 0 -> -x | _ -> 1",
+        "(1, { a = <2, 3> }.a, (4 : Int), not(true); succ(pred(hd(tl(fst(snd(x)))))))",
+        "if a then b",
+    ] {
+        let (e, spans) = parse_spanned(source).unwrap();
+        assert_eq!(spans.span, 0..source.len());
+        check(&e, &spans);
+    }
 }
 
 #[test]
 fn test_num() {
-    assert_eq!(parser("1").unwrap(), Expression::Num(1));
+    assert_eq!(parse("1").unwrap(), Expression::Num(1));
 }
 
 #[test]
 fn test_multi_num() {
-    assert_eq!(parser("123").unwrap(), Expression::Num(123));
+    assert_eq!(parse("123").unwrap(), Expression::Num(123));
+    assert_eq!(parse("42").unwrap(), Expression::Num(42));
 }
 
-#[ignore]
 #[test]
-fn test_add() {
+fn test_num_overflow() {
+    assert_eq!(parse("2147483648").unwrap(), Expression::Num(2147483648));
     assert_eq!(
-        parser("1 + 2").unwrap(),
-        Expression::Add(Box::new(Expression::Num(1)), Box::new(Expression::Num(2)))
+        parse("9223372036854775807").unwrap(),
+        Expression::Num(i64::MAX)
     );
+    assert!(parse("9223372036854775808").is_err());
 }
 
 #[test]
-fn test_double_add() {
+fn test_negative_num() {
+    assert_eq!(parse("-5").unwrap(), Expression::Num(-5));
+    assert_eq!(parse("-0").unwrap(), Expression::Num(0));
+    assert_eq!(parse("-2147483648").unwrap(), Expression::Num(-2147483648));
     assert_eq!(
-        parser("1 + 2 + 3").unwrap(),
-        Expression::Add(
-            Box::new(Expression::Add(
-                Box::new(Expression::Num(1)),
-                Box::new(Expression::Num(2))
-            )),
-            Box::new(Expression::Num(3))
-        )
+        parse("-9223372036854775808").unwrap(),
+        Expression::Num(i64::MIN)
+    );
+    assert!(parse("-9223372036854775809").is_err());
+    // With a space, `-` is prefix negation rather than part of the literal.
+    assert_eq!(
+        parse("- 5").unwrap(),
+        Expression::Neg(Rc::new(Expression::Num(5)))
     );
 }
 
 #[test]
-fn test_and() {
+fn test_num_separators() {
+    assert_eq!(parse("1_000").unwrap(), Expression::Num(1000));
+    assert_eq!(parse("1_000_000").unwrap(), Expression::Num(1000000));
+    assert_eq!(parse("-1_0").unwrap(), Expression::Num(-10));
+    assert!(parse("1_").is_err());
+    assert!(parse("1__0").is_err());
+    // A leading underscore makes an identifier, not a number.
     assert_eq!(
-        parser("1 and 2").unwrap(),
-        Expression::And(Box::new(Expression::Num(1)), Box::new(Expression::Num(2)))
+        parse("_1").unwrap(),
+        Expression::Var(Variable { ident: "_1".into() })
     );
 }
 
 #[test]
-fn test_double_and() {
+fn test_num_radix() {
+    assert_eq!(parse("0xFF").unwrap(), Expression::Num(255));
+    assert_eq!(parse("0xff").unwrap(), Expression::Num(255));
+    assert_eq!(parse("0o17").unwrap(), Expression::Num(15));
+    assert_eq!(parse("0b1010").unwrap(), Expression::Num(10));
+    assert_eq!(parse("0b1111_0000").unwrap(), Expression::Num(240));
+    assert_eq!(parse("-0x10").unwrap(), Expression::Num(-16));
     assert_eq!(
-        parser("1 and 2 and 3").unwrap(),
-        Expression::And(
-            Box::new(Expression::And(
-                Box::new(Expression::Num(1)),
-                Box::new(Expression::Num(2))
-            )),
-            Box::new(Expression::Num(3))
-        )
+        parse("0x7FFF_FFFF_FFFF_FFFF").unwrap(),
+        Expression::Num(i64::MAX)
+    );
+    assert_eq!(
+        parse("-0x8000_0000_0000_0000").unwrap(),
+        Expression::Num(i64::MIN)
     );
+
+    assert!(parse("0b12").is_err());
+    assert!(parse("0o8").is_err());
+    assert!(parse("0xG").is_err());
+    assert!(parse("0x").is_err());
+    assert!(parse("0x8000_0000_0000_0000").is_err());
 }
 
 #[test]
-fn test_fn() {
+fn test_float() {
+    assert_eq!(parse("2.75").unwrap(), Expression::Float(2.75));
+    assert_eq!(parse("3.0").unwrap(), Expression::Float(3.0));
+    assert_eq!(parse("-0.5").unwrap(), Expression::Float(-0.5));
+    assert_eq!(parse("1_000.25").unwrap(), Expression::Float(1000.25));
+    // Integers without a decimal point are still integers.
+    assert_eq!(parse("3").unwrap(), Expression::Num(3));
+
+    assert!(parse(".5").is_err());
+    assert!(parse("3.").is_err());
+    assert!(parse("3.x").is_err());
+}
+
+#[test]
+fn test_string() {
+    assert_eq!(
+        parse("\"hello\"").unwrap(),
+        Expression::Str("hello".to_string())
+    );
+    assert_eq!(parse("\"\"").unwrap(), Expression::Str("".to_string()));
+    assert_eq!(
+        parse(r#""a\"b""#).unwrap(),
+        Expression::Str("a\"b".to_string())
+    );
+    assert_eq!(
+        parse(r#""back\\slash\n""#).unwrap(),
+        Expression::Str("back\\slash\n".to_string())
+    );
+    // Whitespace inside a string is kept as-is.
     assert_eq!(
-        parser("1 ( 2 )").unwrap(),
-        Expression::Apply(Box::new(Expression::Num(1)), Box::new(Expression::Num(2)))
+        parse("\" a  b \"").unwrap(),
+        Expression::Str(" a  b ".to_string())
     );
 }
 
 #[test]
-fn test_double_fn() {
+fn test_unterminated_string() {
+    let e = parse("\"abc").unwrap_err();
+    assert_eq!(e.offset, 4);
+    assert_eq!(e.expected.as_deref(), Some("closing quote"));
+}
+
+#[test]
+fn test_char() {
+    assert_eq!(parse("'a'").unwrap(), Expression::Char('a'));
+    assert_eq!(parse("' '").unwrap(), Expression::Char(' '));
+    assert_eq!(parse("'\"'").unwrap(), Expression::Char('"'));
+    assert_eq!(parse(r"'\n'").unwrap(), Expression::Char('\n'));
+    assert_eq!(parse(r"'\t'").unwrap(), Expression::Char('\t'));
+    assert_eq!(parse(r"'\\'").unwrap(), Expression::Char('\\'));
+    assert_eq!(parse(r"'\''").unwrap(), Expression::Char('\''));
+
+    assert!(parse("'ab'").is_err());
+    assert!(parse("''").is_err());
+    assert!(parse("'a").is_err());
+    assert!(parse(r"'\q'").is_err());
+}
+
+#[test]
+fn test_unit() {
+    assert_eq!(parse("()").unwrap(), Expression::Unit);
+    assert_eq!(parse("( )").unwrap(), Expression::Unit);
     assert_eq!(
-        parser("1 ( 2 ) ( 3 )").unwrap(),
+        parse("f ()").unwrap(),
         Expression::Apply(
-            Box::new(Expression::Apply(
-                Box::new(Expression::Num(1)),
-                Box::new(Expression::Num(2))
-            )),
-            Box::new(Expression::Num(3))
+            Rc::new(Expression::Var(Variable { ident: "f".into() })),
+            Rc::new(Expression::Unit)
         )
     );
 }
+
+#[test]
+fn test_list() {
+    assert_eq!(parse("[]").unwrap(), Expression::Nil);
+    assert_eq!(
+        parse("[1, 2, 3]").unwrap(),
+        Expression::Cons(
+            Rc::new(Expression::Num(1)),
+            Rc::new(Expression::Cons(
+                Rc::new(Expression::Num(2)),
+                Rc::new(Expression::Cons(
+                    Rc::new(Expression::Num(3)),
+                    Rc::new(Expression::Nil)
+                ))
+            ))
+        )
+    );
+    assert!(parse("[1, 2,]").is_err());
+    assert!(parse("[,]").is_err());
+}
+
+#[test]
+fn test_nested_list() {
+    let singleton = |n| Expression::Cons(Rc::new(Expression::Num(n)), Rc::new(Expression::Nil));
+    assert_eq!(
+        parse("[[1], [2]]").unwrap(),
+        Expression::Cons(
+            Rc::new(singleton(1)),
+            Rc::new(Expression::Cons(
+                Rc::new(singleton(2)),
+                Rc::new(Expression::Nil)
+            ))
+        )
+    );
+}
+
+#[test]
+fn test_or() {
+    assert_eq!(
+        parse("true or false and true").unwrap(),
+        Expression::Or(
+            Rc::new(Expression::True),
+            Rc::new(Expression::And(
+                Rc::new(Expression::False),
+                Rc::new(Expression::True)
+            ))
+        )
+    );
+    assert_eq!(
+        parse("true and false or true").unwrap(),
+        Expression::Or(
+            Rc::new(Expression::And(
+                Rc::new(Expression::True),
+                Rc::new(Expression::False)
+            )),
+            Rc::new(Expression::True)
+        )
+    );
+}
+
+#[test]
+fn test_double_or() {
+    assert_eq!(
+        parse("true or false or true").unwrap(),
+        Expression::Or(
+            Rc::new(Expression::Or(
+                Rc::new(Expression::True),
+                Rc::new(Expression::False)
+            )),
+            Rc::new(Expression::True)
+        )
+    );
+}
+
+#[test]
+fn test_word_operator_boundary() {
+    let var = |s: &str| Rc::new(Expression::Var(Variable { ident: s.into() }));
+    assert_eq!(
+        parse("x orange").unwrap(),
+        Expression::Apply(var("x"), var("orange"))
+    );
+    assert_eq!(
+        parse("x android").unwrap(),
+        Expression::Apply(var("x"), var("android"))
+    );
+}
+
+#[test]
+fn test_pred_succ() {
+    assert_eq!(
+        parse("pred(succ(0))").unwrap(),
+        Expression::Pred(Rc::new(Expression::Succ(Rc::new(Expression::Num(0)))))
+    );
+}
+
+#[test]
+fn test_add() {
+    assert_eq!(
+        parse("1 + 2").unwrap(),
+        Expression::Add(Rc::new(Expression::Num(1)), Rc::new(Expression::Num(2)))
+    );
+}
+
+#[test]
+fn test_expression_size() {
+    // Children are behind pointers, so an expression is no bigger than its
+    // largest variant's handful of them, however deep the tree.
+    assert!(
+        std::mem::size_of::<Expression>() <= 40,
+        "{}",
+        std::mem::size_of::<Expression>()
+    );
+}
+
+#[test]
+fn test_clone_shares_children() {
+    // Cloning copies only the root: the children are shared, not copied.
+    let input = vec!["1"; 5000].join(" + ");
+    let e = parse(&input).unwrap();
+    let copy = e.clone();
+    let (Expression::Add(left, right), Expression::Add(copy_left, copy_right)) = (&e, &copy) else {
+        panic!("expected an addition");
+    };
+    assert!(Rc::ptr_eq(left, copy_left));
+    assert!(Rc::ptr_eq(right, copy_right));
+    assert_eq!(Rc::strong_count(left), 2);
+}
+
+#[test]
+fn test_deep_nesting() {
+    let nested = |n| format!("{}0{}", "succ(".repeat(n), ")".repeat(n));
+
+    let e = parse(&nested(DEFAULT_DEPTH_LIMIT)).unwrap();
+    assert_eq!(crate::visit::depth(&e), DEFAULT_DEPTH_LIMIT + 1);
+    // Dropping the tree recurses too, so leave that to the process.
+    std::mem::forget(e);
+
+    let error = parse(&nested(10_000)).unwrap_err();
+    assert_eq!(
+        error.kind,
+        ParseErrorKind::DepthLimitExceeded {
+            limit: DEFAULT_DEPTH_LIMIT
+        }
+    );
+    // At the `(` of the first `succ` past the limit.
+    assert_eq!(error.offset, 5 * DEFAULT_DEPTH_LIMIT + 4);
+
+    let error = parse_with_depth_limit(&nested(11), 10).unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::DepthLimitExceeded { limit: 10 });
+    assert_eq!(
+        parse_with_depth_limit(&nested(10), 10).unwrap(),
+        parse(&nested(10)).unwrap()
+    );
+    // Brackets in comments and strings don't count towards the limit, though
+    // block comments nest in their own right.
+    assert_eq!(
+        parse_with_depth_limit("succ({- ( -} \"((\")", 2),
+        Ok(Expression::Succ(Rc::new(Expression::Str("((".to_string()))))
+    );
+    assert_eq!(
+        parse_with_depth_limit("succ('(')", 1),
+        Ok(Expression::Succ(Rc::new(Expression::Char('('))))
+    );
+}
+
+#[test]
+fn test_deep_prefix_nesting() {
+    let negated = |n| format!("{}1", "- ".repeat(n));
+
+    let e = parse(&negated(DEFAULT_DEPTH_LIMIT)).unwrap();
+    assert_eq!(crate::visit::depth(&e), DEFAULT_DEPTH_LIMIT + 1);
+    std::mem::forget(e);
+
+    let error = parse(&negated(100_000)).unwrap_err();
+    assert_eq!(
+        error.kind,
+        ParseErrorKind::DepthLimitExceeded {
+            limit: DEFAULT_DEPTH_LIMIT
+        }
+    );
+    // At the first `-` past the limit.
+    assert_eq!(error.offset, 2 * DEFAULT_DEPTH_LIMIT);
+
+    let error = parse_with_depth_limit(&negated(11), 10).unwrap_err();
+    assert_eq!(error.kind, ParseErrorKind::DepthLimitExceeded { limit: 10 });
+    assert!(parse_with_depth_limit(&negated(10), 10).is_ok());
+    // Negations count along with the brackets they're in.
+    assert!(parse_with_depth_limit("- (- - (- 1))", 6).is_ok());
+    assert!(parse_with_depth_limit("- (- - (- 1))", 5).is_err());
+    // Subtractions don't nest.
+    assert!(parse_with_depth_limit(&["x"; 100].join(" - "), 1).is_ok());
+
+    let nots = |n| format!("{}true{}", "not(".repeat(n), ")".repeat(n));
+    let error = parse(&nots(100_000)).unwrap_err();
+    assert_eq!(
+        error.kind,
+        ParseErrorKind::DepthLimitExceeded {
+            limit: DEFAULT_DEPTH_LIMIT
+        }
+    );
+}
+
+#[test]
+fn test_deep_chain_nesting() {
+    let too_deep = |limit| ParseErrorKind::DepthLimitExceeded { limit };
+
+    // Right-associative operators nest to the right, keywords into their
+    // bodies, and pairs within their brackets.
+    for op in [" :: ", "; "] {
+        let error = parse(&vec!["1"; 10_000].join(op)).unwrap_err();
+        assert_eq!(error.kind, too_deep(DEFAULT_DEPTH_LIMIT));
+        assert!(parse_with_depth_limit(&["1"; 11].join(op), 10).is_ok());
+        assert!(parse_with_depth_limit(&["1"; 12].join(op), 10).is_err());
+    }
+    let pairs = |n| format!("{}1{}", "<".repeat(n), ", 1>".repeat(n));
+    assert!(parse_with_depth_limit(&pairs(10), 10).is_ok());
+    let error = parse_with_depth_limit(&pairs(50), 10).unwrap_err();
+    assert_eq!(error.kind, too_deep(10));
+    let lets = |n| format!("{}x", "let x = 1 in ".repeat(n));
+    assert!(parse_with_depth_limit(&lets(10), 10).is_ok());
+    assert_eq!(
+        parse(&lets(10_000)).unwrap_err().kind,
+        too_deep(DEFAULT_DEPTH_LIMIT)
+    );
+    let fns = |n| format!("{}x", "fn x. ".repeat(n));
+    assert!(parse_with_depth_limit(&fns(10), 10).is_ok());
+    assert!(parse_with_depth_limit(&fns(11), 10).is_err());
+
+    // A keyword's nesting ends where it does, and a `<` after an operand is a
+    // comparison.
+    assert!(parse_with_depth_limit("if 1 < 2 then 3 > 4 else let x = 5 in x", 2).is_ok());
+    let ifs = vec!["if true then 1 else 2"; 2_000].join(", ");
+    assert!(parse(&format!("[{}]", ifs)).is_ok());
+    let program = "let x = 1 :: nil ;; ".repeat(2_000);
+    assert!(parse_program(&program).is_ok());
+
+    // pest gives up on input too deep for it, however deep it thinks that is.
+    let error = pest::error::Error::new_from_pos(
+        ErrorVariant::CustomError {
+            message: "stack limit reached".to_string(),
+        },
+        pest::Position::new("((", 1).unwrap(),
+    );
+    let error = parse_error(error, 10);
+    assert_eq!(error.kind, too_deep(10));
+    assert_eq!(error.offset, 1);
+}
+
+#[test]
+fn test_debug() {
+    let debug = format!("{:?}", parse("1+2").unwrap());
+    assert_eq!(debug, "Add(Num(1), Num(2))");
+    let debug = format!("{:?}", parse("let x = 1 in x").unwrap());
+    assert!(debug.contains("Definition"));
+    assert!(debug.contains("Variable"));
+}
+
+#[test]
+fn test_eq() {
+    fn assert_eq_impl<T: Eq>() {}
+    assert_eq_impl::<Expression>();
+    assert_eq_impl::<Definition>();
+    assert_eq_impl::<Variable>();
+
+    assert!(
+        parse("1+2").unwrap()
+            == Expression::Add(Rc::new(Expression::Num(1)), Rc::new(Expression::Num(2)))
+    );
+    assert!(parse("1+2").unwrap() != parse("2+1").unwrap());
+    assert!(parse("let x = 1 in x").unwrap() == parse("let  x=1  in  x").unwrap());
+}
+
+#[test]
+fn test_hash() {
+    use std::collections::HashSet;
+
+    let mut set = HashSet::new();
+    set.insert(parse("let f = fn x. x in f (<2, [3]>) + 1.5").unwrap());
+    set.insert(parse("let f = (fn x. x) in (f (<2, [3]>)) + (1.5)").unwrap());
+    assert_eq!(set.len(), 1);
+    set.insert(parse("let f = fn x. x in f (<2, [4]>) + 1.5").unwrap());
+    assert_eq!(set.len(), 2);
+
+    let hash = |e: &Expression| {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        e.hash(&mut hasher);
+        hasher.finish()
+    };
+    let nan = Expression::Float(f64::NAN);
+    assert_eq!(nan, nan.clone());
+    assert_eq!(hash(&nan), hash(&nan.clone()));
+    assert_ne!(Expression::Float(0.0), Expression::Float(-0.0));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_round_trip() {
+    let e = parse(
+        "let rec f = fn x. match x with 0 -> 1.5 | n when n > 0 -> f (n - 1) in (f 3 : Float)",
+    )
+    .unwrap();
+    let json = serde_json::to_string(&e).unwrap();
+    assert_eq!(serde_json::from_str::<Expression>(&json).unwrap(), e);
+
+    // Variants are tagged by name.
+    assert_eq!(
+        serde_json::to_value(parse("1 + x").unwrap()).unwrap(),
+        serde_json::json!({"Add": [{"Num": 1}, {"Var": {"ident": "x"}}]})
+    );
+}
+
+#[test]
+fn test_double_add() {
+    assert_eq!(
+        parse("1 + 2 + 3").unwrap(),
+        Expression::Add(
+            Rc::new(Expression::Add(
+                Rc::new(Expression::Num(1)),
+                Rc::new(Expression::Num(2))
+            )),
+            Rc::new(Expression::Num(3))
+        )
+    );
+}
+
+#[test]
+fn test_long_add_chain() {
+    let input = vec!["1"; 5000].join(" + ");
+    let mut e = parse(&input).unwrap();
+
+    let mut operators = 0;
+    while let Expression::Add(left, right) = e {
+        assert_eq!(*right, Expression::Num(1));
+        operators += 1;
+        e = Rc::unwrap_or_clone(left);
+    }
+    assert_eq!(e, Expression::Num(1));
+    assert_eq!(operators, 4999);
+}
+
+#[test]
+fn test_sub() {
+    assert_eq!(
+        parse("10 - 3 - 2").unwrap(),
+        Expression::Sub(
+            Rc::new(Expression::Sub(
+                Rc::new(Expression::Num(10)),
+                Rc::new(Expression::Num(3))
+            )),
+            Rc::new(Expression::Num(2))
+        )
+    );
+    assert_eq!(
+        parse("1 - -2").unwrap(),
+        Expression::Sub(Rc::new(Expression::Num(1)), Rc::new(Expression::Num(-2)))
+    );
+}
+
+#[test]
+fn test_add_sub_chain() {
+    assert_eq!(
+        parse("1 + 2 - 3 + 4").unwrap(),
+        Expression::Add(
+            Rc::new(Expression::Sub(
+                Rc::new(Expression::Add(
+                    Rc::new(Expression::Num(1)),
+                    Rc::new(Expression::Num(2))
+                )),
+                Rc::new(Expression::Num(3))
+            )),
+            Rc::new(Expression::Num(4))
+        )
+    );
+    assert_eq!(
+        parse("1 - 2 + -3").unwrap(),
+        Expression::Add(
+            Rc::new(Expression::Sub(
+                Rc::new(Expression::Num(1)),
+                Rc::new(Expression::Num(2))
+            )),
+            Rc::new(Expression::Num(-3))
+        )
+    );
+}
+
+#[test]
+fn test_mul_precedence() {
+    let num = |n| Rc::new(Expression::Num(n));
+    let e = parse("2 + 3 * 4").unwrap();
+    assert_eq!(
+        e,
+        Expression::Add(num(2), Rc::new(Expression::Mul(num(3), num(4))))
+    );
+    assert_ne!(
+        e,
+        Expression::Mul(Rc::new(Expression::Add(num(2), num(3))), num(4))
+    );
+
+    assert_eq!(
+        parse("2 * 3 - 4").unwrap(),
+        Expression::Sub(Rc::new(Expression::Mul(num(2), num(3))), num(4))
+    );
+}
+
+#[test]
+fn test_double_mul() {
+    let num = |n| Rc::new(Expression::Num(n));
+    assert_eq!(
+        parse("2 * 3 * 4").unwrap(),
+        Expression::Mul(Rc::new(Expression::Mul(num(2), num(3))), num(4))
+    );
+}
+
+#[test]
+fn test_neg() {
+    let num = |n| Rc::new(Expression::Num(n));
+    let var = |s: &str| Rc::new(Expression::Var(Variable { ident: s.into() }));
+    assert_eq!(
+        parse("-(1 + 2)").unwrap(),
+        Expression::Neg(Rc::new(Expression::Add(num(1), num(2))))
+    );
+    assert_eq!(
+        parse("- -x").unwrap(),
+        Expression::Neg(Rc::new(Expression::Neg(var("x"))))
+    );
+    assert_eq!(
+        parse("-x * 2").unwrap(),
+        Expression::Mul(Rc::new(Expression::Neg(var("x"))), num(2))
+    );
+    assert_eq!(
+        parse("1 - -x").unwrap(),
+        Expression::Sub(num(1), Rc::new(Expression::Neg(var("x"))))
+    );
+    // Negative literals are still literals.
+    assert_eq!(parse("-1").unwrap(), Expression::Num(-1));
+    // ...but only where no operand comes before them.
+    assert_eq!(parse("x-1").unwrap(), Expression::Sub(var("x"), num(1)));
+    assert_eq!(parse("1-1").unwrap(), Expression::Sub(num(1), num(1)));
+    assert_eq!(parse("f -1").unwrap(), Expression::Sub(var("f"), num(1)));
+    assert_eq!(
+        parse("f (-1)").unwrap(),
+        Expression::Apply(var("f"), num(-1))
+    );
+    assert_eq!(parse("1 - -1").unwrap(), Expression::Sub(num(1), num(-1)));
+    assert_eq!(
+        parse("fn x. -x").unwrap(),
+        Expression::Fn(
+            Variable { ident: "x".into() },
+            Rc::new(Expression::Neg(var("x")))
+        )
+    );
+}
+
+#[test]
+fn test_div() {
+    let num = |n| Rc::new(Expression::Num(n));
+    assert_eq!(
+        parse("8 / 2 / 2").unwrap(),
+        Expression::Div(Rc::new(Expression::Div(num(8), num(2))), num(2))
+    );
+    assert_eq!(
+        parse("8 / 2 * 3").unwrap(),
+        Expression::Mul(Rc::new(Expression::Div(num(8), num(2))), num(3))
+    );
+}
+
+#[test]
+fn test_div_precedence() {
+    let num = |n| Rc::new(Expression::Num(n));
+    assert_eq!(
+        parse("1 + 8 / 2").unwrap(),
+        Expression::Add(num(1), Rc::new(Expression::Div(num(8), num(2))))
+    );
+    assert_eq!(
+        parse("8 / 2 - 1").unwrap(),
+        Expression::Sub(Rc::new(Expression::Div(num(8), num(2))), num(1))
+    );
+}
+
+#[test]
+fn test_mod() {
+    let num = |n| Rc::new(Expression::Num(n));
+    assert_eq!(
+        parse("10 % 3 + 1").unwrap(),
+        Expression::Add(Rc::new(Expression::Mod(num(10), num(3))), num(1))
+    );
+    assert_eq!(
+        parse("1 + 10 % 3").unwrap(),
+        Expression::Add(num(1), Rc::new(Expression::Mod(num(10), num(3))))
+    );
+    assert_eq!(
+        parse("10 % 4 % 3").unwrap(),
+        Expression::Mod(Rc::new(Expression::Mod(num(10), num(4))), num(3))
+    );
+    assert_eq!(
+        parse("10 % 4 * 3").unwrap(),
+        Expression::Mul(Rc::new(Expression::Mod(num(10), num(4))), num(3))
+    );
+}
+
+#[test]
+fn test_operator_precedence() {
+    // Each expression must parse the same as its explicitly bracketed form.
+    let cases = [
+        ("a or b and c", "a or (b and c)"),
+        ("a and b or c", "(a and b) or c"),
+        ("a and b == c", "a and (b == c)"),
+        ("a or b != c", "a or (b != c)"),
+        ("a < b and c > d", "(a < b) and (c > d)"),
+        ("a <= b or c >= d", "(a <= b) or (c >= d)"),
+        ("a == b :: c", "a == (b :: c)"),
+        ("a :: b < c", "(a :: b) < c"),
+        ("a + b :: c", "(a + b) :: c"),
+        ("a :: b - c", "a :: (b - c)"),
+        ("a + b * c", "a + (b * c)"),
+        ("a / b - c", "(a / b) - c"),
+        ("a - b % c", "a - (b % c)"),
+        ("a == b + c * d", "a == (b + (c * d))"),
+        ("a * b + c == d or e", "(((a * b) + c) == d) or e"),
+        ("-a * b", "(-a) * b"),
+    ];
+    for (source, bracketed) in cases {
+        assert_eq!(
+            parse(source).unwrap(),
+            parse(bracketed).unwrap(),
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_operator_associativity() {
+    let cases = [
+        ("a or b or c", "(a or b) or c"),
+        ("a and b and c", "(a and b) and c"),
+        ("a == b != c < d", "((a == b) != c) < d"),
+        ("a - b + c", "(a - b) + c"),
+        ("a / b * c % d", "((a / b) * c) % d"),
+        ("a :: b :: c", "a :: (b :: c)"),
+    ];
+    for (source, bracketed) in cases {
+        assert_eq!(
+            parse(source).unwrap(),
+            parse(bracketed).unwrap(),
+            "{}",
+            source
+        );
+    }
+}
+
+#[test]
+fn test_cons_is_right_associative() {
+    let num = |n| Rc::new(Expression::Num(n));
+    assert_eq!(
+        parse("1 :: 2 :: nil").unwrap(),
+        Expression::Cons(
+            num(1),
+            Rc::new(Expression::Cons(num(2), Rc::new(Expression::Nil)))
+        )
+    );
+    // Arithmetic still folds to the left inside the elements.
+    assert_eq!(
+        parse("1 - 2 - 3 :: nil").unwrap(),
+        Expression::Cons(
+            Rc::new(Expression::Sub(
+                Rc::new(Expression::Sub(num(1), num(2))),
+                num(3)
+            )),
+            Rc::new(Expression::Nil)
+        )
+    );
+    assert_eq!(parse("1 :: 2 :: nil").unwrap(), parse("[1, 2]").unwrap());
+}
+
+#[test]
+fn test_seq() {
+    let var = |s: &str| Rc::new(Expression::Var(Variable { ident: s.into() }));
+    assert_eq!(
+        parse("a; b; c").unwrap(),
+        Expression::Seq(var("a"), Rc::new(Expression::Seq(var("b"), var("c"))))
+    );
+    assert_eq!(parse("a or b; c").unwrap(), parse("(a or b); c").unwrap());
+    assert_eq!(parse("f x; g y").unwrap(), parse("(f x); (g y)").unwrap());
+}
+
+#[test]
+fn test_seq_and_let() {
+    // A let body extends as far as possible, taking in any sequence.
+    assert_eq!(
+        parse("let x = 1 in a; b").unwrap(),
+        parse("let x = 1 in (a; b)").unwrap()
+    );
+    assert_eq!(
+        parse("a; let x = 1 in b").unwrap(),
+        parse("a; (let x = 1 in b)").unwrap()
+    );
+    assert_eq!(
+        parse("let x = a; b in x").unwrap(),
+        parse("let x = (a; b) in x").unwrap()
+    );
+    // `;;` is not a sequence.
+    assert!(parse("a;; b").is_err());
+}
+
+#[test]
+fn test_and() {
+    assert_eq!(
+        parse("1 and 2").unwrap(),
+        Expression::And(Rc::new(Expression::Num(1)), Rc::new(Expression::Num(2)))
+    );
+}
+
+#[test]
+fn test_double_and() {
+    assert_eq!(
+        parse("1 and 2 and 3").unwrap(),
+        Expression::And(
+            Rc::new(Expression::And(
+                Rc::new(Expression::Num(1)),
+                Rc::new(Expression::Num(2))
+            )),
+            Rc::new(Expression::Num(3))
+        )
+    );
+}
+
+#[test]
+fn test_fn() {
+    assert_eq!(
+        parse("1 ( 2 )").unwrap(),
+        Expression::Apply(Rc::new(Expression::Num(1)), Rc::new(Expression::Num(2)))
+    );
+}
+
+#[test]
+fn test_double_fn() {
+    assert_eq!(
+        parse("1 ( 2 ) ( 3 )").unwrap(),
+        Expression::Apply(
+            Rc::new(Expression::Apply(
+                Rc::new(Expression::Num(1)),
+                Rc::new(Expression::Num(2))
+            )),
+            Rc::new(Expression::Num(3))
+        )
+    );
+}
+
+#[test]
+fn test_chained_apply() {
+    let var = |s: &str| Rc::new(Expression::Var(Variable { ident: s.into() }));
+    let apply = |f, a| Rc::new(Expression::Apply(f, a));
+    assert_eq!(
+        parse("f a b c").unwrap(),
+        *apply(apply(apply(var("f"), var("a")), var("b")), var("c"))
+    );
+    assert_eq!(
+        parse("f (g x)").unwrap(),
+        *apply(var("f"), apply(var("g"), var("x")))
+    );
+}
+
+#[test]
+fn test_apply_binds_tightest() {
+    assert_eq!(parse("f x + g y").unwrap(), parse("(f x) + (g y)").unwrap());
+    assert_eq!(parse("f x :: xs").unwrap(), parse("(f x) :: xs").unwrap());
+    assert_eq!(parse("f -x").unwrap(), parse("f - x").unwrap());
+    assert_eq!(parse("<f x, y>").unwrap(), parse("<(f x), y>").unwrap());
+    assert_eq!(parse("[f x, y]").unwrap(), parse("[(f x), y]").unwrap());
+    assert_eq!(
+        parse("let y = f x in y").unwrap(),
+        parse("let y = (f x) in y").unwrap()
+    );
+}
+
+#[test]
+fn test_lambda() {
+    assert_eq!(
+        parse("fn a. a").unwrap(),
+        Expression::Fn(
+            Variable { ident: "a".into() },
+            Rc::new(Expression::Var(Variable { ident: "a".into() }))
+        )
+    );
+}
+
+#[test]
+fn test_multi_arg_lambda() {
+    assert_eq!(parse("fn a b. a").unwrap(), parse("fn a. fn b. a").unwrap());
+    assert_eq!(
+        parse("fn x y z. x").unwrap(),
+        parse("fn x. fn y. fn z. x").unwrap()
+    );
+    assert!(parse("fn. 1").is_err());
+}
+
+#[test]
+fn test_neq() {
+    let num = |n| Rc::new(Expression::Num(n));
+    assert_eq!(parse("1 != 2").unwrap(), Expression::Neq(num(1), num(2)));
+    assert_eq!(
+        parse("1 == 2 != 3").unwrap(),
+        Expression::Neq(Rc::new(Expression::Eq(num(1), num(2))), num(3))
+    );
+}
+
+#[test]
+fn test_comparisons() {
+    let num = |n| Rc::new(Expression::Num(n));
+    assert_eq!(parse("1 < 2").unwrap(), Expression::Lt(num(1), num(2)));
+    assert_eq!(parse("1 > 2").unwrap(), Expression::Gt(num(1), num(2)));
+    assert_eq!(parse("1 <= 2").unwrap(), Expression::Le(num(1), num(2)));
+    assert_eq!(parse("1 >= 2").unwrap(), Expression::Ge(num(1), num(2)));
+    assert_eq!(
+        parse("1 < 2 and 3 >= 2").unwrap(),
+        Expression::And(
+            Rc::new(Expression::Lt(num(1), num(2))),
+            Rc::new(Expression::Ge(num(3), num(2)))
+        )
+    );
+}
+
+#[test]
+fn test_lt_pair_ambiguity() {
+    let num = |n| Rc::new(Expression::Num(n));
+    assert_eq!(
+        parse("<1, 2> < <3, 4>").unwrap(),
+        Expression::Lt(
+            Rc::new(Expression::Pair(num(1), num(2))),
+            Rc::new(Expression::Pair(num(3), num(4)))
+        )
+    );
+    assert_eq!(
+        parse("<1 < 2, 3>").unwrap(),
+        Expression::Pair(Rc::new(Expression::Lt(num(1), num(2))), num(3))
+    );
+    assert_eq!(
+        parse("<1, (2 > 3)>").unwrap(),
+        Expression::Pair(num(1), Rc::new(Expression::Gt(num(2), num(3))))
+    );
+    // `f <` is a comparison, so a pair argument must be parenthesized.
+    assert!(parse("f <1, 2>").is_err());
+    assert_eq!(
+        parse("f (<1, 2>)").unwrap(),
+        Expression::Apply(
+            Rc::new(Expression::Var(Variable { ident: "f".into() })),
+            Rc::new(Expression::Pair(num(1), num(2)))
+        )
+    );
+}
+
+#[test]
+fn test_pair() {
+    assert_eq!(
+        parse("<1, 2>").unwrap(),
+        Expression::Pair(Rc::new(Expression::Num(1)), Rc::new(Expression::Num(2)))
+    );
+}
+
+#[test]
+fn test_nested_pair() {
+    assert_eq!(
+        parse("<<1,2>,3>").unwrap(),
+        Expression::Pair(
+            Rc::new(Expression::Pair(
+                Rc::new(Expression::Num(1)),
+                Rc::new(Expression::Num(2))
+            )),
+            Rc::new(Expression::Num(3))
+        )
+    );
+}
+
+#[test]
+fn test_tuple() {
+    let num = |n| Expression::Num(n);
+    assert_eq!(
+        parse("(1, 2, 3)").unwrap(),
+        Expression::Tuple(vec![num(1), num(2), num(3)])
+    );
+    assert_eq!(
+        parse("(1, 2)").unwrap(),
+        Expression::Tuple(vec![num(1), num(2)])
+    );
+    assert_eq!(
+        parse("(f x, (1, 2))").unwrap(),
+        Expression::Tuple(vec![
+            parse("f x").unwrap(),
+            Expression::Tuple(vec![num(1), num(2)])
+        ])
+    );
+    // A single parenthesized expression is only grouped.
+    assert_eq!(parse("(1)").unwrap(), num(1));
+    assert!(parse("(1,)").is_err());
+    assert!(parse("(,)").is_err());
+    assert!(parse("(1, 2 : Int)").is_err());
+}
+
+#[test]
+fn test_record() {
+    assert_eq!(
+        parse("{ a = 1, b = <2, 3> }").unwrap(),
+        Expression::Record(vec![
+            ("a".to_string(), Expression::Num(1)),
+            ("b".to_string(), parse("<2, 3>").unwrap()),
+        ])
+    );
+    assert_eq!(
+        parse("{x=1}.x").unwrap(),
+        Expression::Field(
+            Rc::new(Expression::Record(vec![(
+                "x".to_string(),
+                Expression::Num(1)
+            )])),
+            "x".to_string()
+        )
+    );
+    assert!(parse("{}").is_err());
+    assert!(parse("{ a = 1, }").is_err());
+
+    let err = parse("{ a = 1, a = 2 }").unwrap_err();
+    assert_eq!(err.message, "duplicate field in record");
+    assert_eq!(err.offset, 9);
+}
+
+#[test]
+fn test_field_binds_tightest() {
+    let field = |e: &str, f: &str| Expression::Field(Rc::new(parse(e).unwrap()), f.to_string());
+    assert_eq!(
+        parse("f r.a").unwrap(),
+        Expression::Apply(Rc::new(parse("f").unwrap()), Rc::new(field("r", "a")))
+    );
+    assert_eq!(
+        parse("r.a.b").unwrap(),
+        Expression::Field(Rc::new(field("r", "a")), "b".to_string())
+    );
+    assert_eq!(parse("(f r).a").unwrap(), field("f r", "a"));
+    assert_eq!(parse("fn r. r.a").unwrap(), parse("fn r. (r.a)").unwrap());
+}
+
+#[test]
+fn test_pair_mismatched_delimiters() {
+    assert!(parse("<1,2)").is_err());
+    assert!(parse("(1,2>").is_err());
+}
+
+#[test]
+fn test_whitespace() {
+    assert_eq!(
+        parse("1\t+\r\n  2").unwrap(),
+        Expression::Add(Rc::new(Expression::Num(1)), Rc::new(Expression::Num(2)))
+    );
+    assert_eq!(
+        parse("\n\tsucc \t(\n1 )\n").unwrap(),
+        Expression::Succ(Rc::new(Expression::Num(1)))
+    );
+    assert_eq!(
+        parse("<\t1 ,\n\n2  >").unwrap(),
+        Expression::Pair(Rc::new(Expression::Num(1)), Rc::new(Expression::Num(2)))
+    );
+}
+
+#[test]
+fn test_comments() {
+    assert_eq!(
+        parse("1 + -- one\n 2 -- two").unwrap(),
+        parse("1 + 2").unwrap()
+    );
+    assert_eq!(
+        parse("let x = {- the answer -} 42 in {- use it -} x").unwrap(),
+        parse("let x = 42 in x").unwrap()
+    );
+    assert_eq!(
+        parse("f{-no space needed-}x").unwrap(),
+        parse("f x").unwrap()
+    );
+    // Comments are not recognised inside literals.
+    assert_eq!(
+        parse("\"-- {- -}\"").unwrap(),
+        Expression::Str("-- {- -}".to_string())
+    );
+}
+
+#[test]
+fn test_nested_block_comment() {
+    assert_eq!(
+        parse("{- outer {- inner -} still outer -} 1").unwrap(),
+        Expression::Num(1)
+    );
+    assert_eq!(
+        parse("1 {- {- -- -} -} + 2").unwrap(),
+        parse("1 + 2").unwrap()
+    );
+    assert!(parse("{- {- -} 1").is_err());
+    assert!(parse("1 {- unterminated").is_err());
+}
+
+#[test]
+fn test_keywords_are_not_variables() {
+    let keywords = [
+        "let", "in", "if", "then", "else", "fn", "true", "false", "not", "and", "or", "succ",
+        "pred", "fst", "snd", "nil", "hd", "tl", "rec", "match", "with", "when",
+    ];
+    for keyword in keywords {
+        assert!(
+            !matches!(parse(keyword), Ok(Expression::Var(_))),
+            "{} parsed as a variable",
+            keyword
+        );
+    }
+}
+
+#[test]
+fn test_keyword_prefixed_variables() {
+    let idents = [
+        "lettuce",
+        "inner",
+        "iffy",
+        "thence",
+        "elsewhere",
+        "fnord",
+        "notable",
+        "android",
+        "orange",
+        "succulent",
+        "predator",
+        "fstop",
+        "sndx",
+        "hdr",
+        "tlx",
+        "record",
+        "matches",
+        "without",
+        "whenever",
+        "let_x",
+        "my_var",
+        "if_",
+    ];
+    for ident in idents {
+        assert_eq!(
+            parse(ident).unwrap(),
+            Expression::Var(Variable {
+                ident: ident.into()
+            })
+        );
+    }
+}
+
+#[test]
+fn test_let() {
+    assert_eq!(
+        parse("let x = 1 in x").unwrap(),
+        Expression::Let(
+            vec![Definition {
+                var: Variable { ident: "x".into() },
+                expr: Rc::new(Expression::Num(1)),
+                recursive: false,
+            }],
+            Rc::new(Expression::Var(Variable { ident: "x".into() }))
+        )
+    );
+}
+
+#[test]
+fn test_let_rec() {
+    let f = || Variable { ident: "f".into() };
+    let n = || Variable { ident: "n".into() };
+    let e = parse("let rec f = fn n. f n in f").unwrap();
+    let Expression::Let(definitions, body) = e else {
+        panic!("expected a let, got {:?}", e);
+    };
+    let [definition] = &definitions[..] else {
+        panic!("expected one definition, got {:?}", definitions);
+    };
+    assert!(definition.recursive);
+    assert_eq!(definition.var, f());
+    assert_eq!(
+        *definition.expr,
+        Expression::Fn(
+            n(),
+            Rc::new(Expression::Apply(
+                Rc::new(Expression::Var(f())),
+                Rc::new(Expression::Var(n()))
+            ))
+        )
+    );
+    assert_eq!(*body, Expression::Var(f()));
+
+    // `rec` must be a whole word.
+    let e = parse("let recx = 1 in recx").unwrap();
+    let Expression::Let(definitions, _) = e else {
+        panic!("expected a let, got {:?}", e);
+    };
+    assert!(!definitions[0].recursive);
+}
+
+#[test]
+fn test_let_rec_and() {
+    let e = parse("let rec even = fn n. odd n and odd = fn n. even n in even").unwrap();
+    let Expression::Let(definitions, _) = e else {
+        panic!("expected a let, got {:?}", e);
+    };
+    let names: Vec<&str> = definitions.iter().map(|d| &*d.var.ident).collect();
+    assert_eq!(names, ["even", "odd"]);
+    assert!(definitions.iter().all(|d| d.recursive));
+    assert_eq!(*definitions[0].expr, parse("fn n. odd n").unwrap());
+    assert_eq!(*definitions[1].expr, parse("fn n. even n").unwrap());
+}
+
+#[test]
+fn test_let_and_word_boundary() {
+    let var = |s: &str| Rc::new(Expression::Var(Variable { ident: s.into() }));
+
+    // `andy` is a variable, not `and y`.
+    let e = parse("let rec f = 1 and andy = 2 in f").unwrap();
+    let Expression::Let(definitions, _) = e else {
+        panic!("expected a let, got {:?}", e);
+    };
+    assert_eq!(&*definitions[1].var.ident, "andy");
+
+    // Without a following `=`, `and` is still a conjunction.
+    let e = parse("let x = a and b in x").unwrap();
+    let Expression::Let(definitions, _) = e else {
+        panic!("expected a let, got {:?}", e);
+    };
+    assert_eq!(definitions.len(), 1);
+    assert_eq!(*definitions[0].expr, Expression::And(var("a"), var("b")));
+    assert_eq!(
+        parse("let x = a and b == c in x").unwrap(),
+        parse("let x = (a and (b == c)) in x").unwrap()
+    );
+
+    // Comments may come between `and` and the definition, as whitespace may.
+    let e = parse("let x = 1 {- c -} and {- c -} y -- c\n = 2 in x").unwrap();
+    let Expression::Let(definitions, _) = e else {
+        panic!("expected a let, got {:?}", e);
+    };
+    assert_eq!(definitions.len(), 2);
+    assert_eq!(
+        parse("let x = a and {- c -} b == c in x").unwrap(),
+        parse("let x = (a and (b == c)) in x").unwrap()
+    );
+}
+
+#[test]
+fn test_program() {
+    let program = parse_program("let x = 1 ;; let rec f = fn n. f n and g = f ;; f x").unwrap();
+    assert_eq!(program.declarations.len(), 2);
+    assert_eq!(&*program.declarations[0][0].var.ident, "x");
+    assert_eq!(*program.declarations[0][0].expr, Expression::Num(1));
+    let group: Vec<&str> = program.declarations[1]
+        .iter()
+        .map(|d| &*d.var.ident)
+        .collect();
+    assert_eq!(group, ["f", "g"]);
+    assert!(program.declarations[1].iter().all(|d| d.recursive));
+    assert_eq!(program.main, Some(parse("f x").unwrap()));
+}
+
+#[test]
+fn test_program_newline_separated() {
+    let program = parse_program("let x = 1\nlet y = x + 1\n").unwrap();
+    assert_eq!(program.declarations.len(), 2);
+    assert_eq!(&*program.declarations[1][0].var.ident, "y");
+    assert_eq!(program.main, None);
+
+    // A `let ... in` is a main expression, not a declaration.
+    let program = parse_program("let x = 1 in x").unwrap();
+    assert!(program.declarations.is_empty());
+    assert_eq!(program.main, Some(parse("let x = 1 in x").unwrap()));
+
+    assert_eq!(parse_program("").unwrap().declarations.len(), 0);
+    assert!(parse_program("let x = 1 ;; 1 2 )").is_err());
+}
+
+#[test]
+fn test_match_list() {
+    let var = |s: &str| Variable { ident: s.into() };
+    let expected = Expression::Match(
+        Rc::new(Expression::Var(var("l"))),
+        vec![
+            MatchArm {
+                pattern: Pattern::Nil,
+                guard: None,
+                body: Expression::Num(0),
+            },
+            MatchArm {
+                pattern: Pattern::Cons(
+                    Box::new(Pattern::Var(var("x"))),
+                    Box::new(Pattern::Var(var("xs"))),
+                ),
+                guard: None,
+                body: Expression::Var(var("x")),
+            },
+        ],
+    );
+    assert_eq!(
+        parse("match l with nil -> 0 | x :: xs -> x").unwrap(),
+        expected
+    );
+    assert_eq!(
+        parse("match l with | nil -> 0 | x :: xs -> x").unwrap(),
+        expected
+    );
+    let e = parse("match l with -> 0").unwrap_err();
+    assert_eq!(e.expected.as_deref(), Some("pattern"));
+    assert!(parse("match l with | | nil -> 0").is_err());
+}
+
+#[test]
+fn test_match_nested_cons_pattern() {
+    let var = |s: &str| Box::new(Pattern::Var(Variable { ident: s.into() }));
+    let Expression::Match(_, arms) =
+        parse("match l with a :: (b :: nil) -> a | a :: b :: c -> b").unwrap()
+    else {
+        panic!("expected a match");
+    };
+    assert_eq!(
+        arms[0].pattern,
+        Pattern::Cons(
+            var("a"),
+            Box::new(Pattern::Cons(var("b"), Box::new(Pattern::Nil)))
+        )
+    );
+    assert_eq!(
+        arms[1].pattern,
+        Pattern::Cons(var("a"), Box::new(Pattern::Cons(var("b"), var("c"))))
+    );
+}
+
+#[test]
+fn test_match_pair() {
+    use crate::exprs::pattern_variables;
+
+    let var = |s: &str| Variable { ident: s.into() };
+    let e = parse("match <1, 2> with <a, b> -> a").unwrap();
+    let Expression::Match(scrutinee, arms) = e else {
+        panic!("expected a match, got {:?}", e);
+    };
+    assert_eq!(*scrutinee, parse("<1, 2>").unwrap());
+    let [MatchArm { pattern, body, .. }] = &arms[..] else {
+        panic!("expected one arm, got {:?}", arms);
+    };
+    assert_eq!(
+        *pattern,
+        Pattern::Pair(
+            Box::new(Pattern::Var(var("a"))),
+            Box::new(Pattern::Var(var("b")))
+        )
+    );
+    assert_eq!(pattern_variables(pattern), [&var("a"), &var("b")]);
+    assert_eq!(*body, Expression::Var(var("a")));
+
+    // Pairs nest, and hold any pattern.
+    let Expression::Match(_, arms) = parse("match p with <<a, b>, x :: nil> -> a").unwrap() else {
+        panic!("expected a match");
+    };
+    let names: Vec<&str> = pattern_variables(&arms[0].pattern)
+        .iter()
+        .map(|v| &*v.ident)
+        .collect();
+    assert_eq!(names, ["a", "b", "x"]);
+}
+
+#[test]
+fn test_match_literals_and_wildcard() {
+    let e = parse("match n with 0 -> true | -1 -> false | _ -> n").unwrap();
+    let Expression::Match(_, arms) = e else {
+        panic!("expected a match, got {:?}", e);
+    };
+    let patterns: Vec<Pattern> = arms.into_iter().map(|arm| arm.pattern).collect();
+    assert_eq!(
+        patterns,
+        [
+            Pattern::Literal(Expression::Num(0)),
+            Pattern::Literal(Expression::Num(-1)),
+            Pattern::Wildcard,
+        ]
+    );
+
+    let e = parse("match x with true -> 1 | \"s\" -> 2 | 'c' -> 3 | () -> 4 | _x -> 5").unwrap();
+    let Expression::Match(_, arms) = e else {
+        panic!("expected a match, got {:?}", e);
+    };
+    let patterns: Vec<Pattern> = arms.into_iter().map(|arm| arm.pattern).collect();
+    assert_eq!(
+        patterns,
+        [
+            Pattern::Literal(Expression::True),
+            Pattern::Literal(Expression::Str("s".to_string())),
+            Pattern::Literal(Expression::Char('c')),
+            Pattern::Literal(Expression::Unit),
+            // `_` only starts a wildcard as a whole word.
+            Pattern::Var(Variable { ident: "_x".into() }),
+        ]
+    );
+}
+
+#[test]
+fn test_match_guard() {
+    let e = parse("match n with n when n == 0 -> 1 | _ -> n - 1").unwrap();
+    let Expression::Match(_, arms) = e else {
+        panic!("expected a match, got {:?}", e);
+    };
+    assert_eq!(arms[0].guard, Some(parse("n == 0").unwrap()));
+    assert_eq!(arms[0].body, Expression::Num(1));
+    assert_eq!(arms[1].pattern, Pattern::Wildcard);
+    assert_eq!(arms[1].guard, None);
+    assert_eq!(arms[1].body, parse("n - 1").unwrap());
+
+    // `when` is a keyword, not a pattern variable.
+    assert!(parse("match n with when -> 1").is_err());
+}
+
+#[test]
+fn test_if_whitespace() {
+    assert_eq!(
+        parse("if\n (true)\tthen (1) else 2").unwrap(),
+        Expression::If(
+            Rc::new(Expression::True),
+            Rc::new(Expression::Num(1)),
+            Rc::new(Expression::Num(2))
+        )
+    );
+    assert_eq!(
+        parse("if\n true\tthen 1 else 2").unwrap(),
+        Expression::If(
+            Rc::new(Expression::True),
+            Rc::new(Expression::Num(1)),
+            Rc::new(Expression::Num(2))
+        )
+    );
+}
+
+#[test]
+fn test_annotation() {
+    let int = || Box::new(Type::Int);
+    assert_eq!(
+        parse("(1 : Int)").unwrap(),
+        Expression::Ann(Rc::new(Expression::Num(1)), Type::Int)
+    );
+    assert_eq!(
+        parse("(fn x. x : Int -> Int)").unwrap(),
+        Expression::Ann(Rc::new(parse("fn x. x").unwrap()), Type::Fn(int(), int()))
+    );
+    // Parentheses without an annotation only group.
+    assert_eq!(parse("((1))").unwrap(), Expression::Num(1));
+}
+
+#[test]
+fn test_type_syntax() {
+    let ann = |t: &str| match parse(&format!("(x : {})", t)).unwrap() {
+        Expression::Ann(_, t) => t,
+        e => panic!("expected an annotation, got {:?}", e),
+    };
+    let b = Box::new;
+    assert_eq!(
+        ann("Int -> Bool -> Unit"),
+        Type::Fn(b(Type::Int), b(Type::Fn(b(Type::Bool), b(Type::Unit))))
+    );
+    assert_eq!(
+        ann("(Int -> Bool) -> Unit"),
+        Type::Fn(b(Type::Fn(b(Type::Int), b(Type::Bool))), b(Type::Unit))
+    );
+    assert_eq!(
+        ann("Int * Bool -> Char"),
+        Type::Fn(b(Type::Pair(b(Type::Int), b(Type::Bool))), b(Type::Char))
+    );
+    assert_eq!(
+        ann("Int * Float * String"),
+        Type::Pair(b(Type::Int), b(Type::Pair(b(Type::Float), b(Type::String))))
+    );
+    assert_eq!(
+        ann("Int list list"),
+        Type::List(b(Type::List(b(Type::Int))))
+    );
+    assert_eq!(
+        ann("Int * Bool list"),
+        Type::Pair(b(Type::Int), b(Type::List(b(Type::Bool))))
+    );
+}
+
+#[test]
+fn test_malformed_type() {
+    for source in [
+        "(1 : )",
+        "(1 : Int ->)",
+        "(1 : int)",
+        "(1 : Int Bool)",
+        "(1 : list)",
+        "(1 : Intlist)",
+        "(1 : (Int)",
+    ] {
+        assert!(parse(source).is_err(), "{} parsed", source);
+    }
+    let e = parse("(1 : 2)").unwrap_err();
+    assert_eq!(e.offset, 5);
+    assert_eq!(e.expected.as_deref(), Some("type"));
+}
+
+#[test]
+fn test_if_without_else() {
+    assert_eq!(
+        parse("if true then 1").unwrap(),
+        Expression::If(
+            Rc::new(Expression::True),
+            Rc::new(Expression::Num(1)),
+            Rc::new(Expression::Unit)
+        )
+    );
+    assert_eq!(
+        parse("if a then if b then 1 else 2").unwrap(),
+        parse("if a then (if b then 1 else 2) else ()").unwrap()
+    );
+}
+
+#[test]
+fn test_bool_word_boundary() {
+    assert_eq!(parse("true").unwrap(), Expression::True);
+    assert_eq!(parse("false").unwrap(), Expression::False);
+    assert_eq!(parse("nil").unwrap(), Expression::Nil);
+    for ident in ["trueVar", "trueish", "falsey", "nilly", "true_x", "nil_"] {
+        assert_eq!(
+            parse(ident).unwrap(),
+            Expression::Var(Variable {
+                ident: ident.into()
+            })
+        );
+    }
+}
+
+#[test]
+fn test_trailing_garbage() {
+    let e = parse("1 @@@").unwrap_err();
+    assert_eq!(e.offset, 2);
+    assert_eq!(
+        e.expected.as_deref(),
+        Some("end of input or operator or expression")
+    );
+    assert_eq!(parse("<1, 2> )").unwrap_err().offset, 7);
+    assert_eq!(parse("succ(1) succ(2))").unwrap_err().offset, 15);
+}
+
+#[test]
+fn test_parse_error_position() {
+    let e = parse("succ(").unwrap_err();
+    assert_eq!(e.offset, 5);
+    assert_eq!(e.expected.as_deref(), Some("expression"));
+    assert_eq!(e.to_string(), "expected expression at offset 5");
+
+    let e = parse("let 1 = 2 in 3").unwrap_err();
+    assert_eq!(e.offset, 4);
+    assert_eq!(e.expected.as_deref(), Some("identifier"));
+
+    let e = parse("9223372036854775808").unwrap_err();
+    assert_eq!(e.offset, 0);
+    assert_eq!(e.expected, None);
+    assert_eq!(e.message, "numeric literal is out of range");
+}
@@ -4,8 +4,9 @@ use crate::exprs;
 use crate::parser;
 
 use exprs::expr_needs_paren;
-use parser::Expression;
 use parser::Expression::*;
+use parser::{BinOp, Expression, Pattern, Type};
+use std::fmt;
 
 /// Pretty print an expression into human-readable MiniML.
 pub fn pprint(e: &Expression) -> String {
@@ -13,12 +14,25 @@ pub fn pprint(e: &Expression) -> String {
         True => "True".to_string(),
         False => "False".to_string(),
         Num(n) => n.to_string(),
-        Var(v) => v.clone().ident,
+        // Debug formatting keeps the decimal point, e.g. `3.0` rather than `3`.
+        Float(f) => format!("{:?}", f),
+        Str(s) => format!("\"{}\"", escape(s, '"')),
+        Char(c) => format!("'{}'", escape(&c.to_string(), '\'')),
+        Var(v) => v.ident.to_string(),
         Nil => "nil".to_string(),
-        Let(var, bound_expr, body) => format!(
-            "let {} = {} in {}",
-            var.ident,
-            pprint(bound_expr),
+        Unit => "()".to_string(),
+        Let(definitions, body) => format!(
+            "let {}{} in {}",
+            if definitions.iter().any(|d| d.recursive) {
+                "rec "
+            } else {
+                ""
+            },
+            definitions
+                .iter()
+                .map(|d| format!("{} = {}", d.var.ident, pprint(&d.expr)))
+                .collect::<Vec<_>>()
+                .join(" and "),
             pprint_parenthesize(body)
         ),
         Not(e) => pprint_single_arity_call("not", e),
@@ -30,20 +44,486 @@ pub fn pprint(e: &Expression) -> String {
         ),
         Succ(e) => pprint_single_arity_call("succ", e),
         Pred(e) => pprint_single_arity_call("pred", e),
+        Neg(e) => format!("-{}", pprint_parenthesize(e)),
         Fst(e) => pprint_single_arity_call("fst", e),
         Snd(e) => pprint_single_arity_call("snd", e),
         Hd(e) => pprint_single_arity_call("hd", e),
         Tl(e) => pprint_single_arity_call("tl", e),
         Pair(e1, e2) => format!("<{}, {}>", pprint(e1), pprint(e2)),
+        Tuple(es) => format!("({})", es.iter().map(pprint).collect::<Vec<_>>().join(", ")),
+        Record(fields) => format!(
+            "{{ {} }}",
+            fields
+                .iter()
+                .map(|(f, e)| format!("{} = {}", f, pprint(e)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Field(e, f) => format!("{}.{}", pprint_parenthesize(e), f),
         Fn(v, e) => format!("fn {}. {}", v.ident, pprint(e)),
         Eq(e1, e2) => format!("{} == {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Neq(e1, e2) => format!("{} != {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Lt(e1, e2) => format!("{} < {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Gt(e1, e2) => format!("{} > {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Le(e1, e2) => format!("{} <= {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Ge(e1, e2) => format!("{} >= {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
         Cons(e1, e2) => format!("{} :: {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
         And(e1, e2) => format!("{} && {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Or(e1, e2) => format!("{} || {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
         Add(e1, e2) => format!("{} + {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Sub(e1, e2) => format!("{} - {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Mul(e1, e2) => format!("{} * {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Div(e1, e2) => format!("{} / {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Mod(e1, e2) => format!("{} % {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
         Apply(e1, e2) => format!("{} {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Seq(e1, e2) => format!("{}; {}", pprint_parenthesize(e1), pprint_parenthesize(e2)),
+        Ann(e, t) => format!("({} : {})", pprint(e), pprint_type(t)),
+        Match(e, arms) => format!(
+            "match {} with {}",
+            pprint(e),
+            arms.iter()
+                .map(|arm| match &arm.guard {
+                    Some(guard) => format!(
+                        "{} when {} -> {}",
+                        pprint_pattern(&arm.pattern),
+                        pprint_parenthesize(guard),
+                        pprint_parenthesize(&arm.body)
+                    ),
+                    None => format!(
+                        "{} -> {}",
+                        pprint_pattern(&arm.pattern),
+                        pprint_parenthesize(&arm.body)
+                    ),
+                })
+                .collect::<Vec<_>>()
+                .join(" | ")
+        ),
+    }
+}
+
+/// Pretty print a type, as it would be written in an annotation.
+pub fn pprint_type(t: &Type) -> String {
+    match t {
+        Type::Int => "Int".to_string(),
+        Type::Bool => "Bool".to_string(),
+        Type::Float => "Float".to_string(),
+        Type::String => "String".to_string(),
+        Type::Char => "Char".to_string(),
+        Type::Unit => "Unit".to_string(),
+        Type::Fn(arg, result) => match **arg {
+            Type::Fn(_, _) => format!("({}) -> {}", pprint_type(arg), pprint_type(result)),
+            _ => format!("{} -> {}", pprint_type(arg), pprint_type(result)),
+        },
+        Type::Pair(first, second) => {
+            let first = match **first {
+                Type::Fn(_, _) | Type::Pair(_, _) => format!("({})", pprint_type(first)),
+                _ => pprint_type(first),
+            };
+            let second = match **second {
+                Type::Fn(_, _) => format!("({})", pprint_type(second)),
+                _ => pprint_type(second),
+            };
+            format!("{} * {}", first, second)
+        }
+        Type::List(element) => match **element {
+            Type::Fn(_, _) | Type::Pair(_, _) => format!("({}) list", pprint_type(element)),
+            _ => format!("{} list", pprint_type(element)),
+        },
+        // 'a to 'z, then 'a1 to 'z1, and so on.
+        Type::Var(n) => {
+            let letter = char::from(b'a' + (n % 26) as u8);
+            match n / 26 {
+                0 => format!("'{}", letter),
+                suffix => format!("'{}{}", letter, suffix),
+            }
+        }
+    }
+}
+
+/// Pretty print a pattern of a `match` arm.
+pub fn pprint_pattern(p: &Pattern) -> String {
+    match p {
+        Pattern::Wildcard => "_".to_string(),
+        Pattern::Var(v) => v.ident.to_string(),
+        Pattern::Literal(e) => pprint(e),
+        Pattern::Nil => "nil".to_string(),
+        Pattern::Cons(head, tail) => match **head {
+            Pattern::Cons(_, _) => {
+                format!("({}) :: {}", pprint_pattern(head), pprint_pattern(tail))
+            }
+            _ => format!("{} :: {}", pprint_pattern(head), pprint_pattern(tail)),
+        },
+        Pattern::Pair(p1, p2) => format!("<{}, {}>", pprint_pattern(p1), pprint_pattern(p2)),
+    }
+}
+
+/// Renders an expression as MiniML source, which parses back to the same
+/// expression, with as few parentheses as that allows. Unlike [`pprint`],
+/// booleans are lowercase and `and` and `or` are keywords, as the parser expects.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&display_in(self, Follows::Nothing))
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Literal(e) => write!(f, "{}", e),
+            Pattern::Cons(head, tail) => match **head {
+                Pattern::Cons(_, _) => write!(f, "({}) :: {}", head, tail),
+                _ => write!(f, "{} :: {}", head, tail),
+            },
+            Pattern::Pair(p1, p2) => write!(f, "<{}, {}>", p1, p2),
+            p => f.write_str(&pprint_pattern(p)),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&pprint_type(self))
+    }
+}
+
+impl Expression {
+    /// Render the tree as a Lisp-style s-expression, e.g. `1 + 2` as
+    /// `(add (num 1) (num 2))`. Every node is tagged with its variant's name in
+    /// lowercase, and nodes without children are bare, e.g. `true` and `nil`.
+    pub fn to_sexpr(&self) -> String {
+        let node = |tag: &str, children: &[String]| format!("({} {})", tag, children.join(" "));
+        if let Some((op, e1, e2)) = self.as_binary() {
+            let tag = format!("{:?}", op).to_lowercase();
+            return node(&tag, &[e1.to_sexpr(), e2.to_sexpr()]);
+        }
+        match self {
+            True => "true".to_string(),
+            False => "false".to_string(),
+            Num(n) => node("num", &[n.to_string()]),
+            Float(f) => node("float", &[format!("{:?}", f)]),
+            Str(s) => node("str", &[format!("\"{}\"", escape(s, '"'))]),
+            Char(c) => node("char", &[format!("'{}'", escape(&c.to_string(), '\''))]),
+            Var(v) => format!("(var {})", v.ident),
+            Nil => "nil".to_string(),
+            Unit => "unit".to_string(),
+            Let(definitions, body) => {
+                let tag = if definitions.iter().any(|d| d.recursive) {
+                    "let-rec"
+                } else {
+                    "let"
+                };
+                let definitions = definitions
+                    .iter()
+                    .map(|d| node(&d.var.ident, &[d.expr.to_sexpr()]))
+                    .collect::<Vec<_>>();
+                node(
+                    tag,
+                    &[format!("({})", definitions.join(" ")), body.to_sexpr()],
+                )
+            }
+            Not(e) => node("not", &[e.to_sexpr()]),
+            If(cond, yes, no) => node("if", &[cond.to_sexpr(), yes.to_sexpr(), no.to_sexpr()]),
+            Succ(e) => node("succ", &[e.to_sexpr()]),
+            Pred(e) => node("pred", &[e.to_sexpr()]),
+            Neg(e) => node("neg", &[e.to_sexpr()]),
+            Fst(e) => node("fst", &[e.to_sexpr()]),
+            Snd(e) => node("snd", &[e.to_sexpr()]),
+            Hd(e) => node("hd", &[e.to_sexpr()]),
+            Tl(e) => node("tl", &[e.to_sexpr()]),
+            Pair(e1, e2) => node("pair", &[e1.to_sexpr(), e2.to_sexpr()]),
+            Tuple(es) => node(
+                "tuple",
+                &es.iter().map(Expression::to_sexpr).collect::<Vec<_>>(),
+            ),
+            Record(fields) => node(
+                "record",
+                &fields
+                    .iter()
+                    .map(|(f, e)| node(f, &[e.to_sexpr()]))
+                    .collect::<Vec<_>>(),
+            ),
+            Field(e, f) => node("field", &[e.to_sexpr(), f.clone()]),
+            Fn(v, body) => node("fn", &[v.ident.to_string(), body.to_sexpr()]),
+            Apply(e1, e2) => node("apply", &[e1.to_sexpr(), e2.to_sexpr()]),
+            Ann(e, t) => node("ann", &[e.to_sexpr(), type_sexpr(t)]),
+            Match(e, arms) => {
+                let mut children = vec![e.to_sexpr()];
+                for arm in arms {
+                    let mut arm_children = vec![pattern_sexpr(&arm.pattern)];
+                    if let Some(guard) = &arm.guard {
+                        arm_children.push(node("when", &[guard.to_sexpr()]));
+                    }
+                    arm_children.push(arm.body.to_sexpr());
+                    children.push(node("arm", &arm_children));
+                }
+                node("match", &children)
+            }
+            _ => unreachable!("binary operations are handled above"),
+        }
+    }
+}
+
+fn pattern_sexpr(p: &Pattern) -> String {
+    match p {
+        Pattern::Wildcard => "_".to_string(),
+        Pattern::Var(v) => format!("(var {})", v.ident),
+        Pattern::Literal(e) => e.to_sexpr(),
+        Pattern::Nil => "nil".to_string(),
+        Pattern::Cons(head, tail) => {
+            format!("(cons {} {})", pattern_sexpr(head), pattern_sexpr(tail))
+        }
+        Pattern::Pair(p1, p2) => format!("(pair {} {})", pattern_sexpr(p1), pattern_sexpr(p2)),
+    }
+}
+
+fn type_sexpr(t: &Type) -> String {
+    match t {
+        Type::Fn(arg, result) => format!("(-> {} {})", type_sexpr(arg), type_sexpr(result)),
+        Type::Pair(first, second) => format!("(* {} {})", type_sexpr(first), type_sexpr(second)),
+        Type::List(element) => format!("(list {})", type_sexpr(element)),
+        t => pprint_type(t).to_lowercase(),
+    }
+}
+
+// How tightly each position in the source binds, loosest first. Binary
+// operators take the levels in between, from their precedence.
+const TOP: u8 = 0;
+const OPERAND: u8 = 8;
+const APPLY: u8 = 9;
+const FIELD: u8 = 10;
+const ATOM: u8 = 11;
+
+/// What comes after an expression in the source. `let`, `if` and `match`
+/// extend as far as they can, so they need parentheses if more follows.
+#[derive(Clone, Copy, PartialEq)]
+enum Follows {
+    /// The end of the input, or a closing delimiter or keyword.
+    Nothing,
+    /// Another arm of an enclosing `match`.
+    Arm,
+    /// More of the same expression, e.g. an operator.
+    More,
+}
+
+/// The loosest position in which an expression can appear without parentheses.
+fn level(e: &Expression) -> u8 {
+    if let Some((op, _, _)) = e.as_binary() {
+        return op.precedence();
+    }
+    match e {
+        Fn(_, _) | Neg(_) => OPERAND,
+        // The sign of a negative literal is a prefix `-`.
+        Num(n) if *n < 0 => OPERAND,
+        Float(f) if f.is_sign_negative() => OPERAND,
+        Apply(_, _) => APPLY,
+        Field(_, _) => FIELD,
+        _ => ATOM,
+    }
+}
+
+/// Render an expression as source, in a position binding at least as tightly
+/// as `min`, parenthesizing it only if it would otherwise read differently.
+fn display_at(e: &Expression, min: u8, follows: Follows) -> String {
+    let open = match e {
+        // `>` followed by an operand would be a comparison, as in `<a, b> - c`.
+        Let(_, _) | If(_, _, _) | Pair(_, _) => follows == Follows::More,
+        Match(_, _) => follows != Follows::Nothing,
+        _ => false,
+    };
+    if level(e) < min || open {
+        format!("({})", display_in(e, Follows::Nothing))
+    } else {
+        display_in(e, follows)
+    }
+}
+
+/// Render an element of a pair, tuple or record. `<` would open a pair, as
+/// in `(a < b, c > d)`, so comparisons in the element are parenthesized.
+fn display_element(e: &Expression) -> String {
+    if exposes_angle(e) {
+        format!("({})", display_in(e, Follows::Nothing))
+    } else {
+        display_in(e, Follows::Nothing)
+    }
+}
+
+/// Whether `<` or `>` may appear in the source of `e` outside any brackets.
+fn exposes_angle(e: &Expression) -> bool {
+    match e {
+        Lt(_, _) | Gt(_, _) => true,
+        Let(definitions, body) => {
+            definitions.iter().any(|d| exposes_angle(&d.expr)) || exposes_angle(body)
+        }
+        If(cond, yes, no) => exposes_angle(cond) || exposes_angle(yes) || exposes_angle(no),
+        Match(e, arms) => {
+            exposes_angle(e)
+                || arms.iter().any(|arm| {
+                    arm.guard.as_ref().is_some_and(exposes_angle) || exposes_angle(&arm.body)
+                })
+        }
+        Neg(e) | Fn(_, e) | Field(e, _) => exposes_angle(e),
+        Apply(e1, e2) => exposes_angle(e1) || exposes_angle(e2),
+        _ => match e.as_binary() {
+            Some((_, e1, e2)) => exposes_angle(e1) || exposes_angle(e2),
+            None => false,
+        },
     }
 }
 
+/// Render an expression as source without parentheses around it.
+fn display_in(e: &Expression, follows: Follows) -> String {
+    let call =
+        |keyword: &str, e: &Expression| format!("{}({})", keyword, display_in(e, Follows::Nothing));
+    let comma_separated = |es: &mut dyn Iterator<Item = &Expression>| {
+        es.map(display_element).collect::<Vec<_>>().join(", ")
+    };
+    if let Some((op, e1, e2)) = e.as_binary() {
+        let p = op.precedence();
+        let (left, right) = if op.is_right_assoc() {
+            (p + 1, p)
+        } else {
+            (p, p + 1)
+        };
+        let e1 = display_at(e1, left, Follows::More);
+        let e2 = display_at(e2, right, follows);
+        return match op {
+            BinOp::Seq => format!("{}; {}", e1, e2),
+            op => format!("{} {} {}", e1, op.symbol(), e2),
+        };
+    }
+    match e {
+        True => "true".to_string(),
+        False => "false".to_string(),
+        Num(n) => n.to_string(),
+        // Display never uses an exponent, but drops the point from whole numbers.
+        Float(f) if f.fract() == 0.0 && f.is_finite() => format!("{}.0", f),
+        Float(f) => f.to_string(),
+        Str(s) => format!("\"{}\"", escape(s, '"')),
+        Char(c) => format!("'{}'", escape(&c.to_string(), '\'')),
+        Var(v) => v.ident.to_string(),
+        Nil => "nil".to_string(),
+        Unit => "()".to_string(),
+        Let(definitions, body) => format!(
+            "let {}{} in {}",
+            if definitions.iter().any(|d| d.recursive) {
+                "rec "
+            } else {
+                ""
+            },
+            definitions
+                .iter()
+                .map(|d| format!(
+                    "{} = {}",
+                    d.var.ident,
+                    display_in(&d.expr, Follows::Nothing)
+                ))
+                .collect::<Vec<_>>()
+                .join(" and "),
+            display_at(body, TOP, follows)
+        ),
+        Not(e) => call("not", e),
+        If(cond, yes, no) => format!(
+            "if {} then {} else {}",
+            display_in(cond, Follows::Nothing),
+            display_in(yes, Follows::Nothing),
+            display_at(no, TOP, follows)
+        ),
+        Succ(e) => call("succ", e),
+        Pred(e) => call("pred", e),
+        Neg(e) => {
+            let e = display_at(e, OPERAND, follows);
+            // `-1` would be a literal and `--` a comment.
+            if e.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+                format!("- {}", e)
+            } else {
+                format!("-{}", e)
+            }
+        }
+        Fst(e) => call("fst", e),
+        Snd(e) => call("snd", e),
+        Hd(e) => call("hd", e),
+        Tl(e) => call("tl", e),
+        Pair(e1, e2) => format!("<{}, {}>", display_element(e1), display_element(e2)),
+        Tuple(es) => format!("({})", comma_separated(&mut es.iter())),
+        Record(fields) => format!(
+            "{{ {} }}",
+            fields
+                .iter()
+                .map(|(f, e)| format!("{} = {}", f, display_element(e)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        // `1.x` would be a malformed float.
+        Field(e, f) if matches!(**e, Num(_)) => format!("({}).{}", display_in(e, follows), f),
+        Field(e, f) => format!("{}.{}", display_at(e, FIELD, Follows::More), f),
+        Fn(_, _) => {
+            // `fn x. fn y. e` is written `fn x y. e`.
+            let mut params = Vec::new();
+            let mut body = e;
+            while let Fn(v, e) = body {
+                params.push(&*v.ident);
+                body = e;
+            }
+            format!(
+                "fn {}. {}",
+                params.join(" "),
+                display_at(body, OPERAND, follows)
+            )
+        }
+        Apply(e1, e2) => format!(
+            "{} {}",
+            display_at(e1, APPLY, Follows::More),
+            display_at(e2, FIELD, Follows::More)
+        ),
+        Ann(e, t) => format!("({} : {})", display_in(e, Follows::Nothing), t),
+        Match(e, arms) => format!(
+            "match {} with {}",
+            display_in(e, Follows::Nothing),
+            arms.iter()
+                .enumerate()
+                .map(|(i, arm)| {
+                    let body_follows = if i + 1 == arms.len() {
+                        follows
+                    } else {
+                        Follows::Arm
+                    };
+                    let body = display_at(&arm.body, TOP, body_follows);
+                    match &arm.guard {
+                        Some(guard) => format!(
+                            "{} when {} -> {}",
+                            arm.pattern,
+                            display_in(guard, Follows::Nothing),
+                            body
+                        ),
+                        None => format!("{} -> {}", arm.pattern, body),
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" | ")
+        ),
+        _ => unreachable!("binary operations are handled above"),
+    }
+}
+
+/// Escape the body of a string or character literal delimited by `quote`,
+/// so that it reads back as the same literal.
+fn escape(s: &str, quote: char) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            c if c == quote => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 /// Pretty print an expression, mayber adding parentheses if needed.
 fn pprint_parenthesize(e: &Expression) -> String {
     if expr_needs_paren(e) {
@@ -66,12 +546,14 @@ fn pprint_single_arity_call(s: &str, e: &Expression) -> String {
 mod tests {
     use super::*;
     use crate::exprs;
+    use crate::parser::MatchArm;
     use exprs::*;
+    use std::rc::Rc;
 
     #[test]
     fn test_basic_pprint() {
         let expr = bAdd(bNum(3), bNum(5));
-        assert_eq!(pprint(&*expr), "3 + 5");
+        assert_eq!(pprint(&expr), "3 + 5");
 
         let expr = bLet(
             bVariable("x"),
@@ -79,11 +561,245 @@ mod tests {
             bPair(bEq(bVar("x"), bNum(5)), bTl(bCons(bFalse(), bNil()))),
         );
         assert_eq!(
-            pprint(&*expr),
+            pprint(&expr),
             "let x = succ(3 + y) in <x == 5, tl(False :: nil)>"
         );
 
         let expr = bFn("a", bFn("b", bAdd(bSucc(bVar("a")), bSucc(bVar("b")))));
-        assert_eq!(pprint(&*expr), "fn a. fn b. (succ a) + (succ b)");
+        assert_eq!(pprint(&expr), "fn a. fn b. (succ a) + (succ b)");
+    }
+
+    #[test]
+    fn test_pprint_type() {
+        let b = Box::new;
+        let t = Type::Fn(
+            b(Type::Fn(b(Type::Int), b(Type::Bool))),
+            b(Type::Fn(b(Type::Unit), b(Type::Char))),
+        );
+        assert_eq!(pprint_type(&t), "(Int -> Bool) -> Unit -> Char");
+
+        let t = Type::Pair(
+            b(Type::Pair(b(Type::Int), b(Type::Float))),
+            b(Type::List(b(Type::Pair(b(Type::String), b(Type::Bool))))),
+        );
+        assert_eq!(pprint_type(&t), "(Int * Float) * (String * Bool) list");
+
+        let expr = bTuple(vec![Num(1), Tuple(vec![True, Unit]), Add(bNum(1), bNum(2))]);
+        assert_eq!(pprint(&expr), "(1, (True, ()), 1 + 2)");
+
+        let expr = bField(
+            bRecord(vec![("a", Num(1)), ("b", Apply(bVar("f"), bNum(2)))]),
+            "b",
+        );
+        assert_eq!(pprint(&expr), "{ a = 1, b = f 2 }.b");
+        let expr = bField(bField(bApply(bVar("f"), bVar("r")), "a"), "b");
+        assert_eq!(pprint(&expr), "(f r).a.b");
+
+        let expr = Ann(bNum(1), Type::Int);
+        assert_eq!(pprint(&expr), "(1 : Int)");
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(bAdd(bNum(1), bNum(2)).to_string(), "1 + 2");
+        assert_eq!(And(bTrue(), bFalse()).to_string(), "true and false");
+        assert_eq!(
+            bLet(bVariable("x"), bNum(1), bFn("y", bVar("x"))).to_string(),
+            "let x = 1 in fn y. x"
+        );
+        assert_eq!(
+            bIf(bVar("b"), bFloat(3.0), bFloat(-0.5)).to_string(),
+            "if b then 3.0 else -0.5"
+        );
+    }
+
+    #[test]
+    fn test_display_precedence() {
+        let (one, two, three) = (|| bNum(1), || bNum(2), || bNum(3));
+        assert_eq!(bMul(bAdd(one(), two()), three()).to_string(), "(1 + 2) * 3");
+        assert_eq!(bAdd(bMul(one(), two()), three()).to_string(), "1 * 2 + 3");
+        assert_eq!(bAdd(one(), bMul(two(), three())).to_string(), "1 + 2 * 3");
+        assert_eq!(bMul(one(), bAdd(two(), three())).to_string(), "1 * (2 + 3)");
+        assert_eq!(bEq(bAdd(one(), two()), three()).to_string(), "1 + 2 == 3");
+        assert_eq!(
+            Or(Rc::new(And(bTrue(), bFalse())), bTrue()).to_string(),
+            "true and false or true"
+        );
+        assert_eq!(
+            And(bTrue(), Rc::new(Or(bFalse(), bTrue()))).to_string(),
+            "true and (false or true)"
+        );
+    }
+
+    #[test]
+    fn test_display_associativity() {
+        let (one, two, three) = (|| bNum(1), || bNum(2), || bNum(3));
+        assert_eq!(bSub(bSub(one(), two()), three()).to_string(), "1 - 2 - 3");
+        assert_eq!(bSub(one(), bSub(two(), three())).to_string(), "1 - (2 - 3)");
+        assert_eq!(
+            bCons(one(), bCons(two(), bNil())).to_string(),
+            "1 :: 2 :: nil"
+        );
+        assert_eq!(
+            bCons(bCons(one(), bNil()), bNil()).to_string(),
+            "(1 :: nil) :: nil"
+        );
+        assert_eq!(bSeq(bSeq(one(), two()), three()).to_string(), "(1; 2); 3");
+        assert_eq!(bSeq(one(), bSeq(two(), three())).to_string(), "1; 2; 3");
+    }
+
+    #[test]
+    fn test_display_lambdas_and_applications() {
+        let (f, x, y) = (|| bVar("f"), || bVar("x"), || bVar("y"));
+        assert_eq!(bApply(bApply(f(), x()), y()).to_string(), "f x y");
+        assert_eq!(bApply(f(), bApply(x(), y())).to_string(), "f (x y)");
+        assert_eq!(
+            bFn("x", bFn("y", bApply(x(), y()))).to_string(),
+            "fn x y. x y"
+        );
+        assert_eq!(bFn("x", bAdd(x(), bNum(1))).to_string(), "fn x. (x + 1)");
+        assert_eq!(
+            bApply(bFn("x", x()), bFn("y", y())).to_string(),
+            "(fn x. x) (fn y. y)"
+        );
+        assert_eq!(
+            bFn("x", bFn("y", bApply(bFn("z", x()), y()))).to_string(),
+            "fn x y. (fn z. x) y"
+        );
+        assert_eq!(bAdd(bApply(f(), x()), bNum(1)).to_string(), "f x + 1");
+        assert_eq!(bApply(f(), bNeg(x())).to_string(), "f (-x)");
+        assert_eq!(bNeg(bApply(f(), x())).to_string(), "-f x");
+        assert_eq!(bNeg(bNum(1)).to_string(), "- 1");
+        assert_eq!(bApply(f(), bNum(-1)).to_string(), "f (-1)");
+        assert_eq!(bSub(f(), bNum(1)).to_string(), "f - 1");
+        assert_eq!(bApply(bNum(-1), x()).to_string(), "(-1) x");
+        assert_eq!(bField(bApply(f(), x()), "a").to_string(), "(f x).a");
+        assert_eq!(bApply(f(), bField(x(), "a")).to_string(), "f x.a");
+    }
+
+    #[test]
+    fn test_display_open_forms() {
+        let let_x = || bLet(bVariable("x"), bNum(1), bVar("x"));
+        assert_eq!(bAdd(let_x(), bNum(1)).to_string(), "(let x = 1 in x) + 1");
+        assert_eq!(bAdd(bNum(1), let_x()).to_string(), "1 + let x = 1 in x");
+        assert_eq!(bApply(bVar("f"), let_x()).to_string(), "f (let x = 1 in x)");
+        assert_eq!(
+            bIf(bTrue(), bSeq(bNum(1), bNum(2)), bNum(3)).to_string(),
+            "if true then 1; 2 else 3"
+        );
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for source in [
+            "1 + 2 * 3 - 4 / 5 % 6",
+            "-x + - 1 + -1",
+            "let rec f = fn x. f x and g = fn y. y in f (g 1)",
+            "if a < b then \"yes\\n\" else 'n'",
+            "fn x y. x y; ()",
+            "<(1 < 2), [1, 2, 3]> == <true, nil>",
+            "(1, 2.5, { a = f x, b = r.a.b }.b)",
+            "not(a or b and c) :: succ(pred(1)) :: hd(tl(fst(snd(p)))) :: nil",
+            "match l with nil -> 0 | x :: <_, 'c'> :: xs when x > 0 -> (match x with _ -> 1)",
+            "(let x = 1 in x) + (if b then 1 else 2)",
+            "(f : (Int -> Bool) * Int list)",
+            "(fn x. x) (1).x",
+            "(a < b, c > d)",
+            "{ a = (let x = 1 in x < y), b = 2 }",
+            "match a with 1 -> (match b with _ -> 2) | _ -> 3",
+            "(if a then b else c) d",
+            "- -x - - 1",
+            "fn x. (let y = x in y) + 1",
+            "(fn x. x) + 1",
+            "(<a, b>) - c",
+            "f (<a, b>) x",
+        ] {
+            let e = crate::parser::parse(source).unwrap();
+            assert_eq!(crate::parser::parse(&e.to_string()), Ok(e), "{}", source);
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_display_round_trip_property(e: Expression) {
+            proptest::prop_assert_eq!(crate::parser::parse(&e.to_string()), Ok(e));
+        }
+    }
+
+    #[test]
+    fn test_sexpr() {
+        let sexpr = |source: &str| crate::parser::parse(source).unwrap().to_sexpr();
+        assert_eq!(sexpr("1 + 2"), "(add (num 1) (num 2))");
+        assert_eq!(
+            sexpr("1 + 2 * 3 - 4 / 5 % 6"),
+            "(sub (add (num 1) (mul (num 2) (num 3))) (mod (div (num 4) (num 5)) (num 6)))"
+        );
+        assert_eq!(
+            sexpr("a == b; a != b; a < b; a > b; a <= b; a >= b"),
+            "(seq (eq (var a) (var b)) (seq (neq (var a) (var b)) (seq (lt (var a) (var b)) \
+             (seq (gt (var a) (var b)) (seq (le (var a) (var b)) (ge (var a) (var b)))))))"
+        );
+        assert_eq!(
+            sexpr("true and false or 1 :: nil"),
+            "(or (and true false) (cons (num 1) nil))"
+        );
+        assert_eq!(
+            sexpr("<2.5, \"s\\n\"> :: ('c', (), x) :: nil"),
+            "(cons (pair (float 2.5) (str \"s\\n\")) (cons (tuple (char 'c') unit (var x)) nil))"
+        );
+        assert_eq!(
+            sexpr("not(succ(pred(fst(snd(hd(tl(-x)))))))"),
+            "(not (succ (pred (fst (snd (hd (tl (neg (var x)))))))))"
+        );
+        assert_eq!(
+            sexpr("let x = 1 in if x then f x else { a = 1 }.a"),
+            "(let ((x (num 1))) (if (var x) (apply (var f) (var x)) (field (record (a (num 1))) a)))"
+        );
+        assert_eq!(
+            sexpr("let rec f = fn n. g n and g = fn n. f n in (f : Int -> Int * Bool list)"),
+            "(let-rec ((f (fn n (apply (var g) (var n)))) (g (fn n (apply (var f) (var n))))) \
+             (ann (var f) (-> int (* int (list bool)))))"
+        );
+        assert_eq!(
+            sexpr("match l with nil -> 0 | <x, _> :: 1 :: xs when x -> x"),
+            "(match (var l) (arm nil (num 0)) \
+             (arm (cons (pair (var x) _) (cons (num 1) (var xs))) (when (var x)) (var x)))"
+        );
+    }
+
+    #[test]
+    fn test_pprint_match() {
+        let x = || Box::new(Pattern::Var(*bVariable("x")));
+        let xs = || Box::new(Pattern::Var(*bVariable("xs")));
+        let expr = Match(
+            bVar("l"),
+            vec![
+                MatchArm {
+                    pattern: Pattern::Nil,
+                    guard: None,
+                    body: Num(0),
+                },
+                MatchArm {
+                    pattern: Pattern::Cons(x(), xs()),
+                    guard: Some(Gt(bVar("x"), bNum(0))),
+                    body: Add(bVar("x"), bNum(1)),
+                },
+                MatchArm {
+                    pattern: Pattern::Wildcard,
+                    guard: None,
+                    body: Num(0),
+                },
+            ],
+        );
+        assert_eq!(
+            pprint(&expr),
+            "match l with nil -> 0 | x :: xs when (x > 0) -> (x + 1) | _ -> 0"
+        );
+
+        let pattern = Pattern::Cons(Box::new(Pattern::Cons(x(), xs())), xs());
+        assert_eq!(pprint_pattern(&pattern), "(x :: xs) :: xs");
+
+        let pattern = Pattern::Pair(x(), Box::new(Pattern::Cons(x(), xs())));
+        assert_eq!(pprint_pattern(&pattern), "<x, x :: xs>");
     }
 }
@@ -0,0 +1,116 @@
+//! reduce.rs: Reductions of the lambda calculus, which rewrite functions and
+//! their applications without evaluating anything else.
+
+use std::rc::Rc;
+
+use crate::exprs::map_subexpressions;
+use crate::parser::Expression;
+use crate::subst::{free_vars, subst};
+use crate::visit::map_expr;
+
+/// Reduce the leftmost-outermost application of a function, `(fn x. body) arg`,
+/// to `body` with `arg` substituted for `x`. Redexes under functions are
+/// reduced too. `None` if there are no redexes.
+pub fn beta_reduce(e: &Expression) -> Option<Expression> {
+    if let Expression::Apply(f, arg) = e {
+        if let Expression::Fn(param, body) = &**f {
+            return Some(subst(body, &param.ident, arg));
+        }
+    }
+    let mut reduced = false;
+    let e = map_subexpressions(e.clone(), |child| {
+        if reduced {
+            return child;
+        }
+        match beta_reduce(&child) {
+            Some(child) => {
+                reduced = true;
+                child
+            }
+            None => child,
+        }
+    });
+    reduced.then_some(e)
+}
+
+/// Rewrite each `fn x. f x` to just `f`, where `x` isn't free in `f`. Only
+/// variables and functions are unwrapped, so evaluation is unchanged: `f`
+/// would otherwise be evaluated when the function was, rather than when it
+/// was applied, which may fail or not terminate.
+pub fn eta_reduce(e: Expression) -> Expression {
+    map_expr(e, &|e| match e {
+        Expression::Fn(param, body) => match Rc::unwrap_or_clone(body) {
+            Expression::Apply(f, arg)
+                if *arg == Expression::Var(param.clone())
+                    && matches!(*f, Expression::Var(_) | Expression::Fn(_, _))
+                    && !free_vars(&f).contains(&*param.ident) =>
+            {
+                Rc::unwrap_or_clone(f)
+            }
+            body => Expression::Fn(param, Rc::new(body)),
+        },
+        e => e,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn reduced(e: &str) -> Option<String> {
+        beta_reduce(&parse(e).unwrap()).map(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_beta_reduce() {
+        assert_eq!(reduced("(fn x. (x + 1)) 5"), Some("5 + 1".to_string()));
+        assert_eq!(reduced("1 + 2"), None);
+        assert_eq!(reduced("fn x. x"), None);
+        assert_eq!(reduced("f 1"), None);
+        // The outer application is reduced before the one within it.
+        assert_eq!(
+            reduced("(fn f. f 1) ((fn x. x) 2)"),
+            Some("(fn x. x) 2 1".to_string())
+        );
+        // The left operand is reduced before the right.
+        assert_eq!(
+            reduced("<(fn x. x) 1, (fn y. y) 2>"),
+            Some("<1, (fn y. y) 2>".to_string())
+        );
+        // Redexes under functions are reduced, unlike in evaluation.
+        assert_eq!(reduced("fn y. (fn x. x) y"), Some("fn y. y".to_string()));
+        // Substitution avoids capturing free variables of the argument.
+        assert_eq!(
+            reduced("(fn x. fn y. (x + y)) y"),
+            Some("fn y1. (y + y1)".to_string())
+        );
+    }
+
+    fn eta_reduced(e: &str) -> String {
+        eta_reduce(parse(e).unwrap()).to_string()
+    }
+
+    #[test]
+    fn test_eta_reduce() {
+        assert_eq!(eta_reduced("fn x. f x"), "f");
+        assert_eq!(eta_reduced("fn x. (fn y. (y + 1)) x"), "fn y. (y + 1)");
+        assert_eq!(eta_reduced("fn x. fn y. g y"), "fn x. g");
+        // Reducing the inner function exposes the outer one to reduction.
+        assert_eq!(eta_reduced("fn x. (fn y. g y) x"), "g");
+        assert_eq!(eta_reduced("map (fn x. f x) l"), "map f l");
+        // x is free in what it's applied to.
+        assert_eq!(eta_reduced("fn x. f x x"), "fn x. f x x");
+        assert_eq!(eta_reduced("fn x. x x"), "fn x. x x");
+        assert_eq!(
+            eta_reduced("fn x. (fn y. (x + y)) x"),
+            "fn x. (fn y. (x + y)) x"
+        );
+        // The function isn't applied to its parameter.
+        assert_eq!(eta_reduced("fn x. f y"), "fn x. f y");
+        assert_eq!(eta_reduced("fn x. f (x + 1)"), "fn x. f (x + 1)");
+        // Evaluating g 1 or f x early could fail, so they're left alone.
+        assert_eq!(eta_reduced("fn x. g 1 x"), "fn x. g 1 x");
+        assert_eq!(eta_reduced("fn x. fn y. f x y"), "fn x y. f x y");
+    }
+}
@@ -0,0 +1,498 @@
+//! secd.rs: Landin's SECD machine, which evaluates MiniML expressions with a
+//! Stack of values, an Environment, the Control still to do and a Dump of
+//! the states to return to, rather than recursing as interp.rs does. It gives
+//! the same results.
+
+use std::rc::Rc;
+
+use crate::interp::{
+    apply_primitive, is_function, match_pattern, Env, EvalError, Primitive, Value,
+};
+use crate::parser::{Expression, Variable};
+use crate::value;
+
+type Result<T> = std::result::Result<T, EvalError>;
+
+/// Evaluate a closed expression to a value on an SECD machine.
+pub fn secd_eval(e: &Expression) -> Result<Value> {
+    let mut machine = Machine {
+        stack: vec![],
+        env: Env::empty(),
+        control: vec![Control::Eval(Rc::new(e.clone()))],
+        dump: vec![],
+    };
+    loop {
+        if let Some(value) = machine.step()? {
+            return Ok(value);
+        }
+    }
+}
+
+/// An item of control, the top of which is done next.
+#[derive(Debug)]
+enum Control {
+    /// Push the value of an expression.
+    Eval(Rc<Expression>),
+    /// Replace the operands on top of the stack with the result of an
+    /// operation on them.
+    Primitive(Primitive, usize),
+    /// Check the function on top of the stack is one, then evaluate its
+    /// argument.
+    Arg(Rc<Expression>),
+    /// Apply the function beneath the argument on top of the stack.
+    Apply,
+    /// Evaluate the body of a `let` with the values on top of the stack bound
+    /// to its variables, as if applying a function of them.
+    Enter(Vec<Variable>, Rc<Expression>),
+    /// Evaluate one of two expressions, as the boolean on top of the stack is
+    /// true or false.
+    Select(Rc<Expression>, Rc<Expression>),
+    /// Evaluate the right operand of `and` if the left, on top of the stack,
+    /// is true, or of `or` if it's false.
+    And(Rc<Expression>),
+    Or(Rc<Expression>),
+    /// Check the value on top of the stack is a boolean.
+    Bool,
+    /// Drop the value on top of the stack, e.g. the first of a sequence.
+    Discard,
+    /// Replace the values on top of the stack with a tuple of them.
+    Tuple(usize),
+    /// Replace the values on top of the stack with a record of them, with
+    /// these fields.
+    Record(Vec<String>),
+    /// Replace the record on top of the stack with one of its fields.
+    Field(String),
+    /// Try the arm of a `match` with this index on the value on top of the
+    /// stack, and the arms after it if it doesn't match.
+    Match(Rc<Expression>, usize),
+    /// Evaluate the body of the arm with this index if its guard, on top of
+    /// the stack above the value being matched, is true, with the pattern's
+    /// variables bound as in the environment. Otherwise try the next arm.
+    Guard(Rc<Expression>, usize, Env),
+}
+
+/// What a function application or `let` body returns to.
+#[derive(Debug)]
+struct Dump {
+    stack: Vec<Value>,
+    env: Env,
+    control: Vec<Control>,
+}
+
+#[derive(Debug)]
+struct Machine {
+    stack: Vec<Value>,
+    env: Env,
+    control: Vec<Control>,
+    dump: Vec<Dump>,
+}
+
+impl Machine {
+    /// Do the top item of control, or return to the state on top of the dump
+    /// if there's none. Gives the result once there's nothing to return to.
+    fn step(&mut self) -> Result<Option<Value>> {
+        let Some(control) = self.control.pop() else {
+            let value = self.pop();
+            let Some(Dump {
+                stack,
+                env,
+                control,
+            }) = self.dump.pop()
+            else {
+                return Ok(Some(value));
+            };
+            (self.stack, self.env, self.control) = (stack, env, control);
+            self.stack.push(value);
+            return Ok(None);
+        };
+        match control {
+            Control::Eval(e) => self.eval(&e)?,
+            Control::Primitive(op, arity) => {
+                let operands = self.stack.split_off(self.stack.len() - arity);
+                let result = apply_primitive(op, &operands)?;
+                self.stack.push(result);
+            }
+            Control::Arg(arg) => match self.stack.last() {
+                Some(Value::Closure { .. }) => self.then([Control::Eval(arg), Control::Apply]),
+                _ => {
+                    return Err(EvalError::TypeMismatch {
+                        expected: "a function",
+                        found: self.pop(),
+                    })
+                }
+            },
+            Control::Apply => {
+                let arg = self.pop();
+                let Value::Closure { param, body, env } = self.pop() else {
+                    unreachable!("checked to be a function before its argument was evaluated")
+                };
+                self.call(env.extend(param, arg), body);
+            }
+            Control::Enter(vars, body) => {
+                let values = self.stack.split_off(self.stack.len() - vars.len());
+                let mut env = self.env.clone();
+                for (var, value) in vars.into_iter().zip(values) {
+                    env = env.extend(var, value);
+                }
+                self.call(env, body);
+            }
+            Control::Select(yes, no) => {
+                let next = if bool(self.pop())? { yes } else { no };
+                self.control.push(Control::Eval(next));
+            }
+            Control::And(right) => match bool(self.pop())? {
+                true => self.then([Control::Eval(right), Control::Bool]),
+                false => self.stack.push(Value::Bool(false)),
+            },
+            Control::Or(right) => match bool(self.pop())? {
+                true => self.stack.push(Value::Bool(true)),
+                false => self.then([Control::Eval(right), Control::Bool]),
+            },
+            Control::Bool => {
+                let b = bool(self.pop())?;
+                self.stack.push(Value::Bool(b));
+            }
+            Control::Discard => {
+                self.pop();
+            }
+            Control::Tuple(n) => {
+                let components = self.stack.split_off(self.stack.len() - n);
+                self.stack.push(Value::Tuple(components));
+            }
+            Control::Record(fields) => {
+                let values = self.stack.split_off(self.stack.len() - fields.len());
+                self.stack
+                    .push(Value::Record(fields.into_iter().zip(values).collect()));
+            }
+            Control::Field(field) => {
+                let record = self.pop();
+                let v = match &record {
+                    Value::Record(fields) => fields.iter().find(|(f, _)| *f == field),
+                    _ => None,
+                };
+                match v {
+                    Some((_, v)) => self.stack.push(v.clone()),
+                    None => {
+                        return Err(EvalError::TypeMismatch {
+                            expected: "a record with that field",
+                            found: record,
+                        })
+                    }
+                }
+            }
+            Control::Match(e, i) => {
+                let v = self.pop();
+                let Expression::Match(_, arms) = &*e else {
+                    unreachable!("only a match's arms are tried")
+                };
+                let Some(arm) = arms.get(i) else {
+                    return Err(EvalError::NoMatch(v));
+                };
+                let mut env = self.env.clone();
+                if !match_pattern(&arm.pattern, v.clone(), &mut env)? {
+                    self.stack.push(v);
+                    self.control.push(Control::Match(e, i + 1));
+                    return Ok(None);
+                }
+                match &arm.guard {
+                    Some(guard) => {
+                        let guard = Rc::new(guard.clone());
+                        self.stack.push(v);
+                        self.control.push(Control::Guard(e, i, env.clone()));
+                        self.call(env, guard);
+                    }
+                    None => self.call(env, Rc::new(arm.body.clone())),
+                }
+            }
+            Control::Guard(e, i, env) => {
+                let taken = bool(self.pop())?;
+                let v = self.pop();
+                let Expression::Match(_, arms) = &*e else {
+                    unreachable!("only a match's arms have guards")
+                };
+                if taken {
+                    self.call(env, Rc::new(arms[i].body.clone()));
+                } else {
+                    self.stack.push(v);
+                    self.control.push(Control::Match(e, i + 1));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn eval(&mut self, e: &Rc<Expression>) -> Result<()> {
+        use Expression::*;
+        if let Some((op, operands)) = Primitive::of(e) {
+            let arity = operands.len();
+            let operands = operands.into_iter().map(|e| Control::Eval(e.clone()));
+            self.then(operands.chain([Control::Primitive(op, arity)]));
+            return Ok(());
+        }
+        let value = match &**e {
+            Num(n) => Value::Int(*n),
+            Float(f) => Value::Float(*f),
+            True => Value::Bool(true),
+            False => Value::Bool(false),
+            Str(s) => Value::Str(s.clone()),
+            Char(c) => Value::Char(*c),
+            Unit => Value::Unit,
+            Nil => Value::Nil,
+            Var(var) => self
+                .env
+                .lookup(var)
+                .ok_or_else(|| EvalError::Unbound(var.clone()))?,
+            Fn(param, body) => Value::Closure {
+                param: param.clone(),
+                body: body.clone(),
+                env: self.env.clone(),
+            },
+            Apply(f, arg) => {
+                self.then([Control::Eval(f.clone()), Control::Arg(arg.clone())]);
+                return Ok(());
+            }
+            // The definitions are evaluated in the outer environment, so they
+            // can't refer to each other.
+            Let(definitions, body) if !definitions.iter().any(|d| d.recursive) => {
+                let values = definitions.iter().map(|d| Control::Eval(d.expr.clone()));
+                let vars = definitions.iter().map(|d| d.var.clone()).collect();
+                self.then(values.chain([Control::Enter(vars, body.clone())]));
+                return Ok(());
+            }
+            // The functions are bound first, then any other definitions are
+            // evaluated with them in scope.
+            Let(definitions, body) => {
+                let others: Vec<_> = definitions.iter().filter(|d| !is_function(d)).collect();
+                let values = others.iter().map(|d| Control::Eval(d.expr.clone()));
+                let vars = others.iter().map(|d| d.var.clone()).collect();
+                let control = values.chain([Control::Enter(vars, body.clone())]).rev();
+                let env = self.env.extend_rec(definitions);
+                self.dump.push(Dump {
+                    stack: std::mem::take(&mut self.stack),
+                    env: std::mem::replace(&mut self.env, env),
+                    control: std::mem::replace(&mut self.control, control.collect()),
+                });
+                return Ok(());
+            }
+            If(cond, yes, no) => {
+                let select = Control::Select(yes.clone(), no.clone());
+                self.then([Control::Eval(cond.clone()), select]);
+                return Ok(());
+            }
+            And(left, right) => {
+                self.then([Control::Eval(left.clone()), Control::And(right.clone())]);
+                return Ok(());
+            }
+            Or(left, right) => {
+                self.then([Control::Eval(left.clone()), Control::Or(right.clone())]);
+                return Ok(());
+            }
+            Seq(first, second) => {
+                let (first, second) = (first.clone(), second.clone());
+                self.then([
+                    Control::Eval(first),
+                    Control::Discard,
+                    Control::Eval(second),
+                ]);
+                return Ok(());
+            }
+            // Annotations have no effect at runtime.
+            Ann(e, _) => {
+                self.control.push(Control::Eval(e.clone()));
+                return Ok(());
+            }
+            // Components and fields aren't shared like other subexpressions,
+            // so are copied, though only down to their own subexpressions.
+            Tuple(components) => {
+                let components = components.iter().map(|e| Control::Eval(Rc::new(e.clone())));
+                let n = components.len();
+                self.then(components.chain([Control::Tuple(n)]));
+                return Ok(());
+            }
+            Record(fields) => {
+                let values = fields
+                    .iter()
+                    .map(|(_, e)| Control::Eval(Rc::new(e.clone())));
+                let names = fields.iter().map(|(field, _)| field.clone()).collect();
+                self.then(values.chain([Control::Record(names)]));
+                return Ok(());
+            }
+            Field(record, field) => {
+                self.then([Control::Eval(record.clone()), Control::Field(field.clone())]);
+                return Ok(());
+            }
+            Match(scrutinee, _) => {
+                let arms = Control::Match(e.clone(), 0);
+                self.then([Control::Eval(scrutinee.clone()), arms]);
+                return Ok(());
+            }
+            _ => unreachable!("primitive operations are done above"),
+        };
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Do `controls`, in order, before what's left.
+    fn then<I>(&mut self, controls: I)
+    where
+        I: IntoIterator<Item = Control>,
+        I::IntoIter: DoubleEndedIterator,
+    {
+        self.control.extend(controls.into_iter().rev());
+    }
+
+    /// Evaluate `body` in `env` with an empty stack, dumping the current
+    /// state to return to with its value.
+    fn call(&mut self, env: Env, body: Rc<Expression>) {
+        self.dump.push(Dump {
+            stack: std::mem::take(&mut self.stack),
+            env: std::mem::replace(&mut self.env, env),
+            control: std::mem::replace(&mut self.control, vec![Control::Eval(body)]),
+        });
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack
+            .pop()
+            .expect("control only consumes values it's pushed")
+    }
+}
+
+fn bool(v: Value) -> Result<bool> {
+    Ok(value::bool(v)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::eval;
+    use crate::parser::parse;
+
+    fn run(source: &str) -> Result<Value> {
+        secd_eval(&parse(source).unwrap())
+    }
+
+    #[test]
+    fn test_secd_eval() {
+        assert_eq!(run("1 + 2 * 3"), Ok(Value::Int(7)));
+        assert_eq!(run("(fn x. (x + 1)) 2"), Ok(Value::Int(3)));
+        assert_eq!(
+            run("let y = 1 in let f = fn x. (x + y) in let y = 100 in f 10"),
+            Ok(Value::Int(11))
+        );
+        assert_eq!(
+            run("x"),
+            Err(EvalError::Unbound(Variable { ident: "x".into() }))
+        );
+        assert_eq!(
+            run("1 2"),
+            Err(EvalError::TypeMismatch {
+                expected: "a function",
+                found: Value::Int(1)
+            })
+        );
+    }
+
+    #[test]
+    fn test_secd_eval_shares_code() {
+        // Control refers to the program's subexpressions rather than copies.
+        let e = parse("fn x. (x + 1)").unwrap();
+        let (
+            Expression::Fn(_, body),
+            Ok(Value::Closure {
+                body: closure_body, ..
+            }),
+        ) = (&e, secd_eval(&e))
+        else {
+            panic!("expected a closure");
+        };
+        assert!(Rc::ptr_eq(body, &closure_body));
+    }
+
+    #[test]
+    fn test_secd_eval_match() {
+        assert_eq!(
+            run("match [1, 2] with nil -> 0 | x :: y :: _ when x < y -> y | _ -> 3"),
+            Ok(Value::Int(2))
+        );
+        assert_eq!(
+            run("match 3 with 1 -> 1"),
+            Err(EvalError::NoMatch(Value::Int(3)))
+        );
+        // Arms are evaluated like function bodies, with the state to return
+        // to dumped on the heap.
+        assert_eq!(
+            run(
+                "let rec len = fn l. match l with nil -> 0 | _ :: t -> 1 + len t in \
+                 let rec upto = fn n. if n < 1 then nil else n :: upto (n - 1) in \
+                 len (upto 10000)"
+            ),
+            Ok(Value::Int(10000))
+        );
+    }
+
+    #[test]
+    fn test_secd_eval_deep_recursion() {
+        // The dump is on the heap, so recursion is only as deep as memory allows.
+        assert_eq!(
+            run("let rec f = fn n. if n < 1 then 0 else 1 + f (n - 1) in f 100000"),
+            Ok(Value::Int(100000))
+        );
+    }
+
+    #[test]
+    fn test_secd_eval_matches_eval() {
+        for source in [
+            "if true then ()",
+            "let x = 1 in let x = x + 1 in x + 10",
+            "let x = 1 and y = 2 in let x = y in x",
+            "let x = 1 and y = x in y",
+            "let add = fn x y. (x + y) in let inc = add 1 in let x = 5 in inc x",
+            "let y = 1 in fn x. y",
+            "let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact 20",
+            "let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact 21",
+            "let rec even = fn n. if n < 1 then true else odd (n - 1) \
+             and odd = fn n. if n < 1 then false else even (n - 1) in odd 7",
+            "let rec f = fn n. if n < 1 then 0 else 1 + f (n - 1) in \
+             let g = f in let f = fn n. 100 in g 3",
+            "let rec f = fn x. (x + 1) and y = f 1 in y",
+            "let rec f = fn x. y and y = 1 in f 1",
+            "let rec map = fn f. fn l. if l == nil then nil else f (hd(l)) :: map f (tl(l)) \
+             in map (fn x. (x * x)) [1, 2, 3]",
+            "let compose = fn f g x. f (g x) in compose (fn x. (x * 2)) (fn x. (x + 1)) 5",
+            "<fst(<1, 'a'>), snd(<\"s\", 2.5>)>",
+            "false and 1 == 1 / 0",
+            "true or 1 == 1 / 0",
+            "true and 1",
+            "1 == 1 / 0 and false",
+            "if 1 then 2 else 3",
+            "succ(9223372036854775807)",
+            "hd(nil)",
+            "let f = fn x. x in f == f",
+            "1 (x)",
+            "1; 2",
+            "1 / 0; 2",
+            "(1 : Int) + 1",
+            "(1, 'a', [2 + 3])",
+            "{ a = 1, b = { c = 2 * 2 } }.b.c",
+            "{ a = 1 }.b",
+            "(1, 2) == (1, 1 + 1)",
+            "match 1 with _ -> 1",
+            "match 3 with 1 -> 1",
+            "match 1 with nil -> 0",
+            "match <1, [2, 3]> with <a, b :: _> when a > b -> a | <_, _ :: c> -> c",
+            "let x = 1 in match 2 with x when x > 5 -> x | _ -> x",
+            "match \"b\" with \"a\" -> 1 | \"b\" -> 2",
+            "match 1 with x when x -> 1",
+            "let rec len = fn l. match l with nil -> 0 | _ :: t -> 1 + len t in len [1, 2, 3]",
+        ] {
+            let e = parse(source).unwrap();
+            assert_eq!(
+                secd_eval(&e).map(|v| v.to_string()),
+                eval(&e).map(|v| v.to_string()),
+                "{}",
+                source
+            );
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! step.rs: A small-step evaluator, which reduces a closed expression one
+//! step at a time, so that each intermediate program can be seen.
+
+use std::rc::Rc;
+
+use crate::exprs::map_subexpressions;
+use crate::interp::{self, is_function, EvalError, Value};
+use crate::parser::{Definition, Expression, MatchArm, Pattern, Variable};
+use crate::subst::subst;
+
+/// The outcome of reducing an expression by one step.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Step {
+    /// The expression reduced to another.
+    Reduced(Expression),
+    /// The expression is a value, so there's nothing left to reduce.
+    Done(Value),
+}
+
+type Result<T> = std::result::Result<T, EvalError>;
+
+/// Reduce a closed expression by one step. Subexpressions are reduced to
+/// values from left to right, and functions are applied to values, so
+/// stepping until [`Step::Done`] gives the same result as [`interp::eval`].
+pub fn step(e: Expression) -> Result<Step> {
+    if is_value(&e) {
+        interp::eval(&e).map(Step::Done)
+    } else {
+        reduce(e).map(Step::Reduced)
+    }
+}
+
+/// Whether an expression is fully reduced.
+fn is_value(e: &Expression) -> bool {
+    use Expression::*;
+    match e {
+        Num(_) | Float(_) | True | False | Str(_) | Char(_) | Unit | Nil | Fn(_, _) => true,
+        Pair(e1, e2) | Cons(e1, e2) => is_value(e1) && is_value(e2),
+        Tuple(components) => components.iter().all(is_value),
+        Record(fields) => fields.iter().all(|(_, e)| is_value(e)),
+        _ => false,
+    }
+}
+
+fn reduce(e: Expression) -> Result<Expression> {
+    use Expression::*;
+    match e {
+        Var(var) => Err(EvalError::Unbound(var)),
+        // Only the branch taken, or the operand needed, is reduced further.
+        If(cond, yes, no) if is_value(&cond) => Ok(if boolean(Rc::unwrap_or_clone(cond))? {
+            Rc::unwrap_or_clone(yes)
+        } else {
+            Rc::unwrap_or_clone(no)
+        }),
+        And(left, right) if is_value(&left) => Ok(if boolean(Rc::unwrap_or_clone(left))? {
+            Rc::unwrap_or_clone(right)
+        } else {
+            False
+        }),
+        Or(left, right) if is_value(&left) => Ok(if boolean(Rc::unwrap_or_clone(left))? {
+            True
+        } else {
+            Rc::unwrap_or_clone(right)
+        }),
+        Seq(first, second) if is_value(&first) => Ok(Rc::unwrap_or_clone(second)),
+        Ann(e, _) => Ok(Rc::unwrap_or_clone(e)),
+        Apply(f, arg) if is_value(&f) && is_value(&arg) => match Rc::unwrap_or_clone(f) {
+            Fn(param, body) => Ok(subst(&body, &param.ident, &arg)),
+            f => Err(EvalError::TypeMismatch {
+                expected: "a function",
+                found: interp::eval(&f)?,
+            }),
+        },
+        // As in the interpreter, the functions of a recursive group are bound
+        // first, then its other definitions with just the functions in scope.
+        Let(definitions, body) if definitions.iter().any(|d| d.recursive && !is_function(d)) => {
+            let (functions, others): (Vec<_>, Vec<_>) =
+                definitions.into_iter().partition(is_function);
+            let others = others
+                .into_iter()
+                .map(|d| Definition {
+                    recursive: false,
+                    ..d
+                })
+                .collect();
+            let body = Let(others, body);
+            Ok(match functions.is_empty() {
+                true => body,
+                false => Let(functions, Rc::new(body)),
+            })
+        }
+        Let(definitions, body) if definitions.iter().all(|d| is_value(&d.expr)) => Ok(
+            substitute_definitions(&definitions, Rc::unwrap_or_clone(body)),
+        ),
+        Match(scrutinee, arms) if is_value(&scrutinee) => select_arm(scrutinee, arms),
+        e => {
+            let mut reduced = None;
+            let e = map_subexpressions(e, |child| {
+                if reduced.is_some() || is_value(&child) {
+                    return child;
+                }
+                match reduce(child) {
+                    Ok(child) => {
+                        reduced = Some(Ok(()));
+                        child
+                    }
+                    Err(error) => {
+                        reduced = Some(Err(error));
+                        Unit
+                    }
+                }
+            });
+            match reduced {
+                Some(result) => result.map(|()| e),
+                // The operands are all values, so apply the primitive.
+                None => interp::eval(&e).map(expression),
+            }
+        }
+    }
+}
+
+/// Replace the variables bound by a `let` whose definitions are all values
+/// with those values. A recursive definition is unfolded once, by replacing
+/// each reference within it to the group with the whole group again.
+fn substitute_definitions(definitions: &[Definition], body: Expression) -> Expression {
+    let values = definitions.iter().map(|d| {
+        if !d.recursive {
+            return (*d.expr).clone();
+        }
+        definitions.iter().fold((*d.expr).clone(), |value, group| {
+            let unfolded = Expression::Let(
+                definitions.to_vec(),
+                Rc::new(Expression::Var(group.var.clone())),
+            );
+            subst(&value, &group.var.ident, &unfolded)
+        })
+    });
+    definitions
+        .iter()
+        .zip(values.collect::<Vec<_>>())
+        .fold(body, |body, (d, value)| subst(&body, &d.var.ident, &value))
+}
+
+/// Reduce a `match` on a value by trying its first arm. If the pattern
+/// matches, the arm's guard, if any, is left to be reduced, choosing between
+/// its body and the remaining arms.
+fn select_arm(scrutinee: Rc<Expression>, mut arms: Vec<MatchArm>) -> Result<Expression> {
+    if arms.is_empty() {
+        return Err(EvalError::NoMatch(interp::eval(&scrutinee)?));
+    }
+    let arm = arms.remove(0);
+    let rest = Expression::Match(scrutinee.clone(), arms);
+    let mut bindings = vec![];
+    if !bind(&arm.pattern, &scrutinee, &mut bindings)? {
+        return Ok(rest);
+    }
+    let bound = |e: Expression| {
+        bindings
+            .iter()
+            .fold(e, |e, (var, value)| subst(&e, &var.ident, value))
+    };
+    let body = bound(arm.body);
+    Ok(match arm.guard {
+        Some(guard) => Expression::If(Rc::new(bound(guard)), Rc::new(body), Rc::new(rest)),
+        None => body,
+    })
+}
+
+/// Whether the value `v` matches `pattern`, adding the values the pattern's
+/// variables are bound to to `bindings` if it does.
+fn bind<'a>(
+    pattern: &'a Pattern,
+    v: &Expression,
+    bindings: &mut Vec<(&'a Variable, Expression)>,
+) -> Result<bool> {
+    use Expression::*;
+    let mismatch = |expected| -> Result<bool> {
+        Err(EvalError::TypeMismatch {
+            expected,
+            found: interp::eval(v)?,
+        })
+    };
+    match (pattern, v) {
+        (Pattern::Wildcard, _) => Ok(true),
+        (Pattern::Var(var), v) => {
+            bindings.push((var, v.clone()));
+            Ok(true)
+        }
+        (Pattern::Literal(literal), v) => {
+            let equal = Eq(Rc::new(v.clone()), Rc::new(literal.clone()));
+            Ok(interp::eval(&equal)? == Value::Bool(true))
+        }
+        (Pattern::Nil, Nil) => Ok(true),
+        (Pattern::Nil, Cons(_, _)) | (Pattern::Cons(_, _), Nil) => Ok(false),
+        (Pattern::Cons(head, tail), Cons(h, t)) => {
+            Ok(bind(head, h, bindings)? && bind(tail, t, bindings)?)
+        }
+        (Pattern::Nil | Pattern::Cons(_, _), _) => mismatch("a list"),
+        (Pattern::Pair(first, second), Pair(f, s)) => {
+            Ok(bind(first, f, bindings)? && bind(second, s, bindings)?)
+        }
+        (Pattern::Pair(_, _), _) => mismatch("a pair"),
+    }
+}
+
+fn boolean(e: Expression) -> Result<bool> {
+    match e {
+        Expression::True => Ok(true),
+        Expression::False => Ok(false),
+        e => Err(EvalError::TypeMismatch {
+            expected: "a boolean",
+            found: interp::eval(&e)?,
+        }),
+    }
+}
+
+/// The expression which evaluates to a value. Closures here have been
+/// evaluated without an environment, so don't need one.
+fn expression(v: Value) -> Expression {
+    use std::rc::Rc;
+    let boxed = |v: Rc<Value>| Rc::new(expression((*v).clone()));
+    match v {
+        Value::Int(n) => Expression::Num(n),
+        Value::Float(f) => Expression::Float(f),
+        Value::Bool(true) => Expression::True,
+        Value::Bool(false) => Expression::False,
+        Value::Str(s) => Expression::Str(s),
+        Value::Char(c) => Expression::Char(c),
+        Value::Unit => Expression::Unit,
+        Value::Nil => Expression::Nil,
+        Value::Cons(head, tail) => Expression::Cons(boxed(head), boxed(tail)),
+        Value::Pair(first, second) => Expression::Pair(boxed(first), boxed(second)),
+        Value::Tuple(components) => {
+            Expression::Tuple(components.into_iter().map(expression).collect())
+        }
+        Value::Record(fields) => Expression::Record(
+            fields
+                .into_iter()
+                .map(|(field, v)| (field, expression(v)))
+                .collect(),
+        ),
+        Value::Closure { param, body, .. } => Expression::Fn(param, body),
+        Value::Thunk(_) => unreachable!("strict evaluation doesn't delay anything"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    /// Each expression reduction passes through, as source, and the final value.
+    fn trace(source: &str) -> (Vec<String>, Value) {
+        let mut e = parse(source).unwrap();
+        let mut steps = vec![e.to_string()];
+        loop {
+            match step(e).unwrap() {
+                Step::Reduced(next) => {
+                    steps.push(next.to_string());
+                    e = next;
+                }
+                Step::Done(v) => return (steps, v),
+            }
+        }
+    }
+
+    #[test]
+    fn test_step_order() {
+        // The multiplication is reduced before the addition.
+        assert_eq!(
+            trace("1 + 2 * 3"),
+            (
+                vec![
+                    "1 + 2 * 3".to_string(),
+                    "1 + 6".to_string(),
+                    "7".to_string()
+                ],
+                Value::Int(7)
+            )
+        );
+        assert_eq!(
+            trace("(fn x. (x * x)) (1 + 2)").0,
+            ["(fn x. (x * x)) (1 + 2)", "(fn x. (x * x)) 3", "3 * 3", "9"]
+        );
+        assert_eq!(
+            trace("if 1 < 2 then 3 else 4 + 5").0,
+            [
+                "if 1 < 2 then 3 else 4 + 5",
+                "if true then 3 else 4 + 5",
+                "3"
+            ]
+        );
+        assert_eq!(
+            trace("let x = 1 + 1 in <x, x>").0,
+            ["let x = 1 + 1 in <x, x>", "let x = 2 in <x, x>", "<2, 2>"]
+        );
+    }
+
+    #[test]
+    fn test_step_agrees_with_eval() {
+        for source in [
+            "let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact 5",
+            "let rec even = fn n. if n < 1 then true else odd (n - 1) \
+             and odd = fn n. if n < 1 then false else even (n - 1) in even 4",
+            "let y = 1 in let f = fn x. (x + y) in let y = 100 in f 10",
+            "hd(tl([1, 2, 3])) :: [fst(<4, 5>)]",
+            "false and 1 == 2 or not(false)",
+            "(fn f. f 1) (fn x. <x, x>)",
+            "let rec f = fn n. n and x = f 1 in x",
+            "let rec x = 1 and y = 2 in x + y",
+            "let rec len = fn l. match l with nil -> 0 | _ :: t -> 1 + len t in len [1, 2, 3]",
+            "match <1, [2, 3]> with <a, b :: _> when a > b -> a | <_, _ :: c> -> c",
+            "let x = 1 in match 2 with x when x > 5 -> x | _ -> x",
+            "match \"b\" with \"a\" -> 1 | \"b\" -> 2",
+            "{ a = 1 + 1, b = (2, 3 * 3) }.b",
+            "(1 + 1, { a = fst(<1, 2>) }.a)",
+            "1 + 1; (2 : Int)",
+        ] {
+            assert_eq!(
+                trace(source).1,
+                interp::eval(&parse(source).unwrap()).unwrap(),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_step_match() {
+        assert_eq!(
+            trace("match 1 :: nil with nil -> 0 | x :: _ when x > 0 -> x | _ -> 2").0,
+            [
+                "match 1 :: nil with nil -> 0 | x :: _ when x > 0 -> x | _ -> 2",
+                "match 1 :: nil with x :: _ when x > 0 -> x | _ -> 2",
+                "if 1 > 0 then 1 else match 1 :: nil with _ -> 2",
+                "if true then 1 else match 1 :: nil with _ -> 2",
+                "1"
+            ]
+        );
+        assert_eq!(
+            step(parse("match 1 with 2 -> 2").unwrap()).and_then(|s| match s {
+                Step::Reduced(e) => step(e),
+                done => Ok(done),
+            }),
+            Err(EvalError::NoMatch(Value::Int(1)))
+        );
+        assert_eq!(
+            step(parse("match 1 with nil -> 0").unwrap()),
+            Err(EvalError::TypeMismatch {
+                expected: "a list",
+                found: Value::Int(1)
+            })
+        );
+    }
+
+    #[test]
+    fn test_step_values() {
+        assert_eq!(step(Expression::Num(1)), Ok(Step::Done(Value::Int(1))));
+        assert!(matches!(
+            step(parse("fn x. x").unwrap()),
+            Ok(Step::Done(Value::Closure { .. }))
+        ));
+        assert_eq!(
+            step(parse("x + 1").unwrap()),
+            Err(EvalError::Unbound(crate::parser::Variable {
+                ident: "x".into()
+            }))
+        );
+        assert_eq!(
+            step(parse("1 / 0").unwrap()),
+            Err(EvalError::DivisionByZero)
+        );
+    }
+}
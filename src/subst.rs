@@ -0,0 +1,554 @@
+//! subst.rs: Free variables, substitution of expressions for them, and the
+//! renaming of bound variables that substitution and comparison up to
+//! renaming need.
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::exprs::{map_subexpressions, pattern_variables, subexpressions};
+use crate::intern::intern;
+use crate::parser::{Definition, Expression, MatchArm, Pattern, Variable};
+use crate::visit::fold;
+
+/// Replace the free occurrences of `var` in `e` with `replacement`. Bound
+/// variables of `e` which would capture a free variable of `replacement` are
+/// renamed first, e.g. substituting `x` for `y` in `fn x. (x + y)` gives
+/// `fn x1. (x1 + x)`.
+pub fn subst(e: &Expression, var: &str, replacement: &Expression) -> Expression {
+    Substitution {
+        var,
+        replacement,
+        free: free_vars(replacement),
+    }
+    .apply(e)
+}
+
+/// The variables used but not bound within `e`, e.g. just `y` in
+/// `fn x. (x + y)`. Functions, `let`s and `match` arms bind variables.
+pub fn free_vars(e: &Expression) -> HashSet<String> {
+    free_var_counts(e).into_keys().collect()
+}
+
+/// How many times each free variable of `e` occurs in it.
+pub fn free_var_counts(e: &Expression) -> HashMap<String, usize> {
+    let mut free = HashMap::new();
+    collect_free_vars(e, &mut Vec::new(), &mut free);
+    free
+}
+
+/// Count the occurrences of the variables of `e` which aren't `bound`, either
+/// outside or within `e`, in `free`.
+fn collect_free_vars<'a>(
+    e: &'a Expression,
+    bound: &mut Vec<&'a str>,
+    free: &mut HashMap<String, usize>,
+) {
+    use Expression::*;
+    match e {
+        Var(v) => {
+            if !bound.contains(&&*v.ident) {
+                *free.entry(v.ident.to_string()).or_default() += 1;
+            }
+        }
+        Fn(param, body) => {
+            bound.push(&param.ident);
+            collect_free_vars(body, bound, free);
+            bound.pop();
+        }
+        // The definitions of `let rec` are in scope in every definition, but
+        // those of a plain `let` are only in scope in the body.
+        Let(definitions, body) => {
+            let outer = bound.len();
+            if definitions.iter().any(|d| d.recursive) {
+                bound.extend(definitions.iter().map(|d| &*d.var.ident));
+            }
+            for definition in definitions {
+                collect_free_vars(&definition.expr, bound, free);
+            }
+            bound.truncate(outer);
+            bound.extend(definitions.iter().map(|d| &*d.var.ident));
+            collect_free_vars(body, bound, free);
+            bound.truncate(outer);
+        }
+        Match(scrutinee, arms) => {
+            collect_free_vars(scrutinee, bound, free);
+            for arm in arms {
+                let outer = bound.len();
+                bound.extend(pattern_variables(&arm.pattern).iter().map(|v| &*v.ident));
+                if let Some(guard) = &arm.guard {
+                    collect_free_vars(guard, bound, free);
+                }
+                collect_free_vars(&arm.body, bound, free);
+                bound.truncate(outer);
+            }
+        }
+        e => {
+            for child in subexpressions(e) {
+                collect_free_vars(child, bound, free);
+            }
+        }
+    }
+}
+
+struct Substitution<'a> {
+    var: &'a str,
+    replacement: &'a Expression,
+    /// The free variables of `replacement`, which mustn't be captured.
+    free: HashSet<String>,
+}
+
+/// A bound variable and what it's been renamed to.
+type Renaming = (Variable, Variable);
+
+impl Substitution<'_> {
+    fn apply(&self, e: &Expression) -> Expression {
+        use Expression::*;
+        match e {
+            Var(v) if *v.ident == *self.var => self.replacement.clone(),
+            Fn(param, body) => {
+                if *param.ident == *self.var || !free_vars(body).contains(self.var) {
+                    return e.clone();
+                }
+                let mut scope = [(**body).clone()];
+                let renamings = self.rename_captured([param], &mut scope);
+                let [body] = scope;
+                Fn(renamed(param, &renamings), Rc::new(self.apply(&body)))
+            }
+            Let(definitions, body) => {
+                let binds_var = definitions.iter().any(|d| *d.var.ident == *self.var);
+                let recursive = definitions.iter().any(|d| d.recursive);
+                if binds_var && recursive {
+                    return e.clone();
+                }
+                let vars = definitions.iter().map(|d| &d.var);
+                let mut scope: Vec<_> = definitions.iter().map(|d| (*d.expr).clone()).collect();
+                scope.push((**body).clone());
+                // The definitions of a plain `let` are outside their own scope.
+                let renamings = match (recursive, binds_var) {
+                    (true, _) => self.rename_captured(vars, &mut scope),
+                    (false, false) => self.rename_captured(vars, &mut scope[definitions.len()..]),
+                    (false, true) => vec![],
+                };
+                let shadowed = binds_var.then_some(definitions.len());
+                let mut scope: Vec<_> = scope
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, e)| {
+                        if Some(i) == shadowed {
+                            e
+                        } else {
+                            self.apply(&e)
+                        }
+                    })
+                    .collect();
+                let body = scope.pop().expect("the body is in scope");
+                let definitions = definitions
+                    .iter()
+                    .zip(scope)
+                    .map(|(d, expr)| Definition {
+                        var: renamed(&d.var, &renamings),
+                        expr: Rc::new(expr),
+                        recursive: d.recursive,
+                    })
+                    .collect();
+                Let(definitions, Rc::new(body))
+            }
+            Match(scrutinee, arms) => {
+                let arms = arms
+                    .iter()
+                    .map(|arm| {
+                        let vars = pattern_variables(&arm.pattern);
+                        if vars.iter().any(|v| *v.ident == *self.var) {
+                            return arm.clone();
+                        }
+                        let mut scope: Vec<_> = arm.guard.iter().cloned().collect();
+                        scope.push(arm.body.clone());
+                        let renamings = self.rename_captured(vars, &mut scope);
+                        let body = self.apply(&scope.pop().expect("the body is in scope"));
+                        MatchArm {
+                            pattern: rename_pattern(&arm.pattern, &renamings),
+                            guard: scope.pop().map(|guard| self.apply(&guard)),
+                            body,
+                        }
+                    })
+                    .collect();
+                Match(Rc::new(self.apply(scrutinee)), arms)
+            }
+            e => map_subexpressions(e.clone(), |child| self.apply(&child)),
+        }
+    }
+
+    /// Rename those of `binders` which would capture a free variable of the
+    /// replacement, throughout the expressions in their `scope`, returning
+    /// what each was renamed to. Nothing's renamed unless the variable being
+    /// replaced occurs in the scope, as otherwise nothing can be captured.
+    fn rename_captured<'v>(
+        &self,
+        binders: impl IntoIterator<Item = &'v Variable>,
+        scope: &mut [Expression],
+    ) -> Vec<Renaming> {
+        let binders: Vec<_> = binders.into_iter().collect();
+        if !scope.iter().any(|e| free_vars(e).contains(self.var)) {
+            return vec![];
+        }
+        // Avoiding the names bound within the scope too saves renaming those.
+        let mut used: HashSet<String> = scope.iter().flat_map(names).collect();
+        used.extend(self.free.iter().cloned());
+        used.extend(binders.iter().map(|v| v.ident.to_string()));
+        let mut renamings = vec![];
+        for binder in binders {
+            if !self.free.contains(&*binder.ident) {
+                continue;
+            }
+            let fresh = fresh(&binder.ident, &used);
+            used.insert(fresh.ident.to_string());
+            let replacement = Expression::Var(fresh.clone());
+            for e in scope.iter_mut() {
+                *e = subst(e, &binder.ident, &replacement);
+            }
+            renamings.push((binder.clone(), fresh));
+        }
+        renamings
+    }
+}
+
+/// Every variable named in `e`, free or bound.
+pub(crate) fn names(e: &Expression) -> HashSet<String> {
+    fold(e, HashSet::new(), |mut names, e| {
+        match e {
+            Expression::Var(v) | Expression::Fn(v, _) => {
+                names.insert(v.ident.to_string());
+            }
+            Expression::Let(definitions, _) => {
+                names.extend(definitions.iter().map(|d| d.var.ident.to_string()));
+            }
+            Expression::Match(_, arms) => {
+                for arm in arms {
+                    names.extend(
+                        pattern_variables(&arm.pattern)
+                            .iter()
+                            .map(|v| v.ident.to_string()),
+                    );
+                }
+            }
+            _ => {}
+        }
+        names
+    })
+}
+
+/// The first of `name1`, `name2`, ... which isn't `used`.
+fn fresh(name: &str, used: &HashSet<String>) -> Variable {
+    let name = (1..)
+        .map(|i| format!("{}{}", name, i))
+        .find(|candidate| !used.contains(candidate))
+        .expect("there are only finitely many names in use");
+    Variable {
+        ident: intern(&name),
+    }
+}
+
+/// Rename every bound variable of `e` to `$0`, `$1`, ..., in the order
+/// they're bound, so that expressions which differ only in the names of their
+/// bound variables normalize to the same expression. Free variables are left
+/// as they are, and can't clash with the new names, which can't be written in
+/// source.
+pub fn alpha_normalize(e: &Expression) -> Expression {
+    Normalizer::default().normalize(e)
+}
+
+/// Rename the bound variables of `e` so that no two are bound with the same
+/// name, nor with the name of a free variable, e.g. `let x = 1 in (let x = 2
+/// in x) + x` becomes `let x = 1 in (let x1 = 2 in x1) + x`. The first
+/// binding of each name keeps it. A binding can then be moved out of its
+/// scope without capturing anything, as long as it stays within its
+/// variables' uses.
+pub fn rename_apart(e: &Expression) -> Expression {
+    Normalizer {
+        taken: Some(free_vars(e)),
+        ..Normalizer::default()
+    }
+    .normalize(e)
+}
+
+#[derive(Default)]
+struct Normalizer {
+    /// How many variables have been renamed so far.
+    count: usize,
+    /// The variables in scope, innermost last.
+    scope: Vec<Renaming>,
+    /// When renaming apart, the names which have been bound or are free, so
+    /// mustn't be bound again.
+    taken: Option<HashSet<String>>,
+}
+
+impl Normalizer {
+    fn normalize(&mut self, e: &Expression) -> Expression {
+        use Expression::*;
+        match e {
+            Var(v) => Var(self
+                .scope
+                .iter()
+                .rev()
+                .find(|(from, _)| from == v)
+                .map_or_else(|| v.clone(), |(_, to)| to.clone())),
+            Fn(param, body) => {
+                let outer = self.bind([param]);
+                let param = self.scope[outer].1.clone();
+                let body = self.normalize(body);
+                self.scope.truncate(outer);
+                Fn(param, Rc::new(body))
+            }
+            // The group's variables are numbered before anything within it.
+            Let(definitions, body) => {
+                let outer = self.bind(definitions.iter().map(|d| &d.var));
+                let renamings = self.scope.split_off(outer);
+                let recursive = definitions.iter().any(|d| d.recursive);
+                if recursive {
+                    self.scope.extend(renamings.iter().cloned());
+                }
+                let exprs: Vec<_> = definitions
+                    .iter()
+                    .map(|d| self.normalize(&d.expr))
+                    .collect();
+                if !recursive {
+                    self.scope.extend(renamings.iter().cloned());
+                }
+                let body = self.normalize(body);
+                self.scope.truncate(outer);
+                let definitions = definitions
+                    .iter()
+                    .zip(renamings)
+                    .zip(exprs)
+                    .map(|((d, (_, var)), expr)| Definition {
+                        var,
+                        expr: Rc::new(expr),
+                        recursive: d.recursive,
+                    })
+                    .collect();
+                Let(definitions, Rc::new(body))
+            }
+            Match(scrutinee, arms) => {
+                let scrutinee = self.normalize(scrutinee);
+                let arms = arms
+                    .iter()
+                    .map(|arm| {
+                        let outer = self.bind(pattern_variables(&arm.pattern));
+                        let pattern = rename_pattern(&arm.pattern, &self.scope[outer..]);
+                        let guard = arm.guard.as_ref().map(|guard| self.normalize(guard));
+                        let body = self.normalize(&arm.body);
+                        self.scope.truncate(outer);
+                        MatchArm {
+                            pattern,
+                            guard,
+                            body,
+                        }
+                    })
+                    .collect();
+                Match(Rc::new(scrutinee), arms)
+            }
+            e => map_subexpressions(e.clone(), |child| self.normalize(&child)),
+        }
+    }
+
+    /// Bring `vars` into scope under new names, returning where they start in
+    /// the scope.
+    fn bind<'v>(&mut self, vars: impl IntoIterator<Item = &'v Variable>) -> usize {
+        let outer = self.scope.len();
+        for var in vars {
+            let name = match &mut self.taken {
+                Some(taken) => {
+                    let name = if taken.contains(&*var.ident) {
+                        fresh(&var.ident, taken)
+                    } else {
+                        var.clone()
+                    };
+                    taken.insert(name.ident.to_string());
+                    name
+                }
+                None => {
+                    self.count += 1;
+                    Variable {
+                        ident: intern(&format!("${}", self.count - 1)),
+                    }
+                }
+            };
+            self.scope.push((var.clone(), name));
+        }
+        outer
+    }
+}
+
+fn renamed(var: &Variable, renamings: &[Renaming]) -> Variable {
+    renamings
+        .iter()
+        .find(|(from, _)| from == var)
+        .map_or_else(|| var.clone(), |(_, to)| to.clone())
+}
+
+fn rename_pattern(p: &Pattern, renamings: &[Renaming]) -> Pattern {
+    match p {
+        Pattern::Var(v) => Pattern::Var(renamed(v, renamings)),
+        Pattern::Wildcard | Pattern::Literal(_) | Pattern::Nil => p.clone(),
+        Pattern::Cons(p1, p2) => Pattern::Cons(
+            Box::new(rename_pattern(p1, renamings)),
+            Box::new(rename_pattern(p2, renamings)),
+        ),
+        Pattern::Pair(p1, p2) => Pattern::Pair(
+            Box::new(rename_pattern(p1, renamings)),
+            Box::new(rename_pattern(p2, renamings)),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn substituted(e: &str, var: &str, replacement: &str) -> Expression {
+        subst(&parse(e).unwrap(), var, &parse(replacement).unwrap())
+    }
+
+    fn free(e: &str) -> Vec<String> {
+        let mut free: Vec<_> = free_vars(&parse(e).unwrap()).into_iter().collect();
+        free.sort();
+        free
+    }
+
+    #[test]
+    fn test_free_vars() {
+        assert_eq!(free("fn x. (x + y)"), ["y"]);
+        assert_eq!(free("fn x. fn y. (x y z)"), ["z"]);
+        assert_eq!(free("(fn x. x) x"), ["x"]);
+        assert_eq!(free("fn x. (fn y. y) y"), ["y"]);
+        assert_eq!(free("1 + 2"), Vec::<String>::new());
+        // A plain let's definitions can't see each other, or themselves.
+        assert_eq!(free("let x = 1 and y = x in x + y + z"), ["x", "z"]);
+        assert_eq!(free("let x = x in x"), ["x"]);
+        assert_eq!(free("let rec f = fn n. g n and g = fn n. f h in f"), ["h"]);
+        assert_eq!(free("<let x = 1 in x, x>"), ["x"]);
+        assert_eq!(
+            free("match xs with x :: rest when x > y -> rest | _ -> x"),
+            ["x", "xs", "y"]
+        );
+    }
+
+    #[test]
+    fn test_alpha_normalize() {
+        let normalized = |e| alpha_normalize(&parse(e).unwrap());
+        assert_eq!(normalized("fn x. x"), normalized("fn y. y"));
+        assert_eq!(
+            normalized("fn x. x"),
+            Expression::Fn(
+                Variable { ident: "$0".into() },
+                Rc::new(Expression::Var(Variable { ident: "$0".into() }))
+            )
+        );
+        assert_ne!(normalized("fn x. fn y. x"), normalized("fn x. fn y. y"));
+        // Free variables keep their names.
+        assert_eq!(normalized("fn x. (x + y)"), normalized("fn z. (z + y)"));
+        assert_ne!(normalized("fn x. (x + y)"), normalized("fn x. (x + z)"));
+        assert_eq!(
+            normalized("let x = 1 in let y = x in fn z. (x + y + z)"),
+            normalized("let a = 1 in let b = a in fn c. (a + b + c)")
+        );
+        // Inner bindings shadow outer ones.
+        assert_eq!(normalized("fn x. fn x. x"), normalized("fn y. fn z. z"));
+        assert_eq!(normalized("let x = x in x"), normalized("let y = x in y"));
+        assert_eq!(
+            normalized("let rec f = fn n. f n in f"),
+            normalized("let rec g = fn m. g m in g")
+        );
+        assert_eq!(
+            normalized("match l with h :: t when h > 0 -> t | h -> h"),
+            normalized("match l with x :: xs when x > 0 -> xs | y -> y")
+        );
+    }
+
+    #[test]
+    fn test_rename_apart() {
+        let renamed = |e| rename_apart(&parse(e).unwrap()).to_string();
+        assert_eq!(renamed("fn x. fn y. x"), "fn x y. x");
+        assert_eq!(
+            renamed("let x = 1 in (let x = 2 in x) + x"),
+            "let x = 1 in (let x1 = 2 in x1) + x"
+        );
+        // Nor may a binding take a free variable's name.
+        assert_eq!(renamed("let x = x in x"), "let x1 = x in x1");
+        assert_eq!(renamed("fn x1. fn x. (x + x1)"), "fn x1 x. (x + x1)");
+        assert_eq!(renamed("fn x. fn x. (x + x1)"), "fn x x2. (x2 + x1)");
+        assert_eq!(
+            renamed("let rec f = fn n. f n in let rec f = fn n. f n in f"),
+            "let rec f = fn n. f n in let rec f1 = fn n1. f1 n1 in f1"
+        );
+        assert_eq!(
+            renamed("match l with h :: t -> t | h -> h"),
+            "match l with h :: t -> t | h1 -> h1"
+        );
+    }
+
+    #[test]
+    fn test_free_var_counts() {
+        let counts = free_var_counts(&parse("x + y * x + (fn x. x) + let y = x in y").unwrap());
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["x"], 3);
+        assert_eq!(counts["y"], 1);
+    }
+
+    #[test]
+    fn test_subst() {
+        assert_eq!(
+            substituted("fn x. (x + y)", "y", "z * 2"),
+            parse("fn x. (x + z * 2)").unwrap()
+        );
+        assert_eq!(substituted("<y, x>", "y", "1"), parse("<1, x>").unwrap());
+        // Bound occurrences aren't replaced.
+        assert_eq!(
+            substituted("fn y. (x + y)", "y", "1"),
+            parse("fn y. (x + y)").unwrap()
+        );
+        assert_eq!(
+            substituted("<y, let y = y in y>", "y", "1"),
+            parse("<1, let y = 1 in y>").unwrap()
+        );
+        assert_eq!(
+            substituted("let rec y = fn n. y n in y", "y", "1"),
+            parse("let rec y = fn n. y n in y").unwrap()
+        );
+        assert_eq!(
+            substituted("match y with y :: nil -> y | x -> y", "y", "1"),
+            parse("match 1 with y :: nil -> y | x -> 1").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_subst_avoids_capture() {
+        // x is free in the replacement, so the function's x is renamed.
+        assert_eq!(
+            substituted("fn x. (x + y)", "y", "x"),
+            parse("fn x1. (x1 + x)").unwrap()
+        );
+        assert_eq!(
+            substituted("fn x. fn x1. (x + x1 + y)", "y", "x"),
+            parse("fn x2. fn x1. (x2 + x1 + x)").unwrap()
+        );
+        // Nothing is renamed if y doesn't occur under the binder.
+        assert_eq!(
+            substituted("<y, fn x. x>", "y", "x"),
+            parse("<x, fn x. x>").unwrap()
+        );
+        assert_eq!(
+            substituted("let x = y in x + y", "y", "x"),
+            parse("let x1 = x in x1 + x").unwrap()
+        );
+        assert_eq!(
+            substituted("let rec f = fn n. f y in f", "y", "f"),
+            parse("let rec f1 = fn n. f1 f in f1").unwrap()
+        );
+        assert_eq!(
+            substituted("match z with x :: xs when x > y -> y | _ -> y", "y", "xs"),
+            parse("match z with x :: xs1 when x > xs -> xs | _ -> xs").unwrap()
+        );
+    }
+}
@@ -0,0 +1,268 @@
+//! value.rs: The primitive operations on values, and how values print, shared
+//! by interp.rs and vm.rs. Their values only differ in how functions are
+//! represented, and in interp.rs's being possibly unevaluated.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::interp::Primitive;
+use crate::parser::Expression;
+
+/// A value, as far as primitive operations can see into it.
+pub(crate) enum View<'a, V> {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(&'a str),
+    Char(char),
+    Unit,
+    Nil,
+    Cons(&'a Rc<V>, &'a Rc<V>),
+    Pair(&'a Rc<V>, &'a Rc<V>),
+    Tuple(&'a [V]),
+    /// Named fields in the order they were written.
+    Record(&'a [(String, V)]),
+    Function,
+    /// A value which hasn't been evaluated yet.
+    Delayed,
+}
+
+/// The values an evaluator computes.
+pub(crate) trait Data: Clone + fmt::Display {
+    fn view(&self) -> View<'_, Self>;
+    fn from_int(n: i64) -> Self;
+    fn from_float(x: f64) -> Self;
+    fn from_bool(b: bool) -> Self;
+    fn from_pair(first: Rc<Self>, second: Rc<Self>) -> Self;
+    fn from_cons(head: Rc<Self>, tail: Rc<Self>) -> Self;
+
+    /// What the value has been evaluated to, if it was delayed and has
+    /// been. Otherwise the value itself.
+    fn forced(self) -> Self {
+        self
+    }
+}
+
+/// An error raised by a primitive operation, which each evaluator turns
+/// into its own.
+pub(crate) enum Error<V> {
+    TypeMismatch { expected: &'static str, found: V },
+    Overflow,
+    DivisionByZero,
+}
+
+/// Perform a primitive operation on its evaluated operands. Components of
+/// values being compared for equality are evaluated with `force` as they're
+/// reached.
+pub(crate) fn apply<V, E>(
+    op: Primitive,
+    operands: &[V],
+    force: &dyn Fn(V) -> Result<V, E>,
+) -> Result<V, E>
+where
+    V: Data,
+    E: From<Error<V>>,
+{
+    use Primitive::*;
+    let result = match (op, operands) {
+        (Not, [v]) => Ok(V::from_bool(!bool(v.clone())?)),
+        (Succ, [v]) => checked(int(v)?.checked_add(1)),
+        (Pred, [v]) => checked(int(v)?.checked_sub(1)),
+        (Neg, [v]) => match v.view() {
+            View::Int(n) => checked(n.checked_neg()),
+            View::Float(x) => Ok(V::from_float(-x)),
+            _ => Err(mismatch("a number", v)),
+        },
+        (Fst | Snd, [v]) => match (op, v.view()) {
+            (Fst, View::Pair(first, _)) => Ok((**first).clone()),
+            (_, View::Pair(_, second)) => Ok((**second).clone()),
+            _ => Err(mismatch("a pair", v)),
+        },
+        (Hd | Tl, [v]) => match (op, v.view()) {
+            (Hd, View::Cons(head, _)) => Ok((**head).clone()),
+            (_, View::Cons(_, tail)) => Ok((**tail).clone()),
+            _ => Err(mismatch("a non-empty list", v)),
+        },
+        (Add, [l, r]) => arithmetic(l, r, i64::checked_add, |l, r| l + r),
+        (Sub, [l, r]) => arithmetic(l, r, i64::checked_sub, |l, r| l - r),
+        (Mul, [l, r]) => arithmetic(l, r, i64::checked_mul, |l, r| l * r),
+        (Div, [l, r]) => divide(l, r, i64::checked_div, |l, r| l / r),
+        (Mod, [l, r]) => divide(l, r, i64::checked_rem, |l, r| l % r),
+        (Eq, [l, r]) => return Ok(V::from_bool(equal(l, r, force)?)),
+        (Neq, [l, r]) => return Ok(V::from_bool(!equal(l, r, force)?)),
+        (Lt, [l, r]) => compare(l, r, Ordering::is_lt),
+        (Gt, [l, r]) => compare(l, r, Ordering::is_gt),
+        (Le, [l, r]) => compare(l, r, Ordering::is_le),
+        (Ge, [l, r]) => compare(l, r, Ordering::is_ge),
+        (Pair, [l, r]) => Ok(V::from_pair(Rc::new(l.clone()), Rc::new(r.clone()))),
+        (Cons, [l, r]) => Ok(V::from_cons(Rc::new(l.clone()), Rc::new(r.clone()))),
+        _ => unreachable!("{:?} applied to {} operands", op, operands.len()),
+    };
+    result.map_err(E::from)
+}
+
+/// Whether two values are structurally equal. Both must be of the same type,
+/// and not contain functions.
+fn equal<V, E>(left: &V, right: &V, force: &dyn Fn(V) -> Result<V, E>) -> Result<bool, E>
+where
+    V: Data,
+    E: From<Error<V>>,
+{
+    let (left, right) = (force(left.clone())?, force(right.clone())?);
+    match (left.view(), right.view()) {
+        (View::Int(l), View::Int(r)) => Ok(l == r),
+        (View::Float(l), View::Float(r)) => Ok(l == r),
+        (View::Bool(l), View::Bool(r)) => Ok(l == r),
+        (View::Str(l), View::Str(r)) => Ok(l == r),
+        (View::Char(l), View::Char(r)) => Ok(l == r),
+        (View::Unit, View::Unit) | (View::Nil, View::Nil) => Ok(true),
+        (View::Nil, View::Cons(_, _)) | (View::Cons(_, _), View::Nil) => Ok(false),
+        (View::Cons(h1, t1), View::Cons(h2, t2)) => {
+            Ok(equal(&**h1, &**h2, force)? && equal(&**t1, &**t2, force)?)
+        }
+        (View::Pair(l1, r1), View::Pair(l2, r2)) => {
+            Ok(equal(&**l1, &**l2, force)? && equal(&**r1, &**r2, force)?)
+        }
+        (View::Tuple(l), View::Tuple(r)) if l.len() == r.len() => {
+            for (l, r) in l.iter().zip(r) {
+                if !equal(l, r, force)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        (View::Record(l), View::Record(r))
+            if l.len() == r.len() && l.iter().zip(r).all(|((f1, _), (f2, _))| f1 == f2) =>
+        {
+            for ((_, l), (_, r)) in l.iter().zip(r) {
+                if !equal(l, r, force)? {
+                    return Ok(false);
+                }
+            }
+            Ok(true)
+        }
+        (View::Function, _) => Err(mismatch("a value other than a function", &left).into()),
+        _ => Err(mismatch("a value of the same type", &right).into()),
+    }
+}
+
+fn checked<V: Data>(n: Option<i64>) -> Result<V, Error<V>> {
+    n.map(V::from_int).ok_or(Error::Overflow)
+}
+
+/// Apply an arithmetic operator to two integers or two floats.
+fn arithmetic<V: Data>(
+    left: &V,
+    right: &V,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> Result<V, Error<V>> {
+    match (left.view(), right.view()) {
+        (View::Int(l), View::Int(r)) => checked(int_op(l, r)),
+        (View::Float(l), View::Float(r)) => Ok(V::from_float(float_op(l, r))),
+        (View::Int(_), _) => Err(mismatch("an integer", right)),
+        (View::Float(_), _) => Err(mismatch("a float", right)),
+        _ => Err(mismatch("a number", left)),
+    }
+}
+
+/// Like [`arithmetic`], but integers can't be divided by zero. Floats can.
+fn divide<V: Data>(
+    left: &V,
+    right: &V,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> Result<V, Error<V>> {
+    if matches!((left.view(), right.view()), (View::Int(_), View::Int(0))) {
+        return Err(Error::DivisionByZero);
+    }
+    arithmetic(left, right, int_op, float_op)
+}
+
+/// Order two integers, floats, characters or strings, and test the ordering.
+/// Floats which aren't ordered, i.e. NaNs, fail every test.
+fn compare<V: Data>(left: &V, right: &V, test: fn(Ordering) -> bool) -> Result<V, Error<V>> {
+    let ordering = match (left.view(), right.view()) {
+        (View::Int(l), View::Int(r)) => l.partial_cmp(&r),
+        (View::Float(l), View::Float(r)) => l.partial_cmp(&r),
+        (View::Char(l), View::Char(r)) => l.partial_cmp(&r),
+        (View::Str(l), View::Str(r)) => l.partial_cmp(r),
+        (View::Int(_) | View::Float(_) | View::Char(_) | View::Str(_), _) => {
+            return Err(mismatch("a value of the same type", right))
+        }
+        _ => return Err(mismatch("a number, character or string", left)),
+    };
+    Ok(V::from_bool(ordering.is_some_and(test)))
+}
+
+fn int<V: Data>(v: &V) -> Result<i64, Error<V>> {
+    match v.view() {
+        View::Int(n) => Ok(n),
+        _ => Err(mismatch("an integer", v)),
+    }
+}
+
+/// The boolean a value is, e.g. the condition of an `if`.
+pub(crate) fn bool<V: Data>(v: V) -> Result<bool, Error<V>> {
+    match v.view() {
+        View::Bool(b) => Ok(b),
+        _ => Err(mismatch("a boolean", &v)),
+    }
+}
+
+fn mismatch<V: Data>(expected: &'static str, found: &V) -> Error<V> {
+    Error::TypeMismatch {
+        expected,
+        found: found.clone(),
+    }
+}
+
+/// Print a value as the MiniML literal it'd be written as, lists in
+/// brackets, and functions as `<fun>`. Lists whose tails haven't been
+/// evaluated print as their elements so far consed onto `<lazy>`.
+pub(crate) fn fmt<V: Data>(v: &V, f: &mut fmt::Formatter) -> fmt::Result {
+    match v.view() {
+        View::Int(n) => write!(f, "{}", n),
+        View::Bool(b) => write!(f, "{}", b),
+        View::Float(x) => fmt::Display::fmt(&Expression::Float(x), f),
+        View::Str(s) => fmt::Display::fmt(&Expression::Str(s.to_string()), f),
+        View::Char(c) => fmt::Display::fmt(&Expression::Char(c), f),
+        View::Unit => f.write_str("()"),
+        View::Pair(first, second) => write!(f, "<{}, {}>", first, second),
+        View::Tuple(components) => {
+            let components: Vec<_> = components.iter().map(V::to_string).collect();
+            write!(f, "({})", components.join(", "))
+        }
+        View::Record(fields) => {
+            let fields: Vec<_> = fields
+                .iter()
+                .map(|(field, v)| format!("{} = {}", field, v))
+                .collect();
+            write!(f, "{{ {} }}", fields.join(", "))
+        }
+        View::Function => f.write_str("<fun>"),
+        View::Delayed => f.write_str("<lazy>"),
+        View::Nil | View::Cons(_, _) => {
+            let mut elements = Vec::new();
+            let mut rest = v.clone();
+            loop {
+                let tail = match rest.view() {
+                    View::Cons(head, tail) => {
+                        elements.push(head.to_string());
+                        (**tail).clone().forced()
+                    }
+                    View::Nil => return write!(f, "[{}]", elements.join(", ")),
+                    // The rest of the list hasn't been evaluated.
+                    _ => {
+                        for element in elements {
+                            write!(f, "{} :: ", element)?;
+                        }
+                        return write!(f, "{}", rest);
+                    }
+                };
+                rest = tail;
+            }
+        }
+    }
+}
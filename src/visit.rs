@@ -0,0 +1,182 @@
+//! visit.rs: Generic traversals of expressions, so that analyses needn't
+//! spell out the recursion through every variant.
+
+use crate::exprs::{map_subexpressions, subexpressions};
+use crate::parser::Expression;
+
+/// An analysis over an expression tree. Override `visit_expr` to handle the
+/// variants of interest, calling [`walk_expr`] to carry on into children.
+pub trait Visitor {
+    fn visit_expr(&mut self, e: &Expression) {
+        walk_expr(self, e)
+    }
+}
+
+/// Visit each of `e`'s subexpressions, from left to right.
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, e: &Expression) {
+    for child in subexpressions(e) {
+        visitor.visit_expr(child);
+    }
+}
+
+/// Accumulate a value over `e` and all its subexpressions, visiting each node
+/// before its children, and children from left to right.
+pub fn fold<T>(e: &Expression, init: T, mut f: impl FnMut(T, &Expression) -> T) -> T {
+    let mut acc = init;
+    let mut stack = vec![e];
+    while let Some(e) = stack.pop() {
+        acc = f(acc, e);
+        stack.extend(subexpressions(e).into_iter().rev());
+    }
+    acc
+}
+
+/// Rebuild `e` bottom-up, replacing each node with `f` of it once its
+/// subexpressions have been replaced.
+pub fn map_expr(e: Expression, f: &impl Fn(Expression) -> Expression) -> Expression {
+    f(map_subexpressions(e, |child| map_expr(child, f)))
+}
+
+/// The number of nodes in `e`, counting `e` itself.
+pub fn node_count(e: &Expression) -> usize {
+    fold(e, 0, |count, _| count + 1)
+}
+
+/// The number of nodes on the longest path from `e` down to a leaf, so a
+/// literal has depth 1.
+pub fn depth(e: &Expression) -> usize {
+    let mut deepest = 0;
+    let mut stack = vec![(e, 1)];
+    while let Some((e, d)) = stack.pop() {
+        deepest = deepest.max(d);
+        stack.extend(subexpressions(e).into_iter().map(|child| (child, d + 1)));
+    }
+    deepest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use std::rc::Rc;
+
+    /// Counts the `Num`s in an expression.
+    struct NumCounter(usize);
+
+    impl Visitor for NumCounter {
+        fn visit_expr(&mut self, e: &Expression) {
+            if let Expression::Num(_) = e {
+                self.0 += 1;
+            }
+            walk_expr(self, e)
+        }
+    }
+
+    #[test]
+    fn test_visitor() {
+        let mut counter = NumCounter(0);
+        counter.visit_expr(
+            &parse("let x = 1 in <x + 2, match x with 3 when 4 > x -> [5, 6] | _ -> nil>").unwrap(),
+        );
+        assert_eq!(counter.0, 5);
+
+        let mut counter = NumCounter(0);
+        counter.visit_expr(&parse("1").unwrap());
+        assert_eq!(counter.0, 1);
+    }
+
+    #[test]
+    fn test_fold() {
+        let sum = fold(&parse("1 + 2 * 3").unwrap(), 0, |sum, e| match e {
+            Expression::Num(n) => sum + n,
+            _ => sum,
+        });
+        assert_eq!(sum, 6);
+
+        // Nodes come before their children, which go from left to right.
+        let order = fold(&parse("f (1 - x)").unwrap(), vec![], |mut order, e| {
+            order.push(e.to_sexpr());
+            order
+        });
+        assert_eq!(
+            order,
+            [
+                "(apply (var f) (sub (num 1) (var x)))",
+                "(var f)",
+                "(sub (num 1) (var x))",
+                "(num 1)",
+                "(var x)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_map_expr() {
+        let increment = |e| match e {
+            Expression::Num(n) => Expression::Num(n + 1),
+            e => e,
+        };
+        let e = parse(
+            "let x = 1 in match <x, 2> with <3, _> when x > 4 -> [5] | _ -> (6, { a = 7 }.a)",
+        );
+        assert_eq!(
+            map_expr(e.unwrap(), &increment),
+            parse(
+                "let x = 2 in match <x, 3> with <3, _> when x > 5 -> [6] | _ -> (7, { a = 8 }.a)"
+            )
+            .unwrap()
+        );
+
+        // Children are replaced before their parents see them.
+        let fold_add = |e| match e {
+            Expression::Add(l, r) => match (Rc::unwrap_or_clone(l), Rc::unwrap_or_clone(r)) {
+                (Expression::Num(l), Expression::Num(r)) => Expression::Num(l + r),
+                (l, r) => Expression::Add(Rc::new(l), Rc::new(r)),
+            },
+            e => e,
+        };
+        assert_eq!(
+            map_expr(parse("1 + 2 + (3 + x)").unwrap(), &fold_add),
+            parse("3 + (3 + x)").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = |source: &str| {
+            let e = parse(source).unwrap();
+            (node_count(&e), depth(&e))
+        };
+        assert_eq!(metrics("1"), (1, 1));
+        assert_eq!(metrics("1 + 2"), (3, 2));
+        assert_eq!(metrics("1 + 2 * 3"), (5, 3));
+        assert_eq!(metrics("f a b"), (5, 3));
+        assert_eq!(metrics("let x = 1 in x"), (3, 2));
+        assert_eq!(metrics("(1, 2, 3, 4)"), (5, 2));
+        assert_eq!(metrics("match x with 0 -> 1 | n when n > 0 -> n"), (7, 3));
+
+        // fn x0. fn x1. ... fn x99. x0
+        let params = (0..100).map(|i| format!("x{}", i)).collect::<Vec<_>>();
+        assert_eq!(metrics(&format!("fn {}. x0", params.join(" "))), (101, 101));
+    }
+
+    /// Collects the variables used, without descending into functions.
+    struct ShallowVars(Vec<String>);
+
+    impl Visitor for ShallowVars {
+        fn visit_expr(&mut self, e: &Expression) {
+            match e {
+                Expression::Var(v) => self.0.push(v.ident.to_string()),
+                Expression::Fn(_, _) => {}
+                e => walk_expr(self, e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_visitor_override() {
+        let mut vars = ShallowVars(vec![]);
+        vars.visit_expr(&parse("f x (fn y. y z) w").unwrap());
+        assert_eq!(vars.0, ["f", "x", "w"]);
+    }
+}
@@ -0,0 +1,465 @@
+//! vm.rs: A stack machine which runs the code bytecode.rs compiles, keeping
+//! operands on a stack and the functions being applied in call frames.
+
+use std::fmt;
+use std::rc::Rc;
+
+use crate::bytecode::{primitive, Instr};
+use crate::parser::{Expression, Variable};
+use crate::value::{self, Data, View};
+
+/// A value on the machine's stack.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    Char(char),
+    Unit,
+    Nil,
+    Cons(Rc<Value>, Rc<Value>),
+    Pair(Rc<Value>, Rc<Value>),
+    /// A function, whose body starts at `code`, along with the bindings it
+    /// was made with.
+    Closure {
+        code: usize,
+        env: Env,
+    },
+}
+
+/// Values print as [`crate::interp::Value`]s do.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        value::fmt(self, f)
+    }
+}
+
+impl Data for Value {
+    fn view(&self) -> View<'_, Value> {
+        match self {
+            Value::Int(n) => View::Int(*n),
+            Value::Float(x) => View::Float(*x),
+            Value::Bool(b) => View::Bool(*b),
+            Value::Str(s) => View::Str(s),
+            Value::Char(c) => View::Char(*c),
+            Value::Unit => View::Unit,
+            Value::Nil => View::Nil,
+            Value::Cons(head, tail) => View::Cons(head, tail),
+            Value::Pair(first, second) => View::Pair(first, second),
+            Value::Closure { .. } => View::Function,
+        }
+    }
+
+    fn from_int(n: i64) -> Value {
+        Value::Int(n)
+    }
+
+    fn from_float(x: f64) -> Value {
+        Value::Float(x)
+    }
+
+    fn from_bool(b: bool) -> Value {
+        Value::Bool(b)
+    }
+
+    fn from_pair(first: Rc<Value>, second: Rc<Value>) -> Value {
+        Value::Pair(first, second)
+    }
+
+    fn from_cons(head: Rc<Value>, tail: Rc<Value>) -> Value {
+        Value::Cons(head, tail)
+    }
+}
+
+/// The values bound where code runs, which [`Instr::Load`] numbers from the
+/// innermost. Closures share the bindings they capture.
+#[derive(Clone, Default, Debug)]
+pub struct Env(Option<Rc<Binding>>);
+
+#[derive(Debug)]
+struct Binding {
+    frame: Frame,
+    next: Env,
+}
+
+#[derive(Debug)]
+enum Frame {
+    Value(Value),
+    /// Functions bound by [`Instr::BindRec`], whose bodies start at the
+    /// instructions given. Each is made a closure over the bindings starting
+    /// at this frame as it's loaded, so there's no reference cycle.
+    Rec(Vec<usize>),
+}
+
+impl Frame {
+    fn len(&self) -> usize {
+        match self {
+            Frame::Value(_) => 1,
+            Frame::Rec(starts) => starts.len(),
+        }
+    }
+}
+
+/// Bindings are only equal if they're the same ones, e.g. captured by the
+/// same closure.
+impl PartialEq for Env {
+    fn eq(&self, other: &Env) -> bool {
+        match (&self.0, &other.0) {
+            (Some(b1), Some(b2)) => Rc::ptr_eq(b1, b2),
+            (b1, b2) => b1.is_none() && b2.is_none(),
+        }
+    }
+}
+
+impl Env {
+    fn push(&self, frame: Frame) -> Env {
+        Env(Some(Rc::new(Binding {
+            frame,
+            next: self.clone(),
+        })))
+    }
+
+    fn load(&self, mut n: usize) -> Result<Value> {
+        let mut env = self;
+        while let Some(binding) = &env.0 {
+            let len = binding.frame.len();
+            if n < len {
+                return Ok(match &binding.frame {
+                    Frame::Value(v) => v.clone(),
+                    Frame::Rec(starts) => Value::Closure {
+                        code: starts[len - 1 - n],
+                        env: env.clone(),
+                    },
+                });
+            }
+            n -= len;
+            env = &binding.next;
+        }
+        Err(VmError::Malformed(
+            "a variable was loaded which isn't bound",
+        ))
+    }
+
+    /// The bindings with the innermost `n` dropped.
+    fn drop(&self, mut n: usize) -> Result<Env> {
+        let mut env = self;
+        while n > 0 {
+            match &env.0 {
+                Some(binding) if binding.frame.len() <= n => {
+                    n -= binding.frame.len();
+                    env = &binding.next;
+                }
+                _ => {
+                    return Err(VmError::Malformed(
+                        "bindings were dropped which weren't made",
+                    ))
+                }
+            }
+        }
+        Ok(env.clone())
+    }
+}
+
+/// An error raised while running code.
+#[derive(Clone, PartialEq, Debug)]
+pub enum VmError {
+    /// An operation was applied to a value of the wrong kind, e.g. `not(1)`.
+    TypeMismatch {
+        expected: &'static str,
+        found: Value,
+    },
+    /// A variable which isn't bound was looked up.
+    Unbound(Variable),
+    /// Integer arithmetic overflowed.
+    Overflow,
+    /// An integer was divided by zero.
+    DivisionByZero,
+    /// The code reached an expression the machine can't evaluate.
+    Unsupported(Expression),
+    /// The code couldn't have been compiled from an expression, e.g. it pops
+    /// from an empty stack.
+    Malformed(&'static str),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::TypeMismatch { expected, found } => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            VmError::Unbound(var) => write!(f, "unbound variable {}", var.ident),
+            VmError::Overflow => f.write_str("integer overflow"),
+            VmError::DivisionByZero => f.write_str("division by zero"),
+            VmError::Unsupported(e) => write!(f, "cannot evaluate {}", e),
+            VmError::Malformed(why) => write!(f, "malformed code: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+impl From<value::Error<Value>> for VmError {
+    fn from(error: value::Error<Value>) -> VmError {
+        match error {
+            value::Error::TypeMismatch { expected, found } => {
+                VmError::TypeMismatch { expected, found }
+            }
+            value::Error::Overflow => VmError::Overflow,
+            value::Error::DivisionByZero => VmError::DivisionByZero,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, VmError>;
+
+/// Run code from the first instruction until it falls off the end, and give
+/// the value it leaves on top of the stack.
+pub fn run(code: &[Instr]) -> Result<Value> {
+    let mut vm = Vm::default();
+    while vm.pc < code.len() {
+        let instr = &code[vm.pc];
+        vm.pc += 1;
+        vm.step(instr)?;
+    }
+    vm.pop()
+}
+
+/// The state of a machine part way through running code.
+#[derive(Default)]
+struct Vm {
+    /// The index of the next instruction to run.
+    pc: usize,
+    stack: Vec<Value>,
+    env: Env,
+    /// Where to return to from each function being applied, and the
+    /// bindings to restore, innermost last.
+    frames: Vec<(usize, Env)>,
+}
+
+impl Vm {
+    fn push(&mut self, v: Value) {
+        self.stack.push(v);
+    }
+
+    fn pop(&mut self) -> Result<Value> {
+        self.stack
+            .pop()
+            .ok_or(VmError::Malformed("a value was popped from an empty stack"))
+    }
+
+    fn step(&mut self, instr: &Instr) -> Result<()> {
+        use Instr::*;
+        match instr {
+            PushInt(n) => self.push(Value::Int(*n)),
+            PushFloat(f) => self.push(Value::Float(*f)),
+            PushBool(b) => self.push(Value::Bool(*b)),
+            PushStr(s) => self.push(Value::Str(s.clone())),
+            PushChar(c) => self.push(Value::Char(*c)),
+            PushUnit => self.push(Value::Unit),
+            PushNil => self.push(Value::Nil),
+            Pop => {
+                self.pop()?;
+            }
+            Load(n) => {
+                let v = self.env.load(*n)?;
+                self.push(v);
+            }
+            Bind => {
+                let v = self.pop()?;
+                self.env = self.env.push(Frame::Value(v));
+            }
+            BindRec(starts) => self.env = self.env.push(Frame::Rec(starts.clone())),
+            Unbind(n) => self.env = self.env.drop(*n)?,
+            MakeClosure(code) => self.push(Value::Closure {
+                code: *code,
+                env: self.env.clone(),
+            }),
+            // The body runs with the bindings the function was made with, not
+            // those where it's applied.
+            Call => {
+                let arg = self.pop()?;
+                match self.pop()? {
+                    Value::Closure { code, env } => {
+                        let caller = std::mem::replace(&mut self.env, env.push(Frame::Value(arg)));
+                        self.frames.push((self.pc, caller));
+                        self.pc = code;
+                    }
+                    found => {
+                        return Err(VmError::TypeMismatch {
+                            expected: "a function",
+                            found,
+                        })
+                    }
+                }
+            }
+            Return => {
+                let (pc, env) = self
+                    .frames
+                    .pop()
+                    .ok_or(VmError::Malformed("a return outside a function"))?;
+                self.pc = pc;
+                self.env = env;
+            }
+            Jump(to) => self.pc = *to,
+            JumpIfFalse(to) => {
+                if !bool(self.pop()?)? {
+                    self.pc = *to;
+                }
+            }
+            Unbound(var) => return Err(VmError::Unbound(var.clone())),
+            Unsupported(e) => return Err(VmError::Unsupported(e.clone())),
+            _ => {
+                let (op, arity) =
+                    primitive(instr).expect("every other instruction is a primitive operation");
+                let mut operands = Vec::with_capacity(arity);
+                for _ in 0..arity {
+                    operands.push(self.pop()?);
+                }
+                operands.reverse();
+                let result = value::apply::<_, VmError>(op, &operands, &Ok)?;
+                self.push(result);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn bool(v: Value) -> Result<bool> {
+    Ok(value::bool(v)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::{compile, peephole};
+    use crate::interp;
+    use crate::parser::parse;
+
+    fn run_source(source: &str) -> Result<Value> {
+        run(&compile(&parse(source).unwrap()))
+    }
+
+    #[test]
+    fn test_run() {
+        assert_eq!(run_source("1 + 2"), Ok(Value::Int(3)));
+        assert_eq!(
+            run(&[Instr::PushInt(1), Instr::PushInt(2), Instr::Add]),
+            Ok(Value::Int(3))
+        );
+        assert_eq!(
+            run_source("if 1 < 2 then 'y' else 'n'"),
+            Ok(Value::Char('y'))
+        );
+        assert_eq!(run_source("(fn x. (x * x)) 7"), Ok(Value::Int(49)));
+        assert_eq!(run_source("(); \"a\""), Ok(Value::Str("a".to_string())));
+        assert!(matches!(run_source("fn x. x"), Ok(Value::Closure { .. })));
+        assert_eq!(
+            run_source("1 + true"),
+            Err(VmError::TypeMismatch {
+                expected: "an integer",
+                found: Value::Bool(true)
+            })
+        );
+        assert_eq!(
+            run_source("1 2"),
+            Err(VmError::TypeMismatch {
+                expected: "a function",
+                found: Value::Int(1)
+            })
+        );
+        // Unbound variables only fail if they're reached.
+        assert_eq!(run_source("if true then 1 else x"), Ok(Value::Int(1)));
+        assert_eq!(
+            run_source("if false then 1 else x"),
+            Err(VmError::Unbound(Variable { ident: "x".into() }))
+        );
+        assert!(matches!(run(&[Instr::Add]), Err(VmError::Malformed(_))));
+        assert!(matches!(run(&[Instr::Return]), Err(VmError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_run_recursion() {
+        // Deep recursion uses the machine's stack, not Rust's.
+        assert_eq!(
+            run_source("let rec sum = fn n. if n < 1 then 0 else n + sum (n - 1) in sum 100000"),
+            Ok(Value::Int(5000050000))
+        );
+    }
+
+    #[test]
+    fn test_run_agrees_with_eval() {
+        for source in [
+            "1 + 2 * 3 - 4 / 2",
+            "7 % 3 == 1 and not(2.5 > 3.0) or false",
+            "-(succ(pred(5)))",
+            "\"abc\" < \"abd\"",
+            "<1, <'a', true>>",
+            "hd(tl([1, 2, 3])) :: [fst(<4, 5>), snd(<6, 7>)]",
+            "[1, 2] == [1, 2]",
+            "let x = 1 in let y = x + 1 in <x, y>",
+            "let x = 1 in let x = 2 and y = x in <x, y>",
+            "let y = 1 in let f = fn x. (x + y) in let y = 100 in f 10",
+            "let compose = fn f. fn g. fn x. f (g x) in compose (fn x. (x * 2)) (fn x. (x + 1)) 5",
+            "let twice = fn f. fn x. f (f x) in twice twice (fn x. (x + 3)) 0",
+            "let rec fact = fn n. if n < 1 then 1 else n * fact (n - 1) in fact 10",
+            "let rec even = fn n. if n < 1 then true else odd (n - 1) \
+             and odd = fn n. if n < 1 then false else even (n - 1) in <even 10, odd 7>",
+            "let rec map = fn f. fn l. if l == nil then nil else f (hd(l)) :: map f (tl(l)) \
+             in map (fn x. (x * x)) [1, 2, 3]",
+            "let rec f = fn n. if n < 1 then 0 else 1 + f (n - 1) in \
+             let g = f in let f = fn n. 100 in g 3",
+            "let rec f = fn n. n and x = f 5 in x",
+            "(fn x. x) (fn y. y)",
+            "1 / 0",
+            "9223372036854775807 + 1",
+            "hd(nil)",
+            "fst(1)",
+            "(fn x. x) == (fn x. x)",
+            "if 1 then 2 else 3",
+            "x + 1",
+        ] {
+            let e = parse(source).unwrap();
+            assert_eq!(
+                run(&compile(&e))
+                    .map(|v| v.to_string())
+                    .map_err(|error| error.to_string()),
+                interp::eval(&e)
+                    .map(|v| v.to_string())
+                    .map_err(|error| error.to_string()),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_peephole() {
+        for source in [
+            "1 + 2 * 3 - 4 / 2",
+            "-(succ(pred(5))) == -5 and not(false)",
+            "if 1 + 1 == 2 then <3 * 3, 'a'> else <0, 'b'>",
+            "let x = 2 * 3 in let f = fn y. (x + y * (4 - 1)) in f (10 % 4)",
+            "let rec fact = fn n. if n < 1 + 0 then 1 else n * fact (n - 1) in fact (2 * 5)",
+            "let rec even = fn n. if n < 1 then true else odd (n - 1) \
+             and odd = fn n. if n < 1 then false else even (n - 1) in <even (5 + 5), odd 7>",
+            "[1 + 1, 2 * 2] == [2, 4]",
+            "1 / (1 - 1)",
+            "9223372036854775807 + (0 + 1)",
+            "true and 1 + 2",
+            "x + (1 + 2)",
+        ] {
+            let code = compile(&parse(source).unwrap());
+            assert_eq!(
+                run(&peephole(code.clone()))
+                    .map(|v| v.to_string())
+                    .map_err(|error| error.to_string()),
+                run(&code)
+                    .map(|v| v.to_string())
+                    .map_err(|error| error.to_string()),
+                "{}",
+                source
+            );
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! wasm.rs: A backend generating WebAssembly text for the subset of MiniML
+//! on integers and booleans, which are represented as `i64`s and `i32`s.
+
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::codegen::{unique, CodegenError};
+use crate::infer::infer;
+use crate::parser::{Expression, Type};
+
+type Result<T> = std::result::Result<T, CodegenError>;
+
+/// A WebAssembly module exporting a function `main`, which returns the value
+/// of `e`: an integer as an `i64`, or a boolean as an `i32` 1 or 0. `e` must
+/// be well-typed, and only use integers, booleans, arithmetic, comparisons,
+/// `if` and `let`.
+pub fn emit_wat(e: &Expression) -> Result<String> {
+    if !matches!(infer(e)?, Type::Int | Type::Bool) {
+        return Err(CodegenError::Unsupported(e.clone()));
+    }
+    let mut wat = Wat::default();
+    let (body, ty) = wat.expression(e)?;
+    let mut module = format!("(module\n  (func $main (export \"main\") (result {})\n", ty);
+    for (local, ty) in &wat.locals {
+        module += &format!("    (local ${} {})\n", local, ty);
+    }
+    module += &format!("    {}))\n", body);
+    Ok(module)
+}
+
+/// The WebAssembly types integers and booleans are represented as.
+const INT: &str = "i64";
+const BOOL: &str = "i32";
+
+#[derive(Default)]
+struct Wat {
+    /// The function's locals, one per variable bound, and their types.
+    locals: Vec<(String, &'static str)>,
+    used: HashSet<String>,
+    /// The local each variable in scope is held in, and its type, innermost
+    /// last.
+    scope: Vec<(Rc<str>, String, &'static str)>,
+}
+
+impl Wat {
+    /// A folded instruction leaving the value of `e` on the stack, and the
+    /// type of that value.
+    fn expression(&mut self, e: &Expression) -> Result<(String, &'static str)> {
+        use Expression::*;
+        let unsupported = || CodegenError::Unsupported(e.clone());
+        Ok(match e {
+            Num(n) => (format!("(i64.const {})", n), INT),
+            True => ("(i32.const 1)".to_string(), BOOL),
+            False => ("(i32.const 0)".to_string(), BOOL),
+            Var(var) => match self
+                .scope
+                .iter()
+                .rev()
+                .find(|(name, _, _)| *name == var.ident)
+            {
+                Some((_, local, ty)) => (format!("(local.get ${})", local), *ty),
+                None => return Err(unsupported()),
+            },
+            Add(e1, e2) => (self.instr("i64.add", &[e1, e2])?, INT),
+            Sub(e1, e2) => (self.instr("i64.sub", &[e1, e2])?, INT),
+            Mul(e1, e2) => (self.instr("i64.mul", &[e1, e2])?, INT),
+            Div(e1, e2) => (self.instr("i64.div_s", &[e1, e2])?, INT),
+            Mod(e1, e2) => (self.instr("i64.rem_s", &[e1, e2])?, INT),
+            // Both integers and booleans may be compared.
+            Eq(e1, e2) => (self.compare("eq", e1, e2)?, BOOL),
+            Neq(e1, e2) => (self.compare("ne", e1, e2)?, BOOL),
+            Lt(e1, e2) => (self.compare("lt_s", e1, e2)?, BOOL),
+            Gt(e1, e2) => (self.compare("gt_s", e1, e2)?, BOOL),
+            Le(e1, e2) => (self.compare("le_s", e1, e2)?, BOOL),
+            Ge(e1, e2) => (self.compare("ge_s", e1, e2)?, BOOL),
+            Not(e) => (self.instr("i32.eqz", &[e])?, BOOL),
+            Neg(e) => (
+                format!("(i64.sub (i64.const 0) {})", self.expression(e)?.0),
+                INT,
+            ),
+            Succ(e) => (
+                format!("(i64.add {} (i64.const 1))", self.expression(e)?.0),
+                INT,
+            ),
+            Pred(e) => (
+                format!("(i64.sub {} (i64.const 1))", self.expression(e)?.0),
+                INT,
+            ),
+            // Only the branch taken, or the operand needed, is evaluated.
+            If(cond, yes, no) => self.branch(cond, yes, no)?,
+            And(left, right) => self.branch(left, right, &False)?,
+            Or(left, right) => self.branch(left, &True, right)?,
+            Let(definitions, body) if !definitions.iter().any(|d| d.recursive) => {
+                let mut sets = String::new();
+                let mut bound = vec![];
+                for d in definitions {
+                    let local = unique(&d.var.ident, &mut self.used);
+                    let (value, ty) = self.expression(&d.expr)?;
+                    self.locals.push((local.clone(), ty));
+                    sets += &format!(" (local.set ${} {})", local, value);
+                    bound.push((d.var.ident.clone(), local, ty));
+                }
+                let depth = self.scope.len();
+                self.scope.extend(bound);
+                let (body, ty) = self.expression(body)?;
+                self.scope.truncate(depth);
+                (format!("(block (result {}){} {})", ty, sets, body), ty)
+            }
+            Ann(e, _) => self.expression(e)?,
+            _ => return Err(unsupported()),
+        })
+    }
+
+    /// A folded instruction applied to operands.
+    fn instr(&mut self, instr: &str, operands: &[&Expression]) -> Result<String> {
+        let mut folded = format!("({}", instr);
+        for operand in operands {
+            folded += &format!(" {}", self.expression(operand)?.0);
+        }
+        Ok(folded + ")")
+    }
+
+    /// A comparison of two operands of the same type, with the instruction
+    /// `op` for that type.
+    fn compare(&mut self, op: &str, e1: &Expression, e2: &Expression) -> Result<String> {
+        let (left, ty) = self.expression(e1)?;
+        let (right, _) = self.expression(e2)?;
+        Ok(format!("({}.{} {} {})", ty, op, left, right))
+    }
+
+    fn branch(
+        &mut self,
+        cond: &Expression,
+        yes: &Expression,
+        no: &Expression,
+    ) -> Result<(String, &'static str)> {
+        let (cond, _) = self.expression(cond)?;
+        let (yes, ty) = self.expression(yes)?;
+        let (no, _) = self.expression(no)?;
+        Ok((
+            format!("(if (result {}) {} (then {}) (else {}))", ty, cond, yes, no),
+            ty,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::TypeError;
+    use crate::parser::parse;
+
+    fn wat(source: &str) -> Result<String> {
+        emit_wat(&parse(source).unwrap())
+    }
+
+    #[test]
+    fn test_emit_wat() {
+        let module = wat("1 + 2").unwrap();
+        assert!(module.contains("(i64.add (i64.const 1) (i64.const 2))"));
+        assert!(module.contains("(export \"main\") (result i64)"));
+        assert_eq!(
+            wat("let x = 6 in let x = x * 7 in x == 42 or false").unwrap(),
+            "(module
+  (func $main (export \"main\") (result i32)
+    (local $x i64)
+    (local $x1 i64)
+    (block (result i32) (local.set $x (i64.const 6)) \
+(block (result i32) (local.set $x1 (i64.mul (local.get $x) (i64.const 7))) \
+(if (result i32) (i64.eq (local.get $x1) (i64.const 42)) \
+(then (i32.const 1)) (else (i32.const 0)))))))
+"
+        );
+        assert!(wat("let y = 1 in if not(true) then -y else succ(2) % 2")
+            .unwrap()
+            .contains(
+                "(if (result i64) (i32.eqz (i32.const 1)) \
+                       (then (i64.sub (i64.const 0) (local.get $y))) \
+                       (else (i64.rem_s (i64.add (i64.const 2) (i64.const 1)) (i64.const 2))))"
+            ));
+        // Integers are 64-bit, so don't wrap where the interpreter wouldn't.
+        assert!(wat("5000000000 > 0")
+            .unwrap()
+            .contains("(i64.gt_s (i64.const 5000000000) (i64.const 0))"));
+        assert!(wat("-9223372036854775808 < 9223372036854775807")
+            .unwrap()
+            .contains("(i64.const -9223372036854775808)"));
+        assert!(wat("let b = true in b == false")
+            .unwrap()
+            .contains("(local $b i32)"));
+    }
+
+    #[test]
+    fn test_emit_wat_errors() {
+        assert_eq!(
+            wat("fn x. (x + 1)"),
+            Err(CodegenError::Unsupported(parse("fn x. (x + 1)").unwrap()))
+        );
+        assert_eq!(
+            wat("let f = fn x. (x + 1) in f 2"),
+            Err(CodegenError::Unsupported(parse("fn x. (x + 1)").unwrap()))
+        );
+        assert_eq!(
+            wat("1.5 == 2.5"),
+            Err(CodegenError::Unsupported(Expression::Float(1.5)))
+        );
+        assert_eq!(
+            wat("1 + true"),
+            Err(CodegenError::Type(TypeError::Mismatch {
+                expected: Type::Int,
+                found: Type::Bool
+            }))
+        );
+        assert_eq!(
+            wat("1 + true").unwrap_err().to_string(),
+            "type error: expected Int but found Bool"
+        );
+    }
+}
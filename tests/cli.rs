@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Run the compiler on `source`, written to a file named `name`.
+fn run(name: &str, source: &str, args: &[&str]) -> Output {
+    let path: PathBuf = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+    std::fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_miniml_compiler"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_emit_ast_json() {
+    let output = run(
+        "sum.ml",
+        "let a = 2 ;; let b = 2 ;; a + b",
+        &["--emit", "ast-json"],
+    );
+    assert!(output.status.success());
+
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["declarations"].as_array().unwrap().len(), 2);
+    assert!(json["main"]["Add"].is_array());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_parse_error() {
+    let output = run("bad.ml", "let a = in a", &["--emit", "ast-json"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("expected expression at offset 8"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_usage_error() {
+    let output = run("usage.ml", "1", &["--emit", "llvm"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("usage:"));
+}
+
+#[cfg(not(feature = "serde"))]
+#[test]
+fn test_emit_ast_json_needs_serde() {
+    let output = run("json.ml", "1", &["--emit", "ast-json"]);
+    assert_eq!(output.status.code(), Some(2));
+}